@@ -0,0 +1,314 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Audit log of video access and configuration changes.
+//!
+//! Entries are written to the database immediately rather than on the next flush, so that they
+//! survive a crash. The log's size is bounded by `meta.max_audit_entries`: each addition removes
+//! the oldest entries beyond that limit.
+
+use crate::auth::{self, FromSqlIpAddr, SessionHash};
+use crate::db::{FromSqlUuid, StreamType};
+use crate::recording;
+use failure::{Error, bail};
+use rusqlite::{Connection, params};
+use std::ops::Range;
+use uuid::Uuid;
+
+/// An entry in the audit log.
+#[derive(Clone, Debug, Default)]
+pub struct Entry {
+    /// Assigned when the entry is added; ignored by `State::add`.
+    pub id: i64,
+
+    /// The time, peer address, and user agent of the request. `when_sec` is required.
+    pub request: auth::Request,
+
+    /// The user behind the request, if any.
+    pub user_id: Option<i32>,
+
+    /// The user's name as of when the entry was added; ignored by `State::add`.
+    pub username: Option<String>,
+
+    /// The session or API token behind the request, if any.
+    pub session_id_hash: Option<SessionHash>,
+
+    /// What was done: the endpoint for video access, such as `view.mp4`, or a short description
+    /// of a configuration change, such as `add user`.
+    pub action: String,
+
+    pub camera_uuid: Option<Uuid>,
+    pub stream_type: Option<StreamType>,
+
+    /// The time range of the video accessed.
+    pub time_90k: Option<Range<recording::Time>>,
+
+    /// The number of bytes of video sent.
+    pub bytes: Option<i64>,
+
+    /// Any further detail, such as the name of the user added.
+    pub detail: Option<String>,
+}
+
+/// Criteria for `State::list`. Matching entries are returned newest first.
+#[derive(Debug)]
+pub struct Query {
+    /// If set, only entries with smaller ids, for paging through older entries.
+    pub before_id: Option<i64>,
+
+    /// If set, only entries added within this time range, in seconds since epoch.
+    pub time_sec: Option<Range<i64>>,
+
+    pub username: Option<String>,
+    pub camera_uuid: Option<Uuid>,
+    pub limit: i64,
+}
+
+/// All state associated with the audit log. This is the entry point to this module.
+pub(crate) struct State {
+    max_entries: Option<i64>,
+}
+
+impl State {
+    pub fn init(conn: &Connection) -> Result<Self, Error> {
+        let max_entries: Option<i64> =
+            conn.query_row("select max_audit_entries from meta", params![], |row| row.get(0))?;
+        Ok(State { max_entries })
+    }
+
+    /// Adds an entry made by the user with the given name (which should match `e.user_id`),
+    /// returning its id.
+    pub fn add(&self, conn: &Connection, e: &Entry, username: Option<&str>) -> Result<i64, Error> {
+        let when_sec = match e.request.when_sec {
+            Some(w) => w,
+            None => bail!("audit entry must have a time"),
+        };
+        let mut stmt = conn.prepare_cached(r#"
+            insert into audit (time_sec,  user_agent,  peer_addr,  user_id,  username,
+                               session_id_hash,  action,  camera_uuid,  stream_type,  start_90k,
+                               end_90k,  bytes,  detail)
+                       values (:time_sec, :user_agent, :peer_addr, :user_id, :username,
+                               :session_id_hash, :action, :camera_uuid, :stream_type, :start_90k,
+                               :end_90k, :bytes, :detail)
+        "#)?;
+        let addr = e.request.addr_buf();
+        let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
+        let session_id_hash: Option<&[u8]> = e.session_id_hash.as_ref().map(|h| &h.0[..]);
+        let camera_uuid: Option<&[u8]> = e.camera_uuid.as_ref().map(|u| &u.as_bytes()[..]);
+        stmt.execute_named(&[
+            (":time_sec", &when_sec),
+            (":user_agent", &e.request.user_agent),
+            (":peer_addr", &addr),
+            (":user_id", &e.user_id),
+            (":username", &username),
+            (":session_id_hash", &session_id_hash),
+            (":action", &e.action),
+            (":camera_uuid", &camera_uuid),
+            (":stream_type", &e.stream_type.map(StreamType::as_str)),
+            (":start_90k", &e.time_90k.as_ref().map(|r| r.start.0)),
+            (":end_90k", &e.time_90k.as_ref().map(|r| r.end.0)),
+            (":bytes", &e.bytes),
+            (":detail", &e.detail),
+        ])?;
+        let id = conn.last_insert_rowid();
+
+        // Ids are assigned in increasing order and entries are only removed oldest first, so
+        // the ids are contiguous.
+        if let Some(max) = self.max_entries {
+            let mut stmt = conn.prepare_cached("delete from audit where id <= ?")?;
+            stmt.execute(params![id - max])?;
+        }
+        Ok(id)
+    }
+
+    pub fn list(&self, conn: &Connection, q: &Query) -> Result<Vec<Entry>, Error> {
+        let mut stmt = conn.prepare_cached(r#"
+            select
+              id, time_sec, user_agent, peer_addr, user_id, username, session_id_hash, action,
+              camera_uuid, stream_type, start_90k, end_90k, bytes, detail
+            from
+              audit
+            where
+              (:before_id is null or id < :before_id) and
+              (:start_sec is null or time_sec >= :start_sec) and
+              (:end_sec is null or time_sec < :end_sec) and
+              (:username is null or username = :username) and
+              (:camera_uuid is null or camera_uuid = :camera_uuid)
+            order by id desc
+            limit :limit
+        "#)?;
+        let camera_uuid: Option<&[u8]> = q.camera_uuid.as_ref().map(|u| &u.as_bytes()[..]);
+        let mut rows = stmt.query_named(&[
+            (":before_id", &q.before_id),
+            (":start_sec", &q.time_sec.as_ref().map(|r| r.start)),
+            (":end_sec", &q.time_sec.as_ref().map(|r| r.end)),
+            (":username", &q.username),
+            (":camera_uuid", &camera_uuid),
+            (":limit", &q.limit),
+        ])?;
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            let peer_addr: FromSqlIpAddr = row.get(3)?;
+            let session_id_hash: Option<Vec<u8>> = row.get(6)?;
+            let session_id_hash = match session_id_hash {
+                None => None,
+                Some(h) if h.len() == 24 => {
+                    let mut s = SessionHash::default();
+                    s.0.copy_from_slice(&h);
+                    Some(s)
+                },
+                Some(_) => bail!("audit entry has bad session_id_hash"),
+            };
+            let camera_uuid: Option<FromSqlUuid> = row.get(8)?;
+            let stream_type: Option<String> = row.get(9)?;
+            let start_90k: Option<i64> = row.get(10)?;
+            let end_90k: Option<i64> = row.get(11)?;
+            entries.push(Entry {
+                id: row.get(0)?,
+                request: auth::Request {
+                    when_sec: Some(row.get(1)?),
+                    user_agent: row.get(2)?,
+                    addr: peer_addr.0,
+                },
+                user_id: row.get(4)?,
+                username: row.get(5)?,
+                session_id_hash,
+                action: row.get(7)?,
+                camera_uuid: camera_uuid.map(|u| u.0),
+                stream_type: stream_type.as_ref().and_then(|t| StreamType::parse(t)),
+                time_90k: match (start_90k, end_90k) {
+                    (Some(s), Some(e)) => Some(recording::Time(s) .. recording::Time(e)),
+                    _ => None,
+                },
+                bytes: row.get(12)?,
+                detail: row.get(13)?,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db;
+    use crate::testutil;
+    use rusqlite::Connection;
+    use super::*;
+
+    fn entry(when_sec: i64, user_id: Option<i32>, action: &str) -> Entry {
+        Entry {
+            request: auth::Request {
+                when_sec: Some(when_sec),
+                user_agent: Some(b"some ua".to_vec()),
+                addr: Some(::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(192, 168, 1, 100))),
+            },
+            user_id,
+            action: action.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let state = State::init(&conn).unwrap();
+        let uuid = Uuid::parse_str("9a9d8c0a-8b35-4f6a-9b4c-1c6a3a2f2f3a").unwrap();
+        let mut e = entry(100, Some(1), "view.mp4");
+        e.session_id_hash = Some(SessionHash([7u8; 24]));
+        e.camera_uuid = Some(uuid);
+        e.stream_type = Some(StreamType::SUB);
+        e.time_90k = Some(recording::Time(90_000) .. recording::Time(180_000));
+        e.bytes = Some(1234);
+        let id = state.add(&conn, &e, Some("slamb")).unwrap();
+        state.add(&conn, &entry(200, None, "add user"), None).unwrap();
+
+        let all = Query {
+            before_id: None,
+            time_sec: None,
+            username: None,
+            camera_uuid: None,
+            limit: 10,
+        };
+        let rows = state.list(&conn, &all).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].action, "add user");  // newest first.
+        let r = &rows[1];
+        assert_eq!(r.id, id);
+        assert_eq!(r.request.when_sec, Some(100));
+        assert_eq!(r.request.user_agent.as_ref().map(|u| &u[..]), Some(&b"some ua"[..]));
+        assert_eq!(r.request.addr, e.request.addr);
+        assert_eq!(r.user_id, Some(1));
+        assert_eq!(r.username.as_ref().map(String::as_str), Some("slamb"));
+        assert!(r.session_id_hash == e.session_id_hash);
+        assert_eq!(r.camera_uuid, Some(uuid));
+        assert_eq!(r.stream_type, Some(StreamType::SUB));
+        assert_eq!(r.time_90k, e.time_90k);
+        assert_eq!(r.bytes, Some(1234));
+        assert_eq!(r.detail, None);
+
+        let by_user = state.list(&conn, &Query {
+            username: Some("slamb".to_owned()),
+            .. all
+        }).unwrap();
+        assert_eq!(by_user.len(), 1);
+        assert_eq!(by_user[0].id, id);
+    }
+
+    #[test]
+    fn retention() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        conn.execute("update meta set max_audit_entries = 3", params![]).unwrap();
+        let state = State::init(&conn).unwrap();
+        for i in 0..5 {
+            state.add(&conn, &entry(i, None, "view.mp4"), None).unwrap();
+        }
+        let q = Query {
+            before_id: None,
+            time_sec: None,
+            username: None,
+            camera_uuid: None,
+            limit: 10,
+        };
+        let times: Vec<_> = state.list(&conn, &q).unwrap().iter()
+                                 .map(|e| e.request.when_sec.unwrap())
+                                 .collect();
+        assert_eq!(times, &[4, 3, 2]);
+
+        let q = Query { before_id: Some(4), time_sec: Some(0..3), .. q };
+        let times: Vec<_> = state.list(&conn, &q).unwrap().iter()
+                                 .map(|e| e.request.when_sec.unwrap())
+                                 .collect();
+        assert_eq!(times, &[2]);
+    }
+}
@@ -28,21 +28,26 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use log::info;
+use log::{info, warn};
 use base::strutil;
 use blake2_rfc::blake2b::blake2b;
+use crate::db::Camera;
 use crate::schema::Permissions;
-use failure::{Error, bail, format_err};
+use crate::signal::Signal;
+use crate::totp;
+use failure::{Error, Fail, bail, format_err};
 use fnv::FnvHashMap;
 use lazy_static::lazy_static;
 use libpasta;
 use parking_lot::Mutex;
 use protobuf::Message;
 use rusqlite::{Connection, Transaction, types::ToSql};
+use std::cmp;
 use std::collections::BTreeMap;
 use std::fmt;
 use std::net::IpAddr;
 use std::sync::Arc;
+use uuid::Uuid;
 
 lazy_static! {
     static ref PASTA_CONFIG: Mutex<Arc<libpasta::Config>> =
@@ -59,6 +64,132 @@ pub(crate) fn set_test_config() {
 
 enum UserFlags {
     Disabled = 1,
+    PasswordLocked = 2,
+}
+
+/// The number of consecutive password failures allowed, for a user or peer address, before
+/// further attempts are delayed.
+const FREE_PASSWORD_FAILURES: i64 = 3;
+
+/// The maximum delay between password attempts, in seconds.
+const MAX_PASSWORD_DELAY_SEC: i64 = 600;
+
+/// The number of peer addresses with password failures to track before pruning stale ones.
+const MAX_FAILURE_ADDRS: usize = 10_000;
+
+/// Returns the number of seconds after the latest of `failures` consecutive password failures
+/// before another attempt is allowed. This doubles with each failure past the free ones.
+fn password_delay_sec(failures: i64) -> i64 {
+    if failures < FREE_PASSWORD_FAILURES {
+        return 0;
+    }
+    let exp = failures - FREE_PASSWORD_FAILURES;
+    if exp >= 32 {
+        return MAX_PASSWORD_DELAY_SEC;
+    }
+    cmp::min(1 << exp, MAX_PASSWORD_DELAY_SEC)
+}
+
+/// Returns an error if a password attempt at `now_sec` should be rejected without checking it.
+fn check_password_delay(failures: i64, last_failure_sec: Option<i64>, now_sec: Option<i64>)
+                        -> Result<(), LoginThrottled> {
+    if let (Some(last), Some(now)) = (last_failure_sec, now_sec) {
+        let allowed = last + password_delay_sec(failures);
+        if now < allowed {
+            return Err(LoginThrottled { retry_after_sec: allowed - now });
+        }
+    }
+    Ok(())
+}
+
+/// An error returned when a password attempt is rejected, without being checked, because of
+/// recent failures for the same user or from the same peer address.
+#[derive(Debug, Fail)]
+#[fail(display = "too many failed password attempts; try again in {} seconds", retry_after_sec)]
+pub struct LoginThrottled {
+    pub retry_after_sec: i64,
+}
+
+/// An error returned when a user's password is correct but a two-factor code is also required.
+#[derive(Debug, Fail)]
+#[fail(display = "two-factor code required")]
+pub struct SecondFactorRequired;
+
+/// The number of recovery codes generated on enrolling in two-factor authentication.
+pub const RECOVERY_CODES: usize = 10;
+
+/// The number of random bytes in a recovery code.
+const RECOVERY_CODE_LEN: usize = 10;
+
+/// Returns `n` new recovery codes, each formatted as four groups of five hex digits, along with
+/// their hashes.
+fn generate_recovery_codes(n: usize) -> (Vec<String>, Vec<[u8; 32]>) {
+    let mut codes = Vec::with_capacity(n);
+    let mut hashes = Vec::with_capacity(n);
+    for _ in 0..n {
+        let mut raw = [0u8; RECOVERY_CODE_LEN];
+        ::openssl::rand::rand_bytes(&mut raw).unwrap();
+        let hex = strutil::hex(&raw);
+        let code = format!("{}-{}-{}-{}", &hex[0..5], &hex[5..10], &hex[10..15], &hex[15..20]);
+        hashes.push(hash_recovery_code(&code));
+        codes.push(code);
+    }
+    (codes, hashes)
+}
+
+/// Hashes a recovery code, ignoring case and any separators.
+fn hash_recovery_code(code: &str) -> [u8; 32] {
+    let normalized: String = code.chars()
+                                 .filter(|c| c.is_ascii_alphanumeric())
+                                 .map(|c| c.to_ascii_lowercase())
+                                 .collect();
+    let mut h = [0u8; 32];
+    h.copy_from_slice(blake2b(32, &[], normalized.as_bytes()).as_bytes());
+    h
+}
+
+/// Encodes recovery code hashes for the `user.totp_recovery_hashes` column.
+fn encode_recovery_hashes(hashes: &[[u8; 32]]) -> Option<Vec<u8>> {
+    if hashes.is_empty() {
+        return None;
+    }
+    Some(hashes.iter().flat_map(|h| h.iter().cloned()).collect())
+}
+
+fn decode_recovery_hashes(raw: Option<Vec<u8>>) -> Result<Vec<[u8; 32]>, Error> {
+    let raw = raw.unwrap_or_default();
+    if raw.len() % 32 != 0 {
+        bail!("recovery code hashes have bad length {}", raw.len());
+    }
+    Ok(raw.chunks(32).map(|c| {
+        let mut h = [0u8; 32];
+        h.copy_from_slice(c);
+        h
+    }).collect())
+}
+
+/// Recent password failures from a single peer address.
+#[derive(Debug)]
+struct AddrFailures {
+    count: i64,
+    last_sec: Option<i64>,
+}
+
+impl Permissions {
+    /// Returns true iff these permissions extend to the camera with the given uuid.
+    pub fn allows_camera(&self, uuid: Uuid) -> bool {
+        self.camera_uuids.is_empty() ||
+        self.camera_uuids.iter().any(|u| Uuid::parse_str(u).ok() == Some(uuid))
+    }
+
+    /// Returns true iff these permissions extend to `signal`: that is, if they aren't limited to
+    /// particular cameras or if the signal is associated with at least one camera in scope.
+    pub fn allows_signal(&self, signal: &Signal, cameras_by_id: &BTreeMap<i32, Camera>) -> bool {
+        self.camera_uuids.is_empty() ||
+        signal.cameras.iter().any(|sc| cameras_by_id.get(&sc.camera_id)
+                                                    .map(|c| self.allows_camera(c.uuid))
+                                                    .unwrap_or(false))
+    }
 }
 
 #[derive(Debug)]
@@ -72,9 +203,21 @@ pub struct User {
     pub unix_uid: Option<i32>,
     pub permissions: Permissions,
 
+    /// The secret for two-factor authentication via TOTP, if enrolled.
+    totp_secret: Option<Vec<u8>>,
+
+    /// Hashes of the unused recovery codes, each of which may substitute for a TOTP code once.
+    totp_recovery_hashes: Vec<[u8; 32]>,
+
+    /// The time step of the most recently accepted TOTP code since startup, to prevent replay.
+    last_totp_step: Option<i64>,
+
+    /// The time of the most recent password failure since startup, for throttling.
+    last_password_failure_sec: Option<i64>,
+
     /// True iff this `User` has changed since the last flush.
-    /// Only a couple things are flushed lazily: `password_failure_count` and (on upgrade to a new
-    /// algorithm) `password_hash`.
+    /// Only a few things are flushed lazily: `password_failure_count`, the `PasswordLocked` flag,
+    /// and (on upgrade to a new algorithm) `password_hash`.
     dirty: bool,
 }
 
@@ -85,13 +228,27 @@ impl User {
             username: self.username.clone(),
             flags: self.flags,
             set_password_hash: None,
+            clear_password_failures: false,
+            set_totp_secret: None,
+            set_recovery_hashes: None,
             unix_uid: self.unix_uid,
             permissions: self.permissions.clone(),
         }
     }
 
+    /// Returns true iff password logins also require a TOTP or recovery code.
+    pub fn has_totp(&self) -> bool { self.totp_secret.is_some() }
+
+    pub fn recovery_codes_left(&self) -> usize { self.totp_recovery_hashes.len() }
+
     pub fn has_password(&self) -> bool { self.password_hash.is_some() }
-    fn disabled(&self) -> bool { (self.flags & UserFlags::Disabled as i32) != 0 }
+    pub fn disabled(&self) -> bool { (self.flags & UserFlags::Disabled as i32) != 0 }
+
+    /// Returns true iff password authentication has been locked after too many failures.
+    /// See `LockedDatabase::set_password_lockout_threshold`.
+    pub fn password_locked(&self) -> bool {
+        (self.flags & UserFlags::PasswordLocked as i32) != 0
+    }
 }
 
 /// A change to a user.
@@ -106,6 +263,9 @@ pub struct UserChange {
     pub username: String,
     pub flags: i32,
     set_password_hash: Option<Option<String>>,
+    clear_password_failures: bool,
+    set_totp_secret: Option<Option<Vec<u8>>>,
+    set_recovery_hashes: Option<Vec<[u8; 32]>>,
     pub unix_uid: Option<i32>,
     pub permissions: Permissions,
 }
@@ -117,6 +277,9 @@ impl UserChange {
             username,
             flags: 0,
             set_password_hash: None,
+            clear_password_failures: false,
+            set_totp_secret: None,
+            set_recovery_hashes: None,
             unix_uid: None,
             permissions: Permissions::default(),
         }
@@ -125,15 +288,44 @@ impl UserChange {
     pub fn set_password(&mut self, pwd: String) {
         let c = Arc::clone(&PASTA_CONFIG.lock());
         self.set_password_hash = Some(Some(c.hash_password(&pwd)));
+        self.unlock_password();
     }
 
     pub fn clear_password(&mut self) {
         self.set_password_hash = Some(None);
+        self.unlock_password();
+    }
+
+    /// Clears the password lockout (if any) and the count of failed password attempts.
+    pub fn unlock_password(&mut self) {
+        self.flags &= !(UserFlags::PasswordLocked as i32);
+        self.clear_password_failures = true;
     }
 
     pub fn disable(&mut self) {
         self.flags |= UserFlags::Disabled as i32;
     }
+
+    /// Enrolls in two-factor authentication with the given secret (see `totp::generate_secret`),
+    /// replacing any previous secret and recovery codes. Returns the new recovery codes; only
+    /// their hashes are stored.
+    pub fn set_totp_secret(&mut self, secret: Vec<u8>) -> Vec<String> {
+        self.set_totp_secret = Some(Some(secret));
+        self.regenerate_recovery_codes()
+    }
+
+    /// Unenrolls from two-factor authentication.
+    pub fn clear_totp(&mut self) {
+        self.set_totp_secret = Some(None);
+        self.set_recovery_hashes = Some(Vec::new());
+    }
+
+    /// Replaces the recovery codes, returning the new ones.
+    pub fn regenerate_recovery_codes(&mut self) -> Vec<String> {
+        let (codes, hashes) = generate_recovery_codes(RECOVERY_CODES);
+        self.set_recovery_hashes = Some(hashes);
+        codes
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -144,7 +336,7 @@ pub struct Request {
 }
 
 impl Request {
-    fn addr_buf(&self) -> Option<IpAddrBuf> {
+    pub(crate) fn addr_buf(&self) -> Option<IpAddrBuf> {
         match self.addr {
             None => None,
             Some(IpAddr::V4(ref a)) => Some(IpAddrBuf::V4(a.octets())),
@@ -153,7 +345,7 @@ impl Request {
     }
 }
 
-enum IpAddrBuf {
+pub(crate) enum IpAddrBuf {
     V4([u8; 4]),
     V6([u8; 16]),
 }
@@ -167,7 +359,7 @@ impl AsRef<[u8]> for IpAddrBuf {
     }
 }
 
-pub struct FromSqlIpAddr(Option<IpAddr>);
+pub struct FromSqlIpAddr(pub(crate) Option<IpAddr>);
 
 impl rusqlite::types::FromSql for FromSqlIpAddr {
     fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
@@ -199,11 +391,71 @@ pub enum SessionFlags {
     Secure = 2,
     SameSite = 4,
     SameSiteStrict = 8,
+
+    /// An API token, presented via an `Authorization: Bearer` header rather than a cookie.
+    ApiToken = 16,
 }
 
 #[derive(Copy, Clone)]
 pub enum RevocationReason {
+    /// Logged out from within the session itself.
     LoggedOut = 1,
+
+    /// Revoked by the user via another session.
+    UserRevoked = 2,
+
+    /// The user's password changed, invalidating all of the user's sessions.
+    PasswordChanged = 3,
+
+    /// Past its maximum age or idle time; see `SessionLimits`.
+    Expired = 4,
+
+    /// Evicted because the user had too many sessions; see `SessionLimits`.
+    Evicted = 5,
+}
+
+/// Limits on sessions, set via `LockedDatabase::set_session_limits`. By default, there are none.
+#[derive(Clone, Debug, Default)]
+pub struct SessionLimits {
+    /// The maximum time after a session's creation that it may be used.
+    pub max_age_sec: Option<i64>,
+
+    /// The maximum time after a session's last use (or creation, if unused) that it may be used.
+    pub max_idle_sec: Option<i64>,
+
+    /// The maximum number of valid sessions per user. When creating a session would exceed this,
+    /// the least recently used sessions are evicted.
+    pub max_per_user: Option<usize>,
+}
+
+impl SessionLimits {
+    /// Returns true iff the given session is too old or idle to use at `now_sec`.
+    fn expired(&self, s: &Session, now_sec: Option<i64>) -> bool {
+        let now = match now_sec {
+            None => return false,
+            Some(n) => n,
+        };
+        if let Some(e) = s.expiration_sec {
+            if now >= e {
+                return true;
+            }
+        }
+        if s.is_token() {
+            // API tokens are for long-running programs; only their own expiration applies.
+            return false;
+        }
+        if let (Some(max), Some(c)) = (self.max_age_sec, s.creation.when_sec) {
+            if now >= c + max {
+                return true;
+            }
+        }
+        if let (Some(max), Some(u)) = (self.max_idle_sec, s.last_use_sec()) {
+            if now >= u + max {
+                return true;
+            }
+        }
+        false
+    }
 }
 
 #[derive(Debug, Default)]
@@ -216,6 +468,7 @@ pub struct Session {
 
     creation_password_id: Option<i32>,
     creation: Request,
+    expiration_sec: Option<i64>,
 
     revocation: Request,
     revocation_reason: Option<i32>,  // see RevocationReason enum
@@ -235,6 +488,17 @@ impl Session {
         h.0.copy_from_slice(r.as_bytes());
         h
     }
+
+    pub fn user_id(&self) -> i32 { self.user_id }
+    pub fn description(&self) -> Option<&str> { self.description.as_ref().map(|d| d.as_str()) }
+    pub fn creation(&self) -> &Request { &self.creation }
+    pub fn last_use(&self) -> &Request { &self.last_use }
+    pub fn use_count(&self) -> i32 { self.use_count }
+    pub fn expiration_sec(&self) -> Option<i64> { self.expiration_sec }
+    pub fn is_token(&self) -> bool { (self.flags & SessionFlags::ApiToken as i32) != 0 }
+
+    /// Returns the time of the session's last use, or of its creation if it's never been used.
+    fn last_use_sec(&self) -> Option<i64> { self.last_use.when_sec.or(self.creation.when_sec) }
 }
 
 /// A raw session id (not base64-encoded). Sensitive. Never stored in the database.
@@ -333,6 +597,15 @@ pub(crate) struct State {
     /// evict the oldest when its size exceeds a threshold. Or just evict everything on every flush
     /// (and accept more frequent database accesses).
     sessions: FnvHashMap<SessionHash, Session>,
+
+    /// Recent password failures by peer address, for throttling. Entries are removed on a
+    /// successful login from the address or when stale.
+    failures_by_addr: FnvHashMap<IpAddr, AddrFailures>,
+
+    /// The number of consecutive password failures after which a user's password is locked.
+    password_lockout_threshold: Option<i64>,
+
+    session_limits: SessionLimits,
 }
 
 impl State {
@@ -341,6 +614,9 @@ impl State {
             users_by_id: BTreeMap::new(),
             users_by_name: BTreeMap::new(),
             sessions: FnvHashMap::default(),
+            failures_by_addr: FnvHashMap::default(),
+            password_lockout_threshold: None,
+            session_limits: SessionLimits::default(),
         };
         let mut stmt = conn.prepare(r#"
             select
@@ -351,7 +627,9 @@ impl State {
                 password_id,
                 password_failure_count,
                 unix_uid,
-                permissions
+                permissions,
+                totp_secret,
+                totp_recovery_hashes
             from
                 user
         "#)?;
@@ -369,6 +647,10 @@ impl State {
                 password_id: row.get(4)?,
                 password_failure_count: row.get(5)?,
                 unix_uid: row.get(6)?,
+                totp_secret: row.get(8)?,
+                totp_recovery_hashes: decode_recovery_hashes(row.get(9)?)?,
+                last_totp_step: None,
+                last_password_failure_sec: None,
                 dirty: false,
                 permissions,
             });
@@ -377,9 +659,12 @@ impl State {
         Ok(state)
     }
 
-    pub fn apply(&mut self, conn: &Connection, change: UserChange) -> Result<&User, Error> {
+    /// Applies `change`. `now_sec` is the current time, used to revoke sessions on a password
+    /// change.
+    pub fn apply(&mut self, conn: &Connection, now_sec: i64, change: UserChange)
+                 -> Result<&User, Error> {
         if let Some(id) = change.id {
-            self.update_user(conn, id, change)
+            self.update_user(conn, id, now_sec, change)
         } else {
             self.add_user(conn, change)
         }
@@ -387,7 +672,15 @@ impl State {
 
     pub fn users_by_id(&self) -> &BTreeMap<i32, User> { &self.users_by_id }
 
-    fn update_user(&mut self, conn: &Connection, id: i32, change: UserChange)
+    pub fn set_password_lockout_threshold(&mut self, threshold: Option<i64>) {
+        self.password_lockout_threshold = threshold;
+    }
+
+    pub fn set_session_limits(&mut self, limits: SessionLimits) {
+        self.session_limits = limits;
+    }
+
+    fn update_user(&mut self, conn: &Connection, id: i32, now_sec: i64, change: UserChange)
                    -> Result<&User, Error> {
         let mut stmt = conn.prepare_cached(r#"
             update user
@@ -398,7 +691,9 @@ impl State {
                 password_failure_count = :password_failure_count,
                 flags = :flags,
                 unix_uid = :unix_uid,
-                permissions = :permissions
+                permissions = :permissions,
+                totp_secret = :totp_secret,
+                totp_recovery_hashes = :totp_recovery_hashes
             where
                 id = :id
         "#)?;
@@ -411,10 +706,18 @@ impl State {
             let (phash, pid, pcount) = match change.set_password_hash.as_ref() {
                 None => {
                     let u = e.get();
-                    (&u.password_hash, u.password_id, u.password_failure_count)
+                    let pcount = if change.clear_password_failures {
+                        0
+                    } else {
+                        u.password_failure_count
+                    };
+                    (&u.password_hash, u.password_id, pcount)
                 },
                 Some(h) => (h, e.get().password_id + 1, 0),
             };
+            let totp_secret = change.set_totp_secret.as_ref().unwrap_or(&e.get().totp_secret);
+            let recovery_hashes = encode_recovery_hashes(
+                change.set_recovery_hashes.as_ref().unwrap_or(&e.get().totp_recovery_hashes));
             let permissions = change.permissions.write_to_bytes().expect("proto3->vec is infallible");
             stmt.execute_named(&[
                 (":username", &&change.username[..]),
@@ -425,6 +728,8 @@ impl State {
                 (":unix_uid", &change.unix_uid),
                 (":id", &id),
                 (":permissions", &permissions),
+                (":totp_secret", totp_secret),
+                (":totp_recovery_hashes", &recovery_hashes),
             ])?;
         }
         let u = e.into_mut();
@@ -433,6 +738,22 @@ impl State {
             u.password_hash = h;
             u.password_id += 1;
             u.password_failure_count = 0;
+            let req = Request {
+                when_sec: Some(now_sec),
+                ..Default::default()
+            };
+            revoke_password_sessions(conn, &mut self.sessions, id, req)?;
+        }
+        if change.clear_password_failures {
+            u.password_failure_count = 0;
+            u.last_password_failure_sec = None;
+        }
+        if let Some(s) = change.set_totp_secret {
+            u.totp_secret = s;
+            u.last_totp_step = None;
+        }
+        if let Some(h) = change.set_recovery_hashes {
+            u.totp_recovery_hashes = h;
         }
         u.flags = change.flags;
         u.unix_uid = change.unix_uid;
@@ -442,10 +763,14 @@ impl State {
 
     fn add_user(&mut self, conn: &Connection, change: UserChange) -> Result<&User, Error> {
         let mut stmt = conn.prepare_cached(r#"
-            insert into user (username,  password_hash,  flags,  unix_uid,  permissions)
-                      values (:username, :password_hash, :flags, :unix_uid, :permissions)
+            insert into user (username,  password_hash,  flags,  unix_uid,  permissions,
+                              totp_secret,  totp_recovery_hashes)
+                      values (:username, :password_hash, :flags, :unix_uid, :permissions,
+                              :totp_secret, :totp_recovery_hashes)
         "#)?;
         let password_hash = change.set_password_hash.unwrap_or(None);
+        let totp_secret = change.set_totp_secret.unwrap_or(None);
+        let recovery_hashes = change.set_recovery_hashes.unwrap_or_default();
         let permissions = change.permissions.write_to_bytes().expect("proto3->vec is infallible");
         stmt.execute_named(&[
             (":username", &&change.username[..]),
@@ -453,6 +778,8 @@ impl State {
             (":flags", &change.flags),
             (":unix_uid", &change.unix_uid),
             (":permissions", &permissions),
+            (":totp_secret", &totp_secret),
+            (":totp_recovery_hashes", &encode_recovery_hashes(&recovery_hashes)),
         ])?;
         let id = conn.last_insert_rowid() as i32;
         self.users_by_name.insert(change.username.clone(), id);
@@ -469,6 +796,10 @@ impl State {
             password_id: 0,
             password_failure_count: 0,
             unix_uid: change.unix_uid,
+            totp_secret,
+            totp_recovery_hashes: recovery_hashes,
+            last_totp_step: None,
+            last_password_failure_sec: None,
             dirty: false,
             permissions: change.permissions,
         }))
@@ -496,15 +827,83 @@ impl State {
             .map(|id| self.users_by_id.get(id).expect("users_by_name implies users_by_id"))
     }
 
+    /// Authenticates a Unix domain socket peer by its uid, returning the enabled user with that
+    /// `unix_uid`.
+    pub fn authenticate_unix_uid(&self, uid: i32) -> Result<&User, Error> {
+        let u = match self.users_by_id.values().find(|u| u.unix_uid == Some(uid)) {
+            None => bail!("no user with unix uid {}", uid),
+            Some(u) => u,
+        };
+        if u.disabled() {
+            bail!("user {:?} is disabled", &u.username);
+        }
+        Ok(u)
+    }
+
+    /// Authenticates a request by a username vouched for by a trusted reverse proxy, returning the
+    /// enabled user with that name. The proxy is responsible for checking passwords and such.
+    pub fn authenticate_trusted_username(&self, username: &str) -> Result<&User, Error> {
+        let u = self.get_user(username).ok_or_else(|| format_err!("no user {:?}", username))?;
+        if u.disabled() {
+            bail!("user {:?} is disabled", &u.username);
+        }
+        Ok(u)
+    }
+
+    /// Creates a session after checking the user's password and, if the user has enrolled in
+    /// two-factor authentication, `code`. Without a code, such a user's login fails with
+    /// `SecondFactorRequired` if the password is correct.
     pub fn login_by_password(&mut self, conn: &Connection, req: Request, username: &str,
-                             password: String, domain: Option<Vec<u8>>, session_flags: i32)
-                             -> Result<(RawSessionId, &Session), Error> {
-        let id = self.users_by_name.get(username)
-            .ok_or_else(|| format_err!("no such user {:?}", username))?;
-        let u = self.users_by_id.get_mut(id).expect("users_by_name implies users_by_id");
+                             password: String, code: Option<&str>, domain: Option<Vec<u8>>,
+                             session_flags: i32) -> Result<(RawSessionId, &Session), Error> {
+        let id = self.verify_password(conn, &req, username, password, code)?;
+        self.evict_sessions(conn, id, &req)?;
+        let u = self.users_by_id.get_mut(&id).expect("verified user should exist");
+        let password_id = u.password_id;
+        State::make_session_int(conn, req, u, domain, Some(password_id), session_flags,
+                                &mut self.sessions, u.permissions.clone(), None, None)
+    }
+
+    /// Checks a password without creating a session, for protocols which send credentials with
+    /// every request. These can't carry a two-factor code, so users enrolled in two-factor
+    /// authentication always fail.
+    pub fn authenticate_password(&mut self, conn: &Connection, req: Request, username: &str,
+                                 password: String) -> Result<&User, Error> {
+        let id = self.verify_password(conn, &req, username, password, None)?;
+        Ok(self.users_by_id.get(&id).expect("verified user should exist"))
+    }
+
+    /// Checks a password and, if the user is enrolled, a two-factor code, returning the user id
+    /// on success.
+    ///
+    /// Attempts are throttled both by user and by peer address: after `FREE_PASSWORD_FAILURES`
+    /// consecutive failures, further attempts are rejected with `LoginThrottled` until an
+    /// exponentially increasing delay has passed. This rejection happens before the password is
+    /// checked, so an attacker learns nothing from attempts during the delay. An incorrect
+    /// two-factor code counts as a failure; a missing one doesn't, but it also doesn't reset the
+    /// count.
+    fn verify_password(&mut self, conn: &Connection, req: &Request, username: &str,
+                       password: String, code: Option<&str>) -> Result<i32, Error> {
+        if let Some(f) = req.addr.and_then(|a| self.failures_by_addr.get(&a)) {
+            check_password_delay(f.count, f.last_sec, req.when_sec)?;
+        }
+        let id = match self.users_by_name.get(username) {
+            Some(&id) => id,
+            None => {
+                note_addr_failure(&mut self.failures_by_addr, req);
+                bail!("no such user {:?}", username);
+            },
+        };
+        let u = self.users_by_id.get_mut(&id).expect("users_by_name implies users_by_id");
         if u.disabled() {
             bail!("user {:?} is disabled", username);
         }
+        if u.password_locked() {
+            bail!("password for user {:?} is locked after {} failed attempts",
+                  username, u.password_failure_count);
+        }
+        check_password_delay(u.password_failure_count, u.last_password_failure_sec,
+                             req.when_sec)?;
         let new_hash = {
             let hash = match u.password_hash.as_ref() {
                 None => bail!("no password set for user {:?}", username),
@@ -513,8 +912,8 @@ impl State {
             let c = Arc::clone(&PASTA_CONFIG.lock());
             match c.verify_password_update_hash(hash, &password) {
                 libpasta::HashUpdate::Failed => {
-                    u.dirty = true;
-                    u.password_failure_count += 1;
+                    note_user_failure(u, self.password_lockout_threshold, req);
+                    note_addr_failure(&mut self.failures_by_addr, req);
                     bail!("incorrect password for user {:?}", username);
                 },
                 libpasta::HashUpdate::Verified(new_pwd) => new_pwd,
@@ -524,27 +923,63 @@ impl State {
             u.password_hash = Some(h);
             u.dirty = true;
         }
-        let password_id = u.password_id;
-        State::make_session_int(conn, req, u, domain, Some(password_id), session_flags,
-                            &mut self.sessions, u.permissions.clone())
+        if u.totp_secret.is_some() {
+            let code = code.ok_or(SecondFactorRequired)?;
+            if !check_second_factor(conn, u, req, code)? {
+                note_user_failure(u, self.password_lockout_threshold, req);
+                note_addr_failure(&mut self.failures_by_addr, req);
+                bail!("incorrect two-factor code for user {:?}", username);
+            }
+        }
+        if u.password_failure_count != 0 {
+            u.password_failure_count = 0;
+            u.dirty = true;
+        }
+        u.last_password_failure_sec = None;
+        if let Some(a) = req.addr {
+            self.failures_by_addr.remove(&a);
+        }
+        Ok(id)
     }
 
     /// Makes a session directly (no password required).
     pub fn make_session<'s>(&'s mut self, conn: &Connection, creation: Request, uid: i32,
                             domain: Option<Vec<u8>>, flags: i32, permissions: Permissions)
                             -> Result<(RawSessionId, &'s Session), Error> {
-        let u = self.users_by_id.get_mut(&uid).ok_or_else(|| format_err!("no such uid {:?}", uid))?;
+        if !self.users_by_id.contains_key(&uid) {
+            bail!("no such uid {:?}", uid);
+        }
+        self.evict_sessions(conn, uid, &creation)?;
+        let u = self.users_by_id.get_mut(&uid).expect("checked above");
         if u.disabled() {
             bail!("user is disabled");
         }
         State::make_session_int(conn, creation, u, domain, None, flags, &mut self.sessions,
-                                permissions)
+                                permissions, None, None)
+    }
+
+    /// Makes an API token: a session with the given name (as its description), which is
+    /// presented via an `Authorization: Bearer` header rather than a cookie. Tokens aren't
+    /// subject to `SessionLimits`, only their own optional expiration.
+    pub fn make_token<'s>(&'s mut self, conn: &Connection, creation: Request, uid: i32,
+                          name: String, permissions: Permissions, expiration_sec: Option<i64>)
+                          -> Result<(RawSessionId, &'s Session), Error> {
+        if name.is_empty() {
+            bail!("API token must have a name");
+        }
+        let u = self.users_by_id.get_mut(&uid).ok_or_else(|| format_err!("no such uid {:?}", uid))?;
+        if u.disabled() {
+            bail!("user is disabled");
+        }
+        State::make_session_int(conn, creation, u, None, None, SessionFlags::ApiToken as i32,
+                                &mut self.sessions, permissions, Some(name), expiration_sec)
     }
 
     fn make_session_int<'s>(conn: &Connection, creation: Request, user: &mut User,
                             domain: Option<Vec<u8>>, creation_password_id: Option<i32>, flags: i32,
                             sessions: &'s mut FnvHashMap<SessionHash, Session>,
-                            permissions: Permissions)
+                            permissions: Permissions, description: Option<String>,
+                            expiration_sec: Option<i64>)
                             -> Result<(RawSessionId, &'s Session), Error> {
         let mut session_id = RawSessionId::new();
         ::openssl::rand::rand_bytes(&mut session_id.0).unwrap();
@@ -553,13 +988,13 @@ impl State {
         let hash = session_id.hash();
         let mut stmt = conn.prepare_cached(r#"
             insert into user_session (session_id_hash,  user_id,  seed,  flags,  domain,
-                                      creation_password_id,  creation_time_sec,
+                                      description,  creation_password_id,  creation_time_sec,
                                       creation_user_agent,  creation_peer_addr,
-                                      permissions)
+                                      permissions,  expiration_time_sec)
                               values (:session_id_hash, :user_id, :seed, :flags, :domain,
-                                      :creation_password_id, :creation_time_sec,
+                                      :description, :creation_password_id, :creation_time_sec,
                                       :creation_user_agent, :creation_peer_addr,
-                                      :permissions)
+                                      :permissions, :expiration_time_sec)
         "#)?;
         let addr = creation.addr_buf();
         let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
@@ -570,11 +1005,13 @@ impl State {
             (":seed", &&seed[..]),
             (":flags", &flags),
             (":domain", &domain),
+            (":description", &description),
             (":creation_password_id", &creation_password_id),
             (":creation_time_sec", &creation.when_sec),
             (":creation_user_agent", &creation.user_agent),
             (":creation_peer_addr", &addr),
             (":permissions", &permissions_blob),
+            (":expiration_time_sec", &expiration_sec),
         ])?;
         let e = match sessions.entry(hash) {
             ::std::collections::hash_map::Entry::Occupied(_) => panic!("duplicate session hash!"),
@@ -584,8 +1021,10 @@ impl State {
             user_id: user.id,
             flags,
             domain,
+            description,
             creation_password_id,
             creation,
+            expiration_sec,
             seed: Seed(seed),
             permissions,
             ..Default::default()
@@ -595,6 +1034,17 @@ impl State {
 
     pub fn authenticate_session(&mut self, conn: &Connection, req: Request, hash: &SessionHash)
                                 -> Result<(&Session, &User), Error> {
+        self.authenticate(conn, req, hash, false)
+    }
+
+    /// Authenticates an API token, as presented in an `Authorization: Bearer` header.
+    pub fn authenticate_token(&mut self, conn: &Connection, req: Request, hash: &SessionHash)
+                              -> Result<(&Session, &User), Error> {
+        self.authenticate(conn, req, hash, true)
+    }
+
+    fn authenticate(&mut self, conn: &Connection, req: Request, hash: &SessionHash, token: bool)
+                    -> Result<(&Session, &User), Error> {
         let s = match self.sessions.entry(*hash) {
             ::std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
             ::std::collections::hash_map::Entry::Vacant(e) => e.insert(lookup_session(conn, hash)?),
@@ -603,9 +1053,18 @@ impl State {
             None => bail!("session references nonexistent user!"),
             Some(u) => u,
         };
+        match (s.is_token(), token) {
+            (true, false) => bail!("API token can't be used as a session cookie"),
+            (false, true) => bail!("session can't be used as an API token"),
+            _ => {},
+        }
         if let Some(r) = s.revocation_reason {
             bail!("session is no longer valid (reason={})", r);
         }
+        if self.session_limits.expired(s, req.when_sec) {
+            revoke(conn, s, hash, RevocationReason::Expired, None, req)?;
+            bail!("session is no longer valid (reason={})", RevocationReason::Expired as i32);
+        }
         s.last_use = req;
         s.use_count += 1;
         s.dirty = true;
@@ -622,30 +1081,90 @@ impl State {
             ::std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
             ::std::collections::hash_map::Entry::Vacant(e) => e.insert(lookup_session(conn, hash)?),
         };
-        if s.revocation_reason.is_none() {
-            let mut stmt = conn.prepare(r#"
-                update user_session
-                set
-                    revocation_time_sec = ?,
-                    revocation_user_agent = ?,
-                    revocation_peer_addr = ?,
-                    revocation_reason = ?,
-                    revocation_reason_detail = ?
-                where
-                    session_id_hash = ?
-            "#)?;
-            let addr = req.addr_buf();
-            let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
-            stmt.execute(&[
-                &req.when_sec as &dyn ToSql,
-                &req.user_agent,
-                &addr,
-                &(reason as i32),
-                &detail,
-                &&hash.0[..],
-            ])?;
-            s.revocation = req;
-            s.revocation_reason = Some(reason as i32);
+        revoke(conn, s, hash, reason, detail, req)
+    }
+
+    /// Returns the valid sessions (excluding API tokens) of the given user, least recently used
+    /// first.
+    pub fn list_sessions(&mut self, conn: &Connection, user_id: i32, now_sec: Option<i64>)
+                         -> Result<Vec<(SessionHash, &Session)>, Error> {
+        let mut out = self.list_valid(conn, user_id, now_sec, false)?;
+        out.sort_by_key(|(_, s)| s.last_use_sec());
+        Ok(out)
+    }
+
+    /// Returns the valid API tokens of the given user, oldest first.
+    pub fn list_tokens(&mut self, conn: &Connection, user_id: i32, now_sec: Option<i64>)
+                       -> Result<Vec<(SessionHash, &Session)>, Error> {
+        let mut out = self.list_valid(conn, user_id, now_sec, true)?;
+        out.sort_by_key(|(_, s)| s.creation.when_sec);
+        Ok(out)
+    }
+
+    fn list_valid(&mut self, conn: &Connection, user_id: i32, now_sec: Option<i64>, tokens: bool)
+                  -> Result<Vec<(SessionHash, &Session)>, Error> {
+        let hashes = self.load_user_sessions(conn, user_id, tokens)?;
+        let limits = &self.session_limits;
+        let sessions = &self.sessions;
+        Ok(hashes.iter()
+           .map(|h| (*h, sessions.get(h).expect("loaded above")))
+           .filter(|(_, s)| s.revocation_reason.is_none() && !limits.expired(s, now_sec))
+           .collect())
+    }
+
+    /// Ensures all the given user's unrevoked sessions (if `!tokens`) or API tokens (if `tokens`)
+    /// are in `self.sessions`, returning their hashes.
+    fn load_user_sessions(&mut self, conn: &Connection, user_id: i32, tokens: bool)
+                          -> Result<Vec<SessionHash>, Error> {
+        let mut stmt = conn.prepare_cached(r#"
+            select session_id_hash from user_session
+            where user_id = ? and revocation_reason is null
+        "#)?;
+        let mut rows = stmt.query(&[&user_id])?;
+        let mut hashes = Vec::new();
+        while let Some(row) = rows.next()? {
+            let blob = row.get_raw_checked(0)?.as_blob()?;
+            if blob.len() != 24 {
+                bail!("session_id_hash has length {}, expected 24", blob.len());
+            }
+            let mut h = SessionHash::default();
+            h.0.copy_from_slice(blob);
+            let s = match self.sessions.entry(h) {
+                ::std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                ::std::collections::hash_map::Entry::Vacant(e) => {
+                    e.insert(lookup_session(conn, &h)?)
+                },
+            };
+            if s.is_token() == tokens {
+                hashes.push(h);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Before a session is created for the given user, revokes existing sessions which are
+    /// expired or beyond `SessionLimits::max_per_user`, least recently used first.
+    fn evict_sessions(&mut self, conn: &Connection, user_id: i32, req: &Request)
+                      -> Result<(), Error> {
+        let limits = self.session_limits.clone();
+        if limits.max_age_sec.is_none() && limits.max_idle_sec.is_none() &&
+           limits.max_per_user.is_none() {
+            return Ok(());
+        }
+        let mut hashes = self.load_user_sessions(conn, user_id, false)?;
+        hashes.sort_by_key(|h| self.sessions[h].last_use_sec());
+        let max = limits.max_per_user.map(|m| m.saturating_sub(1)).unwrap_or(usize::max_value());
+        let excess = hashes.len().saturating_sub(max);
+        for (i, h) in hashes.iter().enumerate() {
+            let s = self.sessions.get_mut(h).expect("loaded above");
+            let reason = if limits.expired(s, req.when_sec) {
+                RevocationReason::Expired
+            } else if i < excess {
+                RevocationReason::Evicted
+            } else {
+                continue;
+            };
+            revoke(conn, s, h, reason, None, req.clone())?;
         }
         Ok(())
     }
@@ -659,7 +1178,8 @@ impl State {
             update user
             set
                 password_failure_count = :password_failure_count,
-                password_hash = :password_hash
+                password_hash = :password_hash,
+                flags = :flags
             where
                 id = :id
         "#)?;
@@ -681,10 +1201,11 @@ impl State {
             u_stmt.execute_named(&[
                 (":password_failure_count", &u.password_failure_count),
                 (":password_hash", &u.password_hash),
+                (":flags", &u.flags),
                 (":id", &id),
             ])?;
         }
-        for (_, s) in &self.sessions {
+        for (hash, s) in &self.sessions {
             if !s.dirty {
                 continue;
             }
@@ -695,6 +1216,7 @@ impl State {
                 (":last_use_user_agent", &s.last_use.user_agent),
                 (":last_use_peer_addr", &addr),
                 (":use_count", &s.use_count),
+                (":hash", &&hash.0[..]),
             ])?;
         }
         Ok(())
@@ -713,6 +1235,127 @@ impl State {
     }
 }
 
+/// Revokes the given session, if it's not already revoked.
+fn revoke(conn: &Connection, s: &mut Session, hash: &SessionHash, reason: RevocationReason,
+          detail: Option<String>, req: Request) -> Result<(), Error> {
+    if s.revocation_reason.is_some() {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare_cached(r#"
+        update user_session
+        set
+            revocation_time_sec = ?,
+            revocation_user_agent = ?,
+            revocation_peer_addr = ?,
+            revocation_reason = ?,
+            revocation_reason_detail = ?
+        where
+            session_id_hash = ?
+    "#)?;
+    let addr = req.addr_buf();
+    let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
+    stmt.execute(&[
+        &req.when_sec as &dyn ToSql,
+        &req.user_agent,
+        &addr,
+        &(reason as i32),
+        &detail,
+        &&hash.0[..],
+    ])?;
+    s.revocation = req;
+    s.revocation_reason = Some(reason as i32);
+    s.revocation_reason_detail = detail;
+    Ok(())
+}
+
+/// Revokes all of the given user's unrevoked sessions, including API tokens, as after a password
+/// change. Any of them may have been created by someone who knew the old password.
+fn revoke_password_sessions(conn: &Connection, sessions: &mut FnvHashMap<SessionHash, Session>,
+                            user_id: i32, req: Request) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        update user_session
+        set
+            revocation_time_sec = ?,
+            revocation_reason = ?
+        where
+            user_id = ? and
+            revocation_reason is null
+    "#)?;
+    let n = stmt.execute(&[
+        &req.when_sec as &dyn ToSql,
+        &(RevocationReason::PasswordChanged as i32),
+        &user_id,
+    ])?;
+    if n > 0 {
+        info!("revoked {} sessions of user {} on password change", n, user_id);
+    }
+    for s in sessions.values_mut() {
+        if s.user_id == user_id && s.revocation_reason.is_none() {
+            s.revocation = req.clone();
+            s.revocation_reason = Some(RevocationReason::PasswordChanged as i32);
+        }
+    }
+    Ok(())
+}
+
+/// Notes a password failure from the request's peer address, if known.
+/// Notes a failed password or two-factor code for `u`, locking its password if
+/// `lockout_threshold` is reached.
+fn note_user_failure(u: &mut User, lockout_threshold: Option<i64>, req: &Request) {
+    u.dirty = true;
+    u.password_failure_count += 1;
+    u.last_password_failure_sec = req.when_sec;
+    if let Some(t) = lockout_threshold {
+        if u.password_failure_count >= t {
+            warn!("locking password for user {:?} after {} failed attempts",
+                  &u.username, u.password_failure_count);
+            u.flags |= UserFlags::PasswordLocked as i32;
+        }
+    }
+}
+
+/// Checks `code`, which may be either a current TOTP code or an unused recovery code, for `u`,
+/// which must be enrolled in two-factor authentication. A recovery code is consumed immediately.
+fn check_second_factor(conn: &Connection, u: &mut User, req: &Request, code: &str)
+                       -> Result<bool, Error> {
+    let now_sec = req.when_sec.unwrap_or_else(|| ::time::get_time().sec);
+    let secret = u.totp_secret.as_ref().expect("user should have a TOTP secret");
+    if let Some(step) = totp::verify(secret, code, now_sec, u.last_totp_step)? {
+        u.last_totp_step = Some(step);
+        return Ok(true);
+    }
+    let hash = hash_recovery_code(code);
+    let i = match u.totp_recovery_hashes.iter().position(|h| *h == hash) {
+        None => return Ok(false),
+        Some(i) => i,
+    };
+    let mut remaining = u.totp_recovery_hashes.clone();
+    remaining.remove(i);
+    conn.execute("update user set totp_recovery_hashes = ? where id = ?",
+                 &[&encode_recovery_hashes(&remaining) as &dyn ToSql, &u.id])?;
+    info!("user {:?} used a recovery code; {} remain", &u.username, remaining.len());
+    u.totp_recovery_hashes = remaining;
+    Ok(true)
+}
+
+fn note_addr_failure(failures_by_addr: &mut FnvHashMap<IpAddr, AddrFailures>, req: &Request) {
+    let addr = match req.addr {
+        None => return,
+        Some(a) => a,
+    };
+    if failures_by_addr.len() >= MAX_FAILURE_ADDRS {
+        if let Some(now) = req.when_sec {
+            failures_by_addr.retain(|_, f| match f.last_sec {
+                Some(l) => l + MAX_PASSWORD_DELAY_SEC > now,
+                None => false,
+            });
+        }
+    }
+    let f = failures_by_addr.entry(addr).or_insert(AddrFailures { count: 0, last_sec: None });
+    f.count += 1;
+    f.last_sec = req.when_sec;
+}
+
 fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, Error> {
     let mut stmt = conn.prepare_cached(r#"
         select
@@ -734,7 +1377,8 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, Erro
             last_use_user_agent,
             last_use_peer_addr,
             use_count,
-            permissions
+            permissions,
+            expiration_time_sec
         from
             user_session
         where
@@ -772,6 +1416,7 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, Erro
             addr: last_use_addr.0,
         },
         use_count: row.get(17)?,
+        expiration_sec: row.get(19)?,
         dirty: false,
         permissions,
     })
@@ -805,21 +1450,21 @@ mod tests {
             user_agent: Some(b"some ua".to_vec()),
         };
         let (uid, mut c) = {
-            let u = state.apply(&conn, UserChange::add_user("slamb".to_owned())).unwrap();
+            let u = state.apply(&conn, 0, UserChange::add_user("slamb".to_owned())).unwrap();
             (u.id, u.change())
         };
-        let e = state.login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(),
+        let e = state.login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(), None,
                                         Some(b"nvr.example.com".to_vec()), 0).unwrap_err();
         assert_eq!(format!("{}", e), "no password set for user \"slamb\"");
         c.set_password("hunter2".to_owned());
-        state.apply(&conn, c).unwrap();
+        state.apply(&conn, 0, c).unwrap();
         let e = state.login_by_password(&conn, req.clone(), "slamb",
-                                       "hunter3".to_owned(),
+                                       "hunter3".to_owned(), None,
                                        Some(b"nvr.example.com".to_vec()), 0).unwrap_err();
         assert_eq!(format!("{}", e), "incorrect password for user \"slamb\"");
         let sid = {
             let (sid, s) = state.login_by_password(&conn, req.clone(), "slamb",
-                                                  "hunter2".to_owned(),
+                                                  "hunter2".to_owned(), None,
                                                   Some(b"nvr.example.com".to_vec()), 0).unwrap();
             assert_eq!(s.user_id, uid);
             sid
@@ -841,6 +1486,386 @@ mod tests {
         assert_eq!(format!("{}", e), "session is no longer valid (reason=1)");
     }
 
+    #[test]
+    fn authenticate_password() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        let req = Request::default();
+        let e = state.authenticate_password(req.clone(), "slamb", "hunter3".to_owned())
+                     .unwrap_err();
+        assert_eq!(format!("{}", e), "incorrect password for user \"slamb\"");
+        assert_eq!(state.users_by_id().get(&uid).unwrap().password_failure_count, 1);
+        let u = state.authenticate_password(req.clone(), "slamb", "hunter2".to_owned()).unwrap();
+        assert_eq!(u.id, uid);
+        assert_eq!(u.password_failure_count, 0);
+        let e = state.authenticate_password(req, "nobody", "hunter2".to_owned()).unwrap_err();
+        assert_eq!(format!("{}", e), "no such user \"nobody\"");
+    }
+
+    fn throttled_for(e: Error) -> i64 {
+        e.downcast::<LoginThrottled>().unwrap().retry_after_sec
+    }
+
+    #[test]
+    fn throttle_by_user() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        state.apply(&conn, 0, c).unwrap();
+        let at = |when_sec| Request {
+            when_sec: Some(when_sec),
+            ..Default::default()
+        };
+        for _ in 0..FREE_PASSWORD_FAILURES {
+            state.authenticate_password(at(100), "slamb", "hunter3".to_owned()).unwrap_err();
+        }
+
+        // Even the correct password is rejected during the delay.
+        let e = state.authenticate_password(at(100), "slamb", "hunter2".to_owned()).unwrap_err();
+        assert_eq!(throttled_for(e), 1);
+        state.authenticate_password(at(101), "slamb", "hunter3".to_owned()).unwrap_err();
+        let e = state.authenticate_password(at(102), "slamb", "hunter2".to_owned()).unwrap_err();
+        assert_eq!(throttled_for(e), 1);
+        let u = state.authenticate_password(at(103), "slamb", "hunter2".to_owned()).unwrap();
+        assert_eq!(u.password_failure_count, 0);
+        state.authenticate_password(at(103), "slamb", "hunter2".to_owned()).unwrap();
+    }
+
+    #[test]
+    fn throttle_by_addr() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        state.apply(&conn, 0, c).unwrap();
+        let from = |a| Request {
+            when_sec: Some(100),
+            addr: Some(::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(192, 168, 0, a))),
+            user_agent: None,
+        };
+        for i in 0..FREE_PASSWORD_FAILURES + 2 {
+            let e = state.authenticate_password(from(1), &format!("user{}", i),
+                                                "hunter2".to_owned()).unwrap_err();
+            if i < FREE_PASSWORD_FAILURES {
+                assert_eq!(format!("{}", e), format!("no such user \"user{}\"", i));
+            } else {
+                throttled_for(e);
+            }
+        }
+        let e = state.authenticate_password(from(1), "slamb", "hunter2".to_owned()).unwrap_err();
+        assert_eq!(throttled_for(e), 1);
+        state.authenticate_password(from(2), "slamb", "hunter2".to_owned()).unwrap();
+    }
+
+    #[test]
+    fn lockout() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        state.set_password_lockout_threshold(Some(2));
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        let req = Request::default();
+        state.authenticate_password(req.clone(), "slamb", "hunter3".to_owned()).unwrap_err();
+        assert!(!state.users_by_id().get(&uid).unwrap().password_locked());
+        state.authenticate_password(req.clone(), "slamb", "hunter3".to_owned()).unwrap_err();
+        assert!(state.users_by_id().get(&uid).unwrap().password_locked());
+        let e = state.authenticate_password(req.clone(), "slamb", "hunter2".to_owned())
+                     .unwrap_err();
+        assert_eq!(format!("{}", e),
+                   "password for user \"slamb\" is locked after 2 failed attempts");
+
+        // The lock should persist across reload.
+        {
+            let tx = conn.transaction().unwrap();
+            state.flush(&tx).unwrap();
+            tx.commit().unwrap();
+        }
+        state.post_flush();
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let u = state.users_by_id().get(&uid).unwrap();
+        assert!(u.password_locked());
+        assert_eq!(u.password_failure_count, 2);
+
+        let mut c = u.change();
+        c.unlock_password();
+        state.apply(&conn, 0, c).unwrap();
+        let u = state.authenticate_password(req, "slamb", "hunter2".to_owned()).unwrap();
+        assert!(!u.password_locked());
+        assert_eq!(u.password_failure_count, 0);
+    }
+
+    #[test]
+    fn session_expiry() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        state.set_session_limits(SessionLimits {
+            max_age_sec: Some(100),
+            max_idle_sec: Some(10),
+            max_per_user: None,
+        });
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        let at = |when_sec| Request { when_sec: Some(when_sec), ..Default::default() };
+        let sid = state.login_by_password(&conn, at(0), "slamb", "hunter2".to_owned(),
+                                          None, None, 0).unwrap().0;
+
+        // Using the session within the idle timeout pushes it forward, up to the maximum age.
+        for when_sec in (9..100).step_by(9) {
+            state.authenticate_session(&conn, at(when_sec), &sid.hash()).unwrap();
+        }
+        assert_eq!(state.list_sessions(&conn, uid, Some(99)).unwrap().len(), 1);
+        assert_eq!(state.list_sessions(&conn, uid, Some(100)).unwrap().len(), 0);
+        let e = state.authenticate_session(&conn, at(100), &sid.hash()).unwrap_err();
+        assert_eq!(format!("{}", e), "session is no longer valid (reason=4)");
+
+        // An idle session expires.
+        let sid = state.login_by_password(&conn, at(200), "slamb", "hunter2".to_owned(),
+                                          None, None, 0).unwrap().0;
+        let e = state.authenticate_session(&conn, at(210), &sid.hash()).unwrap_err();
+        assert_eq!(format!("{}", e), "session is no longer valid (reason=4)");
+
+        // The revocation should persist across reload, even with the limits lifted.
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let e = state.authenticate_session(&conn, at(210), &sid.hash()).unwrap_err();
+        assert_eq!(format!("{}", e), "session is no longer valid (reason=4)");
+    }
+
+    #[test]
+    fn session_eviction() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        state.set_session_limits(SessionLimits {
+            max_per_user: Some(2),
+            ..Default::default()
+        });
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        let at = |when_sec| Request { when_sec: Some(when_sec), ..Default::default() };
+        let mut sids = Vec::new();
+        for when_sec in 0..2 {
+            sids.push(state.login_by_password(&conn, at(when_sec), "slamb", "hunter2".to_owned(),
+                                              None, None, 0).unwrap().0);
+        }
+
+        // Use the older session, so that the newer one is least recently used.
+        state.authenticate_session(&conn, at(2), &sids[0].hash()).unwrap();
+        sids.push(state.login_by_password(&conn, at(3), "slamb", "hunter2".to_owned(),
+                                          None, None, 0).unwrap().0);
+        let e = state.authenticate_session(&conn, at(4), &sids[1].hash()).unwrap_err();
+        assert_eq!(format!("{}", e), "session is no longer valid (reason=5)");
+        let l: Vec<SessionHash> = state.list_sessions(&conn, uid, Some(4)).unwrap()
+                                       .iter().map(|&(h, _)| h).collect();
+        assert_eq!(l, vec![sids[0].hash(), sids[2].hash()]);
+    }
+
+    #[test]
+    fn password_change_revokes_sessions() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = Request::default();
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        let pw_sid = state.login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(),
+                                             None, None, 0).unwrap().0;
+        let other_sid = state.make_session(&conn, req.clone(), uid, None, 0,
+                                           Permissions::default()).unwrap().0;
+        let token = state.make_token(&conn, req.clone(), uid, "t".to_owned(),
+                                     Permissions::default(), None).unwrap().0;
+
+        let mut c = state.users_by_id().get(&uid).unwrap().change();
+        c.set_password("hunter3".to_owned());
+        state.apply(&conn, 42, c).unwrap();
+
+        // All of the user's sessions should be revoked as of the change, including on reload.
+        for _ in 0..2 {
+            for sid in &[&pw_sid, &other_sid] {
+                let e = state.authenticate_session(&conn, req.clone(), &sid.hash()).unwrap_err();
+                assert_eq!(format!("{}", e), "session is no longer valid (reason=3)");
+                assert_eq!(state.sessions[&sid.hash()].revocation.when_sec, Some(42));
+            }
+            let e = state.authenticate_token(&conn, req.clone(), &token.hash()).unwrap_err();
+            assert_eq!(format!("{}", e), "session is no longer valid (reason=3)");
+            state = State::init(&conn).unwrap();
+        }
+    }
+
+    #[test]
+    fn api_token() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        state.set_session_limits(SessionLimits {
+            max_idle_sec: Some(10),
+            max_per_user: Some(1),
+            ..Default::default()
+        });
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        let at = |when_sec| Request { when_sec: Some(when_sec), ..Default::default() };
+        let mut perms = Permissions::new();
+        perms.update_signals = true;
+        let tid = state.make_token(&conn, at(0), uid, "motion".to_owned(), perms, Some(100))
+                       .unwrap().0;
+        let sid = state.login_by_password(&conn, at(0), "slamb", "hunter2".to_owned(),
+                                          None, None, 0).unwrap().0;
+
+        // Tokens and sessions aren't interchangeable.
+        let e = state.authenticate_session(&conn, at(1), &tid.hash()).unwrap_err();
+        assert_eq!(format!("{}", e), "API token can't be used as a session cookie");
+        let e = state.authenticate_token(&conn, at(1), &sid.hash()).unwrap_err();
+        assert_eq!(format!("{}", e), "session can't be used as an API token");
+
+        // Session limits don't apply to tokens, but their own expiration does.
+        state.login_by_password(&conn, at(2), "slamb", "hunter2".to_owned(),
+                                None, None, 0).unwrap();
+        {
+            let (s, _) = state.authenticate_token(&conn, at(50), &tid.hash()).unwrap();
+            assert!(s.permissions.update_signals);
+            assert!(!s.permissions.view_video);
+        }
+        assert_eq!(state.list_sessions(&conn, uid, Some(3)).unwrap().len(), 1);
+        {
+            let l = state.list_tokens(&conn, uid, Some(99)).unwrap();
+            assert_eq!(l.len(), 1);
+            assert_eq!(l[0].0, tid.hash());
+            assert_eq!(l[0].1.description(), Some("motion"));
+        }
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        assert_eq!(state.list_tokens(&conn, uid, Some(99)).unwrap().len(), 1);
+        assert_eq!(state.list_tokens(&conn, uid, Some(100)).unwrap().len(), 0);
+        let e = state.authenticate_token(&conn, at(100), &tid.hash()).unwrap_err();
+        assert_eq!(format!("{}", e), "session is no longer valid (reason=4)");
+    }
+
+    #[test]
+    fn unix_uid() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.unix_uid = Some(1000);
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        assert_eq!(state.authenticate_unix_uid(1000).unwrap().id, uid);
+        let e = state.authenticate_unix_uid(1001).unwrap_err();
+        assert_eq!(format!("{}", e), "no user with unix uid 1001");
+
+        let mut c = state.users_by_id().get(&uid).unwrap().change();
+        c.disable();
+        state.apply(&conn, 0, c).unwrap();
+        let e = state.authenticate_unix_uid(1000).unwrap_err();
+        assert_eq!(format!("{}", e), "user \"slamb\" is disabled");
+    }
+
+    #[test]
+    fn trusted_username() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let c = UserChange::add_user("slamb".to_owned());
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        assert_eq!(state.authenticate_trusted_username("slamb").unwrap().id, uid);
+        let e = state.authenticate_trusted_username("root").unwrap_err();
+        assert_eq!(format!("{}", e), "no user \"root\"");
+
+        let mut c = state.users_by_id().get(&uid).unwrap().change();
+        c.disable();
+        state.apply(&conn, 0, c).unwrap();
+        let e = state.authenticate_trusted_username("slamb").unwrap_err();
+        assert_eq!(format!("{}", e), "user \"slamb\" is disabled");
+    }
+
+    #[test]
+    fn two_factor() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        let uid = state.apply(&conn, 0, c).unwrap().id;
+        let mut c = state.users_by_id().get(&uid).unwrap().change();
+        let codes = c.set_totp_secret(b"12345678901234567890".to_vec());  // from RFC 6238.
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        state.apply(&conn, 0, c).unwrap();
+        let at = |when_sec| Request { when_sec: Some(when_sec), ..Default::default() };
+        let login = |state: &mut State, when_sec: i64, code: Option<&str>| {
+            state.login_by_password(&conn, at(when_sec), "slamb", "hunter2".to_owned(), code,
+                                    None, 0).map(|_| ())
+        };
+
+        let e = login(&mut state, 59, None).unwrap_err();
+        assert!(e.downcast_ref::<SecondFactorRequired>().is_some());
+        let e = login(&mut state, 59, Some("000000")).unwrap_err();
+        assert_eq!(format!("{}", e), "incorrect two-factor code for user \"slamb\"");
+        login(&mut state, 59, Some("287082")).unwrap();
+        login(&mut state, 60, Some("287082")).unwrap_err();  // no replay.
+
+        // A recovery code works regardless of case or separators, but only once.
+        let code = codes[0].replace("-", "").to_uppercase();
+        login(&mut state, 1000, Some(code.as_str())).unwrap();
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        assert_eq!(state.users_by_id().get(&uid).unwrap().recovery_codes_left(),
+                   RECOVERY_CODES - 1);
+        login(&mut state, 1000, Some(codes[0].as_str())).unwrap_err();
+        login(&mut state, 1000, Some(codes[1].as_str())).unwrap();
+
+        // Per-request password authentication can't supply a code.
+        let e = state.authenticate_password(&conn, at(1000), "slamb", "hunter2".to_owned())
+                     .unwrap_err();
+        assert_eq!(format!("{}", e), "two-factor code required");
+
+        let mut c = state.users_by_id().get(&uid).unwrap().change();
+        c.clear_totp();
+        state.apply(&conn, 0, c).unwrap();
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        assert!(!state.users_by_id().get(&uid).unwrap().has_totp());
+        login(&mut state, 1000, None).unwrap();
+    }
+
+    #[test]
+    fn camera_permissions() {
+        let a = Uuid::parse_str("f8cd4c9a-0de1-4d3c-a6b3-9e1c2b5a4a0e").unwrap();
+        let b = Uuid::parse_str("2a4d3b7e-54d5-4bb0-8a3c-0b1d9e6f7c21").unwrap();
+        let mut p = Permissions::new();
+        assert!(p.allows_camera(a));
+        assert!(p.allows_camera(b));
+        p.camera_uuids.push(a.to_string());
+        p.camera_uuids.push("bogus".to_owned());
+        assert!(p.allows_camera(a));
+        assert!(!p.allows_camera(b));
+    }
+
     #[test]
     fn revoke_not_in_cache() {
         testutil::init();
@@ -855,10 +1880,10 @@ mod tests {
         {
             let mut c = UserChange::add_user("slamb".to_owned());
             c.set_password("hunter2".to_owned());
-            state.apply(&conn, c).unwrap();
+            state.apply(&conn, 0, c).unwrap();
         };
         let sid = state.login_by_password(&conn, req.clone(), "slamb",
-                                          "hunter2".to_owned(),
+                                          "hunter2".to_owned(), None,
                                           Some(b"nvr.example.com".to_vec()), 0).unwrap().0;
         state.authenticate_session(&conn, req.clone(), &sid.hash()).unwrap();
 
@@ -886,7 +1911,7 @@ mod tests {
         // hunter2, in insecure MD5.
         change.set_password_hash = Some(Some(insecure_hash.clone()));
         let uid = {
-            let u = state.apply(&conn, change).unwrap();
+            let u = state.apply(&conn, 0, change).unwrap();
             assert_eq!(&insecure_hash, u.password_hash.as_ref().unwrap());
             u.id
         };
@@ -896,7 +1921,7 @@ mod tests {
             addr: Some(::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(127, 0, 0, 1))),
             user_agent: Some(b"some ua".to_vec()),
         };
-        state.login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(),
+        state.login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(), None,
                                 Some(b"nvr.example.com".to_vec()), 0).unwrap();
         let new_hash = {
             // Password should have been automatically upgraded.
@@ -922,7 +1947,7 @@ mod tests {
         }
 
         // Login should still work.
-        state.login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(),
+        state.login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(), None,
                                 Some(b"nvr.example.com".to_vec()), 0).unwrap();
     }
 
@@ -940,24 +1965,24 @@ mod tests {
         let uid = {
             let mut c = UserChange::add_user("slamb".to_owned());
             c.set_password("hunter2".to_owned());
-            state.apply(&conn, c).unwrap().id
+            state.apply(&conn, 0, c).unwrap().id
         };
 
         // Get a session for later.
         let sid = state.login_by_password(&conn, req.clone(), "slamb",
-                                          "hunter2".to_owned(),
+                                          "hunter2".to_owned(), None,
                                           Some(b"nvr.example.com".to_vec()), 0).unwrap().0;
 
         // Disable the user.
         {
             let mut c = state.users_by_id().get(&uid).unwrap().change();
             c.disable();
-            state.apply(&conn, c).unwrap();
+            state.apply(&conn, 0, c).unwrap();
         }
 
         // Fresh logins shouldn't work.
         let e = state.login_by_password(&conn, req.clone(), "slamb",
-                                       "hunter2".to_owned(),
+                                       "hunter2".to_owned(), None,
                                        Some(b"nvr.example.com".to_vec()), 0).unwrap_err();
         assert_eq!(format!("{}", e), "user \"slamb\" is disabled");
 
@@ -986,12 +2011,12 @@ mod tests {
         let uid = {
             let mut c = UserChange::add_user("slamb".to_owned());
             c.set_password("hunter2".to_owned());
-            state.apply(&conn, c).unwrap().id
+            state.apply(&conn, 0, c).unwrap().id
         };
 
         // Get a session for later.
         let (sid, _) = state.login_by_password(&conn, req.clone(), "slamb",
-                                               "hunter2".to_owned(),
+                                               "hunter2".to_owned(), None,
                                                Some(b"nvr.example.com".to_vec()), 0).unwrap();
 
         state.delete_user(&mut conn, uid).unwrap();
@@ -1015,14 +2040,14 @@ mod tests {
         let mut state = State::init(&conn).unwrap();
         let mut change = UserChange::add_user("slamb".to_owned());
         change.permissions.view_video = true;
-        let u = state.apply(&conn, change).unwrap();
+        let u = state.apply(&conn, 0, change).unwrap();
         assert!(u.permissions.view_video);
         assert!(!u.permissions.update_signals);
         let mut change = u.change();
         assert!(change.permissions.view_video);
         assert!(!change.permissions.update_signals);
         change.permissions.update_signals = true;
-        let u = state.apply(&conn, change).unwrap();
+        let u = state.apply(&conn, 0, change).unwrap();
         assert!(u.permissions.view_video);
         assert!(u.permissions.update_signals);
         let uid = u.id;
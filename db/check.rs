@@ -86,7 +86,8 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
             }
 
             // Open the directory (checking its metadata) and hold it open (for the lock).
-            let dir = dir::SampleFileDir::open(&dir_path, &meta)?;
+            // The key isn't needed; encrypted files' lengths can be checked without it.
+            let dir = dir::SampleFileDir::open(&dir_path, &meta, None)?;
             let mut streams = read_dir(&dir, opts)?;
             let mut rows = garbage_stmt.query(&[&dir_id])?;
             while let Some(row) = rows.next()? {
@@ -186,9 +187,9 @@ fn summarize_index(video_index: &[u8]) -> Result<RecordingSummary, Error> {
 /// Reads through the given sample file directory.
 /// Logs unexpected files and creates a hash map of the files found there.
 /// If `opts.compare_lens` is set, the values are lengths; otherwise they're insignificant.
-fn read_dir(d: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
+fn read_dir(sample_file_dir: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
     let mut dir = Dir::default();
-    let mut d = d.opendir()?;
+    let mut d = sample_file_dir.opendir()?;
     let fd = d.as_raw_fd();
     for e in d.iter() {
         let e = e?;
@@ -205,7 +206,14 @@ fn read_dir(d: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
             }
         };
         let len = if opts.compare_lens {
-            nix::sys::stat::fstatat(fd, f, AtFlags::empty())?.st_size as u64
+            let len = nix::sys::stat::fstatat(fd, f, AtFlags::empty())?.st_size as u64;
+            match sample_file_dir.plaintext_len(len) {
+                Some(l) => l,
+                None => {
+                    error!("encrypted sample file {} has impossible length {}", id, len);
+                    len
+                },
+            }
         } else { 0 };
         let stream = dir.entry(id.stream()).or_insert_with(Stream::default);
         stream.entry(id.recording()).or_insert_with(Recording::default).file = Some(len);
@@ -0,0 +1,552 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Encryption of sample files and camera credentials at rest.
+//!
+//! An encrypted sample file has the following format:
+//!
+//! *   an 8-byte random nonce prefix, chosen when the file is created.
+//! *   a sequence of chunks, each holding `chunk_size` bytes of plaintext (the last may be
+//!     shorter, and is empty for an empty file) sealed with AES-256-GCM and followed by its
+//!     16-byte tag.
+//!
+//! Each chunk's 96-bit nonce is the prefix followed by the big-endian chunk index. Its additional
+//! authenticated data is the recording's big-endian composite id followed by a byte which is 1 for
+//! the final chunk and 0 otherwise. Thus chunks can't be swapped between or within files, and a
+//! file can't be silently truncated at a chunk boundary. Because the chunks are independent, any
+//! range of plaintext can be read by decrypting only the chunks which overlap it.
+//!
+//! A file which is still being written has no final chunk yet, and the plaintext following its
+//! last full chunk is held in memory. Its `Writer` shares that state with readers via `Growing`,
+//! so in-progress recordings can be served. Their written chunks are all authenticated as
+//! non-final; truncation can only be detected once the file is complete.
+//!
+//! Small values stored in the database, such as camera passwords, are sealed by `seal_value` as a
+//! random 12-byte nonce, the AES-256-GCM ciphertext, and its tag.
+
+use blake2_rfc::blake2b::blake2b;
+use crate::db::CompositeId;
+use failure::{Error, bail, format_err};
+use openssl::symm;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+pub const KEY_LEN: usize = 32;
+const PREFIX_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const KEY_CHECK_LEN: usize = 16;
+const VALUE_NONCE_LEN: usize = 12;
+
+/// The default plaintext bytes per chunk for newly created directories.
+pub const DEFAULT_CHUNK_SIZE: u32 = 1 << 16;
+
+/// A 256-bit key used to encrypt sample files or camera credentials. This is never stored in the
+/// database.
+#[derive(Clone)]
+pub struct Key([u8; KEY_LEN]);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key(...)")
+    }
+}
+
+impl From<[u8; KEY_LEN]> for Key {
+    fn from(k: [u8; KEY_LEN]) -> Self { Key(k) }
+}
+
+impl Key {
+    /// Parses a key from base64, as in an environment variable.
+    pub fn from_base64(s: &str) -> Result<Self, Error> {
+        let data = base64::decode(s.trim()).map_err(|_| format_err!("key isn't valid base64"))?;
+        if data.len() != KEY_LEN {
+            bail!("key has {} bytes; expected {}", data.len(), KEY_LEN);
+        }
+        let mut k = [0u8; KEY_LEN];
+        k.copy_from_slice(&data);
+        Ok(Key(k))
+    }
+
+    /// Reads a key from the given path, which should hold exactly `KEY_LEN` raw bytes.
+    pub fn read(path: &str) -> Result<Self, Error> {
+        let data = fs::read(path).map_err(|e| format_err!("unable to read key {}: {}", path, e))?;
+        if data.len() != KEY_LEN {
+            bail!("key file {} has {} bytes; expected {}", path, data.len(), KEY_LEN);
+        }
+        let mut k = [0u8; KEY_LEN];
+        k.copy_from_slice(&data);
+        Ok(Key(k))
+    }
+
+    /// Returns a value which identifies the key without revealing it, for storage in a
+    /// directory's metadata. This catches attempts to open a directory with the wrong key.
+    pub fn check(&self) -> Vec<u8> {
+        blake2b(KEY_CHECK_LEN, &self.0[..], b"sample file key check").as_bytes().to_vec()
+    }
+}
+
+/// A key along with the chunk size of the directory it's used with.
+#[derive(Clone, Debug)]
+pub(crate) struct Params {
+    pub(crate) key: Key,
+    pub(crate) chunk_size: u32,
+}
+
+fn num_chunks(plaintext_len: u64, chunk_size: u64) -> u64 {
+    if plaintext_len == 0 { 1 } else { (plaintext_len + chunk_size - 1) / chunk_size }
+}
+
+/// Returns the on-disk length of a file with the given plaintext length.
+pub fn encrypted_len(plaintext_len: u64, chunk_size: u32) -> u64 {
+    PREFIX_LEN as u64 + plaintext_len +
+        TAG_LEN as u64 * num_chunks(plaintext_len, u64::from(chunk_size))
+}
+
+/// Returns the plaintext length of a file with the given on-disk length, or `None` if no
+/// plaintext length corresponds to it.
+pub fn plaintext_len(len: u64, chunk_size: u32) -> Option<u64> {
+    let sealed_chunk = u64::from(chunk_size) + TAG_LEN as u64;
+    let body = len.checked_sub(PREFIX_LEN as u64)?;
+    let chunks = std::cmp::max(1, (body + sealed_chunk - 1) / sealed_chunk);
+    let p = body.checked_sub(TAG_LEN as u64 * chunks)?;
+    if encrypted_len(p, chunk_size) != len {
+        return None;
+    }
+    Some(p)
+}
+
+fn nonce(prefix: &[u8; PREFIX_LEN], chunk: u64) -> [u8; 12] {
+    let mut n = [0u8; 12];
+    n[..PREFIX_LEN].copy_from_slice(prefix);
+    n[PREFIX_LEN..].copy_from_slice(&(chunk as u32).to_be_bytes());
+    n
+}
+
+fn aad(id: CompositeId, last: bool) -> [u8; 9] {
+    let mut a = [0u8; 9];
+    a[..8].copy_from_slice(&id.0.to_be_bytes());
+    a[8] = last as u8;
+    a
+}
+
+fn seal(p: &Params, prefix: &[u8; PREFIX_LEN], id: CompositeId, chunk: u64, last: bool,
+        plaintext: &[u8], out: &mut Vec<u8>) {
+    let mut tag = [0u8; TAG_LEN];
+    let c = symm::encrypt_aead(symm::Cipher::aes_256_gcm(), &p.key.0[..],
+                               Some(&nonce(prefix, chunk)[..]), &aad(id, last), plaintext,
+                               &mut tag)
+        .expect("AES-256-GCM encryption is infallible");
+    out.extend_from_slice(&c);
+    out.extend_from_slice(&tag);
+}
+
+/// Encrypts a small value for storage in the database. `aad` should identify the value's purpose,
+/// so that sealed values can't be swapped for one another.
+pub fn seal_value(key: &Key, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(VALUE_NONCE_LEN + plaintext.len() + TAG_LEN);
+    out.resize(VALUE_NONCE_LEN, 0);
+    ::openssl::rand::rand_bytes(&mut out[..]).unwrap();
+    let mut tag = [0u8; TAG_LEN];
+    let c = symm::encrypt_aead(symm::Cipher::aes_256_gcm(), &key.0[..], Some(&out[..]), aad,
+                               plaintext, &mut tag)
+        .expect("AES-256-GCM encryption is infallible");
+    out.extend_from_slice(&c);
+    out.extend_from_slice(&tag);
+    out
+}
+
+/// Decrypts a value sealed by `seal_value` with the same `aad`.
+pub fn open_value(key: &Key, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    if sealed.len() < VALUE_NONCE_LEN + TAG_LEN {
+        bail!("sealed value is too short ({} bytes)", sealed.len());
+    }
+    let (nonce, rest) = sealed.split_at(VALUE_NONCE_LEN);
+    let (data, tag) = rest.split_at(rest.len() - TAG_LEN);
+    symm::decrypt_aead(symm::Cipher::aes_256_gcm(), &key.0[..], Some(nonce), aad, data, tag)
+        .map_err(|_| format_err!("sealed value failed authentication; wrong key?"))
+}
+
+/// The state of an encrypted sample file which is still being written, as shared by its `Writer`
+/// with readers.
+#[derive(Default)]
+pub(crate) struct Growing {
+    /// The number of chunks which have been written to the file. None is final.
+    chunks: u64,
+
+    /// The plaintext following those chunks, which is sealed and written later.
+    plaintext: Vec<u8>,
+}
+
+impl fmt::Debug for Growing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Growing")
+         .field("chunks", &self.chunks)
+         .field("plaintext_len", &self.plaintext.len())
+         .finish()
+    }
+}
+
+/// A reader of encrypted sample files which decrypts chunks on demand.
+pub(crate) struct Decryptor {
+    params: Params,
+    file: fs::File,
+    id: CompositeId,
+    prefix: [u8; PREFIX_LEN],
+    plaintext_len: u64,
+
+    /// For a file which is still being written, the chunks written so far and a copy of the
+    /// plaintext which follows them.
+    growing: Option<(u64, Vec<u8>)>,
+}
+
+impl Decryptor {
+    pub(crate) fn new(params: Params, file: fs::File, id: CompositeId) -> Result<Self, Error> {
+        let len = file.metadata()?.len();
+        let plaintext_len = plaintext_len(len, params.chunk_size)
+            .ok_or_else(|| format_err!("encrypted sample file {} has invalid length {}", id, len))?;
+        let mut prefix = [0u8; PREFIX_LEN];
+        file.read_exact_at(&mut prefix, 0)?;
+        Ok(Decryptor {
+            params,
+            file,
+            id,
+            prefix,
+            plaintext_len,
+            growing: None,
+        })
+    }
+
+    /// Returns a decryptor for a file which is still being written, given its writer's state.
+    pub(crate) fn growing(params: Params, file: fs::File, id: CompositeId, g: &Growing)
+                          -> Result<Self, Error> {
+        let mut prefix = [0u8; PREFIX_LEN];
+        if g.chunks > 0 {
+            // Otherwise the prefix may not have been written yet, and it isn't needed.
+            file.read_exact_at(&mut prefix, 0)?;
+        }
+        Ok(Decryptor {
+            plaintext_len: g.chunks * u64::from(params.chunk_size) + g.plaintext.len() as u64,
+            params,
+            file,
+            id,
+            prefix,
+            growing: Some((g.chunks, g.plaintext.clone())),
+        })
+    }
+
+    pub(crate) fn plaintext_len(&self) -> u64 { self.plaintext_len }
+
+    /// Returns the plaintext bytes per chunk. A read of a chunk-aligned range decrypts each of
+    /// its chunks once.
+    pub(crate) fn chunk_size(&self) -> u64 { u64::from(self.params.chunk_size) }
+
+    /// Decrypts chunk `i`, returning its plaintext.
+    fn chunk(&self, i: u64) -> Result<Vec<u8>, Error> {
+        let chunk_size = u64::from(self.params.chunk_size);
+        let chunks = num_chunks(self.plaintext_len, chunk_size);
+        if i >= chunks {
+            bail!("chunk {} of sample file {} is past end", i, self.id);
+        }
+        let (last, plaintext_len) = match self.growing {
+            Some((written, ref plaintext)) if i >= written => {
+                let start = ((i - written) * chunk_size) as usize;
+                let end = std::cmp::min(start + chunk_size as usize, plaintext.len());
+                return Ok(plaintext[start..end].to_vec());
+            },
+            Some(_) => (false, chunk_size),
+            None if i + 1 == chunks => (true, self.plaintext_len - i * chunk_size),
+            None => (false, chunk_size),
+        };
+        let mut buf = vec![0u8; plaintext_len as usize + TAG_LEN];
+        self.file.read_exact_at(&mut buf, PREFIX_LEN as u64 + i * (chunk_size + TAG_LEN as u64))?;
+        let (data, tag) = buf.split_at(plaintext_len as usize);
+        symm::decrypt_aead(symm::Cipher::aes_256_gcm(), &self.params.key.0[..],
+                           Some(&nonce(&self.prefix, i)[..]), &aad(self.id, last), data, tag)
+            .map_err(|_| format_err!("chunk {} of sample file {} failed authentication; wrong \
+                                      key or corrupt file", i, self.id))
+    }
+
+    /// Reads the given range of plaintext.
+    pub(crate) fn read_range(&self, r: Range<u64>) -> Result<Vec<u8>, Error> {
+        if r.end > self.plaintext_len || r.start > r.end {
+            bail!("range {:?} of sample file {} is out of bounds (len {})",
+                  r, self.id, self.plaintext_len);
+        }
+        let mut out = Vec::with_capacity((r.end - r.start) as usize);
+        if r.start == r.end {
+            return Ok(out);
+        }
+        let chunk_size = u64::from(self.params.chunk_size);
+        for i in r.start / chunk_size ..= (r.end - 1) / chunk_size {
+            let c = self.chunk(i)?;
+            let chunk_start = i * chunk_size;
+            let from = r.start.saturating_sub(chunk_start) as usize;
+            let to = std::cmp::min(r.end - chunk_start, c.len() as u64) as usize;
+            out.extend_from_slice(&c[from..to]);
+        }
+        Ok(out)
+    }
+}
+
+/// A sequential reader of an encrypted sample file's plaintext.
+pub(crate) struct Reader {
+    d: Decryptor,
+    pos: u64,
+    buf: Vec<u8>,
+    buf_pos: usize,
+}
+
+impl Reader {
+    pub(crate) fn new(params: Params, file: fs::File, id: CompositeId) -> Result<Self, Error> {
+        Ok(Reader {
+            d: Decryptor::new(params, file, id)?,
+            pos: 0,
+            buf: Vec::new(),
+            buf_pos: 0,
+        })
+    }
+}
+
+impl io::Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf_pos == self.buf.len() {
+            let end = std::cmp::min(self.d.plaintext_len(), self.pos + self.d.chunk_size());
+            self.buf = self.d.read_range(self.pos .. end)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.compat()))?;
+            self.buf_pos = 0;
+            self.pos = end;
+        }
+        let n = std::cmp::min(buf.len(), self.buf.len() - self.buf_pos);
+        buf[..n].copy_from_slice(&self.buf[self.buf_pos .. self.buf_pos + n]);
+        self.buf_pos += n;
+        Ok(n)
+    }
+}
+
+struct WriterInner {
+    file: fs::File,
+
+    /// Sealed data which hasn't yet been written to `file`.
+    sealed: Vec<u8>,
+
+    /// The index of the next chunk to seal.
+    next_chunk: u64,
+
+    /// True iff the final chunk has been sealed, after which no more data can be written.
+    finished: bool,
+}
+
+impl WriterInner {
+    fn write_sealed(&mut self) -> io::Result<()> {
+        while !self.sealed.is_empty() {
+            let n = self.file.write(&self.sealed)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.sealed.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+/// A writer of an encrypted sample file.
+///
+/// The final chunk is sealed on `sync_all`; data written before then but never synced is lost.
+/// Until then, plaintext which hasn't been written as part of a chunk is kept in `growing`, where
+/// readers can find it. Both `write` and `sync_all` may be retried after failure.
+pub(crate) struct Writer {
+    params: Params,
+    id: CompositeId,
+    prefix: [u8; PREFIX_LEN],
+    growing: Arc<Mutex<Growing>>,
+    inner: RefCell<WriterInner>,
+}
+
+impl Writer {
+    pub(crate) fn new(params: Params, file: fs::File, id: CompositeId) -> Self {
+        let mut prefix = [0u8; PREFIX_LEN];
+        ::openssl::rand::rand_bytes(&mut prefix).unwrap();
+        Writer {
+            params,
+            id,
+            prefix,
+            growing: Arc::new(Mutex::new(Growing::default())),
+            inner: RefCell::new(WriterInner {
+                file,
+                sealed: prefix.to_vec(),
+                next_chunk: 0,
+                finished: false,
+            }),
+        }
+    }
+
+    /// Returns the state to share with readers while the file is being written.
+    pub(crate) fn growing(&self) -> Arc<Mutex<Growing>> { self.growing.clone() }
+
+    pub(crate) fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = self.inner.get_mut();
+        if inner.finished {
+            return Err(io::Error::new(io::ErrorKind::Other, "write after sync_all"));
+        }
+
+        // Write out previously sealed data first, so that on error nothing has been consumed.
+        inner.write_sealed()?;
+        let chunk_size = self.params.chunk_size as usize;
+        let mut g = self.growing.lock();
+        let written = (inner.next_chunk - g.chunks) as usize * chunk_size;
+        g.plaintext.drain(..written);
+        g.chunks = inner.next_chunk;
+        g.plaintext.extend_from_slice(buf);
+
+        // Seal a chunk only once it's known not to be the last. Its plaintext stays in `growing`
+        // until the chunk is written.
+        let mut sealed_len = 0;
+        while g.plaintext.len() - sealed_len > chunk_size {
+            seal(&self.params, &self.prefix, self.id, inner.next_chunk, false,
+                 &g.plaintext[sealed_len .. sealed_len + chunk_size], &mut inner.sealed);
+            sealed_len += chunk_size;
+            inner.next_chunk += 1;
+        }
+        Ok(buf.len())
+    }
+
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        if !inner.finished {
+            // Readers may still use `growing` until the writer is dropped, so leave it be.
+            let g = self.growing.lock();
+            let chunk_size = self.params.chunk_size as usize;
+            let sealed_len = (inner.next_chunk - g.chunks) as usize * chunk_size;
+            let next_chunk = inner.next_chunk;
+            seal(&self.params, &self.prefix, self.id, next_chunk, true, &g.plaintext[sealed_len..],
+                 &mut inner.sealed);
+            inner.finished = true;
+        }
+        inner.write_sealed()?;
+        inner.file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn params() -> Params {
+        Params {
+            key: Key([42u8; KEY_LEN]),
+            chunk_size: 16,
+        }
+    }
+
+    #[test]
+    fn lens() {
+        for &p in &[0, 1, 15, 16, 17, 32, 33, 1000] {
+            let e = encrypted_len(p, 16);
+            assert_eq!(plaintext_len(e, 16), Some(p), "p={} e={}", p, e);
+        }
+        assert_eq!(encrypted_len(0, 16), 8 + 16);
+        assert_eq!(encrypted_len(16, 16), 8 + 16 + 16);
+        assert_eq!(encrypted_len(17, 16), 8 + 17 + 32);
+        assert_eq!(plaintext_len(0, 16), None);
+        assert_eq!(plaintext_len(8 + 16 + 16 + 16, 16), None);  // full chunk plus bare tag.
+    }
+
+    #[test]
+    fn round_trip() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("f");
+        let id = CompositeId::new(1, 2);
+        let data: Vec<u8> = (0..100u8).collect();
+        let mut w = Writer::new(params(), fs::File::create(&path).unwrap(), id);
+        w.write(&data[..7]).unwrap();
+        w.write(&data[7..]).unwrap();
+        w.sync_all().unwrap();
+        drop(w);
+        let f = fs::File::open(&path).unwrap();
+        assert_eq!(f.metadata().unwrap().len(), encrypted_len(100, 16));
+
+        let d = Decryptor::new(params(), f.try_clone().unwrap(), id).unwrap();
+        assert_eq!(d.plaintext_len(), 100);
+        for &(start, end) in &[(0, 100), (0, 0), (5, 6), (15, 17), (16, 32), (90, 100)] {
+            assert_eq!(&d.read_range(start..end).unwrap()[..], &data[start as usize..end as usize]);
+        }
+        d.read_range(0..101).unwrap_err();
+
+        let mut buf = Vec::new();
+        Reader::new(params(), f.try_clone().unwrap(), id).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // The wrong id or key should fail authentication.
+        Decryptor::new(params(), f.try_clone().unwrap(), CompositeId::new(1, 3)).unwrap()
+            .read_range(0..1).unwrap_err();
+        let wrong = Params { key: Key([43u8; KEY_LEN]), chunk_size: 16 };
+        Decryptor::new(wrong, f, id).unwrap().read_range(0..1).unwrap_err();
+    }
+
+    #[test]
+    fn values() {
+        let key = Key([42u8; KEY_LEN]);
+        let sealed = seal_value(&key, b"password", b"hunter2");
+        assert_eq!(sealed.len(), VALUE_NONCE_LEN + 7 + TAG_LEN);
+        assert_eq!(&open_value(&key, b"password", &sealed).unwrap()[..], b"hunter2");
+        assert_ne!(sealed, seal_value(&key, b"password", b"hunter2"));  // random nonce.
+        open_value(&key, b"username", &sealed).unwrap_err();
+        open_value(&Key([43u8; KEY_LEN]), b"password", &sealed).unwrap_err();
+        open_value(&key, b"password", &sealed[..VALUE_NONCE_LEN + TAG_LEN - 1]).unwrap_err();
+        assert_eq!(Key::from_base64(&base64::encode(&[42u8; KEY_LEN][..])).unwrap().0, key.0);
+        Key::from_base64("AAAA").unwrap_err();
+    }
+
+    #[test]
+    fn truncation_detected() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("f");
+        let id = CompositeId::new(1, 2);
+        let mut w = Writer::new(params(), fs::File::create(&path).unwrap(), id);
+        w.write(&[0u8; 48]).unwrap();
+        w.sync_all().unwrap();
+        drop(w);
+
+        // Drop the last chunk; the new last chunk wasn't sealed as such.
+        let f = fs::OpenOptions::new().write(true).read(true).open(&path).unwrap();
+        f.set_len(encrypted_len(32, 16)).unwrap();
+        let d = Decryptor::new(params(), f, id).unwrap();
+        assert_eq!(d.plaintext_len(), 32);
+        d.read_range(0..16).unwrap();
+        d.read_range(16..32).unwrap_err();
+    }
+}
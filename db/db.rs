@@ -54,7 +54,9 @@
 
 use base::clock::{self, Clocks};
 use base::strutil::encode_size;
+use crate::audit;
 use crate::auth;
+use crate::crypto;
 use crate::dir;
 use crate::raw;
 use crate::recording::{self, TIME_UNITS_PER_SEC};
@@ -85,7 +87,7 @@ use time;
 use uuid::Uuid;
 
 /// Expected schema version. See `guide/schema.md` for more information.
-pub const EXPECTED_VERSION: i32 = 5;
+pub const EXPECTED_VERSION: i32 = 6;
 
 const GET_RECORDING_PLAYBACK_SQL: &'static str = r#"
     select
@@ -297,6 +299,19 @@ pub struct SampleFileDir {
     dir: Option<Arc<dir::SampleFileDir>>,
     last_complete_open: Option<Open>,
 
+    /// If set, the directory is in automatic retention mode: the syncer sizes each stream's limit
+    /// from the filesystem's capacity, keeping this many bytes free and dividing the rest among
+    /// the directory's streams according to their `retain_weight`. `retain_bytes` is ignored.
+    pub free_space_margin_bytes: Option<i64>,
+
+    /// If set, the path to the file holding the key with which sample files are encrypted. See
+    /// `crypto`.
+    pub key_path: Option<String>,
+
+    /// If set, the directory has suffered persistent I/O errors and is no longer written to until
+    /// the next restart. See `LockedDatabase::mark_sample_file_dir_failed`.
+    failure: Option<String>,
+
     /// ids which are in the `garbage` database table (rather than `recording`) as of last commit
     /// but may still exist on disk. These can't be safely removed from the database yet.
     pub(crate) garbage_needs_unlink: FnvHashSet<CompositeId>,
@@ -318,6 +333,9 @@ impl SampleFileDir {
                .clone())
     }
 
+    /// Returns the reason this directory was marked failed, if it has been.
+    pub fn failure(&self) -> Option<&str> { self.failure.as_ref().map(String::as_str) }
+
     /// Returns expected existing metadata when opening this directory.
     fn meta(&self, db_uuid: &Uuid) -> schema::DirMeta {
         let mut meta = schema::DirMeta::default();
@@ -346,11 +364,55 @@ pub struct Camera {
     pub short_name: String,
     pub description: String,
     pub onvif_host: String,
-    pub username: String,
-    pub password: String,
+
+    /// The credentials to use when accessing the camera, or `None` to supply no username or
+    /// password.
+    pub credentials: Option<Credentials>,
+
     pub streams: [Option<i32>; 2],
 }
 
+/// A camera's username and password, each sealed with the credentials key (see
+/// `crypto::seal_value`) so that they aren't exposed by the database or its backups. These are
+/// decrypted only when needed to connect to the camera.
+///
+/// The camera's uuid is part of each value's additional authenticated data, so sealed values
+/// can't be swapped between cameras.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub(crate) username: Vec<u8>,
+    pub(crate) password: Vec<u8>,
+}
+
+impl Credentials {
+    pub fn seal(key: &crypto::Key, camera_uuid: Uuid, username: &str, password: &str) -> Self {
+        Credentials {
+            username: crypto::seal_value(key, &credentials_aad(b"camera username", camera_uuid),
+                                         username.as_bytes()),
+            password: crypto::seal_value(key, &credentials_aad(b"camera password", camera_uuid),
+                                         password.as_bytes()),
+        }
+    }
+
+    /// Returns the plaintext `(username, password)` of the camera with the given uuid.
+    pub fn open(&self, key: &crypto::Key, camera_uuid: Uuid) -> Result<(String, String), Error> {
+        let username = crypto::open_value(
+            key, &credentials_aad(b"camera username", camera_uuid), &self.username)?;
+        let password = crypto::open_value(
+            key, &credentials_aad(b"camera password", camera_uuid), &self.password)?;
+        Ok((String::from_utf8(username)?, String::from_utf8(password)?))
+    }
+}
+
+/// Returns the additional authenticated data for a credential: its name followed by the camera's
+/// uuid.
+fn credentials_aad(name: &[u8], camera_uuid: Uuid) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(name.len() + 16);
+    aad.extend_from_slice(name);
+    aad.extend_from_slice(&camera_uuid.as_bytes()[..]);
+    aad
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum StreamType { MAIN, SUB }
 
@@ -403,6 +465,10 @@ pub struct Stream {
     pub retain_bytes: i64,
     pub flush_if_sec: i64,
 
+    /// This stream's share of its directory's capacity when the directory is in automatic
+    /// retention mode (see `SampleFileDir::free_space_margin_bytes`). Always positive.
+    pub retain_weight: i32,
+
     /// The time range of recorded data associated with this stream (minimum start time and maximum
     /// end time). `None` iff there are no recordings for this camera.
     pub range: Option<Range<recording::Time>>,
@@ -473,8 +539,7 @@ pub struct CameraChange {
     pub short_name: String,
     pub description: String,
     pub onvif_host: String,
-    pub username: String,
-    pub password: String,
+    pub credentials: Option<Credentials>,
 
     /// `StreamType t` is represented by `streams[t.index()]`. A default StreamChange will
     /// correspond to no stream in the database, provided there are no existing recordings for that
@@ -611,6 +676,7 @@ pub struct LockedDatabase {
 
     auth: auth::State,
     signal: signal::State,
+    audit: audit::State,
 
     sample_file_dirs_by_id: BTreeMap<i32, SampleFileDir>,
     cameras_by_id: BTreeMap<i32, Camera>,
@@ -756,6 +822,7 @@ impl StreamStateChanger {
                         sample_file_dir_id: sc.sample_file_dir_id,
                         rtsp_url: mem::replace(&mut sc.rtsp_url, String::new()),
                         retain_bytes: 0,
+                        retain_weight: 1,
                         flush_if_sec: sc.flush_if_sec,
                         range: None,
                         sample_file_bytes: 0,
@@ -791,6 +858,7 @@ pub struct RetentionChange {
     pub stream_id: i32,
     pub new_record: bool,
     pub new_limit: i64,
+    pub new_weight: i32,
 }
 
 impl LockedDatabase {
@@ -800,6 +868,18 @@ impl LockedDatabase {
         &self.sample_file_dirs_by_id
     }
 
+    /// Returns the sample file directory of each stream whose directory is open. This can change
+    /// while the server is running, via `writer::migrate_stream`.
+    pub fn dirs_by_stream_id(&self) -> FnvHashMap<i32, Arc<dir::SampleFileDir>> {
+        self.streams_by_id
+            .iter()
+            .filter_map(|(&id, s)| {
+                let d = self.sample_file_dirs_by_id.get(&s.sample_file_dir_id?)?;
+                Some((id, d.dir.as_ref()?.clone()))
+            })
+            .collect()
+    }
+
     /// Returns the number of completed database flushes since startup.
     pub fn flushes(&self) -> usize { self.flush_count }
 
@@ -946,8 +1026,8 @@ impl LockedDatabase {
                 }
             }
         }
-        for dir in self.sample_file_dirs_by_id.values() {
-            raw::mark_sample_files_deleted(&tx, &dir.garbage_unlinked)?;
+        for (&dir_id, dir) in &self.sample_file_dirs_by_id {
+            raw::mark_sample_files_deleted(&tx, dir_id, &dir.garbage_unlinked)?;
         }
         for (&stream_id, r) in &mut new_ranges {
             *r = raw::get_range(&tx, stream_id)?;
@@ -1088,7 +1168,8 @@ impl LockedDatabase {
                 open.id = o.id;
                 open.uuid.extend_from_slice(&o.uuid.as_bytes()[..]);
             }
-            let d = dir::SampleFileDir::open(&dir.path, &meta)?;
+            let d = dir::SampleFileDir::open(&dir.path, &meta,
+                                             dir.key_path.as_ref().map(String::as_str))?;
             if self.open.is_none() {  // read-only mode; it's already fully opened.
                 dir.dir = Some(d);
             } else {  // read-write mode; there are more steps to do.
@@ -1384,7 +1465,9 @@ impl LockedDatabase {
               d.path,
               d.uuid,
               d.last_complete_open_id,
-              o.uuid
+              o.uuid,
+              d.free_space_margin_bytes,
+              d.key_path
             from
               sample_file_dir d left join open o on (d.last_complete_open_id = o.id);
         "#)?;
@@ -1405,6 +1488,9 @@ impl LockedDatabase {
                 path: row.get(1)?,
                 dir: None,
                 last_complete_open,
+                free_space_margin_bytes: row.get(5)?,
+                key_path: row.get(6)?,
+                failure: None,
                 garbage_needs_unlink: raw::list_garbage(&self.conn, id)?,
                 garbage_unlinked: Vec::new(),
             });
@@ -1433,14 +1519,18 @@ impl LockedDatabase {
         while let Some(row) = rows.next()? {
             let id = row.get(0)?;
             let uuid: FromSqlUuid = row.get(1)?;
+            let credentials = match (row.get(5)?, row.get(6)?) {
+                (Some(username), Some(password)) => Some(Credentials { username, password }),
+                (None, None) => None,
+                _ => bail!("camera {} has only one of username and password", id),
+            };
             self.cameras_by_id.insert(id, Camera {
                 id: id,
                 uuid: uuid.0,
                 short_name: row.get(2)?,
                 description: row.get(3)?,
                 onvif_host: row.get(4)?,
-                username: row.get(5)?,
-                password: row.get(6)?,
+                credentials,
                 streams: Default::default(),
             });
             self.cameras_by_uuid.insert(uuid.0, id);
@@ -1463,7 +1553,8 @@ impl LockedDatabase {
               retain_bytes,
               flush_if_sec,
               next_recording_id,
-              record
+              record,
+              retain_weight
             from
               stream;
         "#)?;
@@ -1488,6 +1579,7 @@ impl LockedDatabase {
                 rtsp_url: row.get(4)?,
                 retain_bytes: row.get(5)?,
                 flush_if_sec,
+                retain_weight: row.get(9)?,
                 range: None,
                 sample_file_bytes: 0,
                 to_delete: Vec::new(),
@@ -1551,7 +1643,10 @@ impl LockedDatabase {
         Ok(id)
     }
 
-    pub fn add_sample_file_dir(&mut self, path: String) -> Result<i32, Error> {
+    /// Adds a sample file directory, encrypting its sample files with the key at `key_path` if
+    /// supplied.
+    pub fn add_sample_file_dir(&mut self, path: String, key_path: Option<String>)
+                               -> Result<i32, Error> {
         let mut meta = schema::DirMeta::default();
         let uuid = Uuid::new_v4();
         let uuid_bytes = &uuid.as_bytes()[..];
@@ -1568,11 +1663,12 @@ impl LockedDatabase {
             open.uuid.extend_from_slice(&o.uuid.as_bytes()[..]);
         }
 
-        let dir = dir::SampleFileDir::create(&path, &meta)?;
+        let dir = dir::SampleFileDir::create(&path, &meta,
+                                             key_path.as_ref().map(String::as_str))?;
         self.conn.execute(r#"
-            insert into sample_file_dir (path, uuid, last_complete_open_id)
-                                 values (?,    ?,    ?)
-        "#, &[&path as &dyn ToSql, &uuid_bytes, &o.id])?;
+            insert into sample_file_dir (path, uuid, last_complete_open_id, key_path)
+                                 values (?,    ?,    ?,                     ?)
+        "#, &[&path as &dyn ToSql, &uuid_bytes, &o.id, &key_path])?;
         let id = self.conn.last_insert_rowid() as i32;
         use ::std::collections::btree_map::Entry;
         let e = self.sample_file_dirs_by_id.entry(id);
@@ -1583,6 +1679,9 @@ impl LockedDatabase {
                 uuid,
                 dir: Some(dir),
                 last_complete_open: None,
+                free_space_margin_bytes: None,
+                key_path,
+                failure: None,
                 garbage_needs_unlink: FnvHashSet::default(),
                 garbage_unlinked: Vec::new(),
             }),
@@ -1608,7 +1707,8 @@ impl LockedDatabase {
             bail!("must collect garbage before deleting directory {}", d.get().path);
         }
         let dir = match d.get_mut().dir.take() {
-            None => dir::SampleFileDir::open(&d.get().path, &d.get().meta(&self.uuid))?,
+            None => dir::SampleFileDir::open(&d.get().path, &d.get().meta(&self.uuid),
+                                             d.get().key_path.as_ref().map(String::as_str))?,
             Some(arc) => match Arc::strong_count(&arc) {
                 1 => {
                     d.get_mut().dir = Some(arc);  // put it back.
@@ -1631,9 +1731,24 @@ impl LockedDatabase {
         Ok(())
     }
 
-    /// Adds a camera.
-    pub fn add_camera(&mut self, mut camera: CameraChange) -> Result<i32, Error> {
-        let uuid = Uuid::new_v4();
+    /// Marks a sample file directory as failed, so that writers stop using it.
+    ///
+    /// This is in-memory state only; the directory will be tried again on the next run. Recordings
+    /// in progress on the directory are never committed.
+    pub fn mark_sample_file_dir_failed(&mut self, dir_id: i32, reason: String) {
+        let d = match self.sample_file_dirs_by_id.get_mut(&dir_id) {
+            None => return,
+            Some(d) => d,
+        };
+        if d.failure.is_none() {
+            error!("sample file dir {} ({}) has failed; no longer recording to it: {}",
+                   dir_id, d.path, reason);
+            d.failure = Some(reason);
+        }
+    }
+
+    /// Adds a camera with the given new uuid, which its credentials should be sealed with.
+    pub fn add_camera(&mut self, uuid: Uuid, mut camera: CameraChange) -> Result<i32, Error> {
         let uuid_bytes = &uuid.as_bytes()[..];
         let tx = self.conn.transaction()?;
         let streams;
@@ -1650,8 +1765,8 @@ impl LockedDatabase {
                 (":short_name", &camera.short_name),
                 (":description", &camera.description),
                 (":onvif_host", &camera.onvif_host),
-                (":username", &camera.username),
-                (":password", &camera.password),
+                (":username", &camera.credentials.as_ref().map(|c| &c.username[..])),
+                (":password", &camera.credentials.as_ref().map(|c| &c.password[..])),
             ])?;
             camera_id = tx.last_insert_rowid() as i32;
             streams = StreamStateChanger::new(&tx, camera_id, None, &self.streams_by_id,
@@ -1665,8 +1780,7 @@ impl LockedDatabase {
             short_name: camera.short_name,
             description: camera.description,
             onvif_host: camera.onvif_host,
-            username: camera.username,
-            password: camera.password,
+            credentials: camera.credentials,
             streams,
         });
         self.cameras_by_uuid.insert(uuid, camera_id);
@@ -1699,8 +1813,8 @@ impl LockedDatabase {
                 (":short_name", &camera.short_name),
                 (":description", &camera.description),
                 (":onvif_host", &camera.onvif_host),
-                (":username", &camera.username),
-                (":password", &camera.password),
+                (":username", &camera.credentials.as_ref().map(|c| &c.username[..])),
+                (":password", &camera.credentials.as_ref().map(|c| &c.password[..])),
             ])?;
             if rows != 1 {
                 bail!("Camera {} missing from database", camera_id);
@@ -1710,8 +1824,7 @@ impl LockedDatabase {
         c.short_name = camera.short_name;
         c.description = camera.description;
         c.onvif_host = camera.onvif_host;
-        c.username = camera.username;
-        c.password = camera.password;
+        c.credentials = camera.credentials;
         c.streams = streams.apply(&mut self.streams_by_id);
         Ok(())
     }
@@ -1758,7 +1871,8 @@ impl LockedDatabase {
                 update stream
                 set
                   record = :record,
-                  retain_bytes = :retain
+                  retain_bytes = :retain,
+                  retain_weight = :weight
                 where
                   id = :id
             "#)?;
@@ -1767,9 +1881,14 @@ impl LockedDatabase {
                     bail!("can't set limit for stream {} to {}; must be >= 0",
                           c.stream_id, c.new_limit);
                 }
+                if c.new_weight <= 0 {
+                    bail!("can't set weight for stream {} to {}; must be > 0",
+                          c.stream_id, c.new_weight);
+                }
                 let rows = stmt.execute_named(&[
                     (":record", &c.new_record),
                     (":retain", &c.new_limit),
+                    (":weight", &c.new_weight),
                     (":id", &c.stream_id),
                 ])?;
                 if rows != 1 {
@@ -1782,16 +1901,109 @@ impl LockedDatabase {
             let s = self.streams_by_id.get_mut(&c.stream_id).expect("stream in db but not state");
             s.record = c.new_record;
             s.retain_bytes = c.new_limit;
+            s.retain_weight = c.new_weight;
         }
         Ok(())
     }
 
+    /// Sets the retention mode of a sample file directory.
+    ///
+    /// `Some(margin)` switches the directory to automatic mode with the given free space margin;
+    /// `None` returns it to manual mode, in which each stream's `retain_bytes` applies.
+    pub fn update_dir_retention(&mut self, dir_id: i32, free_space_margin_bytes: Option<i64>)
+                                -> Result<(), Error> {
+        if let Some(m) = free_space_margin_bytes {
+            if m < 0 {
+                bail!("can't set free space margin for dir {} to {}; must be >= 0", dir_id, m);
+            }
+        }
+        let d = self.sample_file_dirs_by_id.get_mut(&dir_id)
+                    .ok_or_else(|| format_err!("no such dir {}", dir_id))?;
+        let rows = self.conn.execute(r#"
+            update sample_file_dir set free_space_margin_bytes = ? where id = ?
+        "#, &[&free_space_margin_bytes as &dyn ToSql, &dir_id])?;
+        if rows != 1 {
+            bail!("no such dir {}", dir_id);
+        }
+        d.free_space_margin_bytes = free_space_margin_bytes;
+        Ok(())
+    }
+
+    /// Moves a stream to a different sample file directory.
+    ///
+    /// The caller must already have copied (and synced) every committed recording's sample file
+    /// into the new directory; see `writer::migrate_stream`. This atomically points the stream
+    /// at the new directory and marks the old copies as garbage, to be unlinked by the old
+    /// directory's syncer (or `writer::collect_garbage`) as usual.
+    pub fn move_stream(&mut self, stream_id: i32, new_dir_id: i32) -> Result<(), Error> {
+        let old_dir_id;
+        {
+            let s = self.streams_by_id.get(&stream_id)
+                        .ok_or_else(|| format_err!("no such stream {}", stream_id))?;
+            old_dir_id = s.sample_file_dir_id
+                          .ok_or_else(|| format_err!("stream {} has no directory", stream_id))?;
+            if old_dir_id == new_dir_id {
+                bail!("stream {} is already in dir {}", stream_id, new_dir_id);
+            }
+            if !s.uncommitted.is_empty() || !s.to_delete.is_empty() {
+                bail!("can't move stream {} with pending changes; stop recording and flush first",
+                      stream_id);
+            }
+        }
+        if !self.sample_file_dirs_by_id.contains_key(&new_dir_id) {
+            bail!("no such dir {}", new_dir_id);
+        }
+        let mut garbage = Vec::new();
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(r#"
+                update stream set sample_file_dir_id = :sample_file_dir_id where id = :id
+            "#)?;
+            let rows = stmt.execute_named(&[
+                (":sample_file_dir_id", &new_dir_id),
+                (":id", &stream_id),
+            ])?;
+            if rows != 1 {
+                bail!("no such stream {}", stream_id);
+            }
+            let mut stmt = tx.prepare_cached(r#"
+                insert into garbage (sample_file_dir_id, composite_id)
+                select
+                  :sample_file_dir_id,
+                  composite_id
+                from
+                  recording
+                where
+                  stream_id = :stream_id
+            "#)?;
+            stmt.execute_named(&[
+                (":sample_file_dir_id", &old_dir_id),
+                (":stream_id", &stream_id),
+            ])?;
+            raw::list_recordings_by_id(&tx, stream_id, 0 .. i32::max_value(), &mut |r| {
+                garbage.push(r.id);
+                Ok(())
+            })?;
+        }
+        tx.commit()?;
+        self.streams_by_id.get_mut(&stream_id).unwrap().sample_file_dir_id = Some(new_dir_id);
+        self.sample_file_dirs_by_id.get_mut(&old_dir_id).unwrap()
+            .garbage_needs_unlink.extend(garbage);
+        Ok(())
+    }
+
+    /// Gets the SHA-1 of the given committed recording's sample file, if known.
+    pub fn get_sample_file_sha1(&self, id: CompositeId) -> Result<Option<[u8; 20]>, Error> {
+        raw::get_sample_file_sha1(&self.conn, id)
+    }
+
     // ---- auth ----
 
     pub fn users_by_id(&self) -> &BTreeMap<i32, User> { self.auth.users_by_id() }
 
-    pub fn apply_user_change(&mut self, change: UserChange) -> Result<&User, Error> {
-        self.auth.apply(&self.conn, change)
+    fn apply_user_change<C: Clocks>(&mut self, clocks: &C, change: UserChange)
+                                    -> Result<&User, Error> {
+        self.auth.apply(&self.conn, clocks.realtime().sec, change)
     }
 
     pub fn delete_user(&mut self, id: i32) -> Result<(), Error> {
@@ -1802,10 +2014,64 @@ impl LockedDatabase {
         self.auth.get_user(username)
     }
 
+    pub fn authenticate_unix_uid(&self, uid: i32) -> Result<&User, Error> {
+        self.auth.authenticate_unix_uid(uid)
+    }
+
+    pub fn authenticate_trusted_username(&self, username: &str) -> Result<&User, Error> {
+        self.auth.authenticate_trusted_username(username)
+    }
+
     pub fn login_by_password(&mut self, req: auth::Request, username: &str, password: String,
-                             domain: Option<Vec<u8>>, session_flags: i32)
+                             code: Option<&str>, domain: Option<Vec<u8>>, session_flags: i32)
                              -> Result<(RawSessionId, &Session), Error> {
-        self.auth.login_by_password(&self.conn, req, username, password, domain, session_flags)
+        self.auth.login_by_password(&self.conn, req, username, password, code, domain,
+                                    session_flags)
+    }
+
+    pub fn authenticate_password(&mut self, req: auth::Request, username: &str,
+                                 password: String) -> Result<&User, Error> {
+        self.auth.authenticate_password(&self.conn, req, username, password)
+    }
+
+    /// Sets the number of consecutive failed password attempts after which a user's password is
+    /// locked until an administrator unlocks it (via `UserChange::unlock_password`) or changes it.
+    /// If `None`, passwords are never locked, although attempts are still throttled.
+    pub fn set_password_lockout_threshold(&mut self, threshold: Option<i64>) {
+        self.auth.set_password_lockout_threshold(threshold)
+    }
+
+    pub fn set_session_limits(&mut self, limits: auth::SessionLimits) {
+        self.auth.set_session_limits(limits)
+    }
+
+    /// Adds an entry to the audit log, returning its id.
+    pub fn add_audit_entry(&mut self, e: &audit::Entry) -> Result<i64, Error> {
+        let username = e.user_id.and_then(|id| self.auth.users_by_id().get(&id))
+                                .map(|u| u.username.as_str());
+        self.audit.add(&self.conn, e, username)
+    }
+
+    pub fn list_audit_entries(&self, q: &audit::Query) -> Result<Vec<audit::Entry>, Error> {
+        self.audit.list(&self.conn, q)
+    }
+
+    /// Returns the given user's valid sessions, least recently used first.
+    pub fn list_sessions(&mut self, user_id: i32, now_sec: Option<i64>)
+                         -> Result<Vec<(auth::SessionHash, &Session)>, Error> {
+        self.auth.list_sessions(&self.conn, user_id, now_sec)
+    }
+
+    /// Returns the given user's valid API tokens, oldest first.
+    pub fn list_tokens(&mut self, user_id: i32, now_sec: Option<i64>)
+                       -> Result<Vec<(auth::SessionHash, &Session)>, Error> {
+        self.auth.list_tokens(&self.conn, user_id, now_sec)
+    }
+
+    pub fn make_token(&mut self, creation: Request, uid: i32, name: String,
+                      permissions: schema::Permissions, expiration_sec: Option<i64>)
+                      -> Result<(RawSessionId, &Session), Error> {
+        self.auth.make_token(&self.conn, creation, uid, name, permissions, expiration_sec)
     }
 
     pub fn make_session(&mut self, creation: Request, uid: i32,
@@ -1819,6 +2085,11 @@ impl LockedDatabase {
         self.auth.authenticate_session(&self.conn, req, sid)
     }
 
+    pub fn authenticate_token(&mut self, req: auth::Request, hash: &auth::SessionHash)
+                              -> Result<(&auth::Session, &User), Error> {
+        self.auth.authenticate_token(&self.conn, req, hash)
+    }
+
     pub fn revoke_session(&mut self, reason: auth::RevocationReason, detail: Option<String>,
                           req: auth::Request, hash: &auth::SessionHash) -> Result<(), Error> {
         self.auth.revoke_session(&self.conn, reason, detail, req, hash)
@@ -1948,6 +2219,7 @@ impl<C: Clocks + Clone> Database<C> {
         } else { None };
         let auth = auth::State::init(&conn)?;
         let signal = signal::State::init(&conn)?;
+        let audit = audit::State::init(&conn)?;
         let db = Database {
             db: Some(Mutex::new(LockedDatabase {
                 conn,
@@ -1957,6 +2229,7 @@ impl<C: Clocks + Clone> Database<C> {
                 open_monotonic,
                 auth,
                 signal,
+                audit,
                 sample_file_dirs_by_id: BTreeMap::new(),
                 cameras_by_id: BTreeMap::new(),
                 cameras_by_uuid: BTreeMap::new(),
@@ -2027,6 +2300,11 @@ impl<'db, C: Clocks + Clone> DatabaseGuard<'db, C> {
     pub(crate) fn flush(&mut self, reason: &str) -> Result<(), Error> {
         self.db.flush(self.clocks, reason)
     }
+
+    /// Applies a change to a user. A password change revokes all of the user's sessions.
+    pub fn apply_user_change(&mut self, change: UserChange) -> Result<&User, Error> {
+        self.db.apply_user_change(self.clocks, change)
+    }
 }
 
 impl<'db, C: Clocks + Clone> ::std::ops::Deref for DatabaseGuard<'db, C> {
@@ -2065,8 +2343,9 @@ mod tests {
                 camera_id = row.id;
                 assert_eq!(uuid, row.uuid);
                 assert_eq!("test-camera", row.onvif_host);
-                assert_eq!("foo", row.username);
-                assert_eq!("bar", row.password);
+                let creds = row.credentials.as_ref().unwrap();
+                assert_eq!(("foo".to_owned(), "bar".to_owned()),
+                           creds.open(&testutil::credentials_key(), uuid).unwrap());
                 //assert_eq!("/main", row.main_rtsp_url);
                 //assert_eq!("/sub", row.sub_rtsp_url);
                 //assert_eq!(42, row.retain_bytes);
@@ -2215,20 +2494,20 @@ mod tests {
     fn test_version_too_old() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (4, 0, '');").unwrap();
+        c.execute_batch("delete from version; insert into version values (5, 0, '');").unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(e.to_string().starts_with(
-                "Database schema version 4 is too old (expected 5)"), "got: {:?}", e);
+                "Database schema version 5 is too old (expected 6)"), "got: {:?}", e);
     }
 
     #[test]
     fn test_version_too_new() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (6, 0, '');").unwrap();
+        c.execute_batch("delete from version; insert into version values (7, 0, '');").unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(e.to_string().starts_with(
-                "Database schema version 6 is too new (expected 5)"), "got: {:?}", e);
+                "Database schema version 7 is too new (expected 6)"), "got: {:?}", e);
     }
 
     /// Basic test of running some queries on a fresh database.
@@ -2249,13 +2528,14 @@ mod tests {
         let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
         let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
         let path = tmpdir.path().to_str().unwrap().to_owned();
-        let sample_file_dir_id = { db.lock() }.add_sample_file_dir(path).unwrap();
+        let sample_file_dir_id = { db.lock() }.add_sample_file_dir(path, None).unwrap();
+        let camera_uuid = Uuid::new_v4();
         let mut c = CameraChange {
             short_name: "testcam".to_owned(),
             description: "".to_owned(),
             onvif_host: "test-camera".to_owned(),
-            username: "foo".to_owned(),
-            password: "bar".to_owned(),
+            credentials: Some(Credentials::seal(&testutil::credentials_key(), camera_uuid, "foo",
+                                                "bar")),
             streams: [
                 StreamChange {
                     sample_file_dir_id: Some(sample_file_dir_id),
@@ -2271,7 +2551,7 @@ mod tests {
                 },
             ],
         };
-        let camera_id = db.lock().add_camera(c.clone()).unwrap();
+        let camera_id = db.lock().add_camera(camera_uuid, c.clone()).unwrap();
         let (main_stream_id, sub_stream_id);
         {
             let mut l = db.lock();
@@ -2284,6 +2564,7 @@ mod tests {
                 stream_id: main_stream_id,
                 new_record: true,
                 new_limit: 42,
+                new_weight: 1,
             }]).unwrap();
             {
                 let main = l.streams_by_id().get(&main_stream_id).unwrap();
@@ -2297,7 +2578,7 @@ mod tests {
             l.update_camera(camera_id, c).unwrap();
             assert_eq!(l.streams_by_id().get(&sub_stream_id).unwrap().flush_if_sec, 2);
         }
-        let camera_uuid = { db.lock().cameras_by_id().get(&camera_id).unwrap().uuid };
+        assert_eq!(db.lock().cameras_by_id().get(&camera_id).unwrap().uuid, camera_uuid);
         assert_no_recordings(&db, camera_uuid);
 
         // Closing and reopening the database should present the same contents.
@@ -33,17 +33,21 @@
 //! This includes opening files for serving, rotating away old files, and saving new files.
 
 use crate::coding;
+use crate::crypto;
 use crate::db::CompositeId;
 use crate::schema;
 use cstr::*;
 use failure::{Error, Fail, bail, format_err};
+use fnv::FnvHashMap;
 use log::warn;
-use protobuf::Message;
+use protobuf::{Message, prelude::MessageField};
 use nix::{NixPath, fcntl::{FlockArg, OFlag}, sys::stat::Mode};
 use nix::sys::statvfs::Statvfs;
+use parking_lot::Mutex;
 use std::ffi::{CStr, CString};
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::ops::Range;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Arc;
 
@@ -63,6 +67,98 @@ pub struct SampleFileDir {
     /// The open file descriptor for the directory. The worker uses it to create files and sync the
     /// directory. Other threads use it to open sample files for reading during video serving.
     pub(crate) fd: Fd,
+
+    /// The directory's encryption parameters, as stored in its metadata. These are preserved
+    /// across `write_meta` calls; the metadata supplied by the database lacks them.
+    encryption: Option<Encryption>,
+
+    /// The key and chunk size for reading and writing sample files. Absent if the directory is
+    /// unencrypted or was opened without its key.
+    crypto: Option<crypto::Params>,
+
+    /// Encrypted sample files which are being written, so that readers can find the plaintext
+    /// which hasn't yet been written in sealed chunks.
+    growing: Arc<GrowingFiles>,
+}
+
+type GrowingFiles = Mutex<FnvHashMap<CompositeId, Arc<Mutex<crypto::Growing>>>>;
+
+/// The `encryption` field of a directory's `DirMeta`.
+#[derive(Clone, Debug)]
+struct Encryption {
+    chunk_size: u32,
+    key_check: Vec<u8>,
+}
+
+/// A sample file opened for writing by `SampleFileDir::create_file`.
+///
+/// Writes to an encrypted sample file are buffered until a full chunk is available; the final
+/// chunk is written by `sync_all`, which must be called before the file is closed. Until the writer
+/// is dropped, `SampleFileDir::read_range` reads buffered data from it.
+pub struct SampleFileWriter(SampleFileWriterInner);
+
+enum SampleFileWriterInner {
+    Plain(fs::File),
+    Encrypted(crypto::Writer, CompositeId, Arc<GrowingFiles>),
+}
+
+impl Drop for SampleFileWriter {
+    fn drop(&mut self) {
+        if let SampleFileWriterInner::Encrypted(_, id, ref growing) = self.0 {
+            growing.lock().remove(&id);
+        }
+    }
+}
+
+impl SampleFileWriter {
+    /// As in `std::fs::File::sync_all`.
+    pub fn sync_all(&self) -> Result<(), io::Error> {
+        match self.0 {
+            SampleFileWriterInner::Plain(ref f) => f.sync_all(),
+            SampleFileWriterInner::Encrypted(ref w, _, _) => w.sync_all(),
+        }
+    }
+}
+
+impl Write for SampleFileWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        match self.0 {
+            SampleFileWriterInner::Plain(ref mut f) => f.write(buf),
+            SampleFileWriterInner::Encrypted(ref mut w, _, _) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> { Ok(()) }
+}
+
+/// A sample file opened for sequentially reading its plaintext by `SampleFileDir::open_reader`.
+pub struct SampleFileReader(SampleFileReaderInner);
+
+enum SampleFileReaderInner {
+    Plain(fs::File),
+    Encrypted(crypto::Reader),
+}
+
+impl Read for SampleFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self.0 {
+            SampleFileReaderInner::Plain(ref mut f) => f.read(buf),
+            SampleFileReaderInner::Encrypted(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+/// A sample file opened for reading ranges of its plaintext by `SampleFileDir::open_decryptor`.
+pub struct SampleFileDecryptor(crypto::Decryptor);
+
+impl SampleFileDecryptor {
+    pub fn plaintext_len(&self) -> u64 { self.0.plaintext_len() }
+
+    /// Returns the plaintext bytes per chunk. Reading chunk-aligned ranges avoids decrypting any
+    /// chunk more than once.
+    pub fn chunk_size(&self) -> u64 { self.0.chunk_size() }
+
+    pub fn read_range(&self, r: Range<u64>) -> Result<Vec<u8>, Error> { self.0.read_range(r) }
 }
 
 pub(crate) struct CompositeIdPath([u8; 17]);
@@ -188,10 +284,14 @@ impl SampleFileDir {
     ///
     /// `db_meta.in_progress_open` should be filled if the directory should be opened in read/write
     /// mode; absent in read-only mode.
-    pub fn open(path: &str, db_meta: &schema::DirMeta)
+    ///
+    /// `key_path` names the file holding the directory's encryption key. In read-only mode, it may
+    /// be absent for an encrypted directory when only its metadata and file lengths are needed;
+    /// reading sample files will then fail.
+    pub fn open(path: &str, db_meta: &schema::DirMeta, key_path: Option<&str>)
                 -> Result<Arc<SampleFileDir>, Error> {
         let read_write = db_meta.in_progress_open.is_some();
-        let s = SampleFileDir::open_self(path, false)?;
+        let mut s = SampleFileDir::open_self(path, false)?;
         s.fd.lock(if read_write {
                       FlockArg::LockExclusiveNonblock
                   } else {
@@ -204,6 +304,30 @@ impl SampleFileDir {
             bail!("metadata mismatch.\ndb: {:#?}\ndir: {:#?}\nserialized db: {:#?}",
                   db_meta, &dir_meta, &serialized);
         }
+        s.encryption = dir_meta.encryption.as_ref().map(|e| Encryption {
+            chunk_size: e.chunk_size,
+            key_check: e.key_check.clone(),
+        });
+        s.crypto = match (&s.encryption, key_path) {
+            (None, None) => None,
+            (Some(_), None) if read_write => bail!("dir {} is encrypted but has no key", path),
+            (Some(_), None) => None,
+            (None, Some(p)) => bail!("dir {} is unencrypted but has key {}", path, p),
+            (Some(e), Some(p)) => {
+                let key = crypto::Key::read(p)?;
+                if key.check() != e.key_check {
+                    bail!("key {} doesn't match the one used to encrypt dir {}", p, path);
+                }
+                if e.chunk_size == 0 {
+                    bail!("dir {} has invalid encryption chunk size 0", path);
+                }
+                Some(crypto::Params {
+                    key,
+                    chunk_size: e.chunk_size,
+                })
+            },
+        };
+        let s = Arc::new(s);
         if db_meta.in_progress_open.is_some() {
             s.write_meta(db_meta)?;
         }
@@ -229,9 +353,11 @@ impl SampleFileDir {
         true
     }
 
-    pub(crate) fn create(path: &str, db_meta: &schema::DirMeta)
+    /// Creates a new directory, encrypting its sample files with the key at `key_path` if
+    /// supplied.
+    pub(crate) fn create(path: &str, db_meta: &schema::DirMeta, key_path: Option<&str>)
                          -> Result<Arc<SampleFileDir>, Error> {
-        let s = SampleFileDir::open_self(path, true)?;
+        let mut s = SampleFileDir::open_self(path, true)?;
         s.fd.lock(FlockArg::LockExclusiveNonblock)?;
         let old_meta = read_meta(&s.fd)?;
 
@@ -243,6 +369,18 @@ impl SampleFileDir {
         if !s.is_empty()? {
             bail!("Can't create dir at path {} with existing files", path);
         }
+        if let Some(p) = key_path {
+            let key = crypto::Key::read(p)?;
+            s.encryption = Some(Encryption {
+                chunk_size: crypto::DEFAULT_CHUNK_SIZE,
+                key_check: key.check(),
+            });
+            s.crypto = Some(crypto::Params {
+                key,
+                chunk_size: crypto::DEFAULT_CHUNK_SIZE,
+            });
+        }
+        let s = Arc::new(s);
         s.write_meta(db_meta)?;
         Ok(s)
     }
@@ -266,28 +404,105 @@ impl SampleFileDir {
         Ok(true)
     }
 
-    fn open_self(path: &str, create: bool) -> Result<Arc<SampleFileDir>, Error> {
+    fn open_self(path: &str, create: bool) -> Result<SampleFileDir, Error> {
         let fd = Fd::open(path, create)
             .map_err(|e| format_err!("unable to open sample file dir {}: {}", path, e))?;
-        Ok(Arc::new(SampleFileDir {
+        Ok(SampleFileDir {
             fd,
-        }))
+            encryption: None,
+            crypto: None,
+            growing: Arc::new(Mutex::new(FnvHashMap::default())),
+        })
     }
 
-    /// Opens the given sample file for reading.
+    /// Returns true if this directory's sample files are encrypted.
+    pub fn is_encrypted(&self) -> bool { self.encryption.is_some() }
+
+    /// Opens the given sample file for reading its raw (possibly encrypted) contents.
     pub fn open_file(&self, composite_id: CompositeId) -> Result<fs::File, nix::Error> {
         let p = CompositeIdPath::from(composite_id);
         crate::fs::openat(self.fd.0, &p, OFlag::O_RDONLY, Mode::empty())
     }
 
-    pub fn create_file(&self, composite_id: CompositeId) -> Result<fs::File, nix::Error> {
+    fn crypto(&self) -> Result<&crypto::Params, Error> {
+        self.crypto.as_ref()
+            .ok_or_else(|| format_err!("sample file dir was opened without its key"))
+    }
+
+    /// Opens the given sample file for sequentially reading its plaintext.
+    pub fn open_reader(&self, composite_id: CompositeId) -> Result<SampleFileReader, Error> {
+        let f = self.open_file(composite_id)?;
+        Ok(SampleFileReader(match self.encryption {
+            None => SampleFileReaderInner::Plain(f),
+            Some(_) => SampleFileReaderInner::Encrypted(
+                crypto::Reader::new(self.crypto()?.clone(), f, composite_id)?),
+        }))
+    }
+
+    /// Prepares to read ranges of the plaintext of `f`, a sample file opened with `open_file`.
+    ///
+    /// This is only necessary for encrypted directories; for others, it's more efficient to
+    /// `mmap` the file directly. The file may still be being written; reads see its length as
+    /// of this call.
+    pub fn open_decryptor(&self, f: fs::File, composite_id: CompositeId)
+                          -> Result<SampleFileDecryptor, Error> {
+        let params = self.crypto()?.clone();
+        let growing = self.growing.lock().get(&composite_id).cloned();
+        Ok(SampleFileDecryptor(match growing {
+            None => crypto::Decryptor::new(params, f, composite_id)?,
+            Some(g) => crypto::Decryptor::growing(params, f, composite_id, &g.lock())?,
+        }))
+    }
+
+    /// Reads the given range of the plaintext of `f`, as with `open_decryptor`.
+    pub fn read_range(&self, f: &fs::File, composite_id: CompositeId, r: Range<u64>)
+                      -> Result<Vec<u8>, Error> {
+        self.open_decryptor(f.try_clone()?, composite_id)?.read_range(r)
+    }
+
+    /// Returns the plaintext length of a sample file given its length on disk, or `None` if the
+    /// on-disk length is impossible.
+    pub fn plaintext_len(&self, len: u64) -> Option<u64> {
+        match self.encryption {
+            None => Some(len),
+            Some(ref e) => crypto::plaintext_len(len, e.chunk_size),
+        }
+    }
+
+    /// Creates the given sample file for writing.
+    ///
+    /// Fails with `ENOKEY` if the directory is encrypted but was opened without its key.
+    pub fn create_file(&self, composite_id: CompositeId)
+                       -> Result<SampleFileWriter, nix::Error> {
+        if self.encryption.is_some() && self.crypto.is_none() {
+            return Err(nix::Error::Sys(nix::errno::Errno::ENOKEY));
+        }
         let p = CompositeIdPath::from(composite_id);
-        crate::fs::openat(self.fd.0, &p, OFlag::O_WRONLY | OFlag::O_EXCL | OFlag::O_CREAT,
-                          Mode::S_IRUSR | Mode::S_IWUSR)
+        let f = crate::fs::openat(self.fd.0, &p,
+                                  OFlag::O_WRONLY | OFlag::O_EXCL | OFlag::O_CREAT,
+                                  Mode::S_IRUSR | Mode::S_IWUSR)?;
+        Ok(SampleFileWriter(match self.crypto {
+            None => SampleFileWriterInner::Plain(f),
+            Some(ref c) => {
+                let w = crypto::Writer::new(c.clone(), f, composite_id);
+                self.growing.lock().insert(composite_id, w.growing());
+                SampleFileWriterInner::Encrypted(w, composite_id, self.growing.clone())
+            },
+        }))
     }
 
+    /// Writes the directory's metadata, adding its encryption parameters to `meta`.
     pub(crate) fn write_meta(&self, meta: &schema::DirMeta) -> Result<(), Error> {
-        write_meta(self.fd.0, meta)
+        match self.encryption {
+            None => write_meta(self.fd.0, meta),
+            Some(ref e) => {
+                let mut meta = meta.clone();
+                let m = meta.encryption.mut_message();
+                m.chunk_size = e.chunk_size;
+                m.key_check = e.key_check.clone();
+                write_meta(self.fd.0, &meta)
+            },
+        }
     }
 
     pub fn statfs(&self) -> Result<Statvfs, nix::Error> { self.fd.statfs() }
@@ -338,6 +553,47 @@ mod tests {
         parse_id(b"000000010000000x").unwrap_err();
     }
 
+    /// Reads a sample file in an encrypted directory while it's still being written, as when
+    /// serving an in-progress recording.
+    #[test]
+    fn read_growing_encrypted() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let key_path = tmpdir.path().join("key");
+        fs::write(&key_path, &[1u8; 32][..]).unwrap();
+        let path = tmpdir.path().join("dir");
+        let dir = SampleFileDir::create(path.to_str().unwrap(), &schema::DirMeta::new(),
+                                        Some(key_path.to_str().unwrap())).unwrap();
+        let id = CompositeId::new(1, 1);
+        let data: Vec<u8> = (0..150_000u32).map(|i| i as u8).collect();
+        let mut w = dir.create_file(id).unwrap();
+        let f = dir.open_file(id).unwrap();
+        let mut written = 0;
+
+        // These cross chunk boundaries, so some of the data is in sealed chunks on disk, some in
+        // chunks which are sealed but not yet written, and some not yet sealed.
+        for &end in &[10, 70_000, 140_000, 150_000] {
+            w.write_all(&data[written..end]).unwrap();
+            written = end;
+            assert_eq!(&dir.read_range(&f, id, 0..end as u64).unwrap()[..], &data[..end]);
+            assert_eq!(&dir.read_range(&f, id, 5..(end - 5) as u64).unwrap()[..],
+                       &data[5..end - 5]);
+        }
+        dir.read_range(&f, id, 0..150_001).unwrap_err();
+        w.sync_all().unwrap();
+        assert_eq!(&dir.read_range(&f, id, 65_000..150_000).unwrap()[..], &data[65_000..]);
+
+        // Once the writer is dropped, the file is read as complete.
+        drop(w);
+        assert_eq!(&dir.read_range(&f, id, 65_000..150_000).unwrap()[..], &data[65_000..]);
+        let d = dir.open_decryptor(f, id).unwrap();
+        assert_eq!(d.plaintext_len(), 150_000);
+        let c = d.chunk_size() as usize;
+        assert_eq!(&d.read_range(c as u64..2 * c as u64).unwrap()[..], &data[c..2 * c]);
+        let mut buf = Vec::new();
+        dir.open_reader(id).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
     /// Ensures that a DirMeta with all fields filled fits within the maximum size.
     #[test]
     fn max_len_meta() {
@@ -349,6 +605,8 @@ mod tests {
         meta.last_complete_open.mut_message().id = u32::max_value();
         meta.in_progress_open.mut_message().uuid.extend_from_slice(fake_uuid);
         meta.in_progress_open.mut_message().uuid.extend_from_slice(fake_uuid);
+        meta.encryption.mut_message().chunk_size = u32::max_value();
+        meta.encryption.mut_message().key_check.extend_from_slice(fake_uuid);
         let data = meta.write_length_delimited_to_bytes().expect("proto3->vec is infallible");
         assert!(data.len() <= FIXED_DIR_META_LEN, "{} vs {}", data.len(), FIXED_DIR_META_LEN);
     }
@@ -30,10 +30,12 @@
 
 #![cfg_attr(all(feature="nightly", test), feature(test))]
 
+pub mod audit;
 pub mod auth;
 pub mod check;
 mod coding;
 mod compare;
+pub mod crypto;
 pub mod db;
 pub mod dir;
 mod fs;
@@ -41,6 +43,7 @@ mod raw;
 pub mod recording;
 mod schema;
 pub mod signal;
+pub mod totp;
 pub mod upgrade;
 pub mod writer;
 
@@ -287,12 +287,16 @@ pub(crate) fn delete_recordings(tx: &rusqlite::Transaction, sample_file_dir_id:
 
 /// Marks the given sample files as deleted. This shouldn't be called until the files have
 /// been `unlink()`ed and the parent directory `fsync()`ed.
-pub(crate) fn mark_sample_files_deleted(tx: &rusqlite::Transaction, ids: &[CompositeId])
-                                        -> Result<(), Error> {
+///
+/// The directory is significant: after `LockedDatabase::move_stream`, the same composite id can
+/// be garbage in the old directory while still a live recording in the new one.
+pub(crate) fn mark_sample_files_deleted(tx: &rusqlite::Transaction, sample_file_dir_id: i32,
+                                        ids: &[CompositeId]) -> Result<(), Error> {
     if ids.is_empty() { return Ok(()); }
-    let mut stmt = tx.prepare_cached("delete from garbage where composite_id = ?")?;
+    let mut stmt = tx.prepare_cached(
+        "delete from garbage where sample_file_dir_id = ? and composite_id = ?")?;
     for &id in ids {
-        let changes = stmt.execute(&[&id.0])?;
+        let changes = stmt.execute(&[&sample_file_dir_id as &dyn ToSql, &id.0])?;
         if changes != 1 {
             // panic rather than return error. Errors get retried indefinitely, but there's no
             // recovery from this condition.
@@ -344,6 +348,29 @@ pub(crate) fn get_range(conn: &rusqlite::Connection, stream_id: i32)
     Ok(Some(min_start .. max_end))
 }
 
+/// Gets the SHA-1 of the given recording's sample file, as recorded when it was written.
+pub(crate) fn get_sample_file_sha1(conn: &rusqlite::Connection, id: CompositeId)
+                                   -> Result<Option<[u8; 20]>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select sample_file_sha1 from recording_integrity where composite_id = ?
+    "#)?;
+    let mut rows = stmt.query(&[&id.0])?;
+    let row = match rows.next()? {
+        None => return Ok(None),
+        Some(r) => r,
+    };
+    let sha1: Option<Vec<u8>> = row.get(0)?;
+    Ok(match sha1 {
+        None => None,
+        Some(ref s) if s.len() == 20 => {
+            let mut buf = [0u8; 20];
+            buf.copy_from_slice(&s[..]);
+            Some(buf)
+        },
+        Some(s) => bail!("recording {} has {}-byte sample_file_sha1; expected 20", id, s.len()),
+    })
+}
+
 /// Lists all garbage ids for the given sample file directory.
 pub(crate) fn list_garbage(conn: &rusqlite::Connection, dir_id: i32)
                            -> Result<FnvHashSet<CompositeId>, Error> {
@@ -38,7 +38,11 @@ create table meta (
   -- The maximum number of entries in the signal_state table. If an update
   -- causes this to be exceeded, older times will be garbage collected to stay
   -- within the limit.
-  max_signal_changes integer check (max_signal_changes >= 0)
+  max_signal_changes integer check (max_signal_changes >= 0),
+
+  -- The maximum number of entries in the audit table. Each addition removes
+  -- the oldest entries beyond this limit. If null, there's no limit.
+  max_audit_entries integer default 100000 check (max_audit_entries >= 0)
 );
 
 -- This table tracks the schema version.
@@ -86,7 +90,18 @@ create table sample_file_dir (
 
   -- The last (read/write) open of this directory which fully completed.
   -- See schema.proto:DirMeta for a more complete description.
-  last_complete_open_id integer references open (id)
+  last_complete_open_id integer references open (id),
+
+  -- If non-null, the directory uses automatic retention: rather than honoring
+  -- each stream's retain_bytes, the syncer keeps at least this many bytes
+  -- free on the filesystem and divides the rest among the directory's streams
+  -- in proportion to their retain_weight.
+  free_space_margin_bytes integer check (free_space_margin_bytes >= 0),
+
+  -- If non-null, the path to a file holding the 32-byte key with which the
+  -- directory's sample files are encrypted. The key itself is deliberately
+  -- not stored in the database. See schema.proto:DirMeta.Encryption.
+  key_path text
 );
 
 create table camera (
@@ -105,11 +120,13 @@ create table camera (
   -- "http://192.168.1.110:85/device_service".
   onvif_host text,
 
-  -- The username to use when accessing the camera.
-  -- If empty, no username or password will be supplied.
+  -- The username to use when accessing the camera, sealed with the
+  -- credentials key (see db/crypto.rs). Despite the declared type, this is a
+  -- blob. If null, no username or password will be supplied.
   username text,
 
-  -- The password to use when accessing the camera.
+  -- The password to use when accessing the camera, sealed as above. Null iff
+  -- username is null.
   password text
 );
 
@@ -150,6 +167,11 @@ create table stream (
   -- not decrease if that recording is deleted.
   next_recording_id integer not null check (next_recording_id >= 0),
 
+  -- This stream's relative share of its sample file directory's capacity,
+  -- when the directory uses automatic retention (see
+  -- sample_file_dir.free_space_margin_bytes).
+  retain_weight integer not null default 1 check (retain_weight > 0),
+
   unique (camera_id, type)
 );
 
@@ -321,6 +343,10 @@ create table user (
 
   -- Bitwise mask of flags:
   -- 1: disabled. If set, no method of authentication for this user will succeed.
+  -- 2: password locked. Set after too many consecutive password failures (see
+  --    the run subcommand's --password-lockout-threshold); password
+  --    authentication fails until it's cleared by changing or unlocking the
+  --    password.
   flags integer not null,
 
   -- If set, a hash for password authentication, as generated by `libpasta::hash_password`.
@@ -329,8 +355,9 @@ create table user (
   -- A counter which increments with every password reset or clear.
   password_id integer not null default 0,
 
-  -- Updated lazily on database flush; reset when password_id is incremented.
-  -- This could be used to automatically disable the password on hitting a threshold.
+  -- The number of consecutive password failures. Updated lazily on database
+  -- flush; reset on successful password authentication, when password_id is
+  -- incremented, or when the password is unlocked.
   password_failure_count integer not null default 0,
 
   -- If set, a Unix UID that is accepted for authentication when using HTTP over
@@ -341,7 +368,17 @@ create table user (
 
   -- Permissions available for newly created tokens or when authenticating via
   -- unix_uid above. A serialized "Permissions" protobuf.
-  permissions blob not null default X''
+  permissions blob not null default X'',
+
+  -- If set, the 20-byte secret for RFC 6238 time-based one-time passwords
+  -- (see db/totp.rs). Password logins then additionally require a current code
+  -- or one of the recovery codes below.
+  totp_secret blob,
+
+  -- The concatenated 32-byte unsalted Blake2b-256 hashes of the unused
+  -- one-time recovery codes, which may substitute for a TOTP code. Each is
+  -- removed as soon as it's used.
+  totp_recovery_hashes blob
 );
 
 -- A single session, whether for browser or robot use.
@@ -361,12 +398,15 @@ create table user_session (
   -- and CSRF tokens.
   seed blob not null,
 
-  -- A bitwise mask of flags, currently all properties of the HTTP cookie
-  -- used to hold the session:
+  -- A bitwise mask of flags, mostly properties of the HTTP cookie used to
+  -- hold the session:
   -- 1: HttpOnly
   -- 2: Secure
   -- 4: SameSite=Lax
   -- 8: SameSite=Strict - 4 must also be set.
+  -- 16: an API token, presented in an "Authorization: Bearer" header rather
+  --     than a cookie. Tokens are named via description and have no CSRF
+  --     protection, as browsers never send them automatically.
   flags integer not null,
 
   -- The domain of the HTTP cookie used to store this session. The outbound
@@ -389,13 +429,13 @@ create table user_session (
 
   -- A value indicating the reason for revocation, with optional additional
   -- text detail. Enumeration values:
-  -- 0: logout link clicked (i.e. from within the session itself)
+  -- 1: logout link clicked (i.e. from within the session itself)
+  -- 2: user revoked (while authenticated in another way)
+  -- 3: password change invalidated all sessions created with that password
+  -- 4: expired (due to fixed total time or time inactive)
+  -- 5: evicted (due to too many sessions)
   --
   -- This might be extended for a variety of other reasons:
-  -- x: user revoked (while authenticated in another way)
-  -- x: password change invalidated all sessions created with that password
-  -- x: expired (due to fixed total time or time inactive)
-  -- x: evicted (due to too many sessions)
   -- x: suspicious activity
   revocation_reason integer,
   revocation_reason_detail text,
@@ -407,7 +447,11 @@ create table user_session (
   use_count not null default 0,
 
   -- Permissions associated with this token; a serialized "Permissions" protobuf.
-  permissions blob not null default X''
+  permissions blob not null default X'',
+
+  -- The time after which this session may not be used, currently only set
+  -- for API tokens.
+  expiration_time_sec integer          -- sec since epoch
 ) without rowid;
 
 create index user_session_uid on user_session (user_id);
@@ -493,5 +537,45 @@ create table signal_change (
   changes blob not null
 );
 
+-- Audit log of video access and configuration changes.
+create table audit (
+  id integer primary key,
+
+  -- Information about the request.
+  time_sec integer not null,           -- sec since epoch
+  user_agent text,                     -- User-Agent header from inbound HTTP request.
+  peer_addr blob,                      -- IPv4 or IPv6 address, or null for Unix socket/no peer.
+
+  -- The user and session (or API token) behind the request, if any. These
+  -- aren't foreign keys; entries outlive deleted users and sessions. The
+  -- username is as of the time of the entry.
+  user_id integer,
+  username text,
+  session_id_hash blob,
+
+  -- What was done: the endpoint for video access, such as "view.mp4", or a
+  -- short description of a configuration change, such as "add user".
+  action text not null,
+
+  -- The camera and stream ("main" or "sub") accessed, if any. Cameras are
+  -- recorded by uuid, which outlives their rows.
+  camera_uuid blob check (length(camera_uuid) = 16),
+  stream_type text,
+
+  -- The time range of the video accessed, in 90 kHz units since
+  -- 1970-01-01 00:00:00Z excluding leap seconds.
+  start_90k integer,
+  end_90k integer,
+
+  -- The number of bytes of video sent. This may be less than the whole file
+  -- for range requests or abandoned transfers.
+  bytes integer,
+
+  -- Any further detail, such as the name of the user added.
+  detail text
+);
+
+create index audit_time_sec on audit (time_sec);
+
 insert into version (id, unix_time,                           notes)
-             values (5,  cast(strftime('%s', 'now') as int), 'db creation');
+             values (6,  cast(strftime('%s', 'now') as int), 'db creation');
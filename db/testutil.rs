@@ -29,6 +29,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use base::clock::Clocks;
+use crate::crypto;
 use crate::db;
 use crate::dir;
 use fnv::FnvHashMap;
@@ -56,6 +57,9 @@ pub const TEST_VIDEO_SAMPLE_ENTRY_DATA: &[u8] =
     \x63\x43\x01\x4D\x00\x2A\xFF\xE1\x00\x10\x67\x4D\x00\x2A\x95\xA8\x1E\x00\x89\xF9\x66\xE0\x20\
     \x20\x20\x40\x01\x00\x04\x68\xEE\x3C\x80";
 
+/// Returns the key with which the test camera's credentials are sealed.
+pub fn credentials_key() -> crypto::Key { crypto::Key::from([42u8; crypto::KEY_LEN]) }
+
 /// Performs global initialization for tests.
 ///    * set up logging. (Note the output can be confusing unless `RUST_TEST_THREADS=1` is set in
 ///      the program's environment prior to running.)
@@ -77,7 +81,7 @@ pub fn init() {
 pub struct TestDb<C: Clocks + Clone> {
     pub db: Arc<db::Database<C>>,
     pub dirs_by_stream_id: Arc<FnvHashMap<i32, Arc<dir::SampleFileDir>>>,
-    pub syncer_channel: writer::SyncerChannel<::std::fs::File>,
+    pub syncer_channel: writer::SyncerChannel<dir::SampleFileWriter>,
     pub syncer_join: thread::JoinHandle<()>,
     pub tmpdir: TempDir,
     pub test_camera_uuid: Uuid,
@@ -95,18 +99,19 @@ impl<C: Clocks + Clone> TestDb<C> {
         let mut conn = rusqlite::Connection::open_in_memory().unwrap();
         db::init(&mut conn).unwrap();
         let db = Arc::new(db::Database::new(clocks, conn, true).unwrap());
-        let (test_camera_uuid, sample_file_dir_id);
+        let test_camera_uuid = Uuid::new_v4();
+        let sample_file_dir_id;
         let path = tmpdir.path().to_str().unwrap().to_owned();
         let dir;
         {
             let mut l = db.lock();
-            sample_file_dir_id = l.add_sample_file_dir(path.to_owned()).unwrap();
-            assert_eq!(TEST_CAMERA_ID, l.add_camera(db::CameraChange {
+            sample_file_dir_id = l.add_sample_file_dir(path.to_owned(), None).unwrap();
+            assert_eq!(TEST_CAMERA_ID, l.add_camera(test_camera_uuid, db::CameraChange {
                 short_name: "test camera".to_owned(),
                 description: "".to_owned(),
                 onvif_host: "test-camera".to_owned(),
-                username: "foo".to_owned(),
-                password: "bar".to_owned(),
+                credentials: Some(db::Credentials::seal(&credentials_key(), test_camera_uuid,
+                                                        "foo", "bar")),
                 streams: [
                     db::StreamChange {
                         sample_file_dir_id: Some(sample_file_dir_id),
@@ -117,11 +122,11 @@ impl<C: Clocks + Clone> TestDb<C> {
                     Default::default(),
                 ],
             }).unwrap());
-            test_camera_uuid = l.cameras_by_id().get(&TEST_CAMERA_ID).unwrap().uuid;
             l.update_retention(&[db::RetentionChange {
                 stream_id: TEST_STREAM_ID,
                 new_record: true,
                 new_limit: 1048576,
+                new_weight: 1,
             }]).unwrap();
             dir = l.sample_file_dirs_by_id().get(&sample_file_dir_id).unwrap().get().unwrap();
         }
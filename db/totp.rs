@@ -0,0 +1,170 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Time-based one-time passwords, as in RFC 6238, for two-factor login.
+//!
+//! These use the parameters authenticator apps assume by default: HMAC-SHA1, 30-second time steps,
+//! and six-digit codes. A user enrolls by loading the secret into an app, typically via the
+//! `otpauth://` URI returned by `uri` (rendered as a QR code or typed in by hand).
+
+use failure::Error;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+/// The length of a generated secret in bytes. RFC 4226 recommends 160 bits.
+pub const SECRET_LEN: usize = 20;
+
+const STEP_SEC: i64 = 30;
+const DIGITS: usize = 6;
+
+/// The number of time steps on either side of the current one in which a code is accepted, to
+/// allow for clock skew and for the time spent typing the code.
+const SKEW_STEPS: i64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Returns a new random secret.
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    openssl::rand::rand_bytes(&mut secret).unwrap();
+    secret
+}
+
+/// Returns the code for the given time step, as in RFC 4226 section 5.3.
+fn code(secret: &[u8], step: i64) -> Result<u32, Error> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(&(step as u64).to_be_bytes())?;
+    let h = signer.sign_to_vec()?;
+    let off = (h[h.len() - 1] & 0xf) as usize;
+    let bin = u32::from_be_bytes([h[off] & 0x7f, h[off + 1], h[off + 2], h[off + 3]]);
+    Ok(bin % 10u32.pow(DIGITS as u32))
+}
+
+/// Checks `input` against the codes for the time steps around `now_sec`, returning the matching
+/// step if any. Steps up to and including `last_step`, the most recently accepted, are skipped so
+/// that an observed code can't be replayed.
+pub fn verify(secret: &[u8], input: &str, now_sec: i64, last_step: Option<i64>)
+              -> Result<Option<i64>, Error> {
+    let input = input.trim();
+    if input.len() != DIGITS || !input.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let want: u32 = input.parse()?;
+    let cur = now_sec.div_euclid(STEP_SEC);
+    for step in cur - SKEW_STEPS ..= cur + SKEW_STEPS {
+        if last_step.map(|l| step <= l).unwrap_or(false) {
+            continue;
+        }
+        if code(secret, step)? == want {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+/// Encodes `data` as unpadded RFC 4648 base32, the form authenticator apps expect for secrets.
+pub fn base32(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8 + 4) / 5);
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for &b in data {
+        buf = (buf << 8) | u32::from(b);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buf >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buf << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A' ..= b'Z' | b'a' ..= b'z' | b'0' ..= b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            },
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Returns a provisioning URI in the `otpauth://` format understood by authenticator apps.
+pub fn uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    let issuer = percent_encode(issuer);
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}",
+            issuer, percent_encode(account), base32(secret), issuer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 secret from RFC 6238 appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc_6238_vectors() {
+        // The RFC's vectors are eight digits; these are their last six.
+        for &(time, want) in &[(59, 287082), (1111111109, 81804), (1234567890, 5924),
+                               (2000000000, 279037)] {
+            assert_eq!(code(RFC_SECRET, time / STEP_SEC).unwrap(), want, "time={}", time);
+        }
+    }
+
+    #[test]
+    fn verify_window() {
+        assert_eq!(verify(RFC_SECRET, "287082", 59, None).unwrap(), Some(1));
+        assert_eq!(verify(RFC_SECRET, " 287082 ", 80, None).unwrap(), Some(1));  // next step.
+        assert_eq!(verify(RFC_SECRET, "287082", 130, None).unwrap(), None);  // too late.
+        assert_eq!(verify(RFC_SECRET, "287082", 59, Some(1)).unwrap(), None);  // replayed.
+        assert_eq!(verify(RFC_SECRET, "28708", 59, None).unwrap(), None);
+        assert_eq!(verify(RFC_SECRET, "28708x", 59, None).unwrap(), None);
+    }
+
+    #[test]
+    fn provisioning() {
+        assert_eq!(base32(b""), "");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(uri(RFC_SECRET, "Moonfire NVR", "slamb@example.com"),
+                   "otpauth://totp/Moonfire%20NVR:slamb%40example.com\
+                    ?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Moonfire%20NVR");
+    }
+}
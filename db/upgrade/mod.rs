@@ -32,6 +32,7 @@
 ///
 /// See `guide/schema.md` for more information.
 
+use crate::crypto;
 use crate::db;
 use failure::{Error, bail};
 use log::info;
@@ -46,6 +47,7 @@ mod v1_to_v2;
 mod v2_to_v3;
 mod v3_to_v4;
 mod v4_to_v5;
+mod v5_to_v6;
 
 const UPGRADE_NOTES: &'static str =
     concat!("upgraded using moonfire-db ", env!("CARGO_PKG_VERSION"));
@@ -55,6 +57,9 @@ pub struct Args<'a> {
     pub flag_sample_file_dir: Option<&'a str>,
     pub flag_preset_journal: &'a str,
     pub flag_no_vacuum: bool,
+
+    /// The key with which to encrypt camera credentials when upgrading to version 6.
+    pub credentials_key: Option<&'a crypto::Key>,
 }
 
 fn set_journal_mode(conn: &rusqlite::Connection, requested: &str) -> Result<(), Error> {
@@ -72,6 +77,7 @@ fn upgrade(args: &Args, target_ver: i32, conn: &mut rusqlite::Connection) -> Res
         v2_to_v3::run,
         v3_to_v4::run,
         v4_to_v5::run,
+        v5_to_v6::run,
     ];
 
     {
@@ -184,6 +190,7 @@ mod tests {
         testutil::init();
         let tmpdir = tempdir::TempDir::new("moonfire-nvr-test")?;
         let path = tmpdir.path().to_str().ok_or_else(|| format_err!("invalid UTF-8"))?.to_owned();
+        let key = testutil::credentials_key();
         let mut upgraded = new_conn()?;
         upgraded.execute_batch(include_str!("v0.sql"))?;
         upgraded.execute_batch(r#"
@@ -216,11 +223,13 @@ mod tests {
                                   (2, None),  // transitional; don't compare schemas.
                                   (3, Some(include_str!("v3.sql"))),
                                   (4, None),  // transitional; don't compare schemas.
-                                  (5, Some(include_str!("../schema.sql")))] {
+                                  (5, Some(include_str!("v5.sql"))),
+                                  (6, Some(include_str!("../schema.sql")))] {
             upgrade(&Args {
                 flag_sample_file_dir: Some(&path),
                 flag_preset_journal: "delete",
                 flag_no_vacuum: false,
+                credentials_key: Some(&key),
             }, *ver, &mut upgraded).context(format!("upgrading to version {}", ver))?;
             if let Some(f) = fresh_sql {
                 compare(&upgraded, *ver, f)?;
@@ -241,6 +250,15 @@ mod tests {
         // Check that garbage files get cleaned up.
         assert!(!garbage.exists());
 
+        // Check that camera credentials get encrypted.
+        let c = upgraded.query_row(
+            "select username, password from camera", params![],
+            |r| Ok(db::Credentials { username: r.get(0)?, password: r.get(1)? }))?;
+        assert_eq!(c.open(&key, Uuid::nil())?, ("user".to_owned(), "pass".to_owned()));
+
+        // ...bound to that camera.
+        c.open(&key, Uuid::new_v4()).unwrap_err();
+
         Ok(())
     }
 }
@@ -0,0 +1,101 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Upgrades a version 5 schema to a version 6 schema.
+
+use crate::db;
+use failure::{Error, bail};
+use rusqlite::params;
+
+pub fn run(args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(r#"
+        alter table sample_file_dir add column free_space_margin_bytes integer
+            check (free_space_margin_bytes >= 0);
+        alter table sample_file_dir add column key_path text;
+        alter table stream add column retain_weight integer not null default 1
+            check (retain_weight > 0);
+        alter table user_session add column expiration_time_sec integer;
+        alter table user add column totp_secret blob;
+        alter table user add column totp_recovery_hashes blob;
+        alter table meta add column max_audit_entries integer default 100000
+            check (max_audit_entries >= 0);
+        create table audit (
+          id integer primary key,
+          time_sec integer not null,
+          user_agent text,
+          peer_addr blob,
+          user_id integer,
+          username text,
+          session_id_hash blob,
+          action text not null,
+          camera_uuid blob check (length(camera_uuid) = 16),
+          stream_type text,
+          start_90k integer,
+          end_90k integer,
+          bytes integer,
+          detail text
+        );
+        create index audit_time_sec on audit (time_sec);
+    "#)?;
+    encrypt_credentials(args, tx)
+}
+
+/// Replaces each camera's plaintext `username` and `password` with sealed blobs, or with nulls
+/// if the camera has no username.
+fn encrypt_credentials(args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let mut cameras = Vec::new();
+    {
+        let mut stmt = tx.prepare("select id, uuid, username, password from camera")?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id: i32 = row.get(0)?;
+            let uuid: db::FromSqlUuid = row.get(1)?;
+            let username: Option<String> = row.get(2)?;
+            let password: Option<String> = row.get(3)?;
+            cameras.push((id, uuid.0, username.unwrap_or_default(),
+                          password.unwrap_or_default()));
+        }
+    }
+    let mut stmt = tx.prepare("update camera set username = ?, password = ? where id = ?")?;
+    for (id, uuid, username, password) in cameras {
+        if username.is_empty() {
+            stmt.execute(params![None::<Vec<u8>>, None::<Vec<u8>>, id])?;
+            continue;
+        }
+        let key = match args.credentials_key {
+            Some(k) => k,
+            None => bail!("camera {} has credentials which must be encrypted; supply a \
+                           credentials key as described in guide/schema.md", id),
+        };
+        let c = db::Credentials::seal(key, uuid, &username, &password);
+        stmt.execute(params![c.username, c.password, id])?;
+    }
+    Ok(())
+}
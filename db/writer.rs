@@ -39,7 +39,7 @@ use crate::recording;
 use failure::{Error, bail, format_err};
 use fnv::FnvHashMap;
 use parking_lot::Mutex;
-use log::{debug, trace, warn};
+use log::{debug, info, trace, warn};
 use openssl::hash;
 use std::cmp::Ordering;
 use std::cmp;
@@ -57,6 +57,9 @@ pub trait DirWriter : 'static + Send {
     fn create_file(&self, id: CompositeId) -> Result<Self::File, nix::Error>;
     fn sync(&self) -> Result<(), nix::Error>;
     fn unlink_file(&self, id: CompositeId) -> Result<(), nix::Error>;
+
+    /// Returns the bytes available to unprivileged users on the directory's filesystem.
+    fn available_bytes(&self) -> Result<i64, nix::Error>;
 }
 
 pub trait FileWriter : 'static {
@@ -68,7 +71,7 @@ pub trait FileWriter : 'static {
 }
 
 impl DirWriter for Arc<dir::SampleFileDir> {
-    type File = ::std::fs::File;
+    type File = dir::SampleFileWriter;
 
     fn create_file(&self, id: CompositeId) -> Result<Self::File, nix::Error> {
         dir::SampleFileDir::create_file(self, id)
@@ -77,17 +80,85 @@ impl DirWriter for Arc<dir::SampleFileDir> {
     fn unlink_file(&self, id: CompositeId) -> Result<(), nix::Error> {
         dir::SampleFileDir::unlink_file(self, id)
     }
+    fn available_bytes(&self) -> Result<i64, nix::Error> {
+        let stat = dir::SampleFileDir::statfs(self)?;
+        Ok(stat.block_size() as i64 * stat.blocks_available() as i64)
+    }
 }
 
-impl FileWriter for ::std::fs::File {
-    fn sync_all(&self) -> Result<(), io::Error> { self.sync_all() }
+impl FileWriter for dir::SampleFileWriter {
+    fn sync_all(&self) -> Result<(), io::Error> { dir::SampleFileWriter::sync_all(self) }
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> { io::Write::write(self, buf) }
 }
 
+/// Number of consecutive `EIO` errors after which a sample file directory is marked failed.
+const EIO_FAILURE_THRESHOLD: usize = 5;
+
+/// Returns the errno of an error from a `DirWriter` or `FileWriter`, if any.
+fn errno(e: &Error) -> Option<nix::errno::Errno> {
+    if let Some(e) = e.downcast_ref::<io::Error>() {
+        return e.raw_os_error().map(nix::errno::Errno::from_i32);
+    }
+    if let Some(&nix::Error::Sys(e)) = e.downcast_ref::<nix::Error>() {
+        return Some(e);
+    }
+    None
+}
+
+/// Returns the reason the given sample file directory was marked failed, if it was.
+fn dir_failure(db: &db::LockedDatabase, dir_id: i32) -> Option<String> {
+    db.sample_file_dirs_by_id().get(&dir_id).and_then(|d| d.failure()).map(str::to_owned)
+}
+
+/// Retries an operation on a sample file directory until it succeeds, as in
+/// `clock::retry_forever`, but with special handling for a full or failing disk:
+///
+/// *   on `ENOSPC`, calls `on_enospc` before retrying. This may happen many times while the disk
+///     stays full, so it should request an emergency rotation only if none is outstanding.
+/// *   after `EIO_FAILURE_THRESHOLD` consecutive `EIO`s, marks the directory failed and returns
+///     the error.
+/// *   once the directory has been marked failed (possibly by another thread), returns an error
+///     rather than retrying.
+///
+/// `db` must not be locked.
+fn retry_dir_io<C, T, E>(db: &db::Database<C>, dir_id: i32, on_enospc: &mut dyn FnMut(),
+                         f: &mut dyn FnMut() -> Result<T, E>) -> Result<T, Error>
+where C: Clocks + Clone, E: Into<Error> {
+    let mut eios = 0;
+    loop {
+        let e = match f() {
+            Ok(t) => return Ok(t),
+            Err(e) => e.into(),
+        };
+        if let Some(reason) = dir_failure(&db.lock(), dir_id) {
+            bail!("sample file dir {} has failed: {}", dir_id, reason);
+        }
+        match errno(&e) {
+            Some(nix::errno::Errno::ENOSPC) => {
+                eios = 0;
+                on_enospc();
+            },
+            Some(nix::errno::Errno::EIO) => {
+                eios += 1;
+                if eios >= EIO_FAILURE_THRESHOLD {
+                    db.lock().mark_sample_file_dir_failed(
+                        dir_id, format!("{} consecutive I/O errors; last was: {}", eios, e));
+                    return Err(e);
+                }
+            },
+            _ => eios = 0,
+        }
+        let sleep_time = Duration::seconds(1);
+        warn!("dir {}: sleeping for {} after error: {}", dir_id, sleep_time, e);
+        db.clocks().sleep(sleep_time);
+    }
+}
+
 /// A command sent to the syncer. These correspond to methods in the `SyncerChannel` struct.
 enum SyncerCommand<F> {
     AsyncSaveRecording(CompositeId, recording::Duration, F),
     DatabaseFlushed,
+    EmergencyRotation(mpsc::SyncSender<()>),
     Flush(mpsc::SyncSender<()>),
 }
 
@@ -163,7 +234,8 @@ impl Eq for PlannedFlush {}
 /// `LockedDatabase::clear_on_flush`, as this function installs a hook to watch database flushes.
 /// TODO: add a join wrapper which arranges for the on flush hook to be removed automatically.
 pub fn start_syncer<C>(db: Arc<db::Database<C>>, dir_id: i32)
-                       -> Result<(SyncerChannel<::std::fs::File>, thread::JoinHandle<()>), Error>
+                       -> Result<(SyncerChannel<dir::SampleFileWriter>, thread::JoinHandle<()>),
+                                 Error>
 where C: Clocks + Clone {
     let db2 = db.clone();
     let (mut syncer, path) = Syncer::new(&db.lock(), db2, dir_id)?;
@@ -195,31 +267,217 @@ pub fn lower_retention(db: Arc<db::Database>, dir_id: i32, limits: &[NewLimit])
     let (mut syncer, _) = Syncer::new(&db.lock(), db2, dir_id)?;
     syncer.do_rotation(|db| {
         for l in limits {
-            let (bytes_before, extra);
-            {
-                let stream = db.streams_by_id().get(&l.stream_id)
-                               .ok_or_else(|| format_err!("no such stream {}", l.stream_id))?;
-                bytes_before = stream.sample_file_bytes + stream.bytes_to_add -
-                               stream.bytes_to_delete;
-                extra = stream.retain_bytes - l.limit;
-            }
-            if l.limit >= bytes_before { continue }
-            delete_recordings(db, l.stream_id, extra)?;
+            delete_recordings(db, l.stream_id, l.limit)?;
         }
         Ok(())
     })
 }
 
-/// Deletes recordings to bring a stream's disk usage within bounds.
+/// Unlinks all of a directory's garbage and removes it from the database.
+/// The directory must be open and must not have a running syncer.
+pub fn collect_garbage<C>(db: Arc<db::Database<C>>, dir_id: i32) -> Result<(), Error>
+where C: Clocks + Clone {
+    let db2 = db.clone();
+    let (mut syncer, _) = Syncer::new(&db.lock(), db2, dir_id)?;
+    syncer.do_rotation(|_| Ok(()))
+}
+
+/// Moves all of a stream's recordings to another sample file directory.
+///
+/// Each committed recording's sample file is copied, synced, and read back to verify its SHA-1
+/// against the source (and against `recording_integrity`, when known). Then the stream is switched
+/// via `LockedDatabase::move_stream` and the old copies are collected as garbage. The stream must
+/// not be recording.
+///
+/// `syncer_dir_ids` lists the directories which have a running syncer, as when called from the
+/// server. Garbage in those is left for the syncer to collect after its next flush; garbage
+/// elsewhere is collected here.
+///
+/// This is resumable: if interrupted, calling it again with the same arguments will verify
+/// rather than recopy existing intact copies and collect any garbage left in the old directory.
+pub fn migrate_stream<C>(db: Arc<db::Database<C>>, stream_id: i32, to_dir_id: i32,
+                         syncer_dir_ids: &[i32]) -> Result<(), Error>
+where C: Clocks + Clone {
+    // Finish the cleanup of an earlier, interrupted migration.
+    let dirs_with_garbage: Vec<i32> = {
+        let l = db.lock();
+        l.sample_file_dirs_by_id()
+         .iter()
+         .filter(|&(id, _)| !syncer_dir_ids.contains(id))
+         .filter(|(_, d)| d.garbage_needs_unlink.iter().any(|id| id.stream() == stream_id))
+         .map(|(&id, _)| id)
+         .collect()
+    };
+    for dir_id in dirs_with_garbage {
+        db.lock().open_sample_file_dirs(&[dir_id])?;
+        collect_garbage(db.clone(), dir_id)?;
+    }
+
+    let (from_dir_id, from, to, rows) = {
+        let mut l = db.lock();
+        let from_dir_id = l.streams_by_id()
+                           .get(&stream_id)
+                           .ok_or_else(|| format_err!("no such stream {}", stream_id))?
+                           .sample_file_dir_id
+                           .ok_or_else(|| format_err!("stream {} has no sample file dir",
+                                                      stream_id))?;
+        if from_dir_id == to_dir_id {
+            info!("stream {} is already in dir {}", stream_id, to_dir_id);
+            return Ok(());
+        }
+        l.open_sample_file_dirs(&[from_dir_id, to_dir_id])?;
+        let from = l.sample_file_dirs_by_id().get(&from_dir_id).unwrap().get()?;
+        let to = l.sample_file_dirs_by_id()
+                  .get(&to_dir_id)
+                  .ok_or_else(|| format_err!("no such dir {}", to_dir_id))?
+                  .get()?;
+        let mut rows = Vec::new();
+        l.list_recordings_by_id(stream_id, 0 .. i32::max_value(), &mut |r| {
+            rows.push((r.id, r.sample_file_bytes));
+            Ok(())
+        })?;
+        (from_dir_id, from, to, rows)
+    };
+
+    info!("Copying {} recordings of stream {} from dir {} to dir {}",
+          rows.len(), stream_id, from_dir_id, to_dir_id);
+    for (i, &(id, len)) in rows.iter().enumerate() {
+        let expected_sha1 = db.lock().get_sample_file_sha1(id)?;
+        copy_sample_file(&from, &to, id, len as u64, expected_sha1)?;
+        if (i + 1) % 1000 == 0 {
+            info!("Copied {}/{} recordings", i + 1, rows.len());
+        }
+    }
+    to.sync()?;
+
+    db.lock().move_stream(stream_id, to_dir_id)?;
+    if syncer_dir_ids.contains(&from_dir_id) {
+        info!("Stream {} now uses dir {}; its syncer will remove the old files",
+              stream_id, to_dir_id);
+        return Ok(());
+    }
+    info!("Stream {} now uses dir {}; removing old files", stream_id, to_dir_id);
+    collect_garbage(db, from_dir_id)
+}
+
+/// Copies a single sample file for `migrate_stream`, skipping it if an intact copy exists.
+fn copy_sample_file(from: &dir::SampleFileDir, to: &dir::SampleFileDir, id: CompositeId,
+                    len: u64, expected_sha1: Option<[u8; 20]>) -> Result<(), Error> {
+    match to.open_file(id) {
+        Ok(_) => {
+            let want = match expected_sha1 {
+                Some(s) => (s, len),
+                None => sha1_file(from.open_reader(id)?)?,
+            };
+            if sha1_file(to.open_reader(id)?).ok() == Some(want) {
+                return Ok(());
+            }
+            warn!("Replacing incomplete copy of recording {}", id);
+            to.unlink_file(id)?;
+        },
+        Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => {},
+        Err(e) => bail!("unable to open copy of recording {}: {}", id, e),
+    }
+
+    let mut src = from.open_reader(id)?;
+    let mut dst = to.create_file(id)?;
+    let mut hasher = hash::Hasher::new(hash::MessageDigest::sha1())?;
+    let mut buf = vec![0u8; 1 << 16];
+    let mut copied = 0;
+    loop {
+        let n = io::Read::read(&mut src, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n])?;
+        io::Write::write_all(&mut dst, &buf[..n])?;
+        copied += n as u64;
+    }
+    if copied != len {
+        bail!("recording {} has {} bytes on disk; expected {}", id, copied, len);
+    }
+    let mut sha1 = [0u8; 20];
+    sha1.copy_from_slice(&hasher.finish()?[..]);
+    if let Some(e) = expected_sha1 {
+        if e != sha1 {
+            bail!("recording {} doesn't match its SHA-1 in the database; try moonfire-nvr check",
+                  id);
+        }
+    }
+    dst.sync_all()?;
+    drop(dst);
+    if sha1_file(to.open_reader(id)?)? != (sha1, len) {
+        bail!("copy of recording {} doesn't match its source", id);
+    }
+    Ok(())
+}
+
+/// Returns the SHA-1 and length of the given file's plaintext.
+fn sha1_file(mut f: dir::SampleFileReader) -> Result<([u8; 20], u64), Error> {
+    let mut hasher = hash::Hasher::new(hash::MessageDigest::sha1())?;
+    let mut buf = vec![0u8; 1 << 16];
+    let mut len = 0;
+    loop {
+        let n = io::Read::read(&mut f, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n])?;
+        len += n as u64;
+    }
+    let mut sha1 = [0u8; 20];
+    sha1.copy_from_slice(&hasher.finish()?[..]);
+    Ok((sha1, len))
+}
+
+/// Returns the number of bytes a stream may use, as enforced by rotation.
+///
+/// In manual mode, this is simply the stream's `retain_bytes`. When its directory is in automatic
+/// mode (see `db::SampleFileDir::free_space_margin_bytes`), the directory's usable capacity is
+/// divided among its streams in proportion to their `retain_weight`. Usable capacity is the
+/// filesystem's `available_bytes` plus what these streams already occupy, less the margin. It's
+/// recomputed on every rotation, so it follows streams being added to or removed from the
+/// directory as well as other users of the filesystem.
+///
+/// `available_bytes` must be supplied for a directory in automatic mode; the caller should get it
+/// from `DirWriter::available_bytes` before acquiring the database lock.
+fn retain_limit(db: &db::LockedDatabase, stream_id: i32, available_bytes: Option<i64>)
+                -> Result<i64, Error> {
+    let stream = db.streams_by_id().get(&stream_id)
+                   .ok_or_else(|| format_err!("no stream {}", stream_id))?;
+    let dir_id = match stream.sample_file_dir_id {
+        None => return Ok(stream.retain_bytes),
+        Some(d) => d,
+    };
+    let margin = match db.sample_file_dirs_by_id().get(&dir_id)
+                         .and_then(|d| d.free_space_margin_bytes) {
+        None => return Ok(stream.retain_bytes),
+        Some(m) => m,
+    };
+    let available_bytes = available_bytes.ok_or_else(
+        || format_err!("available bytes unknown for dir {} in automatic retention mode", dir_id))?;
+    let mut used = 0;
+    let mut total_weight = 0;
+    for s in db.streams_by_id().values() {
+        if s.sample_file_dir_id == Some(dir_id) {
+            // Recordings to delete on the next flush are still on disk, so they're counted here.
+            used += s.sample_file_bytes + s.bytes_to_add;
+            total_weight += i128::from(s.retain_weight);
+        }
+    }
+    let usable = cmp::max(0, available_bytes + used - margin);
+    Ok((i128::from(usable) * i128::from(stream.retain_weight) / total_weight) as i64)
+}
+
+/// Deletes recordings to bring a stream's disk usage within `limit` bytes.
 fn delete_recordings(db: &mut db::LockedDatabase, stream_id: i32,
-                     extra_bytes_needed: i64) -> Result<(), Error> {
+                     limit: i64) -> Result<(), Error> {
     let bytes_needed = {
         let stream = match db.streams_by_id().get(&stream_id) {
             None => bail!("no stream {}", stream_id),
             Some(s) => s,
         };
-        stream.sample_file_bytes + stream.bytes_to_add - stream.bytes_to_delete + extra_bytes_needed
-            - stream.retain_bytes
+        stream.sample_file_bytes + stream.bytes_to_add - stream.bytes_to_delete - limit
     };
     let mut bytes_to_delete = 0;
     if bytes_needed <= 0 {
@@ -245,6 +503,20 @@ impl<F: FileWriter> SyncerChannel<F> {
         self.0.send(SyncerCommand::AsyncSaveRecording(id, duration, f)).unwrap();
     }
 
+    /// Asynchronously asks the syncer to free space after a write failed with `ENOSPC`, unless
+    /// the rotation last requested via `pending` hasn't finished yet.
+    fn async_emergency_rotation(&self, pending: &mut Option<mpsc::Receiver<()>>) {
+        if let Some(ref rcv) = *pending {
+            // The syncer drops the sender when done.
+            if let Err(mpsc::TryRecvError::Empty) = rcv.try_recv() {
+                return;
+            }
+        }
+        let (snd, rcv) = mpsc::sync_channel(0);
+        self.0.send(SyncerCommand::EmergencyRotation(snd)).unwrap();
+        *pending = Some(rcv);
+    }
+
     /// For testing: flushes the syncer, waiting for all currently-queued commands to complete,
     /// including the next scheduled database flush (if any). Note this doesn't wait for any
     /// post-database flush garbage collection.
@@ -323,13 +595,21 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
         }, d.path.clone()))
     }
 
-    /// Rotates files for all streams and deletes stale files from previous runs.
+    /// Rotates files for this directory's streams and deletes stale files from previous runs.
     /// Called from main thread.
     fn initial_rotation(&mut self) -> Result<(), Error> {
+        let available_bytes = self.auto_available_bytes()?;
+        let dir_id = self.dir_id;
         self.do_rotation(|db| {
-            let streams: Vec<i32> = db.streams_by_id().keys().map(|&id| id).collect();
+            let streams: Vec<i32> =
+                db.streams_by_id()
+                  .iter()
+                  .filter_map(|(&id, s)| if s.sample_file_dir_id == Some(dir_id) { Some(id) }
+                                         else { None })
+                  .collect();
             for &stream_id in &streams {
-                delete_recordings(db, stream_id, 0)?;
+                let limit = retain_limit(db, stream_id, available_bytes)?;
+                delete_recordings(db, stream_id, limit)?;
             }
             Ok(())
         })
@@ -372,6 +652,19 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
 }
 
 impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
+    /// Returns the directory's available bytes if it's in automatic retention mode, or `None`
+    /// otherwise. This may do I/O, so it must be called without the database lock held.
+    fn auto_available_bytes(&self) -> Result<Option<i64>, Error> {
+        let auto = {
+            let l = self.db.lock();
+            l.sample_file_dirs_by_id().get(&self.dir_id).unwrap().free_space_margin_bytes.is_some()
+        };
+        if !auto {
+            return Ok(None);
+        }
+        Ok(Some(self.dir.available_bytes()?))
+    }
+
     /// Processes a single command or timeout.
     ///
     /// Returns true iff the loop should continue.
@@ -403,6 +696,7 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         match cmd {
             SyncerCommand::AsyncSaveRecording(id, dur, f) => self.save(id, dur, f),
             SyncerCommand::DatabaseFlushed => self.collect_garbage(),
+            SyncerCommand::EmergencyRotation(_done) => self.emergency_rotation(),
             SyncerCommand::Flush(flush) => {
                 // The sender is waiting for the supplied writer to be dropped. If there's no
                 // timeout, do so immediately; otherwise wait for that timeout then drop it.
@@ -428,7 +722,7 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         }
         let c = &self.db.clocks();
         for &id in &garbage {
-            clock::retry_forever(c, &mut || {
+            let r = retry_dir_io(&*self.db, self.dir_id, &mut || {}, &mut || {
                 if let Err(e) = self.dir.unlink_file(id) {
                     if e == nix::Error::Sys(nix::errno::Errno::ENOENT) {
                         warn!("dir: recording {} already deleted!", id);
@@ -438,11 +732,52 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
                 }
                 Ok(())
             });
+            if let Err(e) = r {
+                warn!("dir: unable to collect garbage: {}", e);
+                return;
+            }
+        }
+        if let Err(e) = retry_dir_io(&*self.db, self.dir_id, &mut || {}, &mut || self.dir.sync()) {
+            warn!("dir: unable to collect garbage: {}", e);
+            return;
         }
-        clock::retry_forever(c, &mut || self.dir.sync());
         clock::retry_forever(c, &mut || self.db.lock().delete_garbage(self.dir_id, &mut garbage));
     }
 
+    /// Frees space after a writer got `ENOSPC`, by deleting the oldest recording of each of this
+    /// directory's streams regardless of retention limits. Called from worker thread.
+    ///
+    /// The recordings are moved to the garbage table by the flush here and unlinked immediately,
+    /// so the space is free by the time the writer sees this rotation finish and retries.
+    fn emergency_rotation(&mut self) {
+        let mut l = self.db.lock();
+        let dir_id = self.dir_id;
+        let streams: Vec<i32> =
+            l.streams_by_id()
+             .iter()
+             .filter_map(|(&id, s)| if s.sample_file_dir_id == Some(dir_id) { Some(id) }
+                                    else { None })
+             .collect();
+        let mut n = 0;
+        for stream_id in streams {
+            let mut deleted = false;
+            l.delete_oldest_recordings(stream_id, &mut |_| !mem::replace(&mut deleted, true))
+             .unwrap();
+            n += deleted as usize;
+        }
+        if n == 0 {
+            warn!("dir {}: out of space with no recordings left to delete", dir_id);
+            return;
+        }
+        warn!("dir {}: out of space; deleting {} recordings beyond retention limits", dir_id, n);
+        if let Err(e) = l.flush("emergency rotation") {
+            warn!("dir {}: flush failure on emergency rotation: {:?}", dir_id, e);
+            return;
+        }
+        drop(l);
+        self.collect_garbage();
+    }
+
     /// Saves the given recording and causes rotation to happen. Called from worker thread.
     ///
     /// Note that part of rotation is deferred for the next cycle (saved writing or program startup)
@@ -453,12 +788,27 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         trace!("Processing save for {}", id);
         let stream_id = id.stream();
 
+        // If the directory has failed, the recording stays uncommitted until the next run, at which
+        // point its file is abandoned.
+        let dir = &self.dir;
+        let synced = retry_dir_io(&*self.db, self.dir_id, &mut || {}, &mut || f.sync_all())
+            .and_then(|()| retry_dir_io(&*self.db, self.dir_id, &mut || {}, &mut || dir.sync()));
+        if let Err(e) = synced {
+            warn!("{}: unable to save recording: {}", id, e);
+            return;
+        }
+
         // Free up a like number of bytes.
-        clock::retry_forever(&self.db.clocks(), &mut || f.sync_all());
-        clock::retry_forever(&self.db.clocks(), &mut || self.dir.sync());
+        let available_bytes = self.auto_available_bytes();
         let mut db = self.db.lock();
         db.mark_synced(id).unwrap();
-        delete_recordings(&mut db, stream_id, 0).unwrap();
+        match available_bytes.and_then(|a| retain_limit(&db, stream_id, a)) {
+            Ok(limit) => delete_recordings(&mut db, stream_id, limit).unwrap(),
+
+            // Skip rotation rather than guess at a limit; the next save will try again.
+            Err(e) => warn!("{}: unable to compute retention limit; not rotating: {}",
+                            stream_id, e),
+        }
         let s = db.streams_by_id().get(&stream_id).unwrap();
         let c = db.cameras_by_id().get(&s.camera_id).unwrap();
 
@@ -532,8 +882,12 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
 
 /// Struct for writing a single run (of potentially several recordings) to disk and committing its
 /// metadata to the database. `Writer` hands off each recording's state to the syncer when done. It
-/// saves the recording to the database (if I/O errors do not prevent this), retries forever,
-/// or panics (if further writing on this stream is impossible).
+/// saves the recording to the database if I/O errors do not prevent this.
+///
+/// I/O errors are retried as in `retry_dir_io`. While the disk is full, the writer keeps at most
+/// one emergency rotation outstanding, requesting another only once the syncer has finished the
+/// last. Persistent `EIO` marks the directory failed, after which `write` returns errors rather
+/// than retrying.
 pub struct Writer<'a, C: Clocks + Clone, D: DirWriter> {
     dir: &'a D,
     db: &'a db::Database<C>,
@@ -541,6 +895,10 @@ pub struct Writer<'a, C: Clocks + Clone, D: DirWriter> {
     stream_id: i32,
     video_sample_entry_id: i32,
     state: WriterState<D::File>,
+
+    /// The emergency rotation requested on `ENOSPC`, if any; see
+    /// `SyncerChannel::async_emergency_rotation`.
+    emergency_rotation: Option<mpsc::Receiver<()>>,
 }
 
 enum WriterState<F: FileWriter> {
@@ -555,6 +913,7 @@ enum WriterState<F: FileWriter> {
 /// with at least one sample. The sample may have zero duration.
 struct InnerWriter<F: FileWriter> {
     f: F,
+    dir_id: i32,
     r: Arc<Mutex<db::RecordingToInsert>>,
     e: recording::SampleIndexEncoder,
     id: CompositeId,
@@ -660,6 +1019,7 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             stream_id,
             video_sample_entry_id,
             state: WriterState::Unopened,
+            emergency_rotation: None,
         }
     }
 
@@ -673,17 +1033,32 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             WriterState::Open(_) => return Ok(()),
             WriterState::Closed(prev) => Some(prev),
         };
-        let (id, r) = self.db.lock().add_recording(self.stream_id, db::RecordingToInsert {
-            run_offset: prev.map(|p| p.run_offset + 1).unwrap_or(0),
-            start: prev.map(|p| p.end).unwrap_or(recording::Time(i64::max_value())),
-            video_sample_entry_id: self.video_sample_entry_id,
-            flags: db::RecordingFlags::Growing as i32,
-            ..Default::default()
-        })?;
-        let f = clock::retry_forever(&self.db.clocks(), &mut || self.dir.create_file(id));
+        let (dir_id, id, r) = {
+            let mut l = self.db.lock();
+            let dir_id = l.streams_by_id()
+                          .get(&self.stream_id)
+                          .and_then(|s| s.sample_file_dir_id)
+                          .ok_or_else(|| format_err!("stream {} has no sample file dir",
+                                                     self.stream_id))?;
+            if let Some(reason) = dir_failure(&l, dir_id) {
+                bail!("sample file dir {} has failed: {}", dir_id, reason);
+            }
+            let (id, r) = l.add_recording(self.stream_id, db::RecordingToInsert {
+                run_offset: prev.map(|p| p.run_offset + 1).unwrap_or(0),
+                start: prev.map(|p| p.end).unwrap_or(recording::Time(i64::max_value())),
+                video_sample_entry_id: self.video_sample_entry_id,
+                flags: db::RecordingFlags::Growing as i32,
+                ..Default::default()
+            })?;
+            (dir_id, id, r)
+        };
+        let (channel, dir, pending) = (self.channel, self.dir, &mut self.emergency_rotation);
+        let f = retry_dir_io(self.db, dir_id, &mut || channel.async_emergency_rotation(pending),
+                             &mut || dir.create_file(id))?;
 
         self.state = WriterState::Open(InnerWriter {
             f,
+            dir_id,
             r,
             e: recording::SampleIndexEncoder::new(),
             id,
@@ -747,8 +1122,20 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             }
         }
         let mut remaining = pkt;
+        let (channel, pending) = (self.channel, &mut self.emergency_rotation);
         while !remaining.is_empty() {
-            let written = clock::retry_forever(&self.db.clocks(), &mut || w.f.write(remaining));
+            let r = retry_dir_io(self.db, w.dir_id,
+                                 &mut || channel.async_emergency_rotation(pending),
+                                 &mut || w.f.write(remaining));
+            let written = match r {
+                Ok(n) => n,
+                Err(e) => {
+                    // The directory has failed. Abandon this recording; it will never be
+                    // committed, and its file is cleaned up on the next run.
+                    self.state = WriterState::Unopened;
+                    return Err(e);
+                },
+            };
             remaining = &remaining[written..];
         }
         w.unflushed_sample = Some(UnflushedSample {
@@ -865,6 +1252,7 @@ mod tests {
         Create(CompositeId, Box<dyn Fn(CompositeId) -> Result<MockFile, nix::Error> + Send>),
         Sync(Box<dyn Fn() -> Result<(), nix::Error> + Send>),
         Unlink(CompositeId, Box<dyn Fn(CompositeId) -> Result<(), nix::Error> + Send>),
+        AvailableBytes(Box<dyn Fn() -> Result<i64, nix::Error> + Send>),
     }
 
     impl MockDir {
@@ -900,6 +1288,12 @@ mod tests {
                 _ => panic!("got unlink({}), expected something else", id),
             }
         }
+        fn available_bytes(&self) -> Result<i64, nix::Error> {
+            match self.0.lock().pop_front().expect("got available_bytes with no expectation") {
+                MockDirAction::AvailableBytes(f) => f(),
+                _ => panic!("got available_bytes, expected something else"),
+            }
+        }
     }
 
     impl Drop for MockDir {
@@ -987,6 +1381,7 @@ mod tests {
 
     fn eio() -> io::Error { io::Error::new(io::ErrorKind::Other, "got EIO") }
     fn nix_eio() -> nix::Error { nix::Error::Sys(nix::errno::Errno::EIO) }
+    fn os_error(e: nix::errno::Errno) -> io::Error { io::Error::from_raw_os_error(e as i32) }
 
     /// Tests the database flushing while a syncer is still processing a previous flush event.
     #[test]
@@ -997,6 +1392,7 @@ mod tests {
             stream_id: testutil::TEST_STREAM_ID,
             new_record: true,
             new_limit: 3,
+            new_weight: 1,
         }]).unwrap();
 
         // Setup: add a 3-byte recording.
@@ -1079,6 +1475,66 @@ mod tests {
         assert!(h.syncer.planned_flushes.is_empty());
     }
 
+    /// Tests that a directory in automatic retention mode rotates based on its available bytes.
+    #[test]
+    fn auto_retention() {
+        testutil::init();
+        let mut h = new_harness(0);
+        h.db.lock().update_dir_retention(h.dir_id, Some(10)).unwrap();
+
+        // Setup: add a 3-byte recording, which fits within the margin.
+        let video_sample_entry_id = h.db.lock().insert_video_sample_entry(
+            1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+        let mut w = Writer::new(&h.dir, &h.db, &h.channel, testutil::TEST_STREAM_ID,
+                                video_sample_entry_id);
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(CompositeId::new(1, 1),
+                     Box::new({ let f = f.clone(); move |_id| Ok(f.clone()) })));
+        f.expect(MockFileAction::Write(Box::new(|buf| { assert_eq!(buf, b"123"); Ok(3) })));
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        w.write(b"123", recording::Time(2), 0, true).unwrap();
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        h.dir.expect(MockDirAction::AvailableBytes(Box::new(|| Ok(10))));
+        w.close(Some(1)).unwrap();
+        assert!(h.syncer.iter(&h.syncer_rcv)); // AsyncSave
+        assert!(h.syncer.iter(&h.syncer_rcv)); // planned flush
+        assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+
+        // Then a 1-byte recording, after something else has used a byte of the filesystem.
+        // That leaves 9 + 4 - 10 = 3 usable bytes, so the first recording must be deleted.
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(CompositeId::new(1, 2),
+                     Box::new({ let f = f.clone(); move |_id| Ok(f.clone()) })));
+        f.expect(MockFileAction::Write(Box::new(|buf| { assert_eq!(buf, b"4"); Ok(1) })));
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        w.write(b"4", recording::Time(3), 1, true).unwrap();
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        h.dir.expect(MockDirAction::AvailableBytes(Box::new(|| Ok(9))));
+        h.dir.expect(MockDirAction::Unlink(CompositeId::new(1, 1), Box::new(|_| Ok(()))));
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        drop(w);
+        assert!(h.syncer.iter(&h.syncer_rcv)); // AsyncSave
+        assert!(h.syncer.iter(&h.syncer_rcv)); // planned flush
+        assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+
+        {
+            let l = h.db.lock();
+            let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+            assert_eq!(s.sample_file_bytes, 1);
+        }
+
+        // The syncer should shut down cleanly.
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        assert_eq!(h.syncer_rcv.try_recv().err(),
+                   Some(std::sync::mpsc::TryRecvError::Disconnected));
+        assert!(h.syncer.planned_flushes.is_empty());
+    }
+
     #[test]
     fn write_path_retries() {
         testutil::init();
@@ -1136,6 +1592,103 @@ mod tests {
         assert!(h.syncer.planned_flushes.is_empty());
     }
 
+    /// Tests that `ENOSPC` causes deletion of recordings beyond the retention limit.
+    #[test]
+    fn enospc_rotation() {
+        testutil::init();
+        let mut h = new_harness(0);
+
+        // Setup: add a 3-byte recording, well within the retention limit.
+        let video_sample_entry_id = h.db.lock().insert_video_sample_entry(
+            1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+        let mut w = Writer::new(&h.dir, &h.db, &h.channel, testutil::TEST_STREAM_ID,
+                                video_sample_entry_id);
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(CompositeId::new(1, 1),
+                     Box::new({ let f = f.clone(); move |_id| Ok(f.clone()) })));
+        f.expect(MockFileAction::Write(Box::new(|buf| { assert_eq!(buf, b"123"); Ok(3) })));
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        w.write(b"123", recording::Time(2), 0, true).unwrap();
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        w.close(Some(1)).unwrap();
+        assert!(h.syncer.iter(&h.syncer_rcv)); // AsyncSave
+        assert!(h.syncer.iter(&h.syncer_rcv)); // planned flush
+        assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+
+        // Then a 1-byte recording, which initially finds the disk full (twice).
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(CompositeId::new(1, 2),
+                     Box::new({ let f = f.clone(); move |_id| Ok(f.clone()) })));
+        f.expect(MockFileAction::Write(Box::new(|_| Err(os_error(nix::errno::Errno::ENOSPC)))));
+        f.expect(MockFileAction::Write(Box::new(|_| Err(os_error(nix::errno::Errno::ENOSPC)))));
+        f.expect(MockFileAction::Write(Box::new(|buf| { assert_eq!(buf, b"4"); Ok(1) })));
+        w.write(b"4", recording::Time(3), 1, true).unwrap();
+
+        // The writer should have requested a single emergency rotation, which deletes the first
+        // recording even though it's within the limit.
+        h.dir.expect(MockDirAction::Unlink(CompositeId::new(1, 1), Box::new(|_| Ok(()))));
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        assert!(h.syncer.iter(&h.syncer_rcv)); // EmergencyRotation
+        h.dir.ensure_done();
+        assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        assert_eq!(h.syncer_rcv.try_recv().err(), Some(std::sync::mpsc::TryRecvError::Empty));
+
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        drop(w);
+        assert!(h.syncer.iter(&h.syncer_rcv)); // AsyncSave
+        assert!(h.syncer.iter(&h.syncer_rcv)); // planned flush
+        assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+
+        {
+            let l = h.db.lock();
+            let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+            assert_eq!(s.sample_file_bytes, 1);
+        }
+
+        // The syncer should shut down cleanly.
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        assert_eq!(h.syncer_rcv.try_recv().err(),
+                   Some(std::sync::mpsc::TryRecvError::Disconnected));
+        assert!(h.syncer.planned_flushes.is_empty());
+    }
+
+    /// Tests that persistent `EIO` marks the directory failed rather than retrying forever.
+    #[test]
+    fn eio_fails_dir() {
+        testutil::init();
+        let h = new_harness(0);
+        let video_sample_entry_id = h.db.lock().insert_video_sample_entry(
+            1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+        let mut w = Writer::new(&h.dir, &h.db, &h.channel, testutil::TEST_STREAM_ID,
+                                video_sample_entry_id);
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(CompositeId::new(1, 1),
+                     Box::new({ let f = f.clone(); move |_id| Ok(f.clone()) })));
+        for _ in 0 .. super::EIO_FAILURE_THRESHOLD {
+            f.expect(MockFileAction::Write(Box::new(|_| Err(os_error(nix::errno::Errno::EIO)))));
+        }
+        w.write(b"123", recording::Time(2), 0, true).unwrap_err();
+        f.ensure_done();
+        assert!(h.db.lock().sample_file_dirs_by_id().get(&h.dir_id).unwrap().failure().is_some());
+
+        // Further writes should fail without touching the directory.
+        w.write(b"123", recording::Time(2), 0, true).unwrap_err();
+        drop(w);
+        h.dir.ensure_done();
+
+        // Nothing should have been sent to the syncer.
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        assert_eq!(h.syncer_rcv.try_recv().err(),
+                   Some(std::sync::mpsc::TryRecvError::Disconnected));
+    }
+
     #[test]
     fn gc_path_retries() {
         testutil::init();
@@ -1144,6 +1697,7 @@ mod tests {
             stream_id: testutil::TEST_STREAM_ID,
             new_record: true,
             new_limit: 3,
+            new_weight: 1,
         }]).unwrap();
 
         // Setup: add a 3-byte recording.
@@ -1359,4 +1913,91 @@ mod tests {
         assert!(total == expected || total == expected + 1, "total={} vs expected={}",
                 total, expected);
     }
+
+    #[test]
+    fn migrate_stream() {
+        testutil::init();
+        let clocks = SimulatedClocks::new(::time::Timespec::new(0, 0));
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let db = Arc::new(db::Database::new(clocks, conn, true).unwrap());
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let (from_path, to_path) = (tmpdir.path().join("from"), tmpdir.path().join("to"));
+
+        // Migrate from a plaintext dir to an encrypted one.
+        let key_path = tmpdir.path().join("key");
+        ::std::fs::write(&key_path, &[1u8; 32][..]).unwrap();
+        let (from_dir_id, to_dir_id, stream_id, id1, id2);
+        {
+            let mut l = db.lock();
+            from_dir_id = l.add_sample_file_dir(from_path.to_str().unwrap().to_owned(), None)
+                           .unwrap();
+            to_dir_id = l.add_sample_file_dir(to_path.to_str().unwrap().to_owned(),
+                                              Some(key_path.to_str().unwrap().to_owned()))
+                         .unwrap();
+            let camera_id = l.add_camera(uuid::Uuid::new_v4(), db::CameraChange {
+                short_name: "test camera".to_owned(),
+                description: "".to_owned(),
+                onvif_host: "test-camera".to_owned(),
+                credentials: None,
+                streams: [
+                    db::StreamChange {
+                        sample_file_dir_id: Some(from_dir_id),
+                        rtsp_url: "rtsp://test-camera/main".to_owned(),
+                        record: true,
+                        flush_if_sec: 0,
+                    },
+                    Default::default(),
+                ],
+            }).unwrap();
+            stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+            let vse_id = l.insert_video_sample_entry(
+                1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+            let from = l.sample_file_dirs_by_id().get(&from_dir_id).unwrap().get().unwrap();
+            let mut add = |data: &[u8], start| {
+                let (id, _) = l.add_recording(stream_id, db::RecordingToInsert {
+                    sample_file_bytes: data.len() as i32,
+                    start: recording::Time(start),
+                    duration_90k: 90000,
+                    video_samples: 1,
+                    video_sync_samples: 1,
+                    video_sample_entry_id: vse_id,
+                    video_index: [0u8; 100].to_vec(),
+                    sample_file_sha1: openssl::sha::sha1(data),
+                    ..Default::default()
+                }).unwrap();
+                io::Write::write_all(&mut from.create_file(id).unwrap(), data).unwrap();
+                l.mark_synced(id).unwrap();
+                id
+            };
+            id1 = add(b"first recording", 1430006400 * 90000);
+            id2 = add(b"second recording", 1430006401 * 90000);
+            l.flush("migrate_stream test").unwrap();
+        }
+
+        // Simulate an interrupted earlier attempt which left a partial copy.
+        {
+            let l = db.lock();
+            let to = l.sample_file_dirs_by_id().get(&to_dir_id).unwrap().get().unwrap();
+            io::Write::write_all(&mut to.create_file(id2).unwrap(), b"second").unwrap();
+        }
+
+        super::migrate_stream(db.clone(), stream_id, to_dir_id, &[]).unwrap();
+        let l = db.lock();
+        assert_eq!(l.streams_by_id().get(&stream_id).unwrap().sample_file_dir_id,
+                   Some(to_dir_id));
+        let from = l.sample_file_dirs_by_id().get(&from_dir_id).unwrap();
+        assert!(from.garbage_needs_unlink.is_empty());
+        assert!(from.garbage_unlinked.is_empty());
+        assert!(from.get().unwrap().is_empty().unwrap());
+        let to = l.sample_file_dirs_by_id().get(&to_dir_id).unwrap().get().unwrap();
+        for &(id, data) in &[(id1, &b"first recording"[..]), (id2, &b"second recording"[..])] {
+            let mut buf = Vec::new();
+            io::Read::read_to_end(&mut to.open_reader(id).unwrap(), &mut buf).unwrap();
+            assert_eq!(&buf[..], data);
+            let f = to.open_file(id).unwrap();
+            assert_eq!(to.read_range(&f, id, 1..3).unwrap(), &data[1..3]);
+            assert_ne!(f.metadata().unwrap().len(), data.len() as u64);
+        }
+    }
 }
@@ -30,10 +30,12 @@
 
 //! Tools for implementing a `http_serve::Entity` body composed from many "slices".
 
-use base::Error;
-use futures::{Stream, stream};
+use base::{Error, ErrorKind, ResultExt};
+use db::{CompositeId, dir};
+use futures::{Stream, future, stream};
 use reffers::ARefss;
 use std::error::Error as StdError;
+use std::ops::Range;
 use std::pin::Pin;
 
 pub struct Chunk(ARefss<'static, [u8]>);
@@ -46,6 +48,58 @@ pub fn wrap_error(e: Error) -> BoxedError {
     Box::new(e.compat())
 }
 
+/// Gets the given range of a sample file's plaintext as a stream of chunks.
+/// This works by `mmap()`ing in the data. There are a couple caveats:
+///
+///    * The thread which reads the resulting slice is likely to experience major page faults.
+///      Eventually this will likely be rewritten to `mmap()` the memory in another thread, and
+///      `mlock()` and send chunks of it to be read and `munlock()`ed to avoid this problem.
+///
+///    * If the backing file is truncated, the program will crash with `SIGBUS`. This shouldn't
+///      happen because nothing should be touching Moonfire NVR's files but itself.
+///
+/// Encrypted sample files can't be `mmap()`ed; instead, each of the file's chunks overlapping
+/// the range is decrypted into memory as the stream is polled.
+pub fn sample_file_data(dir: &dir::SampleFileDir, id: CompositeId, r: Range<u64>)
+                        -> Result<BodyStream, Error> {
+    if !dir.is_encrypted() {
+        let data = sample_file_bytes(dir, id, r)?;
+        return Ok(Box::new(stream::once(future::ok(data.into()))));
+    }
+    let f = dir.open_file(id).err_kind(ErrorKind::Unknown)?;
+    let d = dir.open_decryptor(f, id).err_kind(ErrorKind::Unknown)?;
+    let chunk_size = d.chunk_size();
+    let mut pos = r.start;
+    Ok(Box::new(stream::iter(std::iter::from_fn(move || {
+        if pos == r.end {
+            return None;
+        }
+        let end = std::cmp::min(r.end, (pos / chunk_size + 1) * chunk_size);
+        let c = d.read_range(pos .. end);
+        pos = if c.is_ok() { end } else { r.end };  // end the stream after an error.
+        Some(c.map(Chunk::from).err_kind(ErrorKind::Unknown).map_err(wrap_error))
+    }))))
+}
+
+/// Gets the given range of a sample file's plaintext in one piece, for callers which must
+/// parse it. As in `sample_file_data`, except an encrypted range is decrypted all at once.
+pub fn sample_file_bytes(dir: &dir::SampleFileDir, id: CompositeId, r: Range<u64>)
+                         -> Result<ARefss<'static, [u8]>, Error> {
+    let f = dir.open_file(id).err_kind(ErrorKind::Unknown)?;
+    if dir.is_encrypted() {
+        let v = dir.read_range(&f, id, r).err_kind(ErrorKind::Unknown)?;
+        return Ok(ARefss::new(v).map(|v| &v[..]));
+    }
+    let mmap = Box::new(unsafe {
+        memmap::MmapOptions::new()
+            .offset(r.start)
+            .len((r.end - r.start) as usize)
+            .map(&f).err_kind(ErrorKind::Internal)?
+        });
+    use core::ops::Deref;
+    Ok(ARefss::new(mmap).map(|m| m.deref()))
+}
+
 impl From<ARefss<'static, [u8]>> for Chunk {
     fn from(r: ARefss<'static, [u8]>) -> Self { Chunk(r) }
 }
@@ -95,6 +149,50 @@ impl hyper::body::HttpBody for Body {
     }
 }
 
+impl Body {
+    /// Returns a body which yields the same data, calling `on_end` with the number of bytes
+    /// yielded once the stream ends or is dropped, whichever comes first.
+    pub fn on_end<F: FnOnce(u64) + Send + Sync + 'static>(self, on_end: F) -> Self {
+        let s: BodyStream = Box::new(CountingStream {
+            inner: self.0,
+            bytes: 0,
+            on_end: Some(Box::new(on_end)),
+        });
+        s.into()
+    }
+}
+
+/// The stream behind `Body::on_end`.
+struct CountingStream {
+    inner: Pin<BodyStream>,
+    bytes: u64,
+    on_end: Option<Box<dyn FnOnce(u64) + Send + Sync>>,
+}
+
+impl Stream for CountingStream {
+    type Item = Result<Chunk, BoxedError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context)
+                 -> std::task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();  // CountingStream is Unpin.
+        let r = this.inner.as_mut().poll_next(cx);
+        match r {
+            std::task::Poll::Ready(Some(Ok(ref c))) => this.bytes += c.0.len() as u64,
+            std::task::Poll::Ready(None) => if let Some(f) = this.on_end.take() { f(this.bytes) },
+            _ => {},
+        };
+        r
+    }
+}
+
+impl Drop for CountingStream {
+    fn drop(&mut self) {
+        if let Some(f) = self.on_end.take() {
+            f(self.bytes);
+        }
+    }
+}
+
 impl From<BodyStream> for Body {
     fn from(b: BodyStream) -> Self { Body(Pin::from(b)) }
 }
@@ -33,28 +33,41 @@ use crate::stream::{self, Opener, Stream};
 use cursive::Cursive;
 use cursive::traits::{Boxable, Identifiable, Finder};
 use cursive::views;
-use db::writer;
-use failure::Error;
+use db::{crypto, writer};
+use failure::{Error, format_err};
 use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
 use url::Url;
+use uuid::Uuid;
 
-/// Builds a `CameraChange` from an active `edit_camera_dialog`.
-fn get_change(siv: &mut Cursive) -> db::CameraChange {
+/// Returns the key for sealing camera credentials, as set by `super::run`.
+fn credentials_key(siv: &mut Cursive) -> Option<crypto::Key> {
+    siv.user_data::<crypto::Key>().cloned()
+}
+
+/// Builds a `CameraChange` from an active `edit_camera_dialog` for the camera with the given uuid.
+fn get_change(siv: &mut Cursive, uuid: Uuid) -> Result<db::CameraChange, Error> {
     // Note: these find_id calls are separate statements, which seems to be important:
     // https://github.com/gyscos/Cursive/issues/144
     let sn = siv.find_id::<views::EditView>("short_name").unwrap().get_content().as_str().into();
     let d = siv.find_id::<views::TextArea>("description").unwrap().get_content().into();
     let h = siv.find_id::<views::EditView>("onvif_host").unwrap().get_content().as_str().into();
-    let u = siv.find_id::<views::EditView>("username").unwrap().get_content().as_str().into();
-    let p = siv.find_id::<views::EditView>("password").unwrap().get_content().as_str().into();
+    let u = siv.find_id::<views::EditView>("username").unwrap().get_content();
+    let p = siv.find_id::<views::EditView>("password").unwrap().get_content();
+    let credentials = if u.is_empty() {
+        None
+    } else {
+        let key = credentials_key(siv).ok_or_else(|| format_err!(
+            "A credentials key is required to store a username and password. See \
+             --credentials-key-file."))?;
+        Some(db::Credentials::seal(&key, uuid, &u, &p))
+    };
     let mut c = db::CameraChange {
         short_name: sn,
         description: d,
         onvif_host: h,
-        username: u,
-        password: p,
+        credentials,
         streams: Default::default(),
     };
     for &t in &db::ALL_STREAM_TYPES {
@@ -75,30 +88,39 @@ fn get_change(siv: &mut Cursive) -> db::CameraChange {
             flush_if_sec: f,
         };
     }
-    c
+    Ok(c)
 }
 
 fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
-    let change = get_change(siv);
-
-    let result = {
+    let uuid = match id {
+        Some(id) => db.lock().cameras_by_id().get(&id).expect("missing camera").uuid,
+        None => Uuid::new_v4(),
+    };
+    let result = get_change(siv, uuid).and_then(|change| {
+        let name = change.short_name.clone();
         let mut l = db.lock();
-        if let Some(id) = id {
+        let r = if let Some(id) = id {
             l.update_camera(id, change)
         } else {
-            l.add_camera(change).map(|_| ())
-        }
-    };
-    if let Err(e) = result {
-        siv.add_layer(views::Dialog::text(format!("Unable to add camera: {}", e))
-                      .title("Error")
-                      .dismiss_button("Abort"));
-    } else {
-        siv.pop_layer();  // get rid of the add/edit camera dialog.
+            l.add_camera(uuid, change).map(|_| ())
+        };
+        r.map(|()| name)
+    });
+    match result {
+        Err(e) => {
+            siv.add_layer(views::Dialog::text(format!("Unable to add camera: {}", e))
+                          .title("Error")
+                          .dismiss_button("Abort"));
+        },
+        Ok(name) => {
+            super::audit(db, if id.is_some() { "update camera" } else { "add camera" }, name);
+            siv.pop_layer();  // get rid of the add/edit camera dialog.
 
-        // Recreate the "Edit cameras" dialog from scratch; it's easier than adding the new entry.
-        siv.pop_layer();
-        top_dialog(db, siv);
+            // Recreate the "Edit cameras" dialog from scratch; it's easier than adding the new
+            // entry.
+            siv.pop_layer();
+            top_dialog(db, siv);
+        },
     }
 }
 
@@ -112,8 +134,11 @@ fn press_test_inner(url: &Url) -> Result<String, Error> {
 }
 
 fn press_test(siv: &mut Cursive, t: db::StreamType) {
-    let c = get_change(siv);
-    let mut url = match Url::parse(&c.streams[t.index()].rtsp_url) {
+    let u = siv.find_id::<views::EditView>("username").unwrap().get_content();
+    let p = siv.find_id::<views::EditView>("password").unwrap().get_content();
+    let rtsp_url = siv.find_id::<views::EditView>(&format!("{}_rtsp_url", t.as_str()))
+                      .unwrap().get_content();
+    let mut url = match Url::parse(&rtsp_url) {
         Ok(u) => u,
         Err(e) => {
             siv.add_layer(views::Dialog::text(
@@ -124,9 +149,9 @@ fn press_test(siv: &mut Cursive, t: db::StreamType) {
         },
     };
 
-    if !c.username.is_empty() {
-        let _ = url.set_username(&c.username);
-        let _ = url.set_password(Some(&c.password));
+    if !u.is_empty() {
+        let _ = url.set_username(&u);
+        let _ = url.set_password(Some(&p));
     }
     siv.add_layer(views::Dialog::text(format!("Testing {} stream at {}. This may take a while \
                                                on timeout or if you have a long key frame interval",
@@ -238,16 +263,23 @@ fn actually_delete(siv: &mut Cursive, db: &Arc<db::Database>, id: i32) {
     siv.pop_layer();  // get rid of the add/edit camera dialog.
     let result = {
         let mut l = db.lock();
-        l.delete_camera(id)
+        let name = l.cameras_by_id().get(&id).map(|c| c.short_name.clone()).unwrap_or_default();
+        l.delete_camera(id).map(|()| name)
     };
-    if let Err(e) = result {
-        siv.add_layer(views::Dialog::text(format!("Unable to delete camera: {}", e))
-                      .title("Error")
-                      .dismiss_button("Abort"));
-    } else {
-        // Recreate the "Edit cameras" dialog from scratch; it's easier than adding the new entry.
-        siv.pop_layer();
-        top_dialog(db, siv);
+    match result {
+        Err(e) => {
+            siv.add_layer(views::Dialog::text(format!("Unable to delete camera: {}", e))
+                          .title("Error")
+                          .dismiss_button("Abort"));
+        },
+        Ok(name) => {
+            super::audit(db, "delete camera", name);
+
+            // Recreate the "Edit cameras" dialog from scratch; it's easier than adding the new
+            // entry.
+            siv.pop_layer();
+            top_dialog(db, siv);
+        },
     }
 }
 
@@ -301,9 +333,31 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
     }
 
     let mut dialog = views::Dialog::around(layout);
+    let key = credentials_key(siv);
     let dialog = if let Some(camera_id) = *item {
         let l = db.lock();
         let camera = l.cameras_by_id().get(&camera_id).expect("missing camera");
+        let (username, password) = match (&camera.credentials, key) {
+            (None, _) => (String::new(), String::new()),
+            (Some(c), Some(k)) => match c.open(&k, camera.uuid) {
+                Ok(c) => c,
+                Err(e) => {
+                    siv.add_layer(views::Dialog::text(format!(
+                            "Unable to decrypt credentials: {}", e))
+                        .title("Error")
+                        .dismiss_button("Abort"));
+                    return;
+                },
+            },
+            (Some(_), None) => {
+                siv.add_layer(views::Dialog::text(
+                        "This camera's credentials are encrypted. Supply its key with \
+                         --credentials-key-file to edit it.")
+                    .title("Error")
+                    .dismiss_button("Abort"));
+                return;
+            },
+        };
         dialog.call_on_id("uuid", |v: &mut views::TextView| v.set_content(camera.uuid.to_string()))
               .expect("missing TextView");
 
@@ -346,8 +400,8 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         let name = camera.short_name.clone();
         for &(view_id, content) in &[("short_name", &*camera.short_name),
                                      ("onvif_host", &*camera.onvif_host),
-                                     ("username", &*camera.username),
-                                     ("password", &*camera.password)] {
+                                     ("username", &*username),
+                                     ("password", &*password)] {
             dialog.call_on_id(view_id, |v: &mut views::EditView| v.set_content(content.to_string()))
                   .expect("missing EditView");
         }
@@ -34,8 +34,9 @@ use cursive::traits::{Boxable, Identifiable};
 use cursive::views;
 use db::writer;
 use failure::Error;
-use log::{debug, trace};
+use log::debug;
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::BTreeMap;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -45,6 +46,7 @@ struct Stream {
     used: i64,
     record: bool,
     retain: Option<i64>,  // None if unparseable
+    weight: Option<i32>,  // None if unparseable
 }
 
 struct Model {
@@ -53,10 +55,32 @@ struct Model {
     fs_capacity: i64,
     total_used: i64,
     total_retain: i64,
+    margin: Option<i64>,  // None for manual retention
+    margin_valid: bool,
     errors: isize,
     streams: BTreeMap<i32, Stream>,
 }
 
+/// Returns true iff the manual limits exceed the filesystem's capacity.
+/// This doesn't apply in automatic mode, where the limits are ignored.
+fn is_over(model: &Model) -> bool {
+    model.margin.is_none() && model.total_retain > model.fs_capacity
+}
+
+/// Returns the limit each stream will be held to after the change.
+/// In automatic mode, this matches the syncer's calculation in `writer::retain_limit`.
+fn new_limits(model: &Model) -> BTreeMap<i32, i64> {
+    let margin = match model.margin {
+        None => return model.streams.iter().map(|(&id, s)| (id, s.retain.unwrap())).collect(),
+        Some(m) => m,
+    };
+    let usable = i128::from(cmp::max(0, model.fs_capacity - margin));
+    let total_weight: i128 = model.streams.values().map(|s| i128::from(s.weight.unwrap())).sum();
+    model.streams.iter()
+         .map(|(&id, s)| (id, (usable * i128::from(s.weight.unwrap()) / total_weight) as i64))
+         .collect()
+}
+
 /// Updates the limits in the database. Doesn't delete excess data (if any).
 fn update_limits_inner(model: &Model) -> Result<(), Error> {
     let mut changes = Vec::with_capacity(model.streams.len());
@@ -65,9 +89,17 @@ fn update_limits_inner(model: &Model) -> Result<(), Error> {
             stream_id,
             new_record: stream.record,
             new_limit: stream.retain.unwrap(),
+            new_weight: stream.weight.unwrap(),
         });
     }
-    model.db.lock().update_retention(&changes)
+    let path = {
+        let mut l = model.db.lock();
+        l.update_retention(&changes)?;
+        l.update_dir_retention(model.dir_id, model.margin)?;
+        l.sample_file_dirs_by_id().get(&model.dir_id).map(|d| d.path.clone()).unwrap_or_default()
+    };
+    super::audit(&model.db, "update retention", path);
+    Ok(())
 }
 
 fn update_limits(model: &Model, siv: &mut Cursive) {
@@ -85,20 +117,11 @@ fn edit_limit(model: &RefCell<Model>, siv: &mut Cursive, id: i32, content: &str)
     let stream = model.streams.get_mut(&id).unwrap();
     let new_value = decode_size(content).ok();
     let delta = new_value.unwrap_or(0) - stream.retain.unwrap_or(0);
-    let old_errors = model.errors;
     if delta != 0 {
-        let prev_over = model.total_retain > model.fs_capacity;
         model.total_retain += delta;
         siv.find_id::<views::TextView>("total_retain")
             .unwrap()
             .set_content(encode_size(model.total_retain));
-        let now_over = model.total_retain > model.fs_capacity;
-        if now_over != prev_over {
-            model.errors += if now_over { 1 } else { -1 };
-            siv.find_id::<views::TextView>("total_ok")
-                .unwrap()
-                .set_content(if now_over { "*" } else { " " });
-        }
     }
     if new_value.is_none() != stream.retain.is_none() {
         model.errors += if new_value.is_none() { 1 } else { -1 };
@@ -107,13 +130,55 @@ fn edit_limit(model: &RefCell<Model>, siv: &mut Cursive, id: i32, content: &str)
             .set_content(if new_value.is_none() { "*" } else { " " });
     }
     stream.retain = new_value;
-    debug!("model.errors = {}", model.errors);
-    if (model.errors == 0) != (old_errors == 0) {
-        trace!("toggling change state: errors={}", model.errors);
-        siv.find_id::<views::Button>("change")
-           .unwrap()
-           .set_enabled(model.errors == 0);
+    update_change_state(model, siv);
+}
+
+fn edit_weight(model: &RefCell<Model>, siv: &mut Cursive, id: i32, content: &str) {
+    let mut model = model.borrow_mut();
+    let model: &mut Model = &mut *model;
+    let stream = model.streams.get_mut(&id).unwrap();
+    let new_value = content.parse::<i32>().ok().filter(|&w| w > 0);
+    if new_value.is_none() != stream.weight.is_none() {
+        model.errors += if new_value.is_none() { 1 } else { -1 };
+        siv.find_id::<views::TextView>(&format!("{}_weight_ok", id))
+            .unwrap()
+            .set_content(if new_value.is_none() { "*" } else { " " });
+    }
+    stream.weight = new_value;
+    update_change_state(model, siv);
+}
+
+fn edit_margin(model: &RefCell<Model>, siv: &mut Cursive, content: &str) {
+    let mut model = model.borrow_mut();
+    let model: &mut Model = &mut *model;
+    let new_value = if content.trim().is_empty() {
+        Some(None)
+    } else {
+        decode_size(content).ok().map(Some)
+    };
+    if new_value.is_some() != model.margin_valid {
+        model.margin_valid = new_value.is_some();
+        model.errors += if model.margin_valid { -1 } else { 1 };
+        siv.find_id::<views::TextView>("margin_ok")
+            .unwrap()
+            .set_content(if model.margin_valid { " " } else { "*" });
     }
+    if let Some(m) = new_value {
+        model.margin = m;
+    }
+    update_change_state(model, siv);
+}
+
+/// Updates the over-capacity marker and enables the change button iff the model is valid.
+fn update_change_state(model: &Model, siv: &mut Cursive) {
+    let over = is_over(model);
+    debug!("model.errors = {}, over = {}", model.errors, over);
+    siv.find_id::<views::TextView>("total_ok")
+        .unwrap()
+        .set_content(if over { "*" } else { " " });
+    siv.find_id::<views::Button>("change")
+       .unwrap()
+       .set_enabled(model.errors == 0 && !over);
 }
 
 fn edit_record(model: &RefCell<Model>, id: i32, record: bool) {
@@ -140,8 +205,8 @@ fn confirm_deletion(model: &RefCell<Model>, siv: &mut Cursive, to_delete: i64) {
 fn actually_delete(model: &RefCell<Model>, siv: &mut Cursive) {
     let model = &*model.borrow();
     let new_limits: Vec<_> =
-        model.streams.iter()
-             .map(|(&id, s)| writer::NewLimit {stream_id: id, limit: s.retain.unwrap()})
+        new_limits(model).into_iter()
+             .map(|(id, limit)| writer::NewLimit {stream_id: id, limit})
              .collect();
     siv.pop_layer();  // deletion confirmation
     siv.pop_layer();  // retention dialog
@@ -159,11 +224,14 @@ fn actually_delete(model: &RefCell<Model>, siv: &mut Cursive) {
 }
 
 fn press_change(model: &Rc<RefCell<Model>>, siv: &mut Cursive) {
-    if model.borrow().errors > 0 {
-        return;
-    }
-    let to_delete = model.borrow().streams.values().map(
-        |s| ::std::cmp::max(s.used - s.retain.unwrap(), 0)).sum();
+    let to_delete = {
+        let model = model.borrow();
+        if model.errors > 0 || is_over(&model) {
+            return;
+        }
+        let limits = new_limits(&model);
+        model.streams.iter().map(|(id, s)| cmp::max(s.used - limits[id], 0)).sum()
+    };
     debug!("change press, to_delete={}", to_delete);
     if to_delete > 0 {
         let prompt = format!("Some streams' usage exceeds new limit. Please confirm the amount \
@@ -217,28 +285,39 @@ fn add_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
                 .child(views::EditView::new()
                     .on_submit({
                         let db = db.clone();
-                        move |siv, path| add_dir(&db, siv, path)
+                        move |siv, _| add_dir(&db, siv)
                     })
                     .with_id("path")
+                    .fixed_width(60))
+                .child(views::DummyView)
+                .child(views::TextView::new(
+                    "encryption key path (optional; a file with 32 random bytes)"))
+                .child(views::EditView::new()
+                    .on_submit({
+                        let db = db.clone();
+                        move |siv, _| add_dir(&db, siv)
+                    })
+                    .with_id("key_path")
                     .fixed_width(60)))
             .button("Add", {
                 let db = db.clone();
-                move |siv| {
-                    let path = siv.find_id::<views::EditView>("path").unwrap().get_content();
-                    add_dir(&db, siv, &path)
-                }
+                move |siv| add_dir(&db, siv)
             })
             .button("Cancel", |siv| { siv.pop_layer(); })
         .title("Add sample file directory"));
 }
 
-fn add_dir(db: &Arc<db::Database>, siv: &mut Cursive, path: &str) {
-    if let Err(e) = db.lock().add_sample_file_dir(path.to_owned()) {
+fn add_dir(db: &Arc<db::Database>, siv: &mut Cursive) {
+    let path = siv.find_id::<views::EditView>("path").unwrap().get_content();
+    let key_path = siv.find_id::<views::EditView>("key_path").unwrap().get_content();
+    let key_path = if key_path.is_empty() { None } else { Some(key_path.as_str().to_owned()) };
+    if let Err(e) = db.lock().add_sample_file_dir(path.as_str().to_owned(), key_path) {
         siv.add_layer(views::Dialog::text(format!("Unable to add path {}: {}", path, e))
                       .dismiss_button("Back")
                       .title("Error"));
         return;
     }
+    super::audit(db, "add sample file directory", path.as_str().to_owned());
     siv.pop_layer();
 
     // Recreate the edit dialog from scratch; it's easier than adding the new entry.
@@ -261,12 +340,22 @@ fn delete_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
 }
 
 fn delete_dir(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
-    if let Err(e) = db.lock().delete_sample_file_dir(dir_id) {
-        siv.add_layer(views::Dialog::text(format!("Unable to delete dir id {}: {}", dir_id, e))
-                      .dismiss_button("Back")
-                      .title("Error"));
-        return;
-    }
+    let result = {
+        let mut l = db.lock();
+        let path = l.sample_file_dirs_by_id().get(&dir_id).map(|d| d.path.clone())
+                    .unwrap_or_default();
+        l.delete_sample_file_dir(dir_id).map(|()| path)
+    };
+    let path = match result {
+        Ok(p) => p,
+        Err(e) => {
+            siv.add_layer(views::Dialog::text(format!("Unable to delete dir id {}: {}", dir_id, e))
+                          .dismiss_button("Back")
+                          .title("Error"));
+            return;
+        },
+    };
+    super::audit(db, "delete sample file directory", path);
     siv.pop_layer();
 
     // Recreate the edit dialog from scratch; it's easier than adding the new entry.
@@ -281,6 +370,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
         let mut total_used = 0;
         let mut total_retain = 0;
         let fs_capacity;
+        let margin;
         {
             let mut l = db.lock();
             for (&id, s) in l.streams_by_id() {
@@ -293,6 +383,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
                     used: s.sample_file_bytes,
                     record: s.record,
                     retain: Some(s.retain_bytes),
+                    weight: Some(s.retain_weight),
                 });
                 total_used += s.sample_file_bytes;
                 total_retain += s.retain_bytes;
@@ -305,6 +396,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
             let stat = dir.get().unwrap().statfs().unwrap();
             fs_capacity = stat.block_size() as i64 * stat.blocks_available() as i64 + total_used;
             path = dir.path.clone();
+            margin = dir.free_space_margin_bytes;
         }
         Rc::new(RefCell::new(Model {
             dir_id,
@@ -312,13 +404,16 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
             fs_capacity,
             total_used,
             total_retain,
-            errors: (total_retain > fs_capacity) as isize,
+            margin,
+            margin_valid: true,
+            errors: 0,
             streams,
         }))
     };
 
     const RECORD_WIDTH: usize = 8;
     const BYTES_WIDTH: usize = 22;
+    const WEIGHT_WIDTH: usize = 8;
 
     let mut list = views::ListView::new();
     list.add_child(
@@ -326,7 +421,8 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
         views::LinearLayout::horizontal()
             .child(views::TextView::new("record").fixed_width(RECORD_WIDTH))
             .child(views::TextView::new("usage").fixed_width(BYTES_WIDTH))
-            .child(views::TextView::new("limit").fixed_width(BYTES_WIDTH)));
+            .child(views::TextView::new("limit").fixed_width(BYTES_WIDTH))
+            .child(views::TextView::new("weight").fixed_width(WEIGHT_WIDTH)));
     for (&id, stream) in &model.borrow().streams {
         let mut record_cb = views::Checkbox::new();
         record_cb.set_checked(stream.record);
@@ -350,9 +446,22 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
                         move |siv, _| press_change(&model, siv)
                     })
                     .fixed_width(20))
-                .child(views::TextView::new("").with_id(format!("{}_ok", id)).fixed_width(1)));
+                .child(views::TextView::new("").with_id(format!("{}_ok", id)).fixed_width(2))
+                .child(views::EditView::new()
+                    .content(stream.weight.unwrap().to_string())
+                    .on_edit({
+                        let model = model.clone();
+                        move |siv, content, _pos| edit_weight(&model, siv, id, content)
+                    })
+                    .on_submit({
+                        let model = model.clone();
+                        move |siv, _| press_change(&model, siv)
+                    })
+                    .fixed_width(WEIGHT_WIDTH - 2))
+                .child(views::TextView::new("").with_id(format!("{}_weight_ok", id))
+                       .fixed_width(1)));
     }
-    let over = model.borrow().total_retain > model.borrow().fs_capacity;
+    let over = is_over(&model.borrow());
     list.add_child(
         "total",
         views::LinearLayout::horizontal()
@@ -368,6 +477,22 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
             .child(views::DummyView{}.fixed_width(3))
             .child(views::DummyView{}.fixed_width(20))
             .child(views::TextView::new(encode_size(model.borrow().fs_capacity)).fixed_width(25)));
+    list.add_child(
+        "free space margin",
+        views::LinearLayout::horizontal()
+            .child(views::DummyView{}.fixed_width(RECORD_WIDTH + BYTES_WIDTH))
+            .child(views::EditView::new()
+                .content(model.borrow().margin.map(encode_size).unwrap_or_default())
+                .on_edit({
+                    let model = model.clone();
+                    move |siv, content, _pos| edit_margin(&model, siv, content)
+                })
+                .on_submit({
+                    let model = model.clone();
+                    move |siv, _| press_change(&model, siv)
+                })
+                .fixed_width(20))
+            .child(views::TextView::new("").with_id("margin_ok").fixed_width(1)));
     let mut change_button = views::Button::new("Change", {
         let model = model.clone();
         move |siv| press_change(&model, siv)
@@ -383,6 +508,10 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
             views::LinearLayout::vertical()
                 .child(list)
                 .child(views::DummyView)
+                .child(views::TextView::new(
+                    "Leave the free space margin blank to retain each stream's limit. Set it to \
+                     instead divide the rest of the filesystem among streams by weight."))
+                .child(views::DummyView)
                 .child(buttons))
         .title(format!("Edit retention for {}", path)));
 }
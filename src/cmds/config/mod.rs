@@ -33,11 +33,12 @@
 //! This code is a bit messy, but it's essentially a prototype. Eventually Moonfire NVR's
 //! configuration will likely be almost entirely done through a web-based UI.
 
-use base::clock;
+use base::clock::{self, Clocks};
 use cursive::Cursive;
 use cursive::views;
 use db;
 use failure::Error;
+use log::warn;
 use serde::Deserialize;
 use std::sync::Arc;
 
@@ -58,11 +59,36 @@ Options:
     --db-dir=DIR           Set the directory holding the SQLite3 index database.
                            This is typically on a flash device.
                            [default: /var/lib/moonfire-nvr/db]
+    --credentials-key-file=FILE
+                           A file holding the 32-byte key with which camera
+                           credentials are encrypted. If absent, the key is
+                           read from the base64-encoded
+                           MOONFIRE_CREDENTIALS_KEY environment variable.
+                           Required to add or edit cameras with credentials.
 "#;
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_db_dir: String,
+    flag_credentials_key_file: Option<String>,
+}
+
+/// Adds a successful configuration change to the audit log. Changes made through this interface
+/// aren't attributed to any user; whoever runs it has direct access to the database.
+fn audit(db: &db::Database, action: &str, detail: String) {
+    let entry = db::audit::Entry {
+        request: db::auth::Request {
+            when_sec: Some(db.clocks().realtime().sec),
+            user_agent: None,
+            addr: None,
+        },
+        action: action.to_owned(),
+        detail: Some(detail),
+        ..Default::default()
+    };
+    if let Err(e) = db.lock().add_audit_entry(&entry) {
+        warn!("Unable to add audit entry for {}: {}", action, e);
+    }
 }
 
 pub fn run() -> Result<(), Error> {
@@ -71,7 +97,13 @@ pub fn run() -> Result<(), Error> {
     let clocks = clock::RealClocks {};
     let db = Arc::new(db::Database::new(clocks, conn, true)?);
 
+    let credentials_key = super::credentials_key(
+        args.flag_credentials_key_file.as_ref().map(|s| s.as_str()))?;
+
     let mut siv = Cursive::ncurses()?;
+    if let Some(k) = credentials_key {
+        siv.set_user_data(k);  // see cameras::credentials_key.
+    }
     //siv.add_global_callback('q', |s| s.quit());
 
     siv.add_layer(views::Dialog::around(
@@ -28,21 +28,28 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use base::clock::Clocks;
 use cursive::Cursive;
 use cursive::traits::{Boxable, Identifiable};
 use cursive::views;
+use failure::{Error, format_err};
 use log::info;
 use std::sync::Arc;
 
 /// Builds a `UserChange` from an active `edit_user_dialog`.
 fn get_change(siv: &mut Cursive, db: &db::LockedDatabase, id: Option<i32>,
-              pw: PasswordChange) -> db::UserChange {
+              pw: PasswordChange, unlock: bool) -> Result<db::UserChange, Error> {
     let mut change = match id {
         Some(id) => db.users_by_id().get(&id).unwrap().change(),
         None => db::UserChange::add_user(String::new()),
     };
     change.username.clear();
     change.username += siv.find_id::<views::EditView>("username").unwrap().get_content().as_str();
+    let unix_uid = siv.find_id::<views::EditView>("unix_uid").unwrap().get_content();
+    change.unix_uid = match unix_uid.trim() {
+        "" => None,
+        u => Some(u.parse().map_err(|_| format_err!("invalid unix uid {:?}", u))?),
+    };
     match pw {
         PasswordChange::Leave => {},
         PasswordChange::Set => {
@@ -51,32 +58,65 @@ fn get_change(siv: &mut Cursive, db: &db::LockedDatabase, id: Option<i32>,
         },
         PasswordChange::Clear => change.clear_password(),
     };
+    if unlock {
+        change.unlock_password();
+    }
     for (id, ref mut b) in &mut [
         ("perm_view_video", &mut change.permissions.view_video),
         ("perm_read_camera_configs", &mut change.permissions.read_camera_configs),
-        ("perm_update_signals", &mut change.permissions.update_signals)] {
+        ("perm_update_signals", &mut change.permissions.update_signals),
+        ("perm_view_audit_log", &mut change.permissions.view_audit_log),
+        ("perm_admin", &mut change.permissions.admin)] {
         **b = siv.find_id::<views::Checkbox>(id).unwrap().is_checked();
         info!("{}: {}", id, **b);
     }
-    change
+    change.permissions.camera_uuids.clear();
+    let cameras = siv.find_id::<views::EditView>("perm_cameras").unwrap().get_content();
+    for name in cameras.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let c = db.cameras_by_id().values()
+                  .find(|c| c.short_name == name || c.uuid.to_string() == name)
+                  .ok_or_else(|| format_err!("no such camera {:?}", name))?;
+        change.permissions.camera_uuids.push(c.uuid.to_string());
+    }
+    Ok(change)
+}
+
+/// Returns the short names of the cameras in `permissions.camera_uuids`, comma-separated, as
+/// understood by `get_change`. Unknown uuids are shown as-is.
+fn camera_names(db: &db::LockedDatabase, permissions: &db::Permissions) -> String {
+    let names: Vec<String> = permissions.camera_uuids.iter().map(|u| {
+        db.cameras_by_id().values()
+          .find(|c| c.uuid.to_string() == *u)
+          .map(|c| c.short_name.clone())
+          .unwrap_or_else(|| u.clone())
+    }).collect();
+    names.join(", ")
 }
 
 fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>, pw: PasswordChange) {
     let result = {
         let mut l = db.lock();
-        let c = get_change(siv, &l, id, pw);
-        l.apply_user_change(c).map(|_| ())
+        let unlock = siv.find_id::<views::Checkbox>("unlock_pw")
+                        .map(|c| c.is_checked())
+                        .unwrap_or(false);
+        get_change(siv, &l, id, pw, unlock)
+            .and_then(|c| l.apply_user_change(c).map(|u| u.username.clone()))
     };
-    if let Err(e) = result {
-        siv.add_layer(views::Dialog::text(format!("Unable to apply change: {}", e))
-                      .title("Error")
-                      .dismiss_button("Abort"));
-    } else {
-        siv.pop_layer();  // get rid of the add/edit user dialog.
+    match result {
+        Err(e) => {
+            siv.add_layer(views::Dialog::text(format!("Unable to apply change: {}", e))
+                          .title("Error")
+                          .dismiss_button("Abort"));
+        },
+        Ok(username) => {
+            super::audit(db, if id.is_some() { "update user" } else { "add user" }, username);
+            siv.pop_layer();  // get rid of the add/edit user dialog.
 
-        // Recreate the "Edit users" dialog from scratch; it's easier than adding the new entry.
-        siv.pop_layer();
-        top_dialog(db, siv);
+            // Recreate the "Edit users" dialog from scratch; it's easier than adding the new
+            // entry.
+            siv.pop_layer();
+            top_dialog(db, siv);
+        },
     }
 }
 
@@ -93,19 +133,316 @@ fn actually_delete(siv: &mut Cursive, db: &Arc<db::Database>, id: i32) {
     siv.pop_layer();  // get rid of the add/edit user dialog.
     let result = {
         let mut l = db.lock();
-        l.delete_user(id)
+        let username = l.users_by_id().get(&id).map(|u| u.username.clone()).unwrap_or_default();
+        l.delete_user(id).map(|()| username)
+    };
+    match result {
+        Err(e) => {
+            siv.add_layer(views::Dialog::text(format!("Unable to delete user: {}", e))
+                          .title("Error")
+                          .dismiss_button("Abort"));
+        },
+        Ok(username) => {
+            super::audit(db, "delete user", username);
+
+            // Recreate the "Edit users" dialog from scratch; it's easier than adding the new
+            // entry.
+            siv.pop_layer();
+            top_dialog(db, siv);
+        },
+    }
+}
+
+/// Creates an API token from an active `new_token_dialog`, then shows it.
+fn press_create_token(siv: &mut Cursive, db: &Arc<db::Database>, uid: i32) {
+    let name = siv.find_id::<views::EditView>("token_name").unwrap().get_content();
+    let days = siv.find_id::<views::EditView>("token_days").unwrap().get_content();
+    let days = match days.trim() {
+        "" => None,
+        d => match d.parse::<i64>() {
+            Ok(d) if d > 0 => Some(d),
+            _ => {
+                siv.add_layer(views::Dialog::text("Expiration must be a positive number of days.")
+                              .title("Error")
+                              .dismiss_button("Abort"));
+                return;
+            },
+        },
+    };
+    let mut permissions = db::Permissions::new();
+
+    // A token is limited to the same cameras as its user.
+    permissions.camera_uuids =
+        db.lock().users_by_id().get(&uid).unwrap().permissions.camera_uuids.clone();
+    for (id, ref mut b) in &mut [
+        ("token_perm_view_video", &mut permissions.view_video),
+        ("token_perm_read_camera_configs", &mut permissions.read_camera_configs),
+        ("token_perm_update_signals", &mut permissions.update_signals),
+        ("token_perm_view_audit_log", &mut permissions.view_audit_log),
+        ("token_perm_admin", &mut permissions.admin)] {
+        **b = siv.find_id::<views::Checkbox>(id).unwrap().is_checked();
+    }
+    let now_sec = db.clocks().realtime().sec;
+    let creation = db::auth::Request {
+        when_sec: Some(now_sec),
+        user_agent: None,
+        addr: None,
+    };
+    let result = {
+        let mut l = db.lock();
+        l.make_token(creation, uid, name.as_str().to_owned(), permissions,
+                     days.map(|d| now_sec + d * 86400))
+         .map(|(t, _)| base64::encode_config(&t, base64::STANDARD_NO_PAD))
     };
+    match result {
+        Err(e) => {
+            siv.add_layer(views::Dialog::text(format!("Unable to create token: {}", e))
+                          .title("Error")
+                          .dismiss_button("Abort"));
+        },
+        Ok(token) => {
+            super::audit(db, "create API token", format!("{:?} for {}", name.as_str(),
+                                                         username(db, uid)));
+            siv.pop_layer();  // get rid of the new token dialog.
+
+            // Recreate the tokens dialog from scratch; it's easier than adding the new entry.
+            siv.pop_layer();
+            tokens_dialog(db, siv, uid);
+            siv.add_layer(views::Dialog::text(
+                    format!("Use this token in an \"Authorization: Bearer\" header. It won't \
+                            be shown again.\n\n{}", token))
+                          .title("New API token")
+                          .dismiss_button("Done"));
+        },
+    }
+}
+
+fn new_token_dialog(db: &Arc<db::Database>, siv: &mut Cursive, uid: i32) {
+    let permissions = db.lock().users_by_id().get(&uid).unwrap().permissions.clone();
+    let mut perms = views::ListView::new();
+    for (name, b) in &[("view_video", permissions.view_video),
+                       ("read_camera_configs", permissions.read_camera_configs),
+                       ("update_signals", permissions.update_signals),
+                       ("view_audit_log", permissions.view_audit_log),
+                       ("admin", permissions.admin)] {
+        let mut checkbox = views::Checkbox::new();
+        checkbox.set_checked(*b);
+        perms.add_child(name, checkbox.with_id(format!("token_perm_{}", name)));
+    }
+    siv.add_layer(views::Dialog::around(
+        views::LinearLayout::vertical()
+            .child(views::ListView::new()
+                   .child("name", views::EditView::new().with_id("token_name").full_width())
+                   .child("expires in days", views::EditView::new().with_id("token_days")))
+            .child(views::TextView::new("(leave blank to never expire)"))
+            .child(views::DummyView)
+            .child(views::TextView::new("permissions"))
+            .child(perms))
+        .title("New API token")
+        .button("Create", {
+            let db = db.clone();
+            move |s| press_create_token(s, &db, uid)
+        })
+        .dismiss_button("Cancel"));
+}
+
+fn press_revoke_token(siv: &mut Cursive, db: &Arc<db::Database>, uid: i32,
+                      hash: db::auth::SessionHash, name: String) {
+    siv.add_layer(views::Dialog::text(format!("Revoke API token {}?", name))
+                  .button("Revoke", {
+                      let db = db.clone();
+                      move |s| actually_revoke_token(s, &db, uid, &hash)
+                  })
+                  .title("Revoke API token").dismiss_button("Cancel"));
+}
+
+fn actually_revoke_token(siv: &mut Cursive, db: &Arc<db::Database>, uid: i32,
+                         hash: &db::auth::SessionHash) {
+    siv.pop_layer();  // get rid of the confirmation dialog.
+    let req = db::auth::Request {
+        when_sec: Some(db.clocks().realtime().sec),
+        user_agent: None,
+        addr: None,
+    };
+    let result = db.lock().revoke_session(db::auth::RevocationReason::UserRevoked, None, req,
+                                          hash);
     if let Err(e) = result {
-        siv.add_layer(views::Dialog::text(format!("Unable to delete user: {}", e))
+        siv.add_layer(views::Dialog::text(format!("Unable to revoke token: {}", e))
                       .title("Error")
                       .dismiss_button("Abort"));
     } else {
-        // Recreate the "Edit users" dialog from scratch; it's easier than adding the new entry.
+        super::audit(db, "revoke API token", username(db, uid));
         siv.pop_layer();
-        top_dialog(db, siv);
+        tokens_dialog(db, siv, uid);
+    }
+}
+
+/// Lists a user's valid API tokens, allowing creating new ones and revoking existing ones.
+fn tokens_dialog(db: &Arc<db::Database>, siv: &mut Cursive, uid: i32) {
+    let now_sec = db.clocks().realtime().sec;
+    let mut items = Vec::new();
+    let result = db.lock().list_tokens(uid, Some(now_sec)).map(|tokens| {
+        for &(hash, s) in &tokens {
+            let name = s.description().unwrap_or("").to_owned();
+            let expiration = match s.expiration_sec() {
+                None => "never expires".to_owned(),
+                Some(e) => format!("expires {}",
+                                   db::recording::Time(e * db::recording::TIME_UNITS_PER_SEC)),
+            };
+            items.push((format!("{} ({}, used {} times)", name, expiration, s.use_count()),
+                        Some((hash, name))));
+        }
+    });
+    if let Err(e) = result {
+        siv.add_layer(views::Dialog::text(format!("Unable to list tokens: {}", e))
+                      .title("Error")
+                      .dismiss_button("Abort"));
+        return;
+    }
+    siv.add_layer(views::Dialog::around(
+        views::SelectView::new()
+            .on_submit({
+                let db = db.clone();
+                move |siv, item: &Option<(db::auth::SessionHash, String)>| match item {
+                    None => new_token_dialog(&db, siv, uid),
+                    Some((hash, name)) => press_revoke_token(siv, &db, uid, *hash, name.clone()),
+                }
+            })
+            .item("<new token>".to_string(), None)
+            .with_all(items)
+            .full_width())
+        .dismiss_button("Done")
+        .title("API tokens"));
+}
+
+fn show_recovery_codes(siv: &mut Cursive, codes: &[String]) {
+    siv.add_layer(views::Dialog::text(
+            format!("Store these recovery codes somewhere safe. Each may be used once in place of \
+                    a code from the authenticator app. They won't be shown again.\n\n{}",
+                    codes.join("\n")))
+                  .title("Recovery codes")
+                  .dismiss_button("Done"));
+}
+
+/// Applies a change to the two-factor authentication of user `uid`, returning its result.
+fn change_two_factor<F, T>(db: &Arc<db::Database>, uid: i32, f: F) -> Result<T, Error>
+where F: FnOnce(&mut db::UserChange) -> T {
+    let mut l = db.lock();
+    let mut c = l.users_by_id().get(&uid).unwrap().change();
+    let t = f(&mut c);
+    let username = l.apply_user_change(c)?.username.clone();
+    drop(l);
+    super::audit(db, "change two-factor authentication", username);
+    Ok(t)
+}
+
+/// Returns the name of user `uid`, for the audit log.
+fn username(db: &db::Database, uid: i32) -> String {
+    db.lock().users_by_id().get(&uid).map(|u| u.username.clone()).unwrap_or_default()
+}
+
+/// Enables two-factor authentication from an active `enroll_dialog`, if the code is correct.
+fn press_enable_totp(siv: &mut Cursive, db: &Arc<db::Database>, uid: i32, secret: &[u8]) {
+    let code = siv.find_id::<views::EditView>("totp_code").unwrap().get_content();
+    let now_sec = db.clocks().realtime().sec;
+    let result = db::totp::verify(secret, &code, now_sec, None).and_then(|step| match step {
+        None => Err(format_err!("incorrect code. Check that the clocks of this machine and the \
+                                authenticator app are set correctly.")),
+        Some(_) => change_two_factor(db, uid, |c| c.set_totp_secret(secret.to_vec())),
+    });
+    match result {
+        Err(e) => {
+            siv.add_layer(views::Dialog::text(format!("Unable to enable: {}", e))
+                          .title("Error")
+                          .dismiss_button("Abort"));
+        },
+        Ok(codes) => {
+            siv.pop_layer();  // get rid of the enroll dialog.
+            show_recovery_codes(siv, &codes);
+        },
+    }
+}
+
+/// Enrolls user `uid` in two-factor authentication with a new secret, once confirmed by a code.
+fn enroll_dialog(db: &Arc<db::Database>, siv: &mut Cursive, uid: i32, username: &str) {
+    let secret = db::totp::generate_secret();
+    let text = format!(
+        "Add this account to an authenticator app by scanning a QR code of the URI below (as \
+        shown by \"qrencode -t ansiutf8 '<URI>'\") or by entering the secret key.\n\n\
+        URI: {}\n\nSecret key: {}\n\nThen enter the app's current code to confirm.",
+        db::totp::uri(&secret, "Moonfire NVR", username), db::totp::base32(&secret));
+    siv.add_layer(views::Dialog::around(
+        views::LinearLayout::vertical()
+            .child(views::TextView::new(text))
+            .child(views::DummyView)
+            .child(views::ListView::new()
+                   .child("code", views::EditView::new().with_id("totp_code").fixed_width(10))))
+        .title("Enable two-factor authentication")
+        .button("Enable", {
+            let db = db.clone();
+            move |s| press_enable_totp(s, &db, uid, &secret)
+        })
+        .dismiss_button("Cancel"));
+}
+
+fn press_new_recovery_codes(siv: &mut Cursive, db: &Arc<db::Database>, uid: i32) {
+    match change_two_factor(db, uid, |c| c.regenerate_recovery_codes()) {
+        Err(e) => {
+            siv.add_layer(views::Dialog::text(format!("Unable to apply change: {}", e))
+                          .title("Error")
+                          .dismiss_button("Abort"));
+        },
+        Ok(codes) => {
+            siv.pop_layer();  // get rid of the two-factor dialog.
+            show_recovery_codes(siv, &codes);
+        },
+    }
+}
+
+fn actually_disable_totp(siv: &mut Cursive, db: &Arc<db::Database>, uid: i32) {
+    siv.pop_layer();  // get rid of the confirmation dialog.
+    if let Err(e) = change_two_factor(db, uid, |c| c.clear_totp()) {
+        siv.add_layer(views::Dialog::text(format!("Unable to apply change: {}", e))
+                      .title("Error")
+                      .dismiss_button("Abort"));
+    } else {
+        siv.pop_layer();  // get rid of the two-factor dialog.
     }
 }
 
+/// Shows whether user `uid` is enrolled in two-factor authentication, allowing changes.
+fn two_factor_dialog(db: &Arc<db::Database>, siv: &mut Cursive, uid: i32) {
+    let (username, enrolled, codes_left) = {
+        let l = db.lock();
+        let u = l.users_by_id().get(&uid).unwrap();
+        (u.username.clone(), u.has_totp(), u.recovery_codes_left())
+    };
+    if !enrolled {
+        return enroll_dialog(db, siv, uid, &username);
+    }
+    siv.add_layer(views::Dialog::text(
+            format!("Password logins by {} also require a code from an authenticator app or \
+                    one of {} remaining recovery codes.", username, codes_left))
+        .title("Two-factor authentication")
+        .button("New recovery codes", {
+            let db = db.clone();
+            move |s| press_new_recovery_codes(s, &db, uid)
+        })
+        .button("Disable", {
+            let db = db.clone();
+            move |s| {
+                s.add_layer(views::Dialog::text("Disable two-factor authentication?")
+                            .button("Disable", {
+                                let db = db.clone();
+                                move |s| actually_disable_totp(s, &db, uid)
+                            })
+                            .title("Disable two-factor authentication")
+                            .dismiss_button("Cancel"));
+            }
+        })
+        .dismiss_button("Done"));
+}
+
 #[derive(Copy, Clone)]
 enum PasswordChange {
     Leave,
@@ -120,26 +457,45 @@ fn select_set(siv: &mut Cursive) {
 /// Adds or updates a user.
 /// (The former if `item` is None; the latter otherwise.)
 fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>) {
-    let (username, id_str, has_password, permissions);
+    let (username, id_str, unix_uid, has_password, password_locked, password_failure_count,
+         permissions, cameras);
     let mut pw_group = views::RadioGroup::new();
     {
         let l = db.lock();
         let u = item.map(|id| l.users_by_id().get(&id).unwrap());
         username = u.map(|u| u.username.clone()).unwrap_or(String::new());
         id_str = item.map(|id| id.to_string()).unwrap_or("<new>".to_string());
+        unix_uid = u.and_then(|u| u.unix_uid).map(|u| u.to_string()).unwrap_or(String::new());
         has_password = u.map(|u| u.has_password()).unwrap_or(false);
+        password_locked = u.map(|u| u.password_locked()).unwrap_or(false);
+        password_failure_count = u.map(|u| u.password_failure_count).unwrap_or(0);
         permissions = u.map(|u| u.permissions.clone()).unwrap_or(db::Permissions::default());
+        cameras = camera_names(&l, &permissions);
     }
     let top_list = views::ListView::new()
         .child("id", views::TextView::new(id_str))
         .child("username", views::EditView::new()
                .content(username.clone())
-               .with_id("username"));
+               .with_id("username"))
+        .child("unix uid", views::EditView::new()
+               .content(unix_uid)
+               .with_id("unix_uid"));
     let mut layout = views::LinearLayout::vertical()
         .child(top_list)
         .child(views::DummyView)
         .child(views::TextView::new("password"));
 
+    if password_locked || password_failure_count > 0 {
+        let status = if password_locked {
+            format!("LOCKED after {} failed attempts", password_failure_count)
+        } else {
+            format!("{} consecutive failed attempts", password_failure_count)
+        };
+        layout.add_child(views::LinearLayout::horizontal()
+                         .child(views::Checkbox::new().with_id("unlock_pw"))
+                         .child(views::DummyView)
+                         .child(views::TextView::new(format!("Unlock ({})", status))));
+    }
     if has_password {
         layout.add_child(pw_group.button(PasswordChange::Leave, "Leave set"));
         layout.add_child(pw_group.button(PasswordChange::Clear, "Clear"));
@@ -168,12 +524,19 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
     let mut perms = views::ListView::new();
     for (name, b) in &[("view_video", permissions.view_video),
                        ("read_camera_configs", permissions.read_camera_configs),
-                       ("update_signals", permissions.update_signals)] {
+                       ("update_signals", permissions.update_signals),
+                       ("view_audit_log", permissions.view_audit_log),
+                       ("admin", permissions.admin)] {
         let mut checkbox = views::Checkbox::new();
         checkbox.set_checked(*b);
         perms.add_child(name, checkbox.with_id(format!("perm_{}", name)));
     }
+    perms.add_child("cameras", views::EditView::new()
+                    .content(cameras)
+                    .with_id("perm_cameras")
+                    .full_width());
     layout.add_child(perms);
+    layout.add_child(views::TextView::new("(comma-separated; leave blank for all cameras)"));
 
     let dialog = views::Dialog::around(layout);
     let dialog = if let Some(id) = item {
@@ -182,6 +545,14 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
                   let db = db.clone();
                   move |s| press_edit(s, &db, item, *pw_group.selection())
               })
+              .button("Tokens", {
+                  let db = db.clone();
+                  move |s| tokens_dialog(&db, s, id)
+              })
+              .button("Two-factor", {
+                  let db = db.clone();
+                  move |s| two_factor_dialog(&db, s, id)
+              })
               .button("Delete", {
                   let db = db.clone();
                   move |s| press_delete(s, &db, id, username.clone())
@@ -207,7 +578,10 @@ pub fn top_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
             .with_all(db.lock()
                         .users_by_id()
                         .iter()
-                        .map(|(&id, user)| (format!("{}: {}", id, user.username), Some(id))))
+                        .map(|(&id, user)| {
+                            let locked = if user.password_locked() { " (locked)" } else { "" };
+                            (format!("{}: {}{}", id, user.username, locked), Some(id))
+                        }))
             .full_width())
         .dismiss_button("Done")
         .title("Edit users"));
@@ -0,0 +1,110 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to move a stream's recordings to another sample file directory.
+
+use base::clock;
+use db::writer;
+use failure::{Error, bail, format_err};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+static USAGE: &'static str = r#"
+Moves a stream's recordings to another sample file directory.
+
+This copies each sample file, verifies the copy, switches the stream to the
+new directory, and removes the old files. The daemon must be stopped while
+this runs. If interrupted, run the same command again to resume.
+
+Usage:
+
+    moonfire-nvr migrate-stream [options] --stream=STREAM --to-dir=PATH
+    moonfire-nvr migrate-stream --help
+
+Options:
+
+    --db-dir=DIR           Set the directory holding the SQLite3 index database.
+                           This is typically on a flash device.
+                           [default: /var/lib/moonfire-nvr/db]
+    --stream=STREAM        The stream to move, as CAMERA/TYPE, where CAMERA is
+                           a camera's short name or uuid and TYPE is "main"
+                           or "sub".
+    --to-dir=PATH          The path of the destination sample file directory.
+                           It must already be added (see "moonfire-nvr config").
+"#;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_db_dir: String,
+    flag_stream: String,
+    flag_to_dir: String,
+}
+
+/// Finds the id of the stream named by `--stream`.
+fn lookup_stream(l: &db::LockedDatabase, name: &str) -> Result<i32, Error> {
+    let slash = name.rfind('/')
+                    .ok_or_else(|| format_err!("--stream={:?} should be CAMERA/TYPE", name))?;
+    let (camera, type_) = (&name[..slash], &name[slash+1..]);
+    let type_ = db::StreamType::parse(type_)
+        .ok_or_else(|| format_err!("unknown stream type {:?}", type_))?;
+    let c = match Uuid::parse_str(camera).ok().and_then(|u| l.get_camera(u)) {
+        Some(c) => c,
+        None => {
+            let mut matches = l.cameras_by_id().values().filter(|c| c.short_name == camera);
+            match (matches.next(), matches.next()) {
+                (Some(c), None) => c,
+                (None, _) => bail!("no such camera {:?}", camera),
+                (Some(_), Some(_)) => bail!("camera name {:?} is ambiguous; use its uuid", camera),
+            }
+        },
+    };
+    c.streams[type_.index()].ok_or_else(|| format_err!("camera {:?} has no {} stream",
+                                                        camera, type_.as_str()))
+}
+
+pub fn run() -> Result<(), Error> {
+    let args: Args = super::parse_args(USAGE)?;
+    let (_db_dir, conn) = super::open_conn(&args.flag_db_dir, super::OpenMode::ReadWrite)?;
+    let clocks = clock::RealClocks {};
+    let db = Arc::new(db::Database::new(clocks, conn, true)?);
+    let (stream_id, to_dir_id) = {
+        let l = db.lock();
+        let stream_id = lookup_stream(&l, &args.flag_stream)?;
+        let to_dir_id = l.sample_file_dirs_by_id()
+                         .values()
+                         .find(|d| d.path == args.flag_to_dir)
+                         .map(|d| d.id)
+                         .ok_or_else(|| format_err!("no sample file dir with path {:?}",
+                                                    &args.flag_to_dir))?;
+        (stream_id, to_dir_id)
+    };
+    writer::migrate_stream(db, stream_id, to_dir_id, &[])
+}
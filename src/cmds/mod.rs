@@ -28,9 +28,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use db::dir;
+use db::{crypto, dir};
 use docopt;
-use failure::{Error, Fail};
+use failure::{Error, Fail, bail, format_err};
 use nix::fcntl::FlockArg;
 use rusqlite;
 use serde::Deserialize;
@@ -40,10 +40,12 @@ mod check;
 mod config;
 mod login;
 mod init;
+mod migrate_stream;
 mod run;
 mod sql;
 mod ts;
 mod upgrade;
+mod verify_export;
 
 #[derive(Debug, Deserialize)]
 pub enum Command {
@@ -51,10 +53,14 @@ pub enum Command {
     Config,
     Login,
     Init,
+    #[serde(rename = "migrate-stream")]
+    MigrateStream,
     Run,
     Sql,
     Ts,
     Upgrade,
+    #[serde(rename = "verify-export")]
+    VerifyExport,
 }
 
 impl Command {
@@ -64,10 +70,12 @@ impl Command {
             Command::Config => config::run(),
             Command::Login => login::run(),
             Command::Init => init::run(),
+            Command::MigrateStream => migrate_stream::run(),
             Command::Run => run::run(),
             Command::Sql => sql::run(),
             Command::Ts => ts::run(),
             Command::Upgrade => upgrade::run(),
+            Command::VerifyExport => verify_export::run(),
         }
     }
 }
@@ -109,6 +117,21 @@ fn open_conn(db_dir: &str, mode: OpenMode) -> Result<(dir::Fd, rusqlite::Connect
     Ok((dir, conn))
 }
 
+/// Returns the key for sealing camera credentials, read from `file` if supplied or else from the
+/// base64-encoded `MOONFIRE_CREDENTIALS_KEY` environment variable. Returns `None` if neither is
+/// set.
+fn credentials_key(file: Option<&str>) -> Result<Option<crypto::Key>, Error> {
+    if let Some(f) = file {
+        return Ok(Some(crypto::Key::read(f)?));
+    }
+    match ::std::env::var("MOONFIRE_CREDENTIALS_KEY") {
+        Ok(k) => Ok(Some(crypto::Key::from_base64(&k).map_err(
+            |e| format_err!("invalid MOONFIRE_CREDENTIALS_KEY: {}", e))?)),
+        Err(::std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => bail!("invalid MOONFIRE_CREDENTIALS_KEY: {}", e),
+    }
+}
+
 fn parse_args<'a, T>(usage: &str) -> Result<T, Error> where T: ::serde::Deserialize<'a> {
     Ok(docopt::Docopt::new(usage)
                       .and_then(|d| d.deserialize())
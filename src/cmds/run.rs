@@ -29,15 +29,19 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use base::clock;
+use crate::export;
+use crate::oidc;
+use crate::rtsp;
 use crate::stream;
 use crate::streamer;
+use crate::tls;
 use crate::web;
 use db::{dir, writer};
 use failure::{Error, ResultExt, bail};
 use fnv::FnvHashMap;
 use futures::future::FutureExt;
 use hyper::service::{make_service_fn, service_fn};
-use log::{info, warn};
+use log::{debug, info, warn};
 use serde::Deserialize;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -68,6 +72,26 @@ Options:
                            [default: /usr/local/lib/moonfire-nvr/ui]
     --http-addr=ADDR       Set the bind address for the unencrypted HTTP server.
                            [default: 0.0.0.0:8080]
+    --https-addr=ADDR      Also serve HTTPS at the given bind address, using
+                           the certificate chain and private key given by
+                           --tls-cert and --tls-key. Both are reloaded on
+                           SIGHUP, as after renewing the certificate. Login
+                           sessions created over HTTPS use Secure cookies.
+    --tls-cert=FILE        A PEM-encoded certificate chain, leaf first.
+    --tls-key=FILE         A PEM-encoded private key.
+    --http-redirect-to-https
+                           Redirect requests to --http-addr to the same URL
+                           on the --https-addr server.
+    --http-unix-socket=PATH
+                           Also serve HTTP on a Unix domain socket at PATH.
+                           Peers are authenticated by uid, with no password
+                           or cookie: a user whose unix_uid matches gets that
+                           user's permissions, and the uid running Moonfire
+                           NVR gets all permissions. Restrict access to the
+                           socket via its directory's permissions.
+    --rtsp-addr=ADDR       Set the bind address for the RTSP server, which
+                           re-streams cameras live and replays recordings.
+                           If absent, there is no RTSP server.
     --read-only            Forces read-only mode / disables recording.
     --allow-unauthenticated-permissions=PERMISSIONS
                            Allow unauthenticated access to the web interface,
@@ -81,16 +105,91 @@ Options:
                            your proxy server is configured to set them and that
                            no untrusted requests bypass the proxy server.
                            You may want to specify --http-addr=127.0.0.1:8080.
+    --trust-user-hdr=HEADER
+                           Trust the given header (such as X-Forwarded-User) to
+                           name the user on requests from the proxy servers
+                           given by --trusted-proxies. Such requests get that
+                           user's permissions without a session cookie. Set
+                           this only if the proxy authenticates every request
+                           and sets or removes the header itself.
+    --trusted-proxies=ADDRS
+                           Comma-separated IP addresses of the proxy servers
+                           trusted to set --trust-user-hdr, as seen by
+                           Moonfire NVR (such as 127.0.0.1).
+    --credentials-key-file=FILE
+                           A file holding the 32-byte key with which camera
+                           credentials are encrypted. If absent, the key is
+                           read from the base64-encoded
+                           MOONFIRE_CREDENTIALS_KEY environment variable.
+    --password-lockout-threshold=N
+                           Lock a user's password after N consecutive failed
+                           attempts, until an administrator unlocks it via
+                           "moonfire-nvr config". Regardless, attempts are
+                           throttled with increasing delays after repeated
+                           failures for a user or from a peer address.
+    --export-key=FILE      A PEM-encoded Ed25519 private key with which to sign
+                           evidence export bundles. If FILE does not exist, a
+                           new key is generated and written there, along with
+                           its public half in FILE.pub. If absent, exports are
+                           unavailable.
+    --session-max-age-hours=H
+                           Expire sessions H hours after they are created.
+    --session-idle-hours=H Expire sessions which have gone unused for H hours.
+    --max-sessions-per-user=N
+                           Allow each user at most N sessions. When logging
+                           in would exceed this, the user's least recently
+                           used sessions are revoked.
+    --oidc-issuer=URL      Allow logging in via the OpenID Connect provider
+                           with the given issuer URL, such as
+                           https://accounts.google.com. Requires
+                           --oidc-client-id, --oidc-client-secret-file, and
+                           --oidc-redirect-uri.
+    --oidc-client-id=ID    The client id registered with the provider.
+    --oidc-client-secret-file=FILE
+                           A file holding the client secret registered with
+                           the provider.
+    --oidc-redirect-uri=URL
+                           The redirect URI registered with the provider, which
+                           must be this server's /api/oidc/callback, such as
+                           https://nvr.example.com/api/oidc/callback.
+    --oidc-username-claim=CLAIM
+                           The ID token claim to match against usernames.
+                           [default: preferred_username]
+    --oidc-auto-provision-permissions=PERMISSIONS
+                           Add users unknown to Moonfire NVR on their first
+                           OpenID Connect login, with the given permissions
+                           (a text Permissions protobuf). If absent, such
+                           logins are rejected.
 "#;
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_db_dir: String,
     flag_http_addr: String,
+    flag_http_unix_socket: Option<String>,
+    flag_https_addr: Option<String>,
+    flag_tls_cert: Option<String>,
+    flag_tls_key: Option<String>,
+    flag_http_redirect_to_https: bool,
+    flag_rtsp_addr: Option<String>,
     flag_ui_dir: String,
     flag_read_only: bool,
     flag_allow_unauthenticated_permissions: Option<String>,
     flag_trust_forward_hdrs: bool,
+    flag_trust_user_hdr: Option<String>,
+    flag_trusted_proxies: Option<String>,
+    flag_credentials_key_file: Option<String>,
+    flag_export_key: Option<String>,
+    flag_password_lockout_threshold: Option<i64>,
+    flag_session_max_age_hours: Option<i64>,
+    flag_session_idle_hours: Option<i64>,
+    flag_max_sessions_per_user: Option<usize>,
+    flag_oidc_issuer: Option<String>,
+    flag_oidc_client_id: Option<String>,
+    flag_oidc_client_secret_file: Option<String>,
+    flag_oidc_redirect_uri: Option<String>,
+    flag_oidc_username_claim: String,
+    flag_oidc_auto_provision_permissions: Option<String>,
 }
 
 fn trim_zoneinfo(p: &str) -> &str {
@@ -160,15 +259,76 @@ fn resolve_zone() -> Result<String, Error> {
     }
 }
 
+/// Binds a Unix domain socket at `path`, first removing any stale socket from a previous run.
+fn bind_unix(path: &str) -> Result<tokio::net::UnixListener, Error> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => {
+            std::fs::remove_file(path)
+                .with_context(|_| format!("Unable to remove stale socket {}", path))?;
+        },
+        Ok(_) => bail!("--http-unix-socket={} exists and is not a socket", path),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => bail!("Unable to stat {}: {}", path, e),
+    }
+    Ok(tokio::net::UnixListener::bind(path)
+       .with_context(|_| format!("Unable to bind --http-unix-socket={}", path))?)
+}
+
+/// Serves HTTPS connections from `listener` until `shutdown` fires.
+///
+/// Unlike the plain HTTP server, this stops accepting connections on shutdown but doesn't wait
+/// for in-flight requests to finish.
+async fn serve_https(mut listener: tokio::net::TcpListener, acceptor: Arc<tls::Acceptor>,
+                     service: web::Service, mut shutdown: futures::channel::oneshot::Receiver<()>) {
+    loop {
+        let r = futures::select! {
+            r = listener.accept().fuse() => r,
+            _ = shutdown => return,
+        };
+        let (conn, peer) = match r {
+            Ok(c) => c,
+            Err(e) => {
+                // Likely a transient resource exhaustion error such as EMFILE.
+                warn!("Unable to accept HTTPS connection: {}", e);
+                tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+                continue;
+            },
+        };
+        let _ = conn.set_nodelay(true);
+
+        // Handshake in a separate task so that a slow peer doesn't hold up other connections.
+        let tls = acceptor.get();
+        let mut service = service.clone();
+        tokio::spawn(async move {
+            let conn = match tls.accept(conn).await {
+                Ok(c) => c,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                },
+            };
+            let p = Some(web::Peer::Addr(peer.ip()));
+            let svc = service_fn(move |req| Pin::from(service.serve(req, p, true)));
+            let conn = hyper::server::conn::Http::new().serve_connection(conn, svc).with_upgrades();
+            if let Err(e) = conn.await {
+                debug!("HTTPS connection with {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
 struct Syncer {
     dir: Arc<dir::SampleFileDir>,
-    channel: writer::SyncerChannel<::std::fs::File>,
+    channel: writer::SyncerChannel<dir::SampleFileWriter>,
     join: thread::JoinHandle<()>,
 }
 
 #[tokio::main]
 pub async fn run() -> Result<(), Error> {
     let args: Args = super::parse_args(USAGE)?;
+    let credentials_key = super::credentials_key(
+        args.flag_credentials_key_file.as_ref().map(|s| s.as_str()))?;
     let clocks = clock::RealClocks {};
     let (_db_dir, conn) = super::open_conn(
         &args.flag_db_dir,
@@ -183,6 +343,15 @@ pub async fn run() -> Result<(), Error> {
         l.open_sample_file_dirs(&dirs_to_open)?;
     }
     info!("Directories are opened.");
+    {
+        let mut l = db.lock();
+        l.set_password_lockout_threshold(args.flag_password_lockout_threshold);
+        l.set_session_limits(db::auth::SessionLimits {
+            max_age_sec: args.flag_session_max_age_hours.map(|h| h * 3600),
+            max_idle_sec: args.flag_session_idle_hours.map(|h| h * 3600),
+            max_per_user: args.flag_max_sessions_per_user,
+        });
+    }
 
     let time_zone_name = resolve_zone()?;
     info!("Resolved timezone: {}", &time_zone_name);
@@ -190,12 +359,86 @@ pub async fn run() -> Result<(), Error> {
         .map(|s| protobuf::text_format::parse_from_str(&s))
         .transpose()
         .context("Unable to parse --allow-unauthenticated-permissions")?;
+    let export_key = args.flag_export_key
+        .map(|p| export::SigningKey::open_or_create(&p).map(Arc::new))
+        .transpose()?;
+    let https_addr: Option<std::net::SocketAddr> = args.flag_https_addr.as_ref()
+        .map(|a| a.parse().with_context(|_| format!("Unable to parse --https-addr={}", a)))
+        .transpose()?;
+    let tls_acceptor = match (&args.flag_tls_cert, &args.flag_tls_key, https_addr) {
+        (Some(c), Some(k), Some(_)) => Some(Arc::new(tls::Acceptor::new(c.into(), k.into())?)),
+        (None, None, None) => None,
+        _ => bail!("--https-addr, --tls-cert, and --tls-key must be specified together"),
+    };
+    if args.flag_http_redirect_to_https && https_addr.is_none() {
+        bail!("--http-redirect-to-https requires --https-addr");
+    }
+    let trusted_user_hdr = args.flag_trust_user_hdr.as_ref()
+        .map(|h| http::header::HeaderName::from_bytes(h.as_bytes())
+                 .with_context(|_| format!("Unable to parse --trust-user-hdr={}", h)))
+        .transpose()?;
+    let trusted_proxies = match (&trusted_user_hdr, &args.flag_trusted_proxies) {
+        (Some(_), Some(a)) => a.split(',')
+            .map(|a| a.trim().parse().with_context(|_| format!("Unable to parse proxy {:?}", a)))
+            .collect::<Result<Vec<std::net::IpAddr>, _>>()?,
+        (None, None) => Vec::new(),
+        _ => bail!("--trust-user-hdr and --trusted-proxies must be specified together"),
+    };
+    let oidc = match (args.flag_oidc_issuer, args.flag_oidc_client_id,
+                      args.flag_oidc_client_secret_file, args.flag_oidc_redirect_uri) {
+        (Some(issuer), Some(client_id), Some(secret_file), Some(redirect_uri)) => {
+            let client_secret = std::fs::read_to_string(&secret_file)
+                .with_context(|_| format!("Unable to read --oidc-client-secret-file={}",
+                                          secret_file))?
+                .trim()
+                .to_owned();
+            let auto_provision = args.flag_oidc_auto_provision_permissions
+                .map(|s| protobuf::text_format::parse_from_str(&s))
+                .transpose()
+                .context("Unable to parse --oidc-auto-provision-permissions")?;
+            Some(Arc::new(oidc::Client::new(oidc::Config {
+                issuer,
+                client_id,
+                client_secret,
+                redirect_uri,
+                username_claim: args.flag_oidc_username_claim,
+                auto_provision,
+            })))
+        },
+        (None, None, None, None) => {
+            if args.flag_oidc_auto_provision_permissions.is_some() {
+                bail!("--oidc-auto-provision-permissions requires --oidc-issuer");
+            }
+            None
+        },
+        _ => bail!("--oidc-issuer, --oidc-client-id, --oidc-client-secret-file, and \
+                    --oidc-redirect-uri must be specified together"),
+    };
+    let live_frames = Arc::new(rtsp::LiveFrames::default());
+    let rtsp_server = if args.flag_rtsp_addr.is_some() {
+        Some(rtsp::Server::new(rtsp::Config {
+            db: db.clone(),
+            live_frames: live_frames.clone(),
+            allow_unauthenticated_permissions: allow_unauthenticated_permissions.clone(),
+        })?)
+    } else {
+        None
+    };
     let s = web::Service::new(web::Config {
         db: db.clone(),
         ui_dir: Some(&args.flag_ui_dir),
         allow_unauthenticated_permissions,
         trust_forward_hdrs: args.flag_trust_forward_hdrs,
         time_zone_name,
+        export_key,
+        https_redirect_port: if args.flag_http_redirect_to_https {
+            https_addr.map(|a| a.port())
+        } else {
+            None
+        },
+        oidc,
+        trusted_user_hdr,
+        trusted_proxies,
     })?;
 
     // Start a streamer for each stream.
@@ -210,6 +453,8 @@ pub async fn run() -> Result<(), Error> {
             db: &db,
             opener: &*stream::FFMPEG,
             shutdown: &shutdown_streamers,
+            credentials_key: credentials_key.as_ref(),
+            live_frames: &live_frames,
         };
 
         // Get the directories that need syncers.
@@ -266,12 +511,81 @@ pub async fn run() -> Result<(), Error> {
         Some(syncers)
     } else { None };
 
+    // Start the web interface on the Unix domain socket, if requested.
+    let (unix_shutdown_tx, unix_shutdown_rx) = futures::channel::oneshot::channel();
+    let unix_handle = match args.flag_http_unix_socket {
+        None => None,
+        Some(ref p) => {
+            let listener = bind_unix(p)?;
+            let make_svc = make_service_fn({
+                let s = s.clone();
+                move |conn: &tokio::net::UnixStream| {
+                    let peer = match conn.peer_cred() {
+                        Ok(c) => Some(web::Peer::Uid(c.uid)),
+                        Err(e) => {
+                            warn!("Unable to get Unix socket peer credentials: {}", e);
+                            None
+                        },
+                    };
+                    futures::future::ok::<_, std::convert::Infallible>(service_fn({
+                        let mut s = s.clone();
+                        move |req| Pin::from(s.serve(req, peer, false))
+                    }))
+                }
+            });
+            let incoming = futures::stream::unfold(listener, |mut l| async move {
+                loop {
+                    match l.accept().await {
+                        Ok((conn, _)) => return Some((Ok::<_, std::io::Error>(conn), l)),
+                        Err(e) => {
+                            // Likely a transient resource exhaustion error such as EMFILE.
+                            warn!("Unable to accept Unix socket connection: {}", e);
+                            tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+                        },
+                    }
+                }
+            });
+            let server = ::hyper::server::Server::builder(
+                hyper::server::accept::from_stream(incoming))
+                .serve(make_svc)
+                .with_graceful_shutdown(unix_shutdown_rx.map(|_| ()));
+            info!("Ready to serve HTTP requests on Unix socket {}", p);
+            Some(tokio::spawn(server))
+        },
+    };
+
+    // Start the HTTPS server, if requested.
+    let (https_shutdown_tx, https_shutdown_rx) = futures::channel::oneshot::channel();
+    let https_handle = match (https_addr, tls_acceptor) {
+        (Some(a), Some(acceptor)) => {
+            let listener = tokio::net::TcpListener::bind(a).await
+                .with_context(|_| format!("Unable to bind --https-addr={}", a))?;
+            let mut hup = signal(SignalKind::hangup())?;
+            tokio::spawn({
+                let acceptor = acceptor.clone();
+                async move {
+                    while let Some(()) = hup.recv().await {
+                        match acceptor.reload() {
+                            Ok(()) => info!("Reloaded TLS certificate and key"),
+                            Err(e) => warn!("Unable to reload TLS certificate and key; \
+                                             continuing with the old ones: {}", e),
+                        }
+                    }
+                }
+            });
+            info!("Ready to serve HTTPS requests on {}", a);
+            Some(tokio::spawn(serve_https(listener, acceptor, s.clone(), https_shutdown_rx)))
+        },
+        _ => None,
+    };
+
     // Start the web interface.
     let addr = args.flag_http_addr.parse().unwrap();
-    let make_svc = make_service_fn(move |_conn| {
+    let make_svc = make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+        let peer = conn.remote_addr().ip();
         futures::future::ok::<_, std::convert::Infallible>(service_fn({
             let mut s = s.clone();
-            move |req| Pin::from(s.serve(req))
+            move |req| Pin::from(s.serve(req, Some(web::Peer::Addr(peer)), false))
         }))
     });
     let server = ::hyper::server::Server::bind(&addr)
@@ -288,9 +602,24 @@ pub async fn run() -> Result<(), Error> {
     let server = server.with_graceful_shutdown(shutdown_rx.map(|_| ()));
     let server_handle = tokio::spawn(server);
 
+    // Start the RTSP server, if requested.
+    let (rtsp_shutdown_tx, rtsp_shutdown_rx) = futures::channel::oneshot::channel();
+    let rtsp_handle = match (rtsp_server, args.flag_rtsp_addr) {
+        (Some(s), Some(a)) => {
+            let listener = tokio::net::TcpListener::bind(a.as_str()).await
+                .with_context(|_| format!("Unable to bind --rtsp-addr={}", a))?;
+            info!("Ready to serve RTSP requests on {}", a);
+            Some(tokio::spawn(s.serve(listener, rtsp_shutdown_rx)))
+        },
+        _ => None,
+    };
+
     info!("Ready to serve HTTP requests");
     shutdown.await;
     shutdown_tx.send(()).unwrap();
+    let _ = unix_shutdown_tx.send(());
+    let _ = https_shutdown_tx.send(());
+    let _ = rtsp_shutdown_tx.send(());
 
     info!("Shutting down streamers.");
     shutdown_streamers.store(true, Ordering::SeqCst);
@@ -312,6 +641,15 @@ pub async fn run() -> Result<(), Error> {
 
     info!("Waiting for HTTP requests to finish.");
     server_handle.await??;
+    if let Some(h) = unix_handle {
+        h.await??;
+    }
+    if let Some(h) = https_handle {
+        h.await?;
+    }
+    if let Some(h) = rtsp_handle {
+        h.await?;
+    }
     info!("Exiting.");
     Ok(())
 }
@@ -55,6 +55,12 @@ Options:
                            to wal after the upgrade.
                            [default: delete]
     --no-vacuum            Skips the normal post-upgrade vacuum operation.
+    --credentials-key-file=FILE
+                           When upgrading from schema version 5 to 6, a file
+                           holding the 32-byte key with which to encrypt camera
+                           credentials. If absent, the key is read from the
+                           base64-encoded MOONFIRE_CREDENTIALS_KEY environment
+                           variable.
 "#;
 
 #[derive(Debug, Deserialize)]
@@ -63,15 +69,19 @@ pub struct Args {
     flag_sample_file_dir: Option<String>,
     flag_preset_journal: String,
     flag_no_vacuum: bool,
+    flag_credentials_key_file: Option<String>,
 }
 
 pub fn run() -> Result<(), Error> {
     let args: Args = super::parse_args(USAGE)?;
+    let credentials_key = super::credentials_key(
+        args.flag_credentials_key_file.as_ref().map(|s| s.as_str()))?;
     let (_db_dir, mut conn) = super::open_conn(&args.flag_db_dir, super::OpenMode::ReadWrite)?;
 
     db::upgrade::run(&db::upgrade::Args {
         flag_sample_file_dir: args.flag_sample_file_dir.as_ref().map(|s| s.as_str()),
         flag_preset_journal: &args.flag_preset_journal,
         flag_no_vacuum: args.flag_no_vacuum,
+        credentials_key: credentials_key.as_ref(),
     }, &mut conn)
 }
@@ -0,0 +1,114 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to verify a signed evidence export bundle offline.
+
+use crate::export;
+use db::recording;
+use failure::{Error, ResultExt, bail};
+use serde::Deserialize;
+use std::fs;
+
+const USAGE: &'static str = r#"
+Verify a signed evidence export bundle, as from the export.tar API endpoint.
+
+This checks the manifest's signature and that the archive's video.mp4 matches
+the length and SHA-256 hash recorded in the manifest. It doesn't require access
+to the NVR or its database.
+
+Usage:
+
+    moonfire-nvr verify-export [options] <archive>
+    moonfire-nvr verify-export --help
+
+Options:
+
+    --public-key=FILE      The PEM-encoded public key with which the archive
+                           should have been signed, as written to the FILE.pub
+                           alongside the NVR's --export-key. If absent, the
+                           public key embedded in the archive is used, which
+                           shows only that the archive is internally
+                           consistent, not that it came from a particular NVR.
+"#;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_public_key: Option<String>,
+    arg_archive: String,
+}
+
+pub fn run() -> Result<(), Error> {
+    let args: Args = super::parse_args(USAGE)?;
+    let mut f = fs::File::open(&args.arg_archive)
+        .with_context(|_| format!("unable to open {}", &args.arg_archive))?;
+    let contents = export::read(&mut f)
+        .with_context(|_| format!("unable to read {}", &args.arg_archive))?;
+
+    let public_key = match args.flag_public_key {
+        Some(ref p) => fs::read(p).with_context(|_| format!("unable to read {}", p))?,
+        None => {
+            println!("WARNING: no --public-key given; trusting the archive's embedded key.");
+            contents.public_key.clone()
+        },
+    };
+    println!("signing key fingerprint (SHA-256): {}", export::fingerprint(&public_key)?);
+    if !export::verify(&public_key, &contents.manifest, &contents.signature)? {
+        bail!("manifest signature is INVALID");
+    }
+    println!("manifest signature is valid");
+
+    let manifest: export::Manifest = serde_json::from_slice(&contents.manifest)
+        .context("unable to parse signed manifest")?;
+    if manifest.mp4_len != contents.mp4_len {
+        bail!("{} is {} bytes; manifest says {}",
+              export::MP4_NAME, contents.mp4_len, manifest.mp4_len);
+    }
+    if manifest.mp4_sha256 != contents.mp4_sha256 {
+        bail!("{} has SHA-256 {}; manifest says {}",
+              export::MP4_NAME, contents.mp4_sha256, manifest.mp4_sha256);
+    }
+    println!("{} matches manifest ({} bytes, SHA-256 {})",
+             export::MP4_NAME, contents.mp4_len, contents.mp4_sha256);
+
+    println!();
+    println!("camera:      {} ({})", manifest.camera_short_name, manifest.camera_uuid);
+    println!("stream:      {}", manifest.stream_type);
+    println!("time range:  {} - {}", recording::Time(manifest.start_time_90k),
+             recording::Time(manifest.end_time_90k));
+    println!("exported at: {} by {}", recording::Time(manifest.export_time_90k),
+             manifest.exported_by.as_ref().map(|u| u.as_str()).unwrap_or("(unauthenticated)"));
+    println!("recordings:");
+    for r in &manifest.recordings {
+        println!("    {}/{} {} - {} sha1 {}", r.open_id, r.id, recording::Time(r.start_time_90k),
+                 recording::Time(r.end_time_90k),
+                 r.sample_file_sha1.as_ref().map(|s| s.as_str()).unwrap_or("(unknown)"));
+    }
+    Ok(())
+}
@@ -0,0 +1,334 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Signed evidence export bundles.
+//!
+//! An export is a `ustar` archive holding, in order:
+//!
+//! *   `video.mp4`, the video itself, as from `view.mp4`.
+//! *   `manifest.json`, a `Manifest` describing its provenance, including the `.mp4`'s SHA-256.
+//! *   `manifest.json.sig`, a detached Ed25519 signature of the manifest's exact bytes.
+//! *   `signing-key.pub.pem`, the public key which made the signature, for convenience.
+//!
+//! The `.mp4` comes first so the archive can be streamed while its hash is computed. The embedded
+//! public key shows only that the archive is internally consistent; `moonfire-nvr verify-export
+//! --public-key=...` checks the signature against a key obtained from the NVR itself.
+
+use base::strutil;
+use failure::{Error, bail, format_err};
+use log::info;
+use openssl::hash;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::sign::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use uuid::Uuid;
+
+pub const MP4_NAME: &str = "video.mp4";
+pub const MANIFEST_NAME: &str = "manifest.json";
+pub const SIGNATURE_NAME: &str = "manifest.json.sig";
+pub const PUBLIC_KEY_NAME: &str = "signing-key.pub.pem";
+
+const TAR_BLOCK_LEN: usize = 512;
+
+/// The manifest of an export, serialized as JSON.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Manifest {
+    pub camera_uuid: Uuid,
+    pub camera_short_name: String,
+    pub stream_type: String,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub recordings: Vec<ManifestRecording>,
+
+    /// The user who requested the export, if the request was authenticated with a session.
+    pub exported_by: Option<String>,
+    pub export_time_90k: i64,
+    pub mp4_len: u64,
+
+    /// The SHA-256 of the `.mp4`, as lowercase hex.
+    pub mp4_sha256: String,
+}
+
+/// A recording which is (partially) included in an export.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct ManifestRecording {
+    pub id: i32,
+    pub open_id: u32,
+
+    /// The wall-clock start of the included portion of the recording.
+    pub start_time_90k: i64,
+
+    /// The wall-clock end of the included portion of the recording.
+    pub end_time_90k: i64,
+
+    /// The SHA-1 of the whole sample file as recorded, as lowercase hex, if known. This is
+    /// absent for recordings which haven't yet been flushed to the database.
+    pub sample_file_sha1: Option<String>,
+}
+
+/// An Ed25519 key for signing exports.
+pub struct SigningKey(PKey<Private>);
+
+impl SigningKey {
+    /// Reads a PEM-encoded private key from `path`. If it doesn't exist, generates a new key and
+    /// writes it there (readable only by the owner), along with its public key at `<path>.pub`.
+    pub fn open_or_create(path: &str) -> Result<Self, Error> {
+        match fs::read(path) {
+            Ok(pem) => {
+                let k = PKey::private_key_from_pem(&pem)
+                    .map_err(|e| format_err!("unable to parse export key {}: {}", path, e))?;
+                if k.id() != openssl::pkey::Id::ED25519 {
+                    bail!("export key {} isn't an Ed25519 key", path);
+                }
+                return Ok(SigningKey(k));
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => bail!("unable to read export key {}: {}", path, e),
+        }
+        let k = SigningKey(PKey::generate_ed25519()?);
+        let pem = k.0.private_key_to_pem_pkcs8()?;
+        fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+            .and_then(|mut f| f.write_all(&pem))
+            .map_err(|e| format_err!("unable to write export key {}: {}", path, e))?;
+        let pub_path = format!("{}.pub", path);
+        fs::write(&pub_path, k.public_key_pem()?)
+            .map_err(|e| format_err!("unable to write export public key {}: {}", pub_path, e))?;
+        info!("Created export signing key {}; public key is in {}", path, pub_path);
+        Ok(k)
+    }
+
+    pub fn public_key_pem(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.public_key_to_pem()?)
+    }
+
+    /// Returns a detached signature of `data`.
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut signer = Signer::new_without_digest(&self.0)?;
+        Ok(signer.sign_oneshot_to_vec(data)?)
+    }
+}
+
+/// Returns true iff `signature` is a valid signature of `data` by the given PEM-encoded key.
+pub fn verify(public_key_pem: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, Error> {
+    let k: PKey<Public> = PKey::public_key_from_pem(public_key_pem)?;
+    if k.id() != openssl::pkey::Id::ED25519 {
+        bail!("public key isn't an Ed25519 key");
+    }
+    let mut verifier = Verifier::new_without_digest(&k)?;
+    Ok(verifier.verify_oneshot(signature, data)?)
+}
+
+/// Returns the lowercase hex SHA-256 of the public key in `public_key_pem`, for comparing keys.
+pub fn fingerprint(public_key_pem: &[u8]) -> Result<String, Error> {
+    let k: PKey<Public> = PKey::public_key_from_pem(public_key_pem)?;
+    Ok(strutil::hex(&hash::hash(hash::MessageDigest::sha256(), &k.public_key_to_der()?)?))
+}
+
+/// Returns a `ustar` header for a regular file of the given name and length.
+pub fn tar_header(name: &str, len: u64, mtime: u64) -> [u8; TAR_BLOCK_LEN] {
+    let mut h = [0u8; TAR_BLOCK_LEN];
+    h[.. name.len()].copy_from_slice(name.as_bytes());
+    h[100 .. 108].copy_from_slice(b"0000644\0");  // mode
+    h[108 .. 116].copy_from_slice(b"0000000\0");  // uid
+    h[116 .. 124].copy_from_slice(b"0000000\0");  // gid
+    if len < 1 << 33 {
+        h[124 .. 136].copy_from_slice(format!("{:011o}\0", len).as_bytes());
+    } else {
+        // GNU base-256 extension for files of 8 GiB or more.
+        h[124] = 0x80;
+        h[128 .. 136].copy_from_slice(&len.to_be_bytes());
+    }
+    h[136 .. 148].copy_from_slice(format!("{:011o}\0", mtime).as_bytes());
+    h[156] = b'0';  // typeflag: regular file
+    h[257 .. 265].copy_from_slice(b"ustar\x0000");
+    h[148 .. 156].copy_from_slice(b"        ");  // checksum is computed with spaces here.
+    let sum: u32 = h.iter().map(|&b| b as u32).sum();
+    h[148 .. 156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    h
+}
+
+/// Returns the number of zero bytes which follow a file of the given length within a tar archive.
+pub fn tar_padding(len: u64) -> usize {
+    (TAR_BLOCK_LEN - (len % TAR_BLOCK_LEN as u64) as usize) % TAR_BLOCK_LEN
+}
+
+/// Returns the two zero blocks which end a tar archive.
+pub fn tar_trailer() -> [u8; 2 * TAR_BLOCK_LEN] { [0u8; 2 * TAR_BLOCK_LEN] }
+
+/// Returns a complete tar entry (header, data, and padding) for a small file.
+pub fn tar_entry(name: &str, data: &[u8], mtime: u64) -> Vec<u8> {
+    let mut v = Vec::with_capacity(TAR_BLOCK_LEN + data.len() + TAR_BLOCK_LEN);
+    v.extend_from_slice(&tar_header(name, data.len() as u64, mtime));
+    v.extend_from_slice(data);
+    v.resize(v.len() + tar_padding(data.len() as u64), 0);
+    v
+}
+
+/// Reads a tar entry header, returning the name and length or `None` at the end of the archive.
+fn read_tar_header(r: &mut dyn Read) -> Result<Option<(String, u64)>, Error> {
+    let mut h = [0u8; TAR_BLOCK_LEN];
+    r.read_exact(&mut h)?;
+    if h.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    let mut unsummed = h;
+    unsummed[148 .. 156].copy_from_slice(b"        ");
+    let sum: u32 = unsummed.iter().map(|&b| b as u32).sum();
+    if parse_octal(&h[148 .. 156])? != sum as u64 {
+        bail!("bad tar header checksum");
+    }
+    let name_len = h[.. 100].iter().position(|&b| b == 0).unwrap_or(100);
+    let name = String::from_utf8(h[.. name_len].to_vec())
+        .map_err(|_| format_err!("tar entry name isn't UTF-8"))?;
+    let len = if h[124] & 0x80 != 0 {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(&h[128 .. 136]);
+        u64::from_be_bytes(b)
+    } else {
+        parse_octal(&h[124 .. 136])?
+    };
+    Ok(Some((name, len)))
+}
+
+fn parse_octal(f: &[u8]) -> Result<u64, Error> {
+    let s = std::str::from_utf8(f).map_err(|_| format_err!("bad octal field in tar header"))?;
+    let s = s.trim_matches(|c| c == '\0' || c == ' ');
+    u64::from_str_radix(s, 8).map_err(|_| format_err!("bad octal field {:?} in tar header", s))
+}
+
+/// The contents of an export archive, as read by `read`.
+pub struct Contents {
+    pub manifest: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub mp4_len: u64,
+    pub mp4_sha256: String,
+}
+
+/// Reads an export archive, hashing its `.mp4` rather than holding it in memory.
+pub fn read(r: &mut dyn Read) -> Result<Contents, Error> {
+    const MAX_SMALL_LEN: u64 = 1 << 20;
+    let mut manifest = None;
+    let mut signature = None;
+    let mut public_key = None;
+    let mut mp4 = None;
+    while let Some((name, len)) = read_tar_header(r)? {
+        if name == MP4_NAME {
+            let mut h = hash::Hasher::new(hash::MessageDigest::sha256())?;
+            let mut buf = vec![0u8; 1 << 16];
+            let mut remaining = len;
+            while remaining > 0 {
+                let n = std::cmp::min(remaining, buf.len() as u64) as usize;
+                r.read_exact(&mut buf[.. n])?;
+                h.update(&buf[.. n])?;
+                remaining -= n as u64;
+            }
+            mp4 = Some((len, strutil::hex(&h.finish()?)));
+        } else {
+            if len > MAX_SMALL_LEN {
+                bail!("unexpectedly large archive entry {} ({} bytes)", name, len);
+            }
+            let mut data = vec![0u8; len as usize];
+            r.read_exact(&mut data)?;
+            let dest = match name.as_str() {
+                MANIFEST_NAME => &mut manifest,
+                SIGNATURE_NAME => &mut signature,
+                PUBLIC_KEY_NAME => &mut public_key,
+                _ => bail!("unexpected archive entry {}", name),
+            };
+            if dest.is_some() {
+                bail!("duplicate archive entry {}", name);
+            }
+            *dest = Some(data);
+        }
+        let mut padding = [0u8; TAR_BLOCK_LEN];
+        r.read_exact(&mut padding[.. tar_padding(len)])?;
+    }
+    let (mp4_len, mp4_sha256) = mp4.ok_or_else(|| format_err!("archive has no {}", MP4_NAME))?;
+    Ok(Contents {
+        manifest: manifest.ok_or_else(|| format_err!("archive has no {}", MANIFEST_NAME))?,
+        signature: signature.ok_or_else(|| format_err!("archive has no {}", SIGNATURE_NAME))?,
+        public_key: public_key.ok_or_else(|| format_err!("archive has no {}", PUBLIC_KEY_NAME))?,
+        mp4_len,
+        mp4_sha256,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tar_header_checksum() {
+        let h = tar_header("manifest.json", 1234, 0);
+        let (name, len) = read_tar_header(&mut &h[..]).unwrap().unwrap();
+        assert_eq!(name, "manifest.json");
+        assert_eq!(len, 1234);
+        assert_eq!(&h[124 .. 136], b"00000002322\0");
+
+        let h = tar_header(MP4_NAME, 10 << 30, 0);
+        let (_, len) = read_tar_header(&mut &h[..]).unwrap().unwrap();
+        assert_eq!(len, 10 << 30);
+    }
+
+    #[test]
+    fn round_trip() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("export-key.pem");
+        let path = path.to_str().unwrap();
+        let key = SigningKey::open_or_create(path).unwrap();
+        let reopened = SigningKey::open_or_create(path).unwrap();
+        let public_key = key.public_key_pem().unwrap();
+        assert_eq!(public_key, reopened.public_key_pem().unwrap());
+        assert_eq!(public_key, fs::read(format!("{}.pub", path)).unwrap());
+
+        let mp4 = b"not really an mp4";
+        let manifest = b"{}";
+        let signature = key.sign(manifest).unwrap();
+        let mut archive = Vec::new();
+        archive.extend_from_slice(&tar_entry(MP4_NAME, mp4, 0));
+        archive.extend_from_slice(&tar_entry(MANIFEST_NAME, manifest, 0));
+        archive.extend_from_slice(&tar_entry(SIGNATURE_NAME, &signature, 0));
+        archive.extend_from_slice(&tar_entry(PUBLIC_KEY_NAME, &public_key, 0));
+        archive.extend_from_slice(&tar_trailer());
+        let c = read(&mut &archive[..]).unwrap();
+        assert_eq!(c.mp4_len, mp4.len() as u64);
+        assert_eq!(c.mp4_sha256,
+                   strutil::hex(&hash::hash(hash::MessageDigest::sha256(), mp4).unwrap()));
+        assert!(verify(&c.public_key, &c.manifest, &c.signature).unwrap());
+        assert!(!verify(&c.public_key, b"{\"tampered\": true}", &c.signature).unwrap());
+        assert_eq!(fingerprint(&public_key).unwrap().len(), 64);
+    }
+}
@@ -40,8 +40,8 @@
 //! through ffmpeg's own generated `.mp4` file. Extracting just this part of their `.mp4` files
 //! would be more trouble than it's worth.
 
-use byteorder::{BigEndian, WriteBytesExt};
-use failure::{Error, bail};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use failure::{Error, bail, format_err};
 use lazy_static::lazy_static;
 use regex::bytes::Regex;
 
@@ -228,6 +228,65 @@ impl ExtraData {
     }
 }
 
+/// Returns the `AVCDecoderConfigurationRecord` (ISO/IEC 14496-15 section 5.2.4.1) within a sample
+/// entry as produced by `ExtraData::parse`.
+pub fn decoder_config_record(sample_entry: &[u8]) -> Result<&[u8], Error> {
+    // The AVCConfigurationBox immediately follows the 86-byte VisualSampleEntry.
+    if sample_entry.len() < 94 || &sample_entry[90..94] != b"avcC" {
+        bail!("sample entry has no avcC box");
+    }
+    let avcc_len = BigEndian::read_u32(&sample_entry[86..90]) as usize;
+    sample_entry.get(94 .. 86 + avcc_len).ok_or_else(|| format_err!("avcC box is truncated"))
+}
+
+/// The parts of an `AVCDecoderConfigurationRecord` (ISO/IEC 14496-15 section 5.2.4.1) needed to
+/// send samples over RTP.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AvcDecoderConfig<'a> {
+    /// The length in bytes of the NAL unit length prefixes within each sample.
+    pub length_size: usize,
+    pub sps: Vec<&'a [u8]>,
+    pub pps: Vec<&'a [u8]>,
+}
+
+impl<'a> AvcDecoderConfig<'a> {
+    /// Parses the configuration from a sample entry as produced by `ExtraData::parse`.
+    pub fn from_sample_entry(sample_entry: &'a [u8]) -> Result<Self, Error> {
+        Self::parse(decoder_config_record(sample_entry)?)
+    }
+
+    fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        fn sets<'a>(data: &'a [u8], pos: &mut usize, count: usize)
+                    -> Result<Vec<&'a [u8]>, Error> {
+            let mut out = Vec::with_capacity(count);
+            for _ in 0 .. count {
+                let len = data.get(*pos .. *pos + 2)
+                              .ok_or_else(|| format_err!("parameter set length is truncated"))?;
+                let len = BigEndian::read_u16(len) as usize;
+                *pos += 2;
+                out.push(data.get(*pos .. *pos + len)
+                             .ok_or_else(|| format_err!("parameter set is truncated"))?);
+                *pos += len;
+            }
+            Ok(out)
+        }
+        if data.len() < 6 || data[0] != 1 {
+            bail!("unsupported AVCDecoderConfigurationRecord");
+        }
+        let length_size = (data[4] & 0x03) as usize + 1;
+        let mut pos = 6;
+        let sps = sets(data, &mut pos, (data[5] & 0x1f) as usize)?;
+        let num_pps = *data.get(pos).ok_or_else(|| format_err!("PPS count is truncated"))?;
+        pos += 1;
+        let pps = sets(data, &mut pos, num_pps as usize)?;
+        Ok(AvcDecoderConfig {
+            length_size,
+            sps,
+            pps,
+        })
+    }
+}
+
 /// Transforms sample data from Annex B format to AVC format. Should be called on samples iff
 /// `ExtraData::need_transform` is true. Uses an out parameter `avc_sample` rather than a return
 /// so that memory allocations can be reused from sample to sample.
@@ -310,6 +369,18 @@ mod tests {
         assert_eq!(e.rfc6381_codec, "avc1.4d001f");
     }
 
+    #[test]
+    fn test_avc_decoder_config_from_sample_entry() {
+        testutil::init();
+        let c = super::AvcDecoderConfig::from_sample_entry(&TEST_OUTPUT).unwrap();
+        assert_eq!(c, super::AvcDecoderConfig {
+            length_size: 4,
+            sps: vec![&ANNEX_B_TEST_INPUT[4 .. 27]],
+            pps: vec![&ANNEX_B_TEST_INPUT[31 ..]],
+        });
+        assert!(super::AvcDecoderConfig::from_sample_entry(&TEST_OUTPUT[.. 100]).is_err());
+    }
+
     #[test]
     fn test_sample_entry_from_annex_b() {
         testutil::init();
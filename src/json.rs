@@ -42,15 +42,16 @@ pub struct TopLevel<'a> {
     pub time_zone_name: &'a str,
 
     // Use a custom serializer which presents the map's values as a sequence and includes the
-    // "days" and "camera_configs" attributes or not, according to the respective bools.
+    // "days" and "camera_configs" attributes or not, according to the respective bools. Only
+    // cameras allowed by the given permissions are included.
     #[serde(serialize_with = "TopLevel::serialize_cameras")]
-    pub cameras: (&'a db::LockedDatabase, bool, bool),
+    pub cameras: (&'a db::LockedDatabase, &'a db::Permissions, bool, bool),
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub session: Option<Session>,
 
     #[serde(serialize_with = "TopLevel::serialize_signals")]
-    pub signals: (&'a db::LockedDatabase, bool),
+    pub signals: (&'a db::LockedDatabase, &'a db::Permissions, bool),
 
     #[serde(serialize_with = "TopLevel::serialize_signal_types")]
     pub signal_types: &'a db::LockedDatabase,
@@ -94,8 +95,6 @@ pub struct Camera<'a> {
 #[serde(rename_all="camelCase")]
 pub struct CameraConfig<'a> {
     pub onvif_host: &'a str,
-    pub username: &'a str,
-    pub password: &'a str,
 }
 
 #[derive(Debug, Serialize)]
@@ -107,6 +106,9 @@ pub struct Stream<'a> {
     pub total_duration_90k: i64,
     pub total_sample_file_bytes: i64,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir_failure: Option<&'a str>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(serialize_with = "Stream::serialize_days")]
     pub days: Option<&'a BTreeMap<db::StreamDayKey, db::StreamDayValue>>,
@@ -117,7 +119,7 @@ pub struct Stream<'a> {
 pub struct Signal<'a> {
     pub id: u32,
     #[serde(serialize_with = "Signal::serialize_cameras")]
-    pub cameras: (&'a db::Signal, &'a db::LockedDatabase),
+    pub cameras: (&'a db::Signal, &'a db::LockedDatabase, &'a db::Permissions),
     pub source: Uuid,
     pub type_: Uuid,
     pub short_name: &'a str,
@@ -135,6 +137,9 @@ pub enum PostSignalsEndBase {
 pub struct LoginRequest<'a> {
     pub username: &'a str,
     pub password: String,
+
+    /// A TOTP or recovery code, required iff the user is enrolled in two-factor authentication.
+    pub code: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -167,6 +172,275 @@ pub struct Signals {
     pub states: Vec<u16>,
 }
 
+/// JSON serialization wrapper for the caller's sessions in `/api/sessions`.
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Sessions {
+    pub sessions: Vec<SessionInfo>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct SessionInfo {
+    pub id: String,
+    pub current: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    pub creation: SessionUse,
+    pub last_use: SessionUse,
+    pub use_count: i32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct SessionUse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_sec: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_addr: Option<String>,
+}
+
+/// Returns the id of a session or API token, as used by `/api/sessions` and `/api/tokens`.
+pub fn session_id(hash: &SessionHash) -> String {
+    let mut id = [0u8; 32];
+    hash.encode_base64(&mut id);
+    ::std::str::from_utf8(&id[..]).expect("base64 is UTF-8").to_owned()
+}
+
+impl SessionInfo {
+    pub fn new(hash: &SessionHash, s: &db::auth::Session, current: bool) -> Self {
+        SessionInfo {
+            id: session_id(hash),
+            current,
+            description: s.description().map(str::to_owned),
+            creation: SessionUse::new(s.creation()),
+            last_use: SessionUse::new(s.last_use()),
+            use_count: s.use_count(),
+        }
+    }
+}
+
+impl SessionUse {
+    fn new(r: &db::auth::Request) -> Self {
+        SessionUse {
+            time_sec: r.when_sec,
+            user_agent: r.user_agent.as_ref().map(|u| String::from_utf8_lossy(u).into_owned()),
+            peer_addr: r.addr.map(|a| a.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct PostSessionsRequest<'a> {
+    /// Required when the caller is authenticated by session cookie.
+    #[serde(default)]
+    pub csrf: Option<&'a str>,
+
+    /// Revoke this user's sessions rather than the caller's own. Requires admin permission.
+    #[serde(default)]
+    pub username: Option<&'a str>,
+
+    /// Ids (as returned by `GET /api/sessions`) of sessions to revoke.
+    #[serde(default)]
+    pub revoke: Vec<String>,
+}
+
+/// JSON serialization wrapper for `db::Permissions`.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, rename_all="camelCase")]
+pub struct Permissions {
+    pub view_video: bool,
+    pub read_camera_configs: bool,
+    pub update_signals: bool,
+
+    /// The cameras to which the above apply, or empty for all cameras.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub camera_uuids: Vec<String>,
+
+    pub view_audit_log: bool,
+    pub admin: bool,
+}
+
+impl Permissions {
+    pub fn wrap(p: &db::Permissions) -> Self {
+        Permissions {
+            view_video: p.view_video,
+            read_camera_configs: p.read_camera_configs,
+            update_signals: p.update_signals,
+            camera_uuids: p.camera_uuids.iter().cloned().collect(),
+            view_audit_log: p.view_audit_log,
+            admin: p.admin,
+        }
+    }
+
+    pub fn to_db(&self) -> db::Permissions {
+        let mut p = db::Permissions::new();
+        p.view_video = self.view_video;
+        p.read_camera_configs = self.read_camera_configs;
+        p.update_signals = self.update_signals;
+        p.camera_uuids = self.camera_uuids.iter().cloned().collect();
+        p.view_audit_log = self.view_audit_log;
+        p.admin = self.admin;
+        p
+    }
+}
+
+/// JSON serialization wrapper for the caller's API tokens in `/api/tokens`.
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Tokens {
+    pub tokens: Vec<TokenInfo>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct TokenInfo {
+    pub id: String,
+    pub name: String,
+    pub permissions: Permissions,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration_time_sec: Option<i64>,
+
+    pub creation: SessionUse,
+    pub last_use: SessionUse,
+    pub use_count: i32,
+}
+
+impl TokenInfo {
+    pub fn new(hash: &SessionHash, s: &db::auth::Session) -> Self {
+        TokenInfo {
+            id: session_id(hash),
+            name: s.description().unwrap_or("").to_owned(),
+            permissions: Permissions::wrap(&s.permissions),
+            expiration_time_sec: s.expiration_sec(),
+            creation: SessionUse::new(s.creation()),
+            last_use: SessionUse::new(s.last_use()),
+            use_count: s.use_count(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct PostTokensRequest<'a> {
+    pub csrf: &'a str,
+
+    /// A token to create, if any.
+    pub create: Option<NewToken>,
+
+    /// Ids (as returned by `GET /api/tokens`) of tokens to revoke.
+    #[serde(default)]
+    pub revoke: Vec<String>,
+}
+
+/// Request body of `POST /api/cameras/<uuid>/<stream>/migrate`.
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct PostMigrateRequest {
+    /// The path of the sample file directory to move the stream into.
+    pub to_dir: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct NewToken {
+    pub name: String,
+
+    /// The token's permissions. If absent, the token gets all of the caller's permissions.
+    pub permissions: Option<Permissions>,
+
+    pub expiration_time_sec: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct PostTokensResponse {
+    pub id: String,
+
+    /// The secret token, for use in an `Authorization: Bearer` header. This is the only time it's
+    /// revealed.
+    pub token: String,
+}
+
+/// JSON serialization wrapper for `/api/audit`.
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct AuditEntries {
+    pub entries: Vec<AuditEntry>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct AuditEntry {
+    pub id: i64,
+    pub time_sec: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_addr: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// The id (as in `/api/sessions` and `/api/tokens`) of the session or API token used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+
+    pub action: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_uuid: Option<Uuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_type: Option<&'static str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time_90k: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time_90k: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(e: db::audit::Entry) -> Self {
+        AuditEntry {
+            id: e.id,
+            time_sec: e.request.when_sec.unwrap_or(0),
+            user_agent: e.request.user_agent.map(|u| String::from_utf8_lossy(&u).into_owned()),
+            peer_addr: e.request.addr.map(|a| a.to_string()),
+            user_id: e.user_id,
+            username: e.username,
+            session_id: e.session_id_hash.as_ref().map(session_id),
+            action: e.action,
+            camera_uuid: e.camera_uuid,
+            stream_type: e.stream_type.map(db::StreamType::as_str),
+            start_time_90k: e.time_90k.as_ref().map(|t| t.start.0),
+            end_time_90k: e.time_90k.as_ref().map(|t| t.end.0),
+            bytes: e.bytes,
+            detail: e.detail,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all="camelCase")]
 pub struct SignalType<'a> {
@@ -198,8 +472,6 @@ impl<'a> Camera<'a> {
                 false => None,
                 true => Some(CameraConfig {
                     onvif_host: &c.onvif_host,
-                    username: &c.username,
-                    password: &c.password,
                 }),
             },
             streams: [
@@ -235,6 +507,9 @@ impl<'a> Stream<'a> {
             max_end_time_90k: s.range.as_ref().map(|r| r.end.0),
             total_duration_90k: s.duration.0,
             total_sample_file_bytes: s.sample_file_bytes,
+            dir_failure: s.sample_file_dir_id
+                          .and_then(|id| db.sample_file_dirs_by_id().get(&id))
+                          .and_then(|d| d.failure()),
             days: if include_days { Some(&s.days) } else { None },
         }))
     }
@@ -261,26 +536,32 @@ impl<'a> Stream<'a> {
 }
 
 impl<'a> Signal<'a> {
-    pub fn wrap(s: &'a db::Signal, db: &'a db::LockedDatabase, _include_days: bool) -> Self {
+    pub fn wrap(s: &'a db::Signal, db: &'a db::LockedDatabase, permissions: &'a db::Permissions,
+                _include_days: bool) -> Self {
         Signal {
             id: s.id,
-            cameras: (s, db),
+            cameras: (s, db, permissions),
             source: s.source,
             type_: s.type_,
             short_name: &s.short_name,
         }
     }
 
-    fn serialize_cameras<S>(cameras: &(&db::Signal, &db::LockedDatabase),
+    /// Serializes the signal's cameras as a map of uuid to type, omitting those outside the given
+    /// permissions.
+    fn serialize_cameras<S>(cameras: &(&db::Signal, &db::LockedDatabase, &db::Permissions),
                          serializer: S) -> Result<S::Ok, S::Error>
     where S: Serializer {
-        let (s, db) = cameras;
-        let mut map = serializer.serialize_map(Some(s.cameras.len()))?;
+        let (s, db, permissions) = cameras;
+        let mut map = serializer.serialize_map(None)?;
         for sc in &s.cameras {
             let c = db.cameras_by_id()
                       .get(&sc.camera_id)
                       .ok_or_else(|| S::Error::custom(format!("signal has missing camera id {}",
                                                               sc.camera_id)))?;
+            if !permissions.allows_camera(c.uuid) {
+                continue;
+            }
             map.serialize_key(&c.uuid)?;
             map.serialize_value(match sc.type_ {
                 db::signal::SignalCameraType::Direct => "direct",
@@ -330,15 +611,17 @@ struct StreamDayValue {
 }
 
 impl<'a> TopLevel<'a> {
-    /// Serializes cameras as a list (rather than a map), optionally including the `days` and
-    /// `cameras` fields.
-    fn serialize_cameras<S>(cameras: &(&db::LockedDatabase, bool, bool),
+    /// Serializes the permitted cameras as a list (rather than a map), optionally including the
+    /// `days` and `cameras` fields.
+    fn serialize_cameras<S>(cameras: &(&db::LockedDatabase, &db::Permissions, bool, bool),
                             serializer: S) -> Result<S::Ok, S::Error>
     where S: Serializer {
-        let (db, include_days, include_config) = *cameras;
-        let cs = db.cameras_by_id();
+        let (db, permissions, include_days, include_config) = *cameras;
+        let cs: Vec<_> = db.cameras_by_id().values()
+                           .filter(|c| permissions.allows_camera(c.uuid))
+                           .collect();
         let mut seq = serializer.serialize_seq(Some(cs.len()))?;
-        for (_, c) in cs {
+        for c in cs {
             seq.serialize_element(
                 &Camera::wrap(c, db, include_days, include_config)
                 .map_err(|e| S::Error::custom(e))?)?;
@@ -346,15 +629,18 @@ impl<'a> TopLevel<'a> {
         seq.end()
     }
 
-    /// Serializes signals as a list (rather than a map), optionally including the `days` field.
-    fn serialize_signals<S>(signals: &(&db::LockedDatabase, bool),
+    /// Serializes the permitted signals as a list (rather than a map), optionally including the
+    /// `days` field.
+    fn serialize_signals<S>(signals: &(&db::LockedDatabase, &db::Permissions, bool),
                             serializer: S) -> Result<S::Ok, S::Error>
         where S: Serializer {
-        let (db, include_days) = *signals;
-        let ss = db.signals_by_id();
+        let (db, permissions, include_days) = *signals;
+        let ss: Vec<_> = db.signals_by_id().values()
+                           .filter(|s| permissions.allows_signal(s, db.cameras_by_id()))
+                           .collect();
         let mut seq = serializer.serialize_seq(Some(ss.len()))?;
-        for (_, s) in ss {
-            seq.serialize_element(&Signal::wrap(s, db, include_days))?;
+        for s in ss {
+            seq.serialize_element(&Signal::wrap(s, db, permissions, include_days))?;
         }
         seq.end()
     }
@@ -399,3 +685,15 @@ pub struct Recording {
     #[serde(skip_serializing_if = "Not::not")]
     pub growing: bool,
 }
+
+/// The header of a live segment sent over a WebSocket; see `live.m4s` in `design/api.md`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct LiveSegmentHeader<'a> {
+    pub content_type: &'a str,
+    pub open_id: u32,
+    pub recording_id: i32,
+    pub rel_start_time_90k: i32,
+    pub rel_end_time_90k: i32,
+    pub video_sample_entry_sha1: &'a str,
+}
@@ -35,12 +35,19 @@ use serde::Deserialize;
 
 mod body;
 mod cmds;
+mod export;
 mod h264;
 mod json;
+mod mkv;
 mod mp4;
+mod oidc;
+mod rtsp;
 mod slices;
 mod stream;
 mod streamer;
+mod tls;
+mod ts;
+mod vtt;
 mod web;
 
 /// Commandline usage string. This is in the particular format expected by the `docopt` crate.
@@ -57,10 +64,12 @@ Options:
 Commands:
     check                  Check database integrity
     init                   Initialize a database
+    migrate-stream         Move a stream's recordings to another directory
     run                    Run the daemon: record from cameras and serve HTTP
     shell                  Start an interactive shell to modify the database
     ts                     Translate human-readable and numeric timestamps
     upgrade                Upgrade the database to the latest schema
+    verify-export          Verify a signed evidence export bundle
 ";
 
 /// Commandline arguments corresponding to `USAGE`; automatically filled by the `docopt` crate.
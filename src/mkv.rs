@@ -0,0 +1,640 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `.mkv` virtual file serving.
+//!
+//! The `mkv` module builds virtual files representing Matroska video from one or more
+//! recordings, for tools which don't accept `.mp4`. Like `mp4::File`, these support HTTP range
+//! serving and have stable etags. Each frame becomes a `SimpleBlock`, and a new cluster starts
+//! at each key frame. Block headers are interleaved with the sample data, so clusters are
+//! generated on request from a table of frames built along with the file. The file is laid out as
+//! follows:
+//!
+//! * EBML header
+//! * Segment
+//! ** SeekHead (locations of Info, Tracks, and Cues)
+//! ** Info
+//! ** Tracks
+//! *** TrackEntry (video)
+//! ** Cluster (one per key frame)
+//! *** Timestamp
+//! *** SimpleBlock (one per frame)
+//! ** Cues (one per cluster)
+
+use base::{strutil, Error, ErrorKind, ResultExt, bail_t, format_err_t};
+use crate::body::{self, Chunk, BoxedError, wrap_error};
+use crate::h264;
+use crate::slices::{self, Slices};
+use db::dir;
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use futures::{Stream, stream};
+use http::header::HeaderValue;
+use log::trace;
+use openssl::hash;
+use reffers::ARefss;
+use std::cmp;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// This value should be incremented any time a change is made to this file that causes different
+/// bytes to be output for a particular set of `FileBuilder` options. Incrementing this value will
+/// cause the etag to change as well.
+const FORMAT_VERSION: [u8; 1] = [0x00];
+
+/// The duration of a Matroska timestamp tick: one millisecond, Matroska's default.
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+const TIME_UNITS_PER_TICK: i64 = TIME_UNITS_PER_SEC / 1000;
+
+/// The largest timestamp of a block relative to its cluster, which is a signed 16-bit integer.
+const MAX_RELATIVE_TICKS: i64 = i16::max_value() as i64;
+
+/// The time between 1970-01-01 00:00:00 UTC and Matroska's epoch, 2001-01-01 00:00:00 UTC.
+const MATROSKA_EPOCH_90K: i64 = 978_307_200 * TIME_UNITS_PER_SEC;
+
+// Element ids, from the Matroska specification.
+const EBML: &[u8] = b"\x1a\x45\xdf\xa3";
+const EBML_VERSION: &[u8] = b"\x42\x86";
+const EBML_READ_VERSION: &[u8] = b"\x42\xf7";
+const EBML_MAX_ID_LENGTH: &[u8] = b"\x42\xf2";
+const EBML_MAX_SIZE_LENGTH: &[u8] = b"\x42\xf3";
+const DOC_TYPE: &[u8] = b"\x42\x82";
+const DOC_TYPE_VERSION: &[u8] = b"\x42\x87";
+const DOC_TYPE_READ_VERSION: &[u8] = b"\x42\x85";
+const SEGMENT: &[u8] = b"\x18\x53\x80\x67";
+const SEEK_HEAD: &[u8] = b"\x11\x4d\x9b\x74";
+const SEEK: &[u8] = b"\x4d\xbb";
+const SEEK_ID: &[u8] = b"\x53\xab";
+const SEEK_POSITION: &[u8] = b"\x53\xac";
+const INFO: &[u8] = b"\x15\x49\xa9\x66";
+const TIMESTAMP_SCALE: &[u8] = b"\x2a\xd7\xb1";
+const DURATION: &[u8] = b"\x44\x89";
+const DATE_UTC: &[u8] = b"\x44\x61";
+const MUXING_APP: &[u8] = b"\x4d\x80";
+const WRITING_APP: &[u8] = b"\x57\x41";
+const TRACKS: &[u8] = b"\x16\x54\xae\x6b";
+const TRACK_ENTRY: &[u8] = b"\xae";
+const TRACK_NUMBER: &[u8] = b"\xd7";
+const TRACK_UID: &[u8] = b"\x73\xc5";
+const TRACK_TYPE: &[u8] = b"\x83";
+const FLAG_LACING: &[u8] = b"\x9c";
+const CODEC_ID: &[u8] = b"\x86";
+const CODEC_PRIVATE: &[u8] = b"\x63\xa2";
+const VIDEO: &[u8] = b"\xe0";
+const PIXEL_WIDTH: &[u8] = b"\xb0";
+const PIXEL_HEIGHT: &[u8] = b"\xba";
+const CLUSTER: &[u8] = b"\x1f\x43\xb6\x75";
+const TIMESTAMP: &[u8] = b"\xe7";
+const SIMPLE_BLOCK: &[u8] = b"\xa3";
+const CUES: &[u8] = b"\x1c\x53\xbb\x6b";
+const CUE_POINT: &[u8] = b"\xbb";
+const CUE_TIME: &[u8] = b"\xb3";
+const CUE_TRACK_POSITIONS: &[u8] = b"\xb7";
+const CUE_TRACK: &[u8] = b"\xf7";
+const CUE_CLUSTER_POSITION: &[u8] = b"\xf1";
+
+/// The length of a cluster's id, size, and `Timestamp` element.
+const CLUSTER_HEADER_LEN: u64 = 4 + 8 + 10;
+
+/// The length of a `SimpleBlock`'s id, size, track number, relative timestamp, and flags.
+const BLOCK_HEADER_LEN: u64 = 1 + 8 + 1 + 2 + 1;
+
+/// Appends an element data size as an 8-byte EBML variable-size integer.
+fn append_size8(buf: &mut Vec<u8>, size: u64) {
+    buf.push(0x01);
+    buf.extend_from_slice(&size.to_be_bytes()[1..]);
+}
+
+/// Appends an element data size in the shortest convenient form.
+fn append_size(buf: &mut Vec<u8>, size: u64) {
+    if size < 0x7f {
+        buf.push(0x80 | size as u8);
+    } else {
+        append_size8(buf, size);
+    }
+}
+
+/// Appends an unsigned integer element. Values are always written with 8 bytes so that
+/// placeholders can be filled in later.
+fn append_uint(buf: &mut Vec<u8>, id: &[u8], v: u64) {
+    buf.extend_from_slice(id);
+    buf.push(0x88);
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn append_float(buf: &mut Vec<u8>, id: &[u8], v: f64) {
+    append_uint(buf, id, v.to_bits());
+}
+
+fn append_bytes(buf: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+    buf.extend_from_slice(id);
+    append_size(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// Appends the id of a master element and a placeholder size to be filled in with
+/// `finish_master`. Returns the position of the size.
+fn start_master(buf: &mut Vec<u8>, id: &[u8]) -> usize {
+    buf.extend_from_slice(id);
+    let pos = buf.len();
+    append_size8(buf, 0);
+    pos
+}
+
+/// Fills in the size of a master element started with `start_master`.
+fn finish_master(buf: &mut Vec<u8>, size_pos: usize, size: u64) {
+    buf[size_pos + 1 .. size_pos + 8].copy_from_slice(&size.to_be_bytes()[1..]);
+}
+
+/// Finishes a master element whose contents are all in `buf`.
+fn finish_master_in_buf(buf: &mut Vec<u8>, size_pos: usize) {
+    let size = (buf.len() - size_pos - 8) as u64;
+    finish_master(buf, size_pos, size);
+}
+
+/// A frame within a `Segment`, as laid out in the generated file.
+#[derive(Debug)]
+struct Frame {
+    /// The position of this frame's headers within the segment's slice.
+    pos: u64,
+
+    /// The position of this frame's data within the sample file.
+    sample_pos: u64,
+    bytes: u32,
+    is_key: bool,
+
+    /// The timestamp of this frame's cluster, in ticks since the start of the file.
+    cluster_ticks: i64,
+
+    /// This frame's timestamp, relative to `cluster_ticks`.
+    relative_ticks: i16,
+
+    /// If this frame starts a cluster, that cluster's data size.
+    cluster_size: Option<u64>,
+}
+
+impl Frame {
+    fn headers_len(&self) -> u64 {
+        BLOCK_HEADER_LEN + if self.cluster_size.is_some() { CLUSTER_HEADER_LEN } else { 0 }
+    }
+
+    fn append_headers(&self, buf: &mut Vec<u8>) {
+        if let Some(size) = self.cluster_size {
+            buf.extend_from_slice(CLUSTER);
+            append_size8(buf, size);
+            append_uint(buf, TIMESTAMP, self.cluster_ticks as u64);
+        }
+        buf.extend_from_slice(SIMPLE_BLOCK);
+        append_size8(buf, 4 + self.bytes as u64);
+        buf.push(0x81);  // track number 1.
+        buf.extend_from_slice(&self.relative_ticks.to_be_bytes());
+        buf.push(if self.is_key { 0x80 } else { 0x00 });
+    }
+}
+
+/// A wrapper around `recording::Segment` with the layout of its clusters.
+struct Segment {
+    s: recording::Segment,
+    frames: Vec<Frame>,
+
+    /// The length of this segment's slice.
+    len: u64,
+}
+
+impl fmt::Debug for Segment {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("mkv::Segment")
+           .field("s", &self.s)
+           .field("frames", &self.frames.len())
+           .field("len", &self.len)
+           .finish()
+    }
+}
+
+pub struct FileBuilder {
+    segments: Vec<Segment>,
+    video_sample_entry: Option<Arc<db::VideoSampleEntry>>,
+
+    /// The total duration of segments appended so far.
+    duration_90k: i64,
+}
+
+impl FileBuilder {
+    pub fn new() -> Self {
+        FileBuilder {
+            segments: Vec::new(),
+            video_sample_entry: None,
+            duration_90k: 0,
+        }
+    }
+
+    /// Reserves space for the given number of additional segments.
+    pub fn reserve(&mut self, additional: usize) {
+        self.segments.reserve(additional);
+    }
+
+    /// Appends a segment for (a subset of) the given recording.
+    pub fn append(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
+                  rel_range_90k: Range<i32>) -> Result<(), Error> {
+        match self.video_sample_entry {
+            None => {
+                let vse = db.video_sample_entries_by_id().get(&row.video_sample_entry_id)
+                            .unwrap();
+                self.video_sample_entry = Some(vse.clone());
+            },
+            Some(ref e) if e.id != row.video_sample_entry_id => {
+                bail_t!(InvalidArgument,
+                        "recording {} has different video parameters than previous recordings; \
+                         this isn't supported in .mkv files", row.id);
+            },
+            Some(_) => {},
+        }
+        let s = recording::Segment::new(db, &row, rel_range_90k).err_kind(ErrorKind::Unknown)?;
+        let mut frames: Vec<Frame> = Vec::with_capacity(s.frames as usize);
+        let mut pos = 0;
+        let mut cluster: Option<usize> = None;
+        let mut end_90k = 0;
+        let actual_start_90k = s.actual_start_90k();
+        let duration_90k = self.duration_90k;
+        db.with_recording_playback(s.id, &mut |playback| s.foreach(playback, |it| {
+            let ticks = (duration_90k + (it.start_90k - actual_start_90k) as i64) /
+                        TIME_UNITS_PER_TICK;
+            let cluster_ticks = match cluster {
+                Some(c) if !it.is_key() &&
+                           ticks - frames[c].cluster_ticks <= MAX_RELATIVE_TICKS => {
+                    frames[c].cluster_ticks
+                },
+                _ => {
+                    cluster = Some(frames.len());
+                    ticks
+                },
+            };
+            let f = Frame {
+                pos,
+                sample_pos: it.pos as u64,
+                bytes: it.bytes as u32,
+                is_key: it.is_key(),
+                cluster_ticks,
+                relative_ticks: (ticks - cluster_ticks) as i16,
+                cluster_size: if cluster == Some(frames.len()) { Some(0) } else { None },
+            };
+            pos += f.headers_len() + f.bytes as u64;
+            end_90k = cmp::min(s.desired_range_90k.end, it.start_90k + it.duration_90k);
+            frames.push(f);
+            Ok(())
+        })).err_kind(ErrorKind::Unknown)?;
+
+        // Fill in the cluster sizes now that it's known where each ends.
+        let mut cluster_start = None;
+        for i in 0 ..= frames.len() {
+            if i < frames.len() && frames[i].cluster_size.is_none() {
+                continue;
+            }
+            let end = frames.get(i).map(|f| f.pos).unwrap_or(pos);
+            if let Some(c) = cluster_start {
+                // The size excludes the cluster's 4-byte id and 8-byte size but not its Timestamp.
+                let f = &mut frames[c];
+                f.cluster_size = Some(end - f.pos - 12);
+            }
+            cluster_start = Some(i);
+        }
+
+        self.duration_90k += (end_90k - actual_start_90k) as i64;
+        self.segments.push(Segment {
+            s,
+            frames,
+            len: pos,
+        });
+        Ok(())
+    }
+
+    /// Builds the `File`, consuming the builder.
+    pub fn build(self, db: Arc<db::Database>,
+                 dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>)
+                 -> Result<File, Error> {
+        let vse = match self.video_sample_entry {
+            None => bail_t!(InvalidArgument, "no recordings specified"),
+            Some(ref e) => e,
+        };
+        let mut etag = hash::Hasher::new(hash::MessageDigest::sha1())
+            .err_kind(ErrorKind::Internal)?;
+        etag.update(&FORMAT_VERSION[..]).err_kind(ErrorKind::Internal)?;
+        etag.update(b":mkv:").err_kind(ErrorKind::Internal)?;
+        let mut max_end = None;
+        for s in &self.segments {
+            let d = &s.s.desired_range_90k;
+            let end = s.s.start + recording::Duration(d.end as i64);
+            max_end = Some(cmp::max(max_end.unwrap_or(end), end));
+            let mut data = Vec::with_capacity(28);
+            data.extend_from_slice(&s.s.id.0.to_be_bytes());
+            data.extend_from_slice(&s.s.start.0.to_be_bytes());
+            data.extend_from_slice(&s.s.open_id.to_be_bytes());
+            data.extend_from_slice(&d.start.to_be_bytes());
+            data.extend_from_slice(&d.end.to_be_bytes());
+            etag.update(&data).err_kind(ErrorKind::Internal)?;
+        }
+        let first = self.segments.first().expect("have video sample entry implies segments");
+        let start = first.s.start + recording::Duration(first.s.actual_start_90k() as i64);
+
+        let mut buf = Vec::with_capacity(1024);
+        let ebml_pos = start_master(&mut buf, EBML);
+        append_uint(&mut buf, EBML_VERSION, 1);
+        append_uint(&mut buf, EBML_READ_VERSION, 1);
+        append_uint(&mut buf, EBML_MAX_ID_LENGTH, 4);
+        append_uint(&mut buf, EBML_MAX_SIZE_LENGTH, 8);
+        append_bytes(&mut buf, DOC_TYPE, b"matroska");
+        append_uint(&mut buf, DOC_TYPE_VERSION, 2);
+        append_uint(&mut buf, DOC_TYPE_READ_VERSION, 2);
+        finish_master_in_buf(&mut buf, ebml_pos);
+
+        // Positions within the segment are relative to the start of its data.
+        let segment_pos = start_master(&mut buf, SEGMENT);
+        let segment_data_start = buf.len() as u64;
+        let seek_head_pos = start_master(&mut buf, SEEK_HEAD);
+        let mut seek_positions = [0; 3];
+        for (i, id) in [INFO, TRACKS, CUES].iter().enumerate() {
+            let seek_pos = start_master(&mut buf, SEEK);
+            append_bytes(&mut buf, SEEK_ID, id);
+            append_uint(&mut buf, SEEK_POSITION, 0);  // placeholder.
+            seek_positions[i] = buf.len() - 8;
+            finish_master_in_buf(&mut buf, seek_pos);
+        }
+        finish_master_in_buf(&mut buf, seek_head_pos);
+
+        let info_start = buf.len() as u64;
+        let info_pos = start_master(&mut buf, INFO);
+        append_uint(&mut buf, TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+        append_float(&mut buf, DURATION, self.duration_90k as f64 / TIME_UNITS_PER_TICK as f64);
+        let since_epoch_90k = start.0 - MATROSKA_EPOCH_90K;
+        append_uint(&mut buf, DATE_UTC, (since_epoch_90k / 9 * 100_000 +
+                                         since_epoch_90k % 9 * 100_000 / 9) as u64);
+        append_bytes(&mut buf, MUXING_APP, b"moonfire-nvr");
+        append_bytes(&mut buf, WRITING_APP, b"moonfire-nvr");
+        finish_master_in_buf(&mut buf, info_pos);
+
+        let tracks_start = buf.len() as u64;
+        let tracks_pos = start_master(&mut buf, TRACKS);
+        let track_entry_pos = start_master(&mut buf, TRACK_ENTRY);
+        append_uint(&mut buf, TRACK_NUMBER, 1);
+        append_uint(&mut buf, TRACK_UID, 1);
+        append_uint(&mut buf, TRACK_TYPE, 1);  // video.
+        append_uint(&mut buf, FLAG_LACING, 0);
+        append_bytes(&mut buf, CODEC_ID, b"V_MPEG4/ISO/AVC");
+        append_bytes(&mut buf, CODEC_PRIVATE, h264::decoder_config_record(&vse.data)
+                                              .err_kind(ErrorKind::Internal)?);
+        let video_pos = start_master(&mut buf, VIDEO);
+        append_uint(&mut buf, PIXEL_WIDTH, vse.width as u64);
+        append_uint(&mut buf, PIXEL_HEIGHT, vse.height as u64);
+        finish_master_in_buf(&mut buf, video_pos);
+        finish_master_in_buf(&mut buf, track_entry_pos);
+        finish_master_in_buf(&mut buf, tracks_pos);
+
+        let mut slices = Slices::new();
+        slices.reserve(2 + self.segments.len());
+        slices.append(Slice { end: buf.len() as u64, t: SliceType::Buf(0) })
+              .err_kind(ErrorKind::Internal)?;
+        let mut segment_starts = Vec::with_capacity(self.segments.len());
+        for (i, s) in self.segments.iter().enumerate() {
+            segment_starts.push(slices.len());
+            slices.append(Slice { end: slices.len() + s.len, t: SliceType::Clusters(i) })
+                  .err_kind(ErrorKind::Internal)?;
+        }
+
+        let cues_buf_start = buf.len();
+        let cues_start = slices.len();
+        let cues_pos = start_master(&mut buf, CUES);
+        for (s, &segment_start) in self.segments.iter().zip(&segment_starts) {
+            for f in s.frames.iter().filter(|f| f.cluster_size.is_some()) {
+                let cue_point_pos = start_master(&mut buf, CUE_POINT);
+                append_uint(&mut buf, CUE_TIME, f.cluster_ticks as u64);
+                let positions_pos = start_master(&mut buf, CUE_TRACK_POSITIONS);
+                append_uint(&mut buf, CUE_TRACK, 1);
+                append_uint(&mut buf, CUE_CLUSTER_POSITION,
+                            segment_start + f.pos - segment_data_start);
+                finish_master_in_buf(&mut buf, positions_pos);
+                finish_master_in_buf(&mut buf, cue_point_pos);
+            }
+        }
+        finish_master_in_buf(&mut buf, cues_pos);
+        slices.append(Slice {
+            end: cues_start + (buf.len() - cues_buf_start) as u64,
+            t: SliceType::Buf(cues_buf_start),
+        }).err_kind(ErrorKind::Internal)?;
+
+        // Fill in the placeholders now that the layout is known.
+        finish_master(&mut buf, segment_pos, slices.len() - segment_data_start);
+        for (&p, &start) in seek_positions.iter().zip(&[info_start, tracks_start, cues_start]) {
+            buf[p .. p + 8].copy_from_slice(&(start - segment_data_start).to_be_bytes());
+        }
+
+        let max_end = max_end.map(|e| e.unix_seconds()).unwrap_or(0);
+        let last_modified = ::std::time::UNIX_EPOCH +
+                            ::std::time::Duration::from_secs(max_end as u64);
+        let etag = etag.finish().err_kind(ErrorKind::Internal)?;
+        Ok(File(Arc::new(FileInner {
+            db,
+            dirs_by_stream_id,
+            segments: self.segments,
+            slices,
+            buf,
+            last_modified,
+            etag: HeaderValue::from_str(&format!("\"{}\"", &strutil::hex(&etag)))
+                  .expect("hex string should be valid UTF-8"),
+        })))
+    }
+}
+
+/// The type of a `Slice`, with its parameter.
+#[derive(Copy, Clone, Debug)]
+enum SliceType {
+    Buf(usize),       // param is the starting index into m.buf
+    Clusters(usize),  // param is index into m.segments
+}
+
+/// A single slice of a `File`, for use with a `Slices` object.
+struct Slice {
+    end: u64,
+    t: SliceType,
+}
+
+impl fmt::Debug for Slice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        // Omit end(); Slices writes that part.
+        write!(f, "{:?}", self.t)
+    }
+}
+
+impl slices::Slice for Slice {
+    type Ctx = File;
+    type Chunk = Chunk;
+
+    fn end(&self) -> u64 { self.end }
+    fn get_range(&self, f: &File, range: Range<u64>, _len: u64)
+                 -> Box<dyn Stream<Item = Result<Self::Chunk, BoxedError>> + Send + Sync> {
+        trace!("getting mkv slice {:?}'s range {:?}", self, range);
+        let chunks = match self.t {
+            SliceType::Buf(p) => {
+                let r = ARefss::new(f.0.clone());
+                Ok(vec![r.map(|f| &f.buf[p + range.start as usize .. p + range.end as usize])
+                         .into()])
+            },
+            SliceType::Clusters(i) => f.0.get_clusters(i, range),
+        };
+        match chunks {
+            Ok(c) => Box::new(stream::iter(c.into_iter().map(Ok::<_, BoxedError>))),
+            Err(e) => Box::new(stream::once(futures::future::err(wrap_error(e)))),
+        }
+    }
+
+    fn get_slices(ctx: &File) -> &Slices<Self> { &ctx.0.slices }
+}
+
+struct FileInner {
+    db: Arc<db::Database>,
+    dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>,
+    segments: Vec<Segment>,
+    slices: Slices<Slice>,
+    buf: Vec<u8>,
+    last_modified: SystemTime,
+    etag: HeaderValue,
+}
+
+impl FileInner {
+    /// Gets the given range of segment `i`'s clusters: headers from its frame table interleaved
+    /// with sample data from disk.
+    fn get_clusters(&self, i: usize, r: Range<u64>) -> Result<Vec<Chunk>, Error> {
+        let s = &self.segments[i];
+        let first = match s.frames.binary_search_by_key(&r.start, |f| f.pos) {
+            Ok(i) => i,
+            Err(i) => i - 1,  // frames[0].pos == 0, so i > 0.
+        };
+        let mut last = first;
+        while last + 1 < s.frames.len() && s.frames[last + 1].pos < r.end {
+            last += 1;
+        }
+        let d = self.dirs_by_stream_id
+                    .get(&s.s.id.stream())
+                    .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found", s.s.id))?;
+        let data_start = s.frames[first].sample_pos;
+        let data_end = s.frames[last].sample_pos + s.frames[last].bytes as u64;
+        let data = Arc::new(body::sample_file_bytes(d, s.s.id, data_start .. data_end)?);
+        let mut chunks = Vec::with_capacity(2 * (last + 1 - first));
+        let mut len = 0;
+        for f in &s.frames[first ..= last] {
+            let mut headers = Vec::with_capacity(f.headers_len() as usize);
+            f.append_headers(&mut headers);
+            let data_pos = f.pos + headers.len() as u64;
+            if r.start < data_pos && f.pos < r.end {
+                let h = (cmp::max(r.start, f.pos) - f.pos) as usize ..
+                        (cmp::min(r.end, data_pos) - f.pos) as usize;
+                len += h.end - h.start;
+                chunks.push(ARefss::new(headers).map(|v| &v[h]).into());
+            }
+            if r.start < data_pos + f.bytes as u64 && data_pos < r.end {
+                let off = f.sample_pos - data_start;
+                let d = (off + cmp::max(r.start, data_pos) - data_pos) as usize ..
+                        (off + cmp::min(r.end, data_pos + f.bytes as u64) - data_pos) as usize;
+                len += d.end - d.start;
+                chunks.push(ARefss::new(data.clone()).map(|v| &v[d]).into());
+            }
+        }
+        if len as u64 != r.end - r.start {
+            bail_t!(Internal, "{}: range {:?} produced incorrect len {}", s.s.id, r, len);
+        }
+        Ok(chunks)
+    }
+}
+
+#[derive(Clone)]
+pub struct File(Arc<FileInner>);
+
+impl http_serve::Entity for File {
+    type Data = Chunk;
+    type Error = BoxedError;
+
+    fn add_headers(&self, hdrs: &mut http::header::HeaderMap) {
+        hdrs.insert(http::header::CONTENT_TYPE, HeaderValue::from_static("video/x-matroska"));
+    }
+    fn last_modified(&self) -> Option<SystemTime> { Some(self.0.last_modified) }
+    fn etag(&self) -> Option<HeaderValue> { Some(self.0.etag.clone()) }
+    fn len(&self) -> u64 { self.0.slices.len() }
+    fn get_range(&self, range: Range<u64>)
+                 -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        self.0.slices.get_range(self, range)
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("mkv::File")
+            .field("last_modified", &self.0.last_modified)
+            .field("etag", &self.0.etag)
+            .field("slices", &self.0.slices)
+            .field("segments", &self.0.segments)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elements() {
+        let mut buf = Vec::new();
+        let pos = start_master(&mut buf, CUE_POINT);
+        append_uint(&mut buf, CUE_TIME, 0x1234);
+        append_bytes(&mut buf, DOC_TYPE, b"matroska");
+        finish_master_in_buf(&mut buf, pos);
+        assert_eq!(&buf[..], &b"\xbb\x01\x00\x00\x00\x00\x00\x00\x16\
+                                \xb3\x88\x00\x00\x00\x00\x00\x00\x12\x34\
+                                \x42\x82\x88matroska"[..]);
+    }
+
+    #[test]
+    fn frame_headers() {
+        let f = Frame {
+            pos: 0,
+            sample_pos: 0,
+            bytes: 0x100,
+            is_key: true,
+            cluster_ticks: 1000,
+            relative_ticks: 0,
+            cluster_size: Some(10 + BLOCK_HEADER_LEN + 0x100),
+        };
+        let mut buf = Vec::new();
+        f.append_headers(&mut buf);
+        assert_eq!(buf.len() as u64, f.headers_len());
+        assert_eq!(&buf[..], &b"\x1f\x43\xb6\x75\x01\x00\x00\x00\x00\x00\x01\x17\
+                                \xe7\x88\x00\x00\x00\x00\x00\x00\x03\xe8\
+                                \xa3\x01\x00\x00\x00\x00\x00\x01\x04\x81\x00\x00\x80"[..]);
+    }
+}
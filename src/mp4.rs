@@ -40,7 +40,7 @@
 //! * moov (container for all the metadata)
 //! ** mvhd (movie header, overall declarations)
 //!
-//! ** trak (video: container for an individual track or stream)
+//! ** trak (video: container for an individual track or stream; one per camera stream)
 //! *** tkhd (track header, overall information about the track)
 //! *** (optional) edts (edit list container)
 //! **** elst (an edit list)
@@ -75,11 +75,14 @@
 //!
 //! * mdat (media data container)
 //! ```
+//!
+//! Most files have a single video track. `FileBuilder::append_track` allows building a file with
+//! one video track per camera stream, all on a common wall-clock timeline.
 
 use base::{strutil, Error, ErrorKind, ResultExt, bail_t, format_err_t};
 use bytes::{Buf, BytesMut};
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
-use crate::body::{Chunk, BoxedError, wrap_error};
+use crate::body::{self, BodyStream, Chunk, BoxedError, wrap_error};
 use db::dir;
 use db::recording::{self, TIME_UNITS_PER_SEC};
 use futures::Stream;
@@ -88,7 +91,6 @@ use http;
 use http::header::HeaderValue;
 use http_serve;
 use log::{debug, error, trace, warn};
-use memmap;
 use openssl::hash;
 use parking_lot::Once;
 use reffers::ARefss;
@@ -96,6 +98,7 @@ use crate::slices::{self, Slices};
 use smallvec::SmallVec;
 use std::cell::UnsafeCell;
 use std::cmp;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io;
 use std::ops::Range;
@@ -106,7 +109,7 @@ use std::time::SystemTime;
 /// This value should be incremented any time a change is made to this file that causes different
 /// bytes to be output for a particular set of `Mp4Builder` options. Incrementing this value will
 /// cause the etag to change as well.
-const FORMAT_VERSION: [u8; 1] = [0x06];
+const FORMAT_VERSION: [u8; 1] = [0x07];
 
 /// An `ftyp` (ISO/IEC 14496-12 section 4.3 `FileType`) box.
 const NORMAL_FTYP_BOX: &'static [u8] = &[
@@ -324,7 +327,7 @@ enum StaticBytestring {
 /// The template fed into strtime for a timestamp subtitle. This must produce fixed-length output
 /// (see `SUBTITLE_LENGTH`) to allow quick calculation of the total size of the subtitles for
 /// a given time range.
-const SUBTITLE_TEMPLATE: &'static str = "%Y-%m-%d %H:%M:%S %z";
+pub const SUBTITLE_TEMPLATE: &'static str = "%Y-%m-%d %H:%M:%S %z";
 
 /// The length of the output of `SUBTITLE_TEMPLATE`.
 const SUBTITLE_LENGTH: usize = 25;  // "2015-07-02 17:10:00 -0700".len();
@@ -350,9 +353,29 @@ struct Segment {
     first_frame_num: u32,
     num_subtitle_samples: u16,
 
+    /// For key-frame-only files, the key frames to include, as filled by `find_key_frames`.
+    key_frames: Option<Box<[KeyFrame]>>,
+
+    /// For key-frame-only files, the 0-based index in the `File` of `key_frames[0]`.
+    first_key_frame: usize,
+
     index_once: Once,
 }
 
+/// A key frame within a key-frame-only `File`. See `FileBuilder::key_frames_only`.
+#[derive(Debug)]
+struct KeyFrame {
+    /// The starting data byte position of this frame within the sample file.
+    pos: u64,
+    bytes: u32,
+
+    /// The starting time of this frame within the recording (in 90 kHz units).
+    start_90k: i32,
+
+    /// The presentation duration, after dividing by the speed factor.
+    duration_90k: u32,
+}
+
 // Manually implement Debug because `index` and `index_once` are not Debug.
 impl fmt::Debug for Segment {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -360,6 +383,7 @@ impl fmt::Debug for Segment {
            .field("s", &self.s)
            .field("first_frame_num", &self.first_frame_num)
            .field("num_subtitle_samples", &self.num_subtitle_samples)
+           .field("key_frames", &self.key_frames.as_ref().map(|k| k.len()))
            .finish()
     }
 }
@@ -375,9 +399,64 @@ impl Segment {
             index_once: Once::new(),
             first_frame_num,
             num_subtitle_samples: 0,
+            key_frames: None,
+            first_key_frame: 0,
         })
     }
 
+    /// Finds the key frames within the desired range for a key-frame-only `File`. Each is
+    /// presented from its start (or the desired start, if later) until the next key frame (or the
+    /// desired end), with that duration divided by `speed`.
+    fn find_key_frames(&mut self, db: &db::LockedDatabase, speed: u32,
+                       first_key_frame: usize) -> Result<(), Error> {
+        let mut key_frames = Vec::with_capacity(self.s.key_frames as usize);
+        let s = &self.s;
+        db.with_recording_playback(s.id, &mut |playback| {
+            s.foreach(playback, |it| {
+                if it.is_key() {
+                    key_frames.push(KeyFrame {
+                        pos: it.pos as u64,
+                        bytes: it.bytes as u32,
+                        start_90k: it.start_90k,
+                        duration_90k: 0,
+                    });
+                }
+                Ok(())
+            })
+        }).err_kind(ErrorKind::Unknown)?;
+
+        // Scale positions relative to the desired start rather than each duration, so that
+        // rounding doesn't accumulate: the durations sum to exactly the segment's scaled length.
+        let d = s.desired_range_90k.clone();
+        let scale = |t: i32| (cmp::min(cmp::max(t, d.start), d.end) - d.start) as u32 / speed;
+        for i in 0 .. key_frames.len() {
+            let end = key_frames.get(i + 1).map(|k| k.start_90k).unwrap_or(d.end);
+            key_frames[i].duration_90k = scale(end) - scale(key_frames[i].start_90k);
+        }
+        self.key_frames = Some(key_frames.into_boxed_slice());
+        self.first_key_frame = first_key_frame;
+        Ok(())
+    }
+
+    /// Returns the number of video samples this segment contributes to the `File`.
+    fn num_samples(&self) -> u32 {
+        match self.key_frames {
+            Some(ref k) => k.len() as u32,
+            None => self.s.frames as u32,
+        }
+    }
+
+    /// Returns the length of this segment's video sample data within `mdat`.
+    fn sample_data_len(&self) -> u64 {
+        match self.key_frames {
+            Some(ref k) => k.iter().map(|k| k.bytes as u64).sum(),
+            None => {
+                let r = self.s.sample_file_range();
+                r.end - r.start
+            },
+        }
+    }
+
     fn get_index<'a, F>(&'a self, db: &db::Database, f: F) -> Result<&'a [u8], Error>
     where F: FnOnce(&[u8], SegmentLengths) -> &[u8] {
         self.index_once.call_once(|| {
@@ -394,10 +473,16 @@ impl Segment {
     }
 
     fn lens(&self) -> SegmentLengths {
+        let samples = self.num_samples() as usize;
         SegmentLengths {
-            stts: mem::size_of::<u32>() * 2 * (self.s.frames as usize),
-            stsz: mem::size_of::<u32>() * self.s.frames as usize,
-            stss: mem::size_of::<u32>() * self.s.key_frames as usize,
+            stts: mem::size_of::<u32>() * 2 * samples,
+            stsz: mem::size_of::<u32>() * samples,
+
+            // A key-frame-only file has no stss; every sample is a sync sample.
+            stss: match self.key_frames {
+                Some(_) => 0,
+                None => mem::size_of::<u32>() * self.s.key_frames as usize,
+            },
         }
     }
 
@@ -415,6 +500,16 @@ impl Segment {
             v.into_boxed_slice()
         };
 
+        if let Some(ref key_frames) = self.key_frames {
+            let (stts, stsz) = buf.split_at_mut(lens.stts);
+            for (i, k) in key_frames.iter().enumerate() {
+                BigEndian::write_u32(&mut stts[8*i .. 8*i+4], 1);
+                BigEndian::write_u32(&mut stts[8*i+4 .. 8*i+8], k.duration_90k);
+                BigEndian::write_u32(&mut stsz[4*i .. 4*i+4], k.bytes);
+            }
+            return Ok(buf);
+        }
+
         {
             let (stts, rest) = buf.split_at_mut(lens.stts);
             let (stsz, stss) = rest.split_at_mut(lens.stsz);
@@ -539,11 +634,168 @@ impl Segment {
     }
 }
 
+/// A text track, following the video track(s).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SubtitleTrack {
+    /// See `FileBuilder::include_timestamp_subtitle_track`.
+    Timestamps,
+
+    /// See `FileBuilder::include_signal_subtitle_track`.
+    Signals,
+}
+
+/// The samples of a signal subtitle track, as computed by `FileBuilder::build`. Unlike the
+/// timestamp subtitles, these vary in length and so are computed eagerly and stored in
+/// `FileInner::buf`.
+#[derive(Debug, Default)]
+struct SignalSubtitles {
+    /// The `(duration_90k, size)` of each sample.
+    samples: Vec<(u32, u32)>,
+
+    /// The samples' data, each a `u16` length followed by UTF-8 text.
+    data: Vec<u8>,
+
+    /// The position within `FileBuilder::body.buf` of the track's single chunk offset, which is
+    /// filled in by `FileBuilder::append_mdat`.
+    co64_pos: Option<usize>,
+}
+
+impl SignalSubtitles {
+    /// Appends a sample, or extends the previous one if it has the same text.
+    fn push(&mut self, duration_90k: u32, text: &str) {
+        if let Some(last) = self.samples.last_mut() {
+            let last_text = &self.data[self.data.len() - last.1 as usize + 2 ..];
+            if last_text == text.as_bytes() {
+                last.0 += duration_90k;
+                return;
+            }
+        }
+        self.data.write_u16::<BigEndian>(text.len() as u16).expect("Vec write shouldn't fail");
+        self.data.extend_from_slice(text.as_bytes());
+        self.samples.push((duration_90k, (mem::size_of::<u16>() + text.len()) as u32));
+    }
+}
+
+/// Divides `range` into intervals in which the states of the given camera's signals are
+/// unchanged, calling `f` with each interval's duration and text. The text lists the signals in a
+/// known state, one per line, as `short_name: state name`; it's empty if there are none.
+/// This is shared by the signal subtitle track and `vtt`.
+pub fn signal_intervals(db: &db::LockedDatabase, camera_id: i32, range: Range<recording::Time>,
+                        f: &mut dyn FnMut(recording::Duration, &str)) {
+    let signals = db.signals_by_id();
+    let types = db.signal_types_by_uuid();
+
+    // This includes the states as of the start, followed by the changes within, by time.
+    let mut changes = Vec::new();
+    db.list_changes_by_time(range.clone(), &mut |c| {
+        let applies = match signals.get(&c.signal) {
+            Some(sig) => sig.cameras.iter().any(|sc| sc.camera_id == camera_id),
+            None => false,
+        };
+        if applies {
+            changes.push(*c);
+        }
+    });
+    let mut states = BTreeMap::new();
+    let mut changes = changes.iter().peekable();
+    let mut cur = range.start;
+    loop {
+        while let Some(c) = changes.peek() {
+            if c.when > cur {
+                break;
+            }
+            states.insert(c.signal, c.state);
+            changes.next();
+        }
+        let next = changes.peek().map(|c| c.when).unwrap_or(range.end);
+        let mut text = String::new();
+        for (&signal, &state) in &states {
+            if state == 0 {
+                continue;  // unknown.
+            }
+            let signal = &signals[&signal];
+            let state_name = types.get(&signal.type_)
+                                  .and_then(|t| t.states.iter().find(|s| s.value == state))
+                                  .map(|s| s.name.clone())
+                                  .unwrap_or_else(|| state.to_string());
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&signal.short_name);
+            text.push_str(": ");
+            text.push_str(&state_name);
+        }
+        f(next - cur, &text);
+        if next >= range.end {
+            break;
+        }
+        cur = next;
+    }
+}
+
+/// An entry in an `EditListBox` (ISO/IEC 14496-12 section 8.6.6).
+#[derive(Debug, Default)]
+struct Edit {
+    segment_duration: u64,
+
+    /// The media time at which this edit starts, or `EMPTY_EDIT` to present nothing for
+    /// `segment_duration`.
+    media_time: u64,
+}
+
+/// The `media_time` of an empty edit: -1 as a version 1 `elst`'s signed 64-bit value.
+const EMPTY_EDIT: u64 = u64::max_value();
+
+/// A video track, made up of consecutive entries in `segments`.
+#[derive(Debug)]
+struct Track {
+    /// For tracks created by `FileBuilder::append_track`, the wall-clock time at which the
+    /// track's presentation starts. Gaps before and between its segments become empty edits.
+    start: Option<recording::Time>,
+
+    /// The range of `segments` within this track.
+    segments: Range<usize>,
+
+    /// Indexes into `video_sample_entries`, in order of this track's sample description index.
+    video_sample_entries: SmallVec<[usize; 1]>,
+
+    /// The edit list, as filled by `FileBuilder::build`.
+    edits: Vec<Edit>,
+}
+
+impl Track {
+    fn new(start: Option<recording::Time>, first_segment: usize) -> Self {
+        Track {
+            start,
+            segments: first_segment .. first_segment,
+            video_sample_entries: SmallVec::new(),
+            edits: Vec::new(),
+        }
+    }
+
+    /// Returns the presentation duration, including empty edits.
+    fn duration_90k(&self) -> u64 { self.edits.iter().map(|e| e.segment_duration).sum() }
+
+    /// Returns the duration of the media presented.
+    fn media_duration_90k(&self) -> u64 {
+        self.edits.iter().filter(|e| e.media_time != EMPTY_EDIT).map(|e| e.segment_duration).sum()
+    }
+
+    /// Returns true iff the edit list maps media time to presentation time one-to-one, so no
+    /// `EditBox` is necessary.
+    fn has_implicit_edits(&self) -> bool {
+        self.edits.len() == 1 && self.edits[0].media_time == 0
+    }
+}
+
 pub struct FileBuilder {
     /// Segments of video: one per "recording" table entry as they should
     /// appear in the video.
     segments: Vec<Segment>,
+    tracks: Vec<Track>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
+
+    /// The 1-indexed frame number within the current track of the next frame to be appended.
     next_frame_num: u32,
     duration_90k: u64,
     num_subtitle_samples: u32,
@@ -551,6 +803,11 @@ pub struct FileBuilder {
     body: BodyState,
     type_: Type,
     include_timestamp_subtitle_track: bool,
+    include_signal_subtitle_track: bool,
+    signal_subtitles: SignalSubtitles,
+
+    /// The speed factor for a key-frame-only file, if any. See `key_frames_only`.
+    key_frames_only: Option<u32>,
 }
 
 /// The portion of `FileBuilder` which is mutated while building the body of the file.
@@ -587,10 +844,11 @@ enum SliceType {
     Stts = 3,                // param is index into m.segments
     Stsz = 4,                // param is index into m.segments
     Stss = 5,                // param is index into m.segments
-    Co64 = 6,                // param is unused
+    Co64 = 6,                // param is index into m.tracks
     VideoSampleData = 7,     // param is index into m.segments
     SubtitleSampleData = 8,  // param is index into m.segments
     Truns = 9,               // param is index into m.segments
+    KeyFrameSampleData = 10, // param is index of key frame within the file
 
     // There must be no value > 15, as this is packed into 4 bits in Slice.
 }
@@ -623,8 +881,7 @@ impl Slice {
         let s = &mp4.0.segments[self.p()];
         let mut pos = mp4.0.initial_sample_byte_pos;
         for ps in &mp4.0.segments[0 .. self.p()] {
-            let r = ps.s.sample_file_range();
-            pos += r.end - r.start;
+            pos += ps.sample_data_len();
         }
         let truns =
             mp4.0.db.lock()
@@ -635,6 +892,10 @@ impl Slice {
     }
 }
 
+fn sample_data_stream(s: Result<BodyStream, Error>) -> BodyStream {
+    s.unwrap_or_else(|e| Box::new(stream::once(futures::future::err(wrap_error(e)))))
+}
+
 impl slices::Slice for Slice {
     type Ctx = File;
     type Chunk = Chunk;
@@ -662,10 +923,16 @@ impl slices::Slice for Slice {
             SliceType::Stts => self.wrap_index(f, range.clone(), &Segment::stts),
             SliceType::Stsz => self.wrap_index(f, range.clone(), &Segment::stsz),
             SliceType::Stss => self.wrap_index(f, range.clone(), &Segment::stss),
-            SliceType::Co64 => f.0.get_co64(range.clone(), len),
-            SliceType::VideoSampleData => f.0.get_video_sample_data(p, range.clone()),
+            SliceType::Co64 => f.0.get_co64(p, range.clone(), len),
             SliceType::SubtitleSampleData => f.0.get_subtitle_sample_data(p, range.clone(), len),
             SliceType::Truns => self.wrap_truns(f, range.clone(), len as usize),
+            // Sample data is streamed as read from disk, possibly in several pieces.
+            SliceType::VideoSampleData => {
+                return sample_data_stream(f.0.get_video_sample_data(p, range))
+            },
+            SliceType::KeyFrameSampleData => {
+                return sample_data_stream(f.0.get_key_frame_data(p, range))
+            },
         };
         Box::new(stream::once(futures::future::ready(res
             .map_err(|e| wrap_error(e))
@@ -722,6 +989,7 @@ impl FileBuilder {
     pub fn new(type_: Type) -> Self {
         FileBuilder {
             segments: Vec::new(),
+            tracks: Vec::new(),
             video_sample_entries: SmallVec::new(),
             next_frame_num: 1,
             duration_90k: 0,
@@ -734,9 +1002,26 @@ impl FileBuilder {
             },
             type_: type_,
             include_timestamp_subtitle_track: false,
+            include_signal_subtitle_track: false,
+            signal_subtitles: SignalSubtitles::default(),
+            key_frames_only: None,
         }
     }
 
+    /// Sets if the generated `.mp4` should include a subtitle track with the states of signals
+    /// associated with the camera (via `signal_camera`), e.g. "motion: on". Default is false.
+    pub fn include_signal_subtitle_track(&mut self, b: bool) {
+        self.include_signal_subtitle_track = b;
+    }
+
+    /// Sets if the generated `.mp4` should include only key frames, for a fast-forward or
+    /// timelapse view of a long range without transcoding. Each key frame is shown until the next
+    /// one would be, with all durations divided by `speed`; e.g. a speed of 720 plays a day in two
+    /// minutes. Default is `None`: all frames at their recorded durations.
+    pub fn key_frames_only(&mut self, speed: Option<u32>) {
+        self.key_frames_only = speed;
+    }
+
     /// Sets if the generated `.mp4` should include a subtitle track with second-level timestamps.
     /// Default is false.
     pub fn include_timestamp_subtitle_track(&mut self, b: bool) {
@@ -750,12 +1035,33 @@ impl FileBuilder {
 
     pub fn append_video_sample_entry(&mut self, ent: Arc<db::VideoSampleEntry>) {
         self.video_sample_entries.push(ent);
+        let i = self.video_sample_entries.len() - 1;
+        self.cur_track().video_sample_entries.push(i);
+    }
+
+    /// Starts a new video track, to which subsequent segments will be appended. Each track's
+    /// presentation begins at the wall-clock time `start`, so tracks for several streams with the
+    /// same `start` play back in sync. If this is never called, the file has a single video track
+    /// which begins with its first segment.
+    pub fn append_track(&mut self, start: recording::Time) {
+        self.tracks.push(Track::new(Some(start), self.segments.len()));
+        self.next_frame_num = 1;
+    }
+
+    /// Returns the track to which segments are currently being appended, creating the implicit
+    /// first track if necessary.
+    fn cur_track(&mut self) -> &mut Track {
+        if self.tracks.is_empty() {
+            self.tracks.push(Track::new(None, self.segments.len()));
+        }
+        self.tracks.last_mut().unwrap()
     }
 
     /// Appends a segment for (a subset of) the given recording.
     pub fn append(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
                   rel_range_90k: Range<i32>) -> Result<(), Error> {
-        if let Some(prev) = self.segments.last() {
+        let track_segments = self.cur_track().segments.clone();
+        if let Some(prev) = self.segments[track_segments].last() {
             if prev.s.have_trailing_zero() {
                 bail_t!(InvalidArgument,
                         "unable to append recording {} after recording {} with trailing zero",
@@ -766,9 +1072,21 @@ impl FileBuilder {
 
         self.next_frame_num += s.s.frames as u32;
         self.segments.push(s);
-        if !self.video_sample_entries.iter().any(|e| e.id == row.video_sample_entry_id) {
-            let vse = db.video_sample_entries_by_id().get(&row.video_sample_entry_id).unwrap();
-            self.video_sample_entries.push(vse.clone());
+        let vse = match self.video_sample_entries.iter()
+                            .position(|e| e.id == row.video_sample_entry_id) {
+            Some(i) => i,
+            None => {
+                let vse = db.video_sample_entries_by_id().get(&row.video_sample_entry_id)
+                            .unwrap();
+                self.video_sample_entries.push(vse.clone());
+                self.video_sample_entries.len() - 1
+            },
+        };
+        let num_segments = self.segments.len();
+        let t = self.cur_track();
+        t.segments.end = num_segments;
+        if !t.video_sample_entries.contains(&vse) {
+            t.video_sample_entries.push(vse);
         }
         Ok(())
     }
@@ -777,6 +1095,32 @@ impl FileBuilder {
     pub fn build(mut self, db: Arc<db::Database>,
                  dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>)
                  -> Result<File, Error> {
+        self.cur_track();
+        if self.tracks.len() > 1 {
+            if self.type_ != Type::Normal {
+                bail_t!(InvalidArgument, "segments can't have multiple tracks");
+            }
+            if self.include_timestamp_subtitle_track || self.include_signal_subtitle_track {
+                bail_t!(InvalidArgument, "subtitles aren't supported with multiple tracks");
+            }
+        }
+        let mut num_key_frames = 0;
+        if let Some(speed) = self.key_frames_only {
+            if self.type_ != Type::Normal {
+                bail_t!(InvalidArgument, "segments can't be key-frame-only");
+            }
+            if self.include_timestamp_subtitle_track || self.include_signal_subtitle_track {
+                bail_t!(InvalidArgument, "subtitles aren't supported with key-frame-only files");
+            }
+            if speed == 0 {
+                bail_t!(InvalidArgument, "key-frame-only speed must be positive");
+            }
+            let l = db.lock();
+            for s in &mut self.segments {
+                s.find_key_frames(&l, speed, num_key_frames)?;
+                num_key_frames += s.num_samples() as usize;
+            }
+        }
         let mut max_end = None;
         let mut etag = hash::Hasher::new(hash::MessageDigest::sha1())
             .err_kind(ErrorKind::Internal)?;
@@ -784,11 +1128,42 @@ impl FileBuilder {
         if self.include_timestamp_subtitle_track {
             etag.update(b":ts:").err_kind(ErrorKind::Internal)?;
         }
+        if self.include_signal_subtitle_track {
+            if self.type_ != Type::Normal {
+                bail_t!(InvalidArgument, "segments can't have signal subtitles");
+            }
+            self.signal_subtitles = self.build_signal_subtitles(&db.lock())?;
+
+            // Signal states for a given time may change after the fact, so the etag must
+            // reflect the subtitles themselves, not just the options that produced them.
+            etag.update(b":signals:").err_kind(ErrorKind::Internal)?;
+            for &(duration_90k, _) in &self.signal_subtitles.samples {
+                let mut data = [0_u8; 4];
+                BigEndian::write_u32(&mut data[..], duration_90k);
+                etag.update(&data[..]).err_kind(ErrorKind::Internal)?;
+            }
+            etag.update(&self.signal_subtitles.data).err_kind(ErrorKind::Internal)?;
+        }
         match self.type_ {
             Type::Normal => {},
             Type::InitSegment => etag.update(b":init:").err_kind(ErrorKind::Internal)?,
             Type::MediaSegment => etag.update(b":media:").err_kind(ErrorKind::Internal)?,
         };
+        if let Some(speed) = self.key_frames_only {
+            let mut data = [0_u8; 4];
+            BigEndian::write_u32(&mut data[..], speed);
+            etag.update(b":key:").err_kind(ErrorKind::Internal)?;
+            etag.update(&data[..]).err_kind(ErrorKind::Internal)?;
+        }
+        for t in &self.tracks {
+            if let Some(start) = t.start {
+                let mut data = [0_u8; 16];
+                BigEndian::write_i64(&mut data[..8], start.0);
+                BigEndian::write_u64(&mut data[8..], (t.segments.end - t.segments.start) as u64);
+                etag.update(b":track:").err_kind(ErrorKind::Internal)?;
+                etag.update(&data[..]).err_kind(ErrorKind::Internal)?;
+            }
+        }
         for s in &mut self.segments {
             let d = &s.s.desired_range_90k;
             self.duration_90k += (d.end - d.start) as u64;
@@ -818,18 +1193,28 @@ impl FileBuilder {
             cursor.write_i32::<BigEndian>(d.end).err_kind(ErrorKind::Internal)?;
             etag.update(cursor.into_inner()).err_kind(ErrorKind::Internal)?;
         }
+        for i in 0 .. self.tracks.len() {
+            let edits = self.track_edits(&self.tracks[i])?;
+            self.tracks[i].edits = edits;
+        }
         let max_end = match max_end {
             None => 0,
             Some(v) => v.unix_seconds(),
         };
         let creation_ts = to_iso14496_timestamp(max_end);
-        let mut est_slices = 16 + self.video_sample_entries.len() + 4 * self.segments.len();
+        let mut est_slices = 16 * self.tracks.len() + self.video_sample_entries.len() +
+                             4 * self.segments.len();
         if self.include_timestamp_subtitle_track {
             est_slices += 16 + self.segments.len();
         }
+        if self.include_signal_subtitle_track {
+            est_slices += 16;
+        }
+        est_slices += num_key_frames;
         self.body.slices.reserve(est_slices);
-        const EST_BUF_LEN: usize = 2048;
-        self.body.buf.reserve(EST_BUF_LEN);
+        const EST_BUF_LEN_PER_TRACK: usize = 2048;
+        let est_buf_len = EST_BUF_LEN_PER_TRACK * self.tracks.len();
+        self.body.buf.reserve(est_buf_len);
         let initial_sample_byte_pos = match self.type_ {
             Type::MediaSegment => {
                 self.append_moof()?;
@@ -865,10 +1250,10 @@ impl FileBuilder {
             debug!("Estimated {} slices; actually were {} slices", est_slices,
                    self.body.slices.num());
         }
-        if EST_BUF_LEN < self.body.buf.len() {
-            warn!("Estimated {} buf bytes; actually were {}", EST_BUF_LEN, self.body.buf.len());
+        if est_buf_len < self.body.buf.len() {
+            warn!("Estimated {} buf bytes; actually were {}", est_buf_len, self.body.buf.len());
         } else {
-            debug!("Estimated {} buf bytes; actually were {}", EST_BUF_LEN, self.body.buf.len());
+            debug!("Estimated {} buf bytes; actually were {}", est_buf_len, self.body.buf.len());
         }
         debug!("segments: {:#?}", self.segments);
         debug!("slices: {:?}", self.body.slices);
@@ -879,6 +1264,7 @@ impl FileBuilder {
             db,
             dirs_by_stream_id,
             segments: self.segments,
+            tracks: self.tracks,
             slices: self.body.slices,
             buf: self.body.buf,
             video_sample_entries: self.video_sample_entries,
@@ -898,8 +1284,18 @@ impl FileBuilder {
         self.body.flush_buf()?;
         let initial_sample_byte_pos = self.body.slices.len();
         for (i, s) in self.segments.iter().enumerate() {
-            let r = s.s.sample_file_range();
-            self.body.append_slice(r.end - r.start, SliceType::VideoSampleData, i)?;
+            match s.key_frames {
+                Some(ref key_frames) => {
+                    // The key frames aren't contiguous within the sample file, so they need a
+                    // slice each, but are contiguous within mdat, so still one chunk per segment.
+                    for (j, k) in key_frames.iter().enumerate() {
+                        self.body.append_slice(k.bytes as u64, SliceType::KeyFrameSampleData,
+                                               s.first_key_frame + j)?;
+                    }
+                },
+                None => self.body.append_slice(s.sample_data_len(), SliceType::VideoSampleData,
+                                               i)?,
+            }
         }
         if let Some(p) = self.subtitle_co64_pos {
             BigEndian::write_u64(&mut self.body.buf[p .. p + 8], self.body.slices.len());
@@ -910,6 +1306,11 @@ impl FileBuilder {
                     SliceType::SubtitleSampleData, i)?;
             }
         }
+        if let Some(p) = self.signal_subtitles.co64_pos {
+            BigEndian::write_u64(&mut self.body.buf[p .. p + 8], self.body.slices.len());
+            self.body.buf.extend_from_slice(&self.signal_subtitles.data);
+            self.body.flush_buf()?;
+        }
         // Fill in the length left as a placeholder above. Note the 16 here is the length
         // of the mdat header.
         BigEndian::write_u64(&mut self.body.buf[mdat_len_pos .. mdat_len_pos + 8],
@@ -922,9 +1323,14 @@ impl FileBuilder {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"moov");
             self.append_mvhd(creation_ts)?;
-            self.append_video_trak(creation_ts)?;
+            for i in 0 .. self.tracks.len() {
+                self.append_video_trak(i, creation_ts)?;
+            }
             if self.include_timestamp_subtitle_track {
-                self.append_subtitle_trak(creation_ts)?;
+                self.append_subtitle_trak(SubtitleTrack::Timestamps, creation_ts)?;
+            }
+            if self.include_signal_subtitle_track {
+                self.append_subtitle_trak(SubtitleTrack::Signals, creation_ts)?;
             }
             if self.type_ == Type::InitSegment {
                 self.append_mvex()?;
@@ -985,12 +1391,15 @@ impl FileBuilder {
                 self.append_truns()?;
 
                 // `TrackFragmentBaseMediaDecodeTimeBox` (ISO/IEC 14496-12 section 8.8.12).
+                // baseMediaDecodeTime is the wall-clock time of the first frame, in 90 kHz units
+                // since the epoch. This keeps consecutive segments on a single timeline, as HLS
+                // requires.
+                let base_media_decode_time = self.segments.first().map(|s| {
+                    (s.s.start + recording::Duration(s.s.actual_start_90k() as i64)).0 as u64
+                }).unwrap_or(0);
                 write_length!(self, {
-                    self.body.buf.extend_from_slice(&[
-                        b't', b'f', b'd', b't',
-                        0x00, 0x00, 0x00, 0x00,  // version + flags
-                        0x00, 0x00, 0x00, 0x00,  // TODO: baseMediaDecodeTime
-                    ]);
+                    self.body.buf.extend_from_slice(b"tfdt\x01\x00\x00\x00");  // version + flags
+                    self.body.append_u64(base_media_decode_time);
                 })?;
             })?;
         })
@@ -1011,46 +1420,51 @@ impl FileBuilder {
             self.body.append_u64(creation_ts as u64);
             self.body.append_u64(creation_ts as u64);
             self.body.append_u32(TIME_UNITS_PER_SEC as u32);
-            let d = self.duration_90k;
+            let d = self.tracks.iter().map(|t| t.duration_90k()).max().unwrap_or(0);
             self.body.append_u64(d);
             self.body.append_static(StaticBytestring::MvhdJunk)?;
-            let next_track_id = if self.include_timestamp_subtitle_track { 3 } else { 2 };
+            let next_track_id = self.tracks.len() as u32 + 1 +
+                                if self.include_timestamp_subtitle_track { 1 } else { 0 } +
+                                if self.include_signal_subtitle_track { 1 } else { 0 };
             self.body.append_u32(next_track_id);
         })
     }
 
     /// Appends a `TrackBox` (ISO/IEC 14496-12 section 8.3.1) suitable for video.
-    fn append_video_trak(&mut self, creation_ts: u32) -> Result<(), Error> {
+    fn append_video_trak(&mut self, track: usize, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"trak");
-            self.append_video_tkhd(creation_ts)?;
-            self.maybe_append_video_edts()?;
-            self.append_video_mdia(creation_ts)?;
+            self.append_video_tkhd(track, creation_ts)?;
+            self.maybe_append_video_edts(track)?;
+            self.append_video_mdia(track, creation_ts)?;
         })
     }
 
     /// Appends a `TrackBox` (ISO/IEC 14496-12 section 8.3.1) suitable for subtitles.
-    fn append_subtitle_trak(&mut self, creation_ts: u32) -> Result<(), Error> {
+    fn append_subtitle_trak(&mut self, which: SubtitleTrack, creation_ts: u32)
+                            -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"trak");
-            self.append_subtitle_tkhd(creation_ts)?;
-            self.append_subtitle_mdia(creation_ts)?;
+            self.append_subtitle_tkhd(which, creation_ts)?;
+            self.append_subtitle_mdia(which, creation_ts)?;
         })
     }
 
     /// Appends a `TrackHeaderBox` (ISO/IEC 14496-12 section 8.3.2) suitable for video.
-    fn append_video_tkhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+    fn append_video_tkhd(&mut self, track: usize, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             // flags 7: track_enabled | track_in_movie | track_in_preview
             self.body.buf.extend_from_slice(b"tkhd\x00\x00\x00\x07");
             self.body.append_u32(creation_ts);
             self.body.append_u32(creation_ts);
-            self.body.append_u32(1);  // track_id
+            self.body.append_u32(track as u32 + 1);  // track_id
             self.body.append_u32(0);  // reserved
-            self.body.append_u32(self.duration_90k as u32);
+            self.body.append_u32(self.tracks[track].duration_90k() as u32);
             self.body.append_static(StaticBytestring::TkhdJunk)?;
 
-            let (width, height) = self.video_sample_entries.iter().fold(None, |m, e| {
+            let entries = &self.video_sample_entries;
+            let track_entries = &self.tracks[track].video_sample_entries;
+            let (width, height) = track_entries.iter().map(|&i| &entries[i]).fold(None, |m, e| {
                 match m {
                     None => Some((e.width, e.height)),
                     Some((w, h)) => Some((cmp::max(w, e.width), cmp::max(h, e.height))),
@@ -1062,13 +1476,20 @@ impl FileBuilder {
     }
 
     /// Appends a `TrackHeaderBox` (ISO/IEC 14496-12 section 8.3.2) suitable for subtitles.
-    fn append_subtitle_tkhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+    fn append_subtitle_tkhd(&mut self, which: SubtitleTrack, creation_ts: u32)
+                            -> Result<(), Error> {
         write_length!(self, {
             // flags 7: track_enabled | track_in_movie | track_in_preview
             self.body.buf.extend_from_slice(b"tkhd\x01\x00\x00\x07");
             self.body.append_u64(creation_ts as u64);
             self.body.append_u64(creation_ts as u64);
-            self.body.append_u32(2);  // track_id
+
+            // track_id: subtitle tracks follow the video tracks, timestamps first.
+            let mut track_id = self.tracks.len() as u32 + 1;
+            if which == SubtitleTrack::Signals && self.include_timestamp_subtitle_track {
+                track_id += 1;
+            }
+            self.body.append_u32(track_id);
             self.body.append_u32(0);  // reserved
             self.body.append_u64(self.duration_90k);
             self.body.append_static(StaticBytestring::TkhdJunk)?;
@@ -1077,55 +1498,97 @@ impl FileBuilder {
         })
     }
 
-    /// Appends an `EditBox` (ISO/IEC 14496-12 section 8.6.5) suitable for video, if necessary.
-    fn maybe_append_video_edts(&mut self) -> Result<(), Error> {
-        #[derive(Debug, Default)]
-        struct Entry {
-            segment_duration: u64,
-            media_time: u64,
-        };
-        let mut flushed: Vec<Entry> = Vec::new();
-        let mut unflushed: Entry = Default::default();
+    /// Computes the edit list for the given track.
+    fn track_edits(&self, t: &Track) -> Result<Vec<Edit>, Error> {
+        let mut flushed: Vec<Edit> = Vec::new();
+        let mut unflushed: Edit = Default::default();
         let mut cur_media_time: u64 = 0;
-        for s in &self.segments {
+        let mut cur_presentation_time = t.start;
+        for s in &self.segments[t.segments.clone()] {
             // The actual range may start before the desired range because it can only start on a
             // key frame. This relationship should hold true:
             // actual start <= desired start <= desired end
             let actual_start_90k = s.s.actual_start_90k();
-            let skip = s.s.desired_range_90k.start - actual_start_90k;
-            let keep = s.s.desired_range_90k.end - s.s.desired_range_90k.start;
+            let d = &s.s.desired_range_90k;
+            let (skip, keep) = match self.key_frames_only {
+                // Key frames are presented from the desired start; see `Segment::find_key_frames`.
+                Some(speed) => (0, (d.end - d.start) / speed as i32),
+                None => (d.start - actual_start_90k, d.end - d.start),
+            };
             if skip < 0 || keep < 0 {
                 bail_t!(Internal, "skip={} keep={} on segment {:#?}", skip, keep, s);
             }
             cur_media_time += skip as u64;
+            if let Some(p) = cur_presentation_time {
+                // Present nothing until the wall-clock time of the desired start.
+                let desired_start = s.s.start + recording::Duration(d.start as i64);
+                let gap = (desired_start - p).0 / self.key_frames_only.unwrap_or(1) as i64;
+                if gap > 0 {
+                    if unflushed.segment_duration > 0 {
+                        flushed.push(unflushed);
+                    }
+                    flushed.push(Edit {
+                        segment_duration: gap as u64,
+                        media_time: EMPTY_EDIT,
+                    });
+                    unflushed = Edit {
+                        segment_duration: 0,
+                        media_time: cur_media_time,
+                    };
+                }
+                cur_presentation_time = Some(cmp::max(p, desired_start) +
+                                             recording::Duration((d.end - d.start) as i64));
+            }
             if unflushed.segment_duration + unflushed.media_time == cur_media_time {
                 unflushed.segment_duration += keep as u64;
             } else {
                 if unflushed.segment_duration > 0 {
                     flushed.push(unflushed);
                 }
-                unflushed = Entry {
+                unflushed = Edit {
                     segment_duration: keep as u64,
                     media_time: cur_media_time,
                 };
             }
             cur_media_time += keep as u64;
         }
+        flushed.push(unflushed);
+        Ok(flushed)
+    }
 
-        if flushed.is_empty() && unflushed.media_time == 0 {
-            return Ok(());  // use implicit one-to-one mapping.
+    /// Computes the signal subtitle track's samples, one per interval from `signal_intervals`
+    /// within each segment's desired range.
+    fn build_signal_subtitles(&self, db: &db::LockedDatabase) -> Result<SignalSubtitles, Error> {
+        let mut out = SignalSubtitles::default();
+        for s in &self.segments {
+            let camera_id = db.streams_by_id().get(&s.s.id.stream())
+                              .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found",
+                                                           s.s.id))?
+                              .camera_id;
+            let d = &s.s.desired_range_90k;
+            let start = s.s.start + recording::Duration(d.start as i64);
+            let end = s.s.start + recording::Duration(d.end as i64);
+            signal_intervals(db, camera_id, start .. end,
+                             &mut |duration, text| out.push(duration.0 as u32, text));
         }
+        Ok(out)
+    }
 
-        flushed.push(unflushed);
+    /// Appends an `EditBox` (ISO/IEC 14496-12 section 8.6.5) suitable for video, if necessary.
+    fn maybe_append_video_edts(&mut self, track: usize) -> Result<(), Error> {
+        let t = &self.tracks[track];
+        if t.has_implicit_edits() {
+            return Ok(());  // use implicit one-to-one mapping.
+        }
 
-        debug!("Using edit list: {:?}", flushed);
+        debug!("Using edit list: {:?}", t.edits);
         write_length!(self, {
             self.body.buf.extend_from_slice(b"edts");
             write_length!(self, {
                 // Use version 1 for 64-bit times.
                 self.body.buf.extend_from_slice(b"elst\x01\x00\x00\x00");
-                self.body.append_u32(flushed.len() as u32);
-                for e in &flushed {
+                self.body.append_u32(t.edits.len() as u32);
+                for e in &t.edits {
                     self.body.append_u64(e.segment_duration);
                     self.body.append_u64(e.media_time);
 
@@ -1137,106 +1600,156 @@ impl FileBuilder {
     }
 
     /// Appends a `MediaBox` (ISO/IEC 14496-12 section 8.4.1) suitable for video.
-    fn append_video_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
+    fn append_video_mdia(&mut self, track: usize, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdia");
-            self.append_mdhd(creation_ts)?;
+            let d = self.tracks[track].media_duration_90k();
+            self.append_mdhd(creation_ts, d)?;
             self.body.append_static(StaticBytestring::VideoHdlrBox)?;
-            self.append_video_minf()?;
+            self.append_video_minf(track)?;
         })
     }
 
     /// Appends a `MediaBox` (ISO/IEC 14496-12 section 8.4.1) suitable for subtitles.
-    fn append_subtitle_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
+    fn append_subtitle_mdia(&mut self, which: SubtitleTrack, creation_ts: u32)
+                            -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdia");
-            self.append_mdhd(creation_ts)?;
+            let d = self.duration_90k;
+            self.append_mdhd(creation_ts, d)?;
             self.body.append_static(StaticBytestring::SubtitleHdlrBox)?;
-            self.append_subtitle_minf()?;
+            self.append_subtitle_minf(which)?;
         })
     }
 
     /// Appends a `MediaHeaderBox` (ISO/IEC 14496-12 section 8.4.2.) suitable for either the video
     /// or subtitle track.
-    fn append_mdhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+    fn append_mdhd(&mut self, creation_ts: u32, duration_90k: u64) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdhd\x01\x00\x00\x00");
             self.body.append_u64(creation_ts as u64);
             self.body.append_u64(creation_ts as u64);
             self.body.append_u32(TIME_UNITS_PER_SEC as u32);
-            self.body.append_u64(self.duration_90k);
+            self.body.append_u64(duration_90k);
             self.body.append_u32(0x55c40000);  // language=und + pre_defined
         })
     }
 
     /// Appends a `MediaInformationBox` (ISO/IEC 14496-12 section 8.4.4) suitable for video.
-    fn append_video_minf(&mut self) -> Result<(), Error> {
+    fn append_video_minf(&mut self, track: usize) -> Result<(), Error> {
         write_length!(self, {
             self.body.append_static(StaticBytestring::VideoMinfJunk)?;
-            self.append_video_stbl()?;
+            self.append_video_stbl(track)?;
         })
     }
 
     /// Appends a `MediaInformationBox` (ISO/IEC 14496-12 section 8.4.4) suitable for subtitles.
-    fn append_subtitle_minf(&mut self) -> Result<(), Error> {
+    fn append_subtitle_minf(&mut self, which: SubtitleTrack) -> Result<(), Error> {
         write_length!(self, {
             self.body.append_static(StaticBytestring::SubtitleMinfJunk)?;
-            self.append_subtitle_stbl()?;
+            self.append_subtitle_stbl(which)?;
         })
     }
 
     /// Appends a `SampleTableBox` (ISO/IEC 14496-12 section 8.5.1) suitable for video.
-    fn append_video_stbl(&mut self) -> Result<(), Error> {
+    fn append_video_stbl(&mut self, track: usize) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"stbl");
-            self.append_video_stsd()?;
-            self.append_video_stts()?;
-            self.append_video_stsc()?;
-            self.append_video_stsz()?;
-            self.append_video_co64()?;
-            self.append_video_stss()?;
+            self.append_video_stsd(track)?;
+            self.append_video_stts(track)?;
+            self.append_video_stsc(track)?;
+            self.append_video_stsz(track)?;
+            self.append_video_co64(track)?;
+            if self.key_frames_only.is_none() {
+                self.append_video_stss(track)?;
+            }
         })
     }
 
     /// Appends a `SampleTableBox` (ISO/IEC 14496-12 section 8.5.1) suitable for subtitles.
-    fn append_subtitle_stbl(&mut self) -> Result<(), Error> {
+    fn append_subtitle_stbl(&mut self, which: SubtitleTrack) -> Result<(), Error> {
         write_length!(self, {
             self.body.append_static(StaticBytestring::SubtitleStblJunk)?;
-            self.append_subtitle_stts()?;
-            self.append_subtitle_stsc()?;
-            self.append_subtitle_stsz()?;
-            self.append_subtitle_co64()?;
+            match which {
+                SubtitleTrack::Timestamps => {
+                    self.append_subtitle_stts()?;
+                    self.append_subtitle_stsc()?;
+                    self.append_subtitle_stsz()?;
+                    self.append_subtitle_co64()?;
+                },
+                SubtitleTrack::Signals => self.append_signal_sample_tables(),
+            }
         })
     }
 
+    /// Appends the `stts`, `stsc`, `stsz`, and `co64` boxes for the signal subtitle track.
+    /// All the samples are in a single chunk, whose offset is filled in by `append_mdat`.
+    fn append_signal_sample_tables(&mut self) {
+        let samples = &self.signal_subtitles.samples;
+        let n = samples.len() as u32;
+        let b = &mut self.body;
+
+        // TimeToSampleBox (ISO/IEC 14496-12 section 8.6.1).
+        b.append_u32(16 + 8 * n);
+        b.buf.extend_from_slice(b"stts\x00\x00\x00\x00");
+        b.append_u32(n);  // entry_count
+        for &(duration_90k, _) in samples {
+            b.append_u32(1);  // count
+            b.append_u32(duration_90k);
+        }
+
+        // SampleToChunkBox (ISO/IEC 14496-12 section 8.7.4).
+        b.buf.extend_from_slice(b"\x00\x00\x00\x1cstsc\x00\x00\x00\x00\x00\x00\x00\x01");
+        b.append_u32(1);  // first_chunk
+        b.append_u32(n);  // samples_per_chunk
+        b.append_u32(1);  // sample_description_index
+
+        // SampleSizeBox (ISO/IEC 14496-12 section 8.7.3).
+        b.append_u32(20 + 4 * n);
+        b.buf.extend_from_slice(b"stsz\x00\x00\x00\x00\x00\x00\x00\x00");
+        b.append_u32(n);  // sample_count
+        for &(_, size) in samples {
+            b.append_u32(size);
+        }
+
+        // ChunkLargeOffsetBox (ISO/IEC 14496-12 section 8.7.5), with a placeholder offset.
+        b.buf.extend_from_slice(
+            b"\x00\x00\x00\x18co64\x00\x00\x00\x00\x00\x00\x00\x01");
+        self.signal_subtitles.co64_pos = Some(b.buf.len());
+        b.append_u64(0);
+    }
+
     /// Appends a `SampleDescriptionBox` (ISO/IEC 14496-12 section 8.5.2) suitable for video.
-    fn append_video_stsd(&mut self) -> Result<(), Error> {
+    fn append_video_stsd(&mut self, track: usize) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"stsd\x00\x00\x00\x00");
-            let n_entries = self.video_sample_entries.len() as u32;
-            self.body.append_u32(n_entries);
+            let entries = &self.tracks[track].video_sample_entries;
+            self.body.append_u32(entries.len() as u32);
             self.body.flush_buf()?;
-            for (i, e) in self.video_sample_entries.iter().enumerate() {
+            for &i in entries {
+                let e = &self.video_sample_entries[i];
                 self.body.append_slice(e.data.len() as u64, SliceType::VideoSampleEntry, i)?;
             }
         })
     }
 
     /// Appends a `TimeToSampleBox` (ISO/IEC 14496-12 section 8.6.1) suitable for video.
-    fn append_video_stts(&mut self) -> Result<(), Error> {
+    fn append_video_stts(&mut self, track: usize) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"stts\x00\x00\x00\x00");
+            let first = self.tracks[track].segments.start;
+            let segments = &self.segments[self.tracks[track].segments.clone()];
             let mut entry_count = 0;
-            for s in &self.segments {
-                entry_count += s.s.frames as u32;
+            for s in segments {
+                entry_count += s.num_samples();
             }
             self.body.append_u32(entry_count);
-            if !self.segments.is_empty() {
+            if !segments.is_empty() {
                 self.body.flush_buf()?;
-                for (i, s) in self.segments.iter().enumerate() {
+                for (i, s) in segments.iter().enumerate() {
                     self.body.append_slice(
-                        2 * (mem::size_of::<u32>() as u64) * (s.s.frames as u64),
-                        SliceType::Stts, i)?;
+                        2 * (mem::size_of::<u32>() as u64) * (s.num_samples() as u64),
+                        SliceType::Stts, first + i)?;
                 }
             }
         })
@@ -1289,17 +1802,20 @@ impl FileBuilder {
     }
 
     /// Appends a `SampleToChunkBox` (ISO/IEC 14496-12 section 8.7.4) suitable for video.
-    fn append_video_stsc(&mut self) -> Result<(), Error> {
+    fn append_video_stsc(&mut self, track: usize) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"stsc\x00\x00\x00\x00");
-            self.body.append_u32(self.segments.len() as u32);
-            for (i, s) in self.segments.iter().enumerate() {
+            let t = &self.tracks[track];
+            let segments = &self.segments[t.segments.clone()];
+            self.body.append_u32(segments.len() as u32);
+            for (i, s) in segments.iter().enumerate() {
                 self.body.append_u32((i + 1) as u32);
-                self.body.append_u32(s.s.frames as u32);
+                self.body.append_u32(s.num_samples());
 
                 // Write sample_description_index.
-                let i = self.video_sample_entries.iter().position(
-                    |e| e.id == s.s.video_sample_entry_id()).unwrap();
+                let entries = &self.video_sample_entries;
+                let i = t.video_sample_entries.iter().position(
+                    |&e| entries[e].id == s.s.video_sample_entry_id()).unwrap();
                 self.body.append_u32((i + 1) as u32);
             }
         })
@@ -1316,19 +1832,22 @@ impl FileBuilder {
     }
 
     /// Appends a `SampleSizeBox` (ISO/IEC 14496-12 section 8.7.3) suitable for video.
-    fn append_video_stsz(&mut self) -> Result<(), Error> {
+    fn append_video_stsz(&mut self, track: usize) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"stsz\x00\x00\x00\x00\x00\x00\x00\x00");
+            let first = self.tracks[track].segments.start;
+            let segments = &self.segments[self.tracks[track].segments.clone()];
             let mut entry_count = 0;
-            for s in &self.segments {
-                entry_count += s.s.frames as u32;
+            for s in segments {
+                entry_count += s.num_samples();
             }
             self.body.append_u32(entry_count);
-            if !self.segments.is_empty() {
+            if !segments.is_empty() {
                 self.body.flush_buf()?;
-                for (i, s) in self.segments.iter().enumerate() {
+                for (i, s) in segments.iter().enumerate() {
                     self.body.append_slice(
-                        (mem::size_of::<u32>()) as u64 * (s.s.frames as u64), SliceType::Stsz, i)?;
+                        (mem::size_of::<u32>()) as u64 * (s.num_samples() as u64), SliceType::Stsz,
+                        first + i)?;
                 }
             }
         })
@@ -1344,15 +1863,17 @@ impl FileBuilder {
     }
 
     /// Appends a `ChunkLargeOffsetBox` (ISO/IEC 14496-12 section 8.7.5) suitable for video.
-    fn append_video_co64(&mut self) -> Result<(), Error> {
+    fn append_video_co64(&mut self, track: usize) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"co64\x00\x00\x00\x00");
-            self.body.append_u32(self.segments.len() as u32);
-            if !self.segments.is_empty() {
+            let segments = self.tracks[track].segments.clone();
+            let num_segments = segments.end - segments.start;
+            self.body.append_u32(num_segments as u32);
+            if num_segments > 0 {
                 self.body.flush_buf()?;
                 self.body.append_slice(
-                    (mem::size_of::<u64>()) as u64 * (self.segments.len() as u64),
-                    SliceType::Co64, 0)?;
+                    (mem::size_of::<u64>()) as u64 * (num_segments as u64),
+                    SliceType::Co64, track)?;
             }
         })
     }
@@ -1368,20 +1889,22 @@ impl FileBuilder {
     }
 
     /// Appends a `SyncSampleBox` (ISO/IEC 14496-12 section 8.6.2) suitable for video.
-    fn append_video_stss(&mut self) -> Result<(), Error> {
+    fn append_video_stss(&mut self, track: usize) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"stss\x00\x00\x00\x00");
+            let first = self.tracks[track].segments.start;
+            let segments = &self.segments[self.tracks[track].segments.clone()];
             let mut entry_count = 0;
-            for s in &self.segments {
+            for s in segments {
                 entry_count += s.s.key_frames as u32;
             }
             self.body.append_u32(entry_count);
-            if !self.segments.is_empty() {
+            if !segments.is_empty() {
                 self.body.flush_buf()?;
-                for (i, s) in self.segments.iter().enumerate() {
+                for (i, s) in segments.iter().enumerate() {
                     self.body.append_slice(
                         (mem::size_of::<u32>() as u64) * (s.s.key_frames as u64),
-                        SliceType::Stss, i)?;
+                        SliceType::Stss, first + i)?;
                 }
             }
         })
@@ -1427,6 +1950,7 @@ struct FileInner {
     db: Arc<db::Database>,
     dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>,
     segments: Vec<Segment>,
+    tracks: Vec<Track>,
     slices: Slices<Slice>,
     buf: Vec<u8>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
@@ -1436,41 +1960,44 @@ struct FileInner {
 }
 
 impl FileInner {
-    fn get_co64(&self, r: Range<u64>, l: u64) -> Result<Chunk, Error> {
+    /// Gets the chunk offsets of the given track. Chunks are one per segment, laid out in order
+    /// of segment within `mdat`.
+    fn get_co64(&self, track: usize, r: Range<u64>, l: u64) -> Result<Chunk, Error> {
         let mut v = Vec::with_capacity(l as usize);
         let mut pos = self.initial_sample_byte_pos;
-        for s in &self.segments {
-            v.write_u64::<BigEndian>(pos).err_kind(ErrorKind::Internal)?;
-            let r = s.s.sample_file_range();
-            pos += r.end - r.start;
+        let segments = &self.tracks[track].segments;
+        for (i, s) in self.segments[.. segments.end].iter().enumerate() {
+            if i >= segments.start {
+                v.write_u64::<BigEndian>(pos).err_kind(ErrorKind::Internal)?;
+            }
+            pos += s.sample_data_len();
         }
         Ok(ARefss::new(v).map(|v| &v[r.start as usize .. r.end as usize]).into())
     }
 
-    /// Gets a `Chunk` of video sample data from disk.
-    /// This works by `mmap()`ing in the data. There are a couple caveats:
-    ///
-    ///    * The thread which reads the resulting slice is likely to experience major page faults.
-    ///      Eventually this will likely be rewritten to `mmap()` the memory in another thread, and
-    ///      `mlock()` and send chunks of it to be read and `munlock()`ed to avoid this problem.
-    ///
-    ///    * If the backing file is truncated, the program will crash with `SIGBUS`. This shouldn't
-    ///      happen because nothing should be touching Moonfire NVR's files but itself.
-    fn get_video_sample_data(&self, i: usize, r: Range<u64>) -> Result<Chunk, Error> {
+    /// Gets a stream of video sample data from disk. See `body::sample_file_data`.
+    fn get_video_sample_data(&self, i: usize, r: Range<u64>) -> Result<BodyStream, Error> {
         let s = &self.segments[i];
-        let f = self.dirs_by_stream_id
+        let d = self.dirs_by_stream_id
                     .get(&s.s.id.stream())
-                    .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found", s.s.id))?
-                    .open_file(s.s.id).err_kind(ErrorKind::Unknown)?;
+                    .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found", s.s.id))?;
         let start = s.s.sample_file_range().start + r.start;
-        let mmap = Box::new(unsafe {
-            memmap::MmapOptions::new()
-                .offset(start)
-                .len((r.end - r.start) as usize)
-                .map(&f).err_kind(ErrorKind::Internal)?
-            });
-        use core::ops::Deref;
-        Ok(ARefss::new(mmap).map(|m| m.deref()).into())
+        body::sample_file_data(d, s.s.id, start .. start + (r.end - r.start))
+    }
+
+    /// Gets a stream of a single key frame's sample data from disk, for key-frame-only files.
+    fn get_key_frame_data(&self, k: usize, r: Range<u64>) -> Result<BodyStream, Error> {
+        let i = match self.segments.binary_search_by_key(&k, |s| s.first_key_frame) {
+            Ok(i) => i,
+            Err(i) => i - 1,  // every segment has at least one key frame, so i > 0.
+        };
+        let s = &self.segments[i];
+        let f = &s.key_frames.as_ref().expect("key-frame-only segment")[k - s.first_key_frame];
+        let d = self.dirs_by_stream_id
+                    .get(&s.s.id.stream())
+                    .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found", s.s.id))?;
+        let start = f.pos + r.start;
+        body::sample_file_data(d, s.s.id, start .. start + (r.end - r.start))
     }
 
     fn get_subtitle_sample_data(&self, i: usize, r: Range<u64>, l: u64) -> Result<Chunk, Error> {
@@ -1545,7 +2072,7 @@ impl fmt::Debug for File {
 ///      detect misunderstandings of the specification or incompatibilities, but they can be used
 ///      to verify the output is byte-for-byte as expected.
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use base::{clock::RealClocks, strutil};
     use bytes::Buf;
     use byteorder::{BigEndian, ByteOrder};
@@ -1778,7 +2305,7 @@ mod tests {
         }
     }
 
-    fn copy_mp4_to_db(db: &TestDb<RealClocks>) {
+    pub fn copy_mp4_to_db(db: &TestDb<RealClocks>) {
         let mut input =
             stream::FFMPEG.open(stream::Source::File("src/testdata/clip.mp4")).unwrap();
 
@@ -2138,6 +2665,177 @@ mod tests {
         assert_eq!(cursor.get_u32(12).await, 174063616);  // first_sample_flags
         assert_eq!(cursor.get_u32(16).await, 1);    // sample duration
         assert_eq!(cursor.get_u32(20).await, 15);   // sample size
+        assert!(cursor.find(b"tfdt").await);
+        assert_eq!(cursor.get_u32(0).await, 0x01000000);  // version + flags
+        assert_eq!(cursor.get_u64(4).await,
+                   (1430006400 * TIME_UNITS_PER_SEC + 2+4) as u64);  // baseMediaDecodeTime
+    }
+
+    /// Tests a file with two synchronized tracks, the first starting before its recording.
+    #[tokio::test]
+    async fn test_multiple_tracks() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        const START: recording::Time = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let mut builder = FileBuilder::new(Type::Normal);
+        for &track_start in &[recording::Time(START.0 - 10), START] {
+            let mut r = db::RecordingToInsert::default();
+            let mut encoder = recording::SampleIndexEncoder::new();
+            for i in 1..6 {
+                let duration_90k = 2 * i;
+                let bytes = 3 * i;
+                encoder.add_sample(duration_90k, bytes, (i % 2) == 1, &mut r).unwrap();
+            }
+            let row = db.insert_recording_from_encoder(r);
+            let d = row.duration_90k;
+            builder.append_track(track_start);
+            builder.append(&db.db.lock(), row, 0 .. d).unwrap();
+        }
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap();
+
+        // The first track should present nothing for its first 10 units.
+        let track = find_track(mp4.clone(), 1).await;
+        let mut cursor = track.edts_cursor.unwrap();
+        cursor.down().await;
+        cursor.find(b"elst").await;
+        assert_eq!(cursor.get_all().await, &[
+            0x01, 0x00, 0x00, 0x00,                          // version + flags
+            0x00, 0x00, 0x00, 0x02,                          // length
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a,  // segment_duration
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,  // media_time (empty)
+            0x00, 0x01, 0x00, 0x00,                          // media_rate_{integer,fraction}
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1e,  // segment_duration
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // media_time
+            0x00, 0x01, 0x00, 0x00,                          // media_rate_{integer,fraction}
+        ]);
+
+        // The second track's samples should be numbered from 1 and its chunk should follow the
+        // first track's in the mdat.
+        let mut mdat = BoxCursor::new(mp4.clone());
+        mdat.down().await;
+        assert!(mdat.find(b"mdat").await);
+        let track = find_track(mp4, 2).await;
+        assert!(track.edts_cursor.is_none());
+        let mut cursor = track.stbl_cursor;
+        cursor.down().await;
+        cursor.find(b"co64").await;
+        assert_eq!(cursor.get_u32(4).await, 1);  // entry_count
+        assert_eq!(cursor.get_u64(8).await, mdat.interior().start + 3+6+9+12+15);
+        cursor.find(b"stss").await;
+        assert_eq!(cursor.get_all().await, &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x03,  // entry_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x01,  // sample_number
+            0x00, 0x00, 0x00, 0x03,
+            0x00, 0x00, 0x00, 0x05,
+        ]);
+    }
+
+    /// Tests sample table for a key-frame-only file at double speed.
+    #[tokio::test]
+    async fn test_key_frames_only() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        for i in 1..6 {
+            let duration_90k = 2 * i;
+            let bytes = 3 * i;
+            encoder.add_sample(duration_90k, bytes, (i % 2) == 1, &mut r).unwrap();
+        }
+        let row = db.insert_recording_from_encoder(r);
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.key_frames_only(Some(2));
+
+        // Time range [2, 30) starts within the 1st key frame, which should be shown from 2.
+        builder.append(&db.db.lock(), row, 2 .. 30).unwrap();
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap();
+        let mut mdat = BoxCursor::new(mp4.clone());
+        mdat.down().await;
+        assert!(mdat.find(b"mdat").await);
+        assert_eq!(mdat.interior().end - mdat.interior().start, 3+9+15);
+        let track = find_track(mp4, 1).await;
+        assert!(track.edts_cursor.is_none());
+        let mut cursor = track.stbl_cursor;
+        cursor.down().await;
+        cursor.find(b"stts").await;
+        assert_eq!(cursor.get_all().await, &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x03,  // entry_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,  // run length / timestamps.
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x07,
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x05,
+        ]);
+
+        cursor.find(b"stsz").await;
+        assert_eq!(cursor.get_all().await, &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x00,  // sample_size
+            0x00, 0x00, 0x00, 0x03,  // sample_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x03,  // size
+            0x00, 0x00, 0x00, 0x09,
+            0x00, 0x00, 0x00, 0x0f,
+        ]);
+
+        // Every sample is a sync sample, so there's no stss.
+        assert!(!cursor.find(b"stss").await);
+    }
+
+    /// Tests the signal subtitle track's layout, for a camera without any signals.
+    #[tokio::test]
+    async fn test_signal_subtitles() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        for i in 1..6 {
+            let duration_90k = 2 * i;
+            let bytes = 3 * i;
+            encoder.add_sample(duration_90k, bytes, (i % 2) == 1, &mut r).unwrap();
+        }
+        let row = db.insert_recording_from_encoder(r);
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.include_signal_subtitle_track(true);
+        builder.append(&db.db.lock(), row, 0 .. 30).unwrap();
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap();
+        let mut mdat = BoxCursor::new(mp4.clone());
+        mdat.down().await;
+        assert!(mdat.find(b"mdat").await);
+        let track = find_track(mp4, 2).await;
+        let mut cursor = track.stbl_cursor;
+        cursor.down().await;
+        cursor.find(b"stts").await;
+        assert_eq!(cursor.get_all().await, &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x01,  // entry_count
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x1e,  // run length / timestamps.
+        ]);
+        cursor.find(b"stsz").await;
+        assert_eq!(cursor.get_all().await, &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x00,  // sample_size
+            0x00, 0x00, 0x00, 0x01,  // sample_count
+            0x00, 0x00, 0x00, 0x02,  // size of an empty sample
+        ]);
+        cursor.find(b"co64").await;
+        assert_eq!(cursor.get_u32(4).await, 1);  // entry_count
+        assert_eq!(cursor.get_u64(8).await, mdat.interior().start + 3+6+9+12+15);
+    }
+
+    #[test]
+    fn signal_subtitles_merge() {
+        let mut s = SignalSubtitles::default();
+        s.push(1, "motion: on");
+        s.push(2, "motion: on");
+        s.push(3, "");
+        assert_eq!(&s.samples[..], &[(3, 12), (3, 2)]);
+        assert_eq!(&s.data[..], b"\x00\x0amotion: on\x00\x00");
     }
 
     #[tokio::test]
@@ -2154,7 +2852,7 @@ mod tests {
         // combine ranges from the new format with ranges from the old format.
         let sha1 = digest(&mp4).await;
         assert_eq!("17376879bcf872dd4ad1197225a32d5473fb0dc6", strutil::hex(&sha1[..]));
-        const EXPECTED_ETAG: &'static str = "\"953dcf1a61debe785d5dec3ae2d3992a819b68ae\"";
+        const EXPECTED_ETAG: &'static str = "\"7b55d0bd4370712bf1a7549f6383ca51b1eb97e9\"";
         assert_eq!(Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()), mp4.etag());
         drop(db.syncer_channel);
         db.db.lock().clear_on_flush();
@@ -2175,7 +2873,7 @@ mod tests {
         // combine ranges from the new format with ranges from the old format.
         let sha1 = digest(&mp4).await;
         assert_eq!("1cd90e0b49747cc54c953153d6709f2fb5df6b14", strutil::hex(&sha1[..]));
-        const EXPECTED_ETAG: &'static str = "\"736655313f10747528a663190517620cdffea6d0\"";
+        const EXPECTED_ETAG: &'static str = "\"f17085373bbee7d2ffc99046575a1ef28f8134e0\"";
         assert_eq!(Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()), mp4.etag());
         drop(db.syncer_channel);
         db.db.lock().clear_on_flush();
@@ -2196,7 +2894,7 @@ mod tests {
         // combine ranges from the new format with ranges from the old format.
         let sha1 = digest(&mp4).await;
         assert_eq!("49893e3997da6bc625a04b09abf4b1ddbe0bc85d", strutil::hex(&sha1[..]));
-        const EXPECTED_ETAG: &'static str = "\"e87ed99dea31b7c4d1e9186045abaf5ac3c2d2f8\"";
+        const EXPECTED_ETAG: &'static str = "\"c48b2819f74b090d89c27fa615ab34e445a4b322\"";
         assert_eq!(Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()), mp4.etag());
         drop(db.syncer_channel);
         db.db.lock().clear_on_flush();
@@ -2217,7 +2915,7 @@ mod tests {
         // combine ranges from the new format with ranges from the old format.
         let sha1 = digest(&mp4).await;
         assert_eq!("0615feaa3c50a7889fb0e6842de3bd3d3143bc78", strutil::hex(&sha1[..]));
-        const EXPECTED_ETAG: &'static str = "\"6f0d21a6027b0e444f404a68527dbf5c9a5c1a26\"";
+        const EXPECTED_ETAG: &'static str = "\"48da7c8f9c15c318ef91ae00148356b3247b671f\"";
         assert_eq!(Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()), mp4.etag());
         drop(db.syncer_channel);
         db.db.lock().clear_on_flush();
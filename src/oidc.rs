@@ -0,0 +1,542 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! OpenID Connect single sign-on via the authorization code flow.
+//!
+//! A login starts at `GET /api/oidc/login`, which redirects the browser to the provider with a
+//! random `state` (also stored in a cookie, tying the login to this browser), a `nonce`, and a
+//! PKCE challenge. The provider redirects back to `GET /api/oidc/callback` with a code, which this
+//! server exchanges directly with the provider for an ID token. The token's RS256 signature is
+//! checked against the provider's published keys (JWKS), along with its issuer, audience,
+//! expiration, and nonce. Finally, a configurable claim is matched against usernames.
+//!
+//! The provider's metadata and keys are fetched on first use rather than at startup, so the NVR
+//! starts even when the provider is unreachable. The keys are refetched when an ID token names an
+//! unknown one, as after the provider rotates its keys.
+
+use failure::{Error, bail, format_err};
+use fnv::FnvHashMap;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use url::form_urlencoded;
+
+/// How long a browser may take to return from the provider, in seconds.
+pub const PENDING_TIMEOUT_SEC: i64 = 600;
+
+/// The maximum number of logins in progress. Beyond this, the oldest is forgotten.
+const MAX_PENDING: usize = 1024;
+
+/// The allowed clock skew between this server and the provider when checking expiration.
+const CLOCK_SKEW_SEC: i64 = 60;
+
+pub struct Config {
+    /// The provider's issuer URL, which must match its metadata and ID tokens exactly.
+    pub issuer: String,
+
+    pub client_id: String,
+    pub client_secret: String,
+
+    /// This server's `/api/oidc/callback` URL, as registered with the provider.
+    pub redirect_uri: String,
+
+    /// The ID token claim to match against usernames, such as `preferred_username` or `email`.
+    pub username_claim: String,
+
+    /// If set, users not yet in the database are created on first login with these permissions.
+    pub auto_provision: Option<db::Permissions>,
+}
+
+/// The subset of the provider's metadata (OpenID Connect Discovery section 3) used here.
+#[derive(Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+
+    #[serde(rename = "use")]
+    use_: Option<String>,
+
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// A login which has been started but not finished, keyed by its `state`.
+struct Pending {
+    nonce: String,
+    verifier: String,
+    created_sec: i64,
+}
+
+type Keys = Vec<(Option<String>, PKey<Public>)>;
+
+pub struct Client {
+    config: Config,
+    http: reqwest::Client,
+    metadata: Mutex<Option<Arc<Metadata>>>,
+    keys: Mutex<Keys>,
+    pending: Mutex<FnvHashMap<String, Pending>>,
+}
+
+/// Returns a random URL-safe string with 256 bits of entropy.
+fn random_string() -> String {
+    let mut raw = [0u8; 32];
+    openssl::rand::rand_bytes(&mut raw).unwrap();
+    base64::encode_config(&raw, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_b64url(s: &str) -> Result<Vec<u8>, Error> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| format_err!("bad base64url {:?}: {}", s, e))
+}
+
+impl Client {
+    pub fn new(config: Config) -> Self {
+        Client {
+            config,
+            http: reqwest::Client::new(),
+            metadata: Mutex::new(None),
+            keys: Mutex::new(Vec::new()),
+            pending: Mutex::new(FnvHashMap::default()),
+        }
+    }
+
+    pub fn auto_provision(&self) -> Option<&db::Permissions> { self.config.auto_provision.as_ref() }
+
+    async fn metadata(&self) -> Result<Arc<Metadata>, Error> {
+        let cached = self.metadata.lock().clone();
+        if let Some(m) = cached {
+            return Ok(m);
+        }
+        let url = format!("{}/.well-known/openid-configuration",
+                          self.config.issuer.trim_end_matches('/'));
+        let m: Metadata = self.http.get(&url).send().await?.error_for_status()?.json().await?;
+        if m.issuer != self.config.issuer {
+            bail!("provider's issuer {:?} doesn't match the configured {:?}",
+                  m.issuer, self.config.issuer);
+        }
+        let m = Arc::new(m);
+        *self.metadata.lock() = Some(m.clone());
+        Ok(m)
+    }
+
+    /// Returns the provider's key with the given id, refetching the keys if it's unknown.
+    async fn key(&self, kid: Option<&str>) -> Result<PKey<Public>, Error> {
+        let cached = find_key(&self.keys.lock(), kid);
+        if let Some(k) = cached {
+            return Ok(k);
+        }
+        let m = self.metadata().await?;
+        let jwks: Jwks = self.http.get(&m.jwks_uri).send().await?.error_for_status()?.json().await?;
+        let keys = parse_keys(jwks)?;
+        let k = find_key(&keys, kid);
+        *self.keys.lock() = keys;
+        k.ok_or_else(|| format_err!("provider has no RSA signing key {:?}", kid))
+    }
+
+    /// Starts a login, returning its `state` and the provider URL to which to send the browser.
+    pub async fn start_login(&self, now_sec: i64) -> Result<(String, String), Error> {
+        let m = self.metadata().await?;
+        let state = random_string();
+        let nonce = random_string();
+        let verifier = random_string();
+        let challenge = base64::encode_config(&openssl::sha::sha256(verifier.as_bytes()),
+                                              base64::URL_SAFE_NO_PAD);
+        let mut url = url::Url::parse(&m.authorization_endpoint)?;
+        url.query_pairs_mut()
+           .append_pair("response_type", "code")
+           .append_pair("client_id", &self.config.client_id)
+           .append_pair("redirect_uri", &self.config.redirect_uri)
+           .append_pair("scope", "openid profile email")
+           .append_pair("state", &state)
+           .append_pair("nonce", &nonce)
+           .append_pair("code_challenge", &challenge)
+           .append_pair("code_challenge_method", "S256");
+        let mut pending = self.pending.lock();
+        pending.retain(|_, p| p.created_sec + PENDING_TIMEOUT_SEC > now_sec);
+        if pending.len() >= MAX_PENDING {
+            let oldest = pending.iter().min_by_key(|(_, p)| p.created_sec).map(|(s, _)| s.clone());
+            if let Some(s) = oldest {
+                pending.remove(&s);
+            }
+        }
+        pending.insert(state.clone(), Pending { nonce, verifier, created_sec: now_sec });
+        Ok((state, url.as_str().to_owned()))
+    }
+
+    /// Finishes a login given the `state` and `code` from the provider's redirect, returning the
+    /// username claimed by the verified ID token.
+    pub async fn finish_login(&self, state: &str, code: &str, now_sec: i64)
+                              -> Result<String, Error> {
+        let p = self.pending.lock().remove(state)
+                    .ok_or_else(|| format_err!("unknown or expired login state"))?;
+        if p.created_sec + PENDING_TIMEOUT_SEC <= now_sec {
+            bail!("login expired");
+        }
+        let m = self.metadata().await?;
+
+        // Use client_secret_basic authentication, the default. RFC 6749 section 2.3.1 says the id
+        // and secret are form-encoded first.
+        let encode = |s: &str| form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+        let resp = self.http.post(&m.token_endpoint)
+            .basic_auth(encode(&self.config.client_id),
+                        Some(encode(&self.config.client_secret)))
+            .form(&[("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", &self.config.redirect_uri[..]),
+                    ("code_verifier", &p.verifier[..])])
+            .send()
+            .await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            bail!("token endpoint returned {}: {}", status, body);
+        }
+        let t: TokenResponse = resp.json().await?;
+        let header = parse_header(&t.id_token)?;
+        let key = self.key(header.kid.as_ref().map(String::as_str)).await?;
+        let claims = verify_id_token(&t.id_token, &key, &m.issuer, &self.config.client_id,
+                                     &p.nonce, now_sec)?;
+        match claims.get(&self.config.username_claim) {
+            Some(Value::String(u)) => Ok(u.clone()),
+            _ => bail!("ID token has no {:?} claim", self.config.username_claim),
+        }
+    }
+}
+
+fn find_key(keys: &Keys, kid: Option<&str>) -> Option<PKey<Public>> {
+    match kid {
+        Some(kid) => keys.iter().find(|k| k.0.as_ref().map(String::as_str) == Some(kid)),
+        None if keys.len() == 1 => keys.first(),
+        None => None,
+    }.map(|k| k.1.clone())
+}
+
+/// Parses the RSA signing keys from a JWKS, ignoring others.
+fn parse_keys(jwks: Jwks) -> Result<Keys, Error> {
+    let mut keys = Vec::new();
+    for k in jwks.keys {
+        if k.kty != "RSA" || k.use_.as_ref().map(|u| u != "sig").unwrap_or(false) {
+            continue;
+        }
+        let (n, e) = match (k.n, k.e) {
+            (Some(n), Some(e)) => (n, e),
+            _ => bail!("RSA key {:?} lacks n or e", k.kid),
+        };
+        let rsa = Rsa::from_public_components(BigNum::from_slice(&decode_b64url(&n)?)?,
+                                              BigNum::from_slice(&decode_b64url(&e)?)?)?;
+        keys.push((k.kid, PKey::from_rsa(rsa)?));
+    }
+    Ok(keys)
+}
+
+fn parse_header(token: &str) -> Result<Header, Error> {
+    let header = token.split('.').next().expect("split returns at least one item");
+    Ok(serde_json::from_slice(&decode_b64url(header)?)?)
+}
+
+/// Verifies an ID token's signature and claims as in OpenID Connect Core section 3.1.3.7,
+/// returning the claims.
+fn verify_id_token(token: &str, key: &PKey<Public>, issuer: &str, client_id: &str, nonce: &str,
+                   now_sec: i64) -> Result<Map<String, Value>, Error> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        bail!("malformed ID token");
+    }
+    let header = parse_header(token)?;
+    if header.alg != "RS256" {
+        bail!("unsupported ID token algorithm {:?}", header.alg);
+    }
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+    verifier.update(token[.. parts[0].len() + 1 + parts[1].len()].as_bytes())?;
+    if !verifier.verify(&decode_b64url(parts[2])?)? {
+        bail!("bad ID token signature");
+    }
+    let claims: Map<String, Value> = serde_json::from_slice(&decode_b64url(parts[1])?)?;
+    let str_claim = |name: &str| claims.get(name).and_then(Value::as_str);
+    if str_claim("iss") != Some(issuer) {
+        bail!("ID token has wrong issuer {:?}", str_claim("iss"));
+    }
+    let aud_ok = match claims.get("aud") {
+        Some(Value::String(a)) => a == client_id,
+        Some(Value::Array(a)) => {
+            a.iter().any(|a| a.as_str() == Some(client_id)) &&
+            (a.len() == 1 || str_claim("azp") == Some(client_id))
+        },
+        _ => false,
+    };
+    if !aud_ok {
+        bail!("ID token isn't intended for this client");
+    }
+    match claims.get("exp").and_then(Value::as_f64) {
+        Some(exp) if exp as i64 + CLOCK_SKEW_SEC > now_sec => {},
+        Some(_) => bail!("ID token has expired"),
+        None => bail!("ID token has no expiration"),
+    }
+    if str_claim("nonce") != Some(nonce) {
+        bail!("ID token has wrong nonce");
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::FutureExt;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+    use serde_json::json;
+    use super::*;
+
+    const CLIENT_ID: &str = "nvr";
+
+    fn sign(key: &PKey<Private>, header: &Value, claims: &Value) -> String {
+        let encode = |v: &Value| base64::encode_config(&v.to_string(), base64::URL_SAFE_NO_PAD);
+        let mut token = format!("{}.{}", encode(header), encode(claims));
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(token.as_bytes()).unwrap();
+        let sig = signer.sign_to_vec().unwrap();
+        token.push('.');
+        token.push_str(&base64::encode_config(&sig, base64::URL_SAFE_NO_PAD));
+        token
+    }
+
+    fn jwk(key: &PKey<Private>, kid: &str) -> Value {
+        let rsa = key.rsa().unwrap();
+        json!({
+            "kty": "RSA",
+            "kid": kid,
+            "use": "sig",
+            "n": base64::encode_config(&rsa.n().to_vec(), base64::URL_SAFE_NO_PAD),
+            "e": base64::encode_config(&rsa.e().to_vec(), base64::URL_SAFE_NO_PAD),
+        })
+    }
+
+    fn public(key: &PKey<Private>) -> PKey<Public> {
+        PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn verify() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let other_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let header = json!({"alg": "RS256", "kid": "k1"});
+        let claims = json!({
+            "iss": "https://idp.example.com",
+            "aud": CLIENT_ID,
+            "sub": "1234",
+            "exp": 1000,
+            "nonce": "n",
+            "preferred_username": "slamb",
+        });
+        let verify = |token: &str, now_sec| verify_id_token(
+            token, &public(&key), "https://idp.example.com", CLIENT_ID, "n", now_sec);
+        let claims_with = |k: &str, v: Value| {
+            let mut c = claims.clone();
+            c[k] = v;
+            c
+        };
+
+        let c = verify(&sign(&key, &header, &claims), 900).unwrap();
+        assert_eq!(c["preferred_username"], "slamb");
+        verify(&sign(&key, &header, &claims_with("aud", json!([CLIENT_ID]))), 900).unwrap();
+
+        let err = |token: String, now_sec| verify(&token, now_sec).unwrap_err().to_string();
+        assert_eq!(err(sign(&other_key, &header, &claims), 900), "bad ID token signature");
+        assert_eq!(err(sign(&key, &json!({"alg": "none"}), &claims), 900),
+                   "unsupported ID token algorithm \"none\"");
+        assert_eq!(err(sign(&key, &header, &claims), 1100), "ID token has expired");
+        assert_eq!(err(sign(&key, &header, &claims_with("nonce", json!("x"))), 900),
+                   "ID token has wrong nonce");
+        assert_eq!(err(sign(&key, &header, &claims_with("aud", json!("other"))), 900),
+                   "ID token isn't intended for this client");
+        assert_eq!(err(sign(&key, &header, &claims_with("aud", json!([CLIENT_ID, "other"]))),
+                       900),
+                   "ID token isn't intended for this client");
+        assert_eq!(err(sign(&key, &header, &claims_with("iss", json!("https://evil"))), 900),
+                   "ID token has wrong issuer Some(\"https://evil\")");
+
+        // Tamper with the claims of a validly signed token.
+        let token = sign(&key, &header, &claims);
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = base64::encode_config(&claims_with("preferred_username", json!("root"))
+                                           .to_string(), base64::URL_SAFE_NO_PAD);
+        assert_eq!(err(format!("{}.{}.{}", parts[0], forged, parts[2]), 900),
+                   "bad ID token signature");
+    }
+
+    /// A minimal provider which serves its metadata and keys, and which issues an ID token for
+    /// the code `good`.
+    struct MockProvider {
+        base_url: String,
+        key: PKey<Private>,
+        nonce: Mutex<Option<String>>,
+        challenge: Mutex<Option<String>>,
+    }
+
+    impl MockProvider {
+        async fn handle(&self, req: hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
+            let json = |v: Value| hyper::Response::builder()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(hyper::Body::from(v.to_string()))
+                .unwrap();
+            match req.uri().path() {
+                "/.well-known/openid-configuration" => json(json!({
+                    "issuer": &self.base_url,
+                    "authorization_endpoint": format!("{}/authorize", self.base_url),
+                    "token_endpoint": format!("{}/token", self.base_url),
+                    "jwks_uri": format!("{}/jwks", self.base_url),
+                })),
+                "/jwks" => json(json!({"keys": [jwk(&self.key, "k1")]})),
+                "/token" => {
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let params: FnvHashMap<String, String> =
+                        form_urlencoded::parse(&body).into_owned().collect();
+                    let challenge = base64::encode_config(
+                        &openssl::sha::sha256(params["code_verifier"].as_bytes()),
+                        base64::URL_SAFE_NO_PAD);
+                    if params["code"] != "good" ||
+                       Some(challenge) != *self.challenge.lock() {
+                        return hyper::Response::builder()
+                            .status(http::StatusCode::BAD_REQUEST)
+                            .body(hyper::Body::from(r#"{"error": "invalid_grant"}"#))
+                            .unwrap();
+                    }
+                    let claims = json!({
+                        "iss": &self.base_url,
+                        "aud": CLIENT_ID,
+                        "sub": "1234",
+                        "exp": 2000,
+                        "nonce": self.nonce.lock().as_ref().unwrap(),
+                        "preferred_username": "slamb",
+                    });
+                    let header = json!({"alg": "RS256", "kid": "k1"});
+                    json(json!({
+                        "access_token": "unused",
+                        "token_type": "Bearer",
+                        "id_token": sign(&self.key, &header, &claims),
+                    }))
+                },
+                _ => hyper::Response::builder()
+                    .status(http::StatusCode::NOT_FOUND)
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_provider() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let provider = Arc::new(MockProvider {
+            base_url: base_url.clone(),
+            key: PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+            nonce: Mutex::new(None),
+            challenge: Mutex::new(None),
+        });
+        let make_svc = hyper::service::make_service_fn({
+            let provider = provider.clone();
+            move |_conn| {
+                let provider = provider.clone();
+                futures::future::ok::<_, std::convert::Infallible>(
+                    hyper::service::service_fn(move |req| {
+                        let provider = provider.clone();
+                        async move {
+                            Ok::<_, std::convert::Infallible>(provider.handle(req).await)
+                        }
+                    }))
+            }
+        });
+        let srv = hyper::Server::from_tcp(listener).unwrap().serve(make_svc);
+        tokio::spawn(srv.map(|_| ()));
+
+        let client = Client::new(Config {
+            issuer: base_url.clone(),
+            client_id: CLIENT_ID.to_owned(),
+            client_secret: "secret".to_owned(),
+            redirect_uri: "https://nvr.example.com/api/oidc/callback".to_owned(),
+            username_claim: "preferred_username".to_owned(),
+            auto_provision: None,
+        });
+
+        // Play the part of the browser, which would send the authorization request to the
+        // provider and receive the code via the redirect.
+        let (state, url) = client.start_login(1000).await.unwrap();
+        let url = url::Url::parse(&url).unwrap();
+        assert_eq!(url.path(), "/authorize");
+        let params: FnvHashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], state);
+        assert_eq!(params["client_id"], CLIENT_ID);
+        *provider.nonce.lock() = Some(params["nonce"].clone());
+        *provider.challenge.lock() = Some(params["code_challenge"].clone());
+
+        let e = client.finish_login("bogus", "good", 1001).await.unwrap_err();
+        assert_eq!(e.to_string(), "unknown or expired login state");
+        assert_eq!(client.finish_login(&state, "good", 1001).await.unwrap(), "slamb");
+
+        // Each state may be used only once.
+        let e = client.finish_login(&state, "good", 1002).await.unwrap_err();
+        assert_eq!(e.to_string(), "unknown or expired login state");
+
+        let (state, _) = client.start_login(1000).await.unwrap();
+        let e = client.finish_login(&state, "bad", 1001).await.unwrap_err();
+        assert!(e.to_string().starts_with("token endpoint returned 400"), "{}", e);
+    }
+}
@@ -0,0 +1,1129 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! RTSP server (RFC 2326) which re-streams cameras to other clients.
+//!
+//! Live video is fanned out from the frames each `Streamer` receives from its camera, so any
+//! number of clients can watch without opening more sessions to the camera. Recorded video can be
+//! replayed in real time by adding `start` and/or `end` parameters to the URL:
+//!
+//! ```text
+//! rtsp://host:port/<camera uuid>/<main|sub>
+//! rtsp://host:port/<camera uuid>/<main|sub>?start=2020-01-02T03:04:05&end=2020-01-02T04:00:00
+//! ```
+//!
+//! Only RTP over the RTSP connection ("interleaved" TCP transport) is supported. It needs no
+//! extra ports, works through NAT, and doesn't drop packets that a video recorder's clients
+//! would rather see late than never.
+
+use crate::h264;
+use crate::web;
+use db::{dir, recording};
+use failure::{Error, bail, format_err};
+use fnv::FnvHashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use std::fs;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+use uuid::Uuid;
+
+mod rtp;
+
+/// The number of frames which may be queued for a live session before it's considered too slow
+/// and dropped.
+const LIVE_QUEUE_LEN: usize = 256;
+
+/// The number of writes which may be queued for a connection.
+const OUT_QUEUE_LEN: usize = 16;
+
+/// The maximum length of a request line or header line.
+const MAX_LINE_LEN: u64 = 4096;
+
+/// The maximum length of a request body.
+const MAX_BODY_LEN: usize = 65536;
+
+/// The session timeout advertised to clients. Sessions actually last as long as the connection.
+const SESSION_TIMEOUT_SEC: u32 = 60;
+
+/// A frame received from a camera, shared among all sessions watching it live.
+#[derive(Clone)]
+struct Frame {
+    pts: i64,
+    is_key: bool,
+
+    /// The frame's data, in the AVC format written to sample files.
+    data: Arc<[u8]>,
+}
+
+struct LiveStream {
+    video_sample_entry_id: i32,
+    subscribers: Vec<mpsc::Sender<Frame>>,
+}
+
+/// Fans out frames from each stream's `Streamer` to the sessions watching it live.
+#[derive(Default)]
+pub struct LiveFrames(Mutex<FnvHashMap<i32, LiveStream>>);
+
+impl LiveFrames {
+    /// Notes that `stream_id` is connected to its camera and will send frames described by the
+    /// given video sample entry.
+    pub fn start(&self, stream_id: i32, video_sample_entry_id: i32) {
+        self.0.lock().insert(stream_id, LiveStream {
+            video_sample_entry_id,
+            subscribers: Vec::new(),
+        });
+    }
+
+    /// Notes that `stream_id` has disconnected from its camera, ending all sessions watching it.
+    pub fn end(&self, stream_id: i32) {
+        self.0.lock().remove(&stream_id);
+    }
+
+    /// Sends a frame to the sessions watching `stream_id`. Those which have ended or aren't
+    /// keeping up are dropped.
+    pub fn send(&self, stream_id: i32, pts: i64, is_key: bool, data: &[u8]) {
+        let mut l = self.0.lock();
+        let s = match l.get_mut(&stream_id) {
+            Some(s) if !s.subscribers.is_empty() => s,
+            _ => return,
+        };
+        let frame = Frame {
+            pts,
+            is_key,
+            data: Arc::from(data),
+        };
+        let mut i = 0;
+        while i < s.subscribers.len() {
+            if s.subscribers[i].try_send(frame.clone()).is_err() {
+                s.subscribers.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Subscribes to `stream_id`'s frames, returning the video sample entry which describes them.
+    /// Returns `None` if the stream isn't currently connected to its camera.
+    fn subscribe(&self, stream_id: i32) -> Option<(i32, mpsc::Receiver<Frame>)> {
+        let mut l = self.0.lock();
+        let s = l.get_mut(&stream_id)?;
+        let (tx, rx) = mpsc::channel(LIVE_QUEUE_LEN);
+        s.subscribers.push(tx);
+        Some((s.video_sample_entry_id, rx))
+    }
+
+    fn video_sample_entry_id(&self, stream_id: i32) -> Option<i32> {
+        self.0.lock().get(&stream_id).map(|s| s.video_sample_entry_id)
+    }
+}
+
+pub struct Config {
+    pub db: Arc<db::Database>,
+    pub live_frames: Arc<LiveFrames>,
+    pub allow_unauthenticated_permissions: Option<db::Permissions>,
+}
+
+struct ServerInner {
+    db: Arc<db::Database>,
+    live_frames: Arc<LiveFrames>,
+    allow_unauthenticated_permissions: Option<db::Permissions>,
+}
+
+pub struct Server(Arc<ServerInner>);
+
+impl Server {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        Ok(Server(Arc::new(ServerInner {
+            db: config.db,
+            live_frames: config.live_frames,
+            allow_unauthenticated_permissions: config.allow_unauthenticated_permissions,
+        })))
+    }
+
+    /// Serves connections from `listener` until `shutdown` is signalled.
+    pub async fn serve(self, mut listener: TcpListener, shutdown: oneshot::Receiver<()>) {
+        let accept = async {
+            let mut incoming = listener.incoming();
+            while let Some(sock) = incoming.next().await {
+                let sock = match sock {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("rtsp: unable to accept connection: {}", e);
+                        continue;
+                    },
+                };
+                let peer = match sock.peer_addr() {
+                    Ok(a) => a,
+                    Err(e) => {
+                        warn!("rtsp: unable to get peer address: {}", e);
+                        continue;
+                    },
+                };
+                let _ = sock.set_nodelay(true);
+                let (out_tx, out_rx) = mpsc::channel(OUT_QUEUE_LEN);
+                let conn = Conn {
+                    inner: self.0.clone(),
+                    peer,
+                    out: out_tx,
+                    authorization: None,
+                    session: None,
+                };
+                tokio::spawn(async move {
+                    if let Err(e) = conn.run(sock, out_rx).await {
+                        debug!("rtsp: {}: connection ended: {}", peer, e);
+                    }
+                });
+            }
+        };
+        futures::pin_mut!(accept);
+        future::select(accept, shutdown).await;
+    }
+}
+
+impl ServerInner {
+    /// Returns the id of the stream `target` refers to.
+    fn stream_id(&self, target: &Target) -> Result<i32, Response> {
+        let db = self.db.lock();
+        let camera = db.get_camera(target.camera_uuid)
+                       .ok_or_else(|| not_found(format!("no such camera {}",
+                                                        target.camera_uuid)))?;
+        camera.streams[target.stream_type.index()]
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", target.camera_uuid,
+                                             target.stream_type)))
+    }
+
+    /// Lists the complete recordings of `stream_id` which overlap `time`, in order.
+    fn list_recordings(&self, stream_id: i32, time: Range<recording::Time>)
+                       -> Result<Vec<db::ListRecordingsRow>, Response> {
+        let mut rows = Vec::new();
+        self.db.lock().list_recordings_by_time(stream_id, time, &mut |r| {
+            if (r.flags & db::RecordingFlags::Growing as i32) == 0 {
+                rows.push(r);
+            }
+            Ok(())
+        }).map_err(internal_error)?;
+        if rows.is_empty() {
+            return Err(not_found("no recordings in range"));
+        }
+        rows.sort_by_key(|r| r.id.recording());
+        Ok(rows)
+    }
+
+    fn video_sample_entry(&self, id: i32) -> Result<Arc<db::VideoSampleEntry>, Response> {
+        self.db.lock().video_sample_entries_by_id().get(&id).cloned()
+            .ok_or_else(|| internal_error(format_err!("no such video sample entry {}", id)))
+    }
+
+    /// Returns the video sample entry `target` will be played with and, for recorded video, its
+    /// duration.
+    fn describe(&self, stream_id: i32, target: &Target)
+                -> Result<(Arc<db::VideoSampleEntry>, Option<recording::Duration>), Response> {
+        let time = match target.replay {
+            None => {
+                let id = self.live_frames.video_sample_entry_id(stream_id)
+                    .ok_or_else(|| not_found("stream is not connected to its camera"))?;
+                return Ok((self.video_sample_entry(id)?, None));
+            },
+            Some(ref t) => t,
+        };
+        let rows = self.list_recordings(stream_id, time.clone())?;
+        let first = rows.first().unwrap();
+        let last = rows.last().unwrap();
+        let start = ::std::cmp::max(time.start, first.start);
+        let end = ::std::cmp::min(
+            time.end, last.start + recording::Duration(last.duration_90k as i64));
+        Ok((self.video_sample_entry(first.video_sample_entry_id)?, Some(end - start)))
+    }
+}
+
+/// The stream and optional range of recorded video named by a request URL.
+#[derive(Debug, PartialEq, Eq)]
+struct Target {
+    camera_uuid: Uuid,
+    stream_type: db::StreamType,
+
+    /// The range of recorded video to replay, or `None` to play live video.
+    replay: Option<Range<recording::Time>>,
+}
+
+impl Target {
+    /// Parses a URL of the form `rtsp://host[:port]/<uuid>/<type>[/trackID=0][?start=&end=]`.
+    fn parse(url: &str) -> Result<Self, Error> {
+        let url = Url::parse(url)?;
+        let mut segments = url.path_segments().ok_or_else(|| format_err!("URL has no path"))?;
+        let camera_uuid = Uuid::parse_str(segments.next().unwrap_or(""))?;
+        let stream_type = segments.next().and_then(db::StreamType::parse)
+            .ok_or_else(|| format_err!("URL has no valid stream type"))?;
+        match segments.next() {
+            None | Some("") | Some("trackID=0") => {},
+            Some(s) => bail!("unexpected path component {:?}", s),
+        }
+        if segments.next().is_some() {
+            bail!("URL has too many path components");
+        }
+        let mut replay = None;
+        for (key, value) in url.query_pairs() {
+            let r = replay.get_or_insert(recording::Time::min_value() ..
+                                         recording::Time::max_value());
+            match &*key {
+                "start" => r.start = recording::Time::parse(&value)?,
+                "end" => r.end = recording::Time::parse(&value)?,
+                _ => bail!("unknown parameter {:?}", key),
+            }
+        }
+        Ok(Target {
+            camera_uuid,
+            stream_type,
+            replay,
+        })
+    }
+}
+
+/// Returns the control URL of the (only) track of the stream at `url`.
+fn track_url(url: &str) -> Result<String, Error> {
+    let mut url = Url::parse(url)?;
+    if !url.path().ends_with("/trackID=0") {
+        let path = format!("{}/trackID=0", url.path().trim_end_matches('/'));
+        url.set_path(&path);
+    }
+    Ok(url.into_string())
+}
+
+/// Returns the interleaved channel requested by a `Transport` header, or `None` if it doesn't
+/// offer RTP over the RTSP connection.
+fn interleaved_channel(transport: &str) -> Option<u8> {
+    for spec in transport.split(',') {
+        let mut params = spec.trim().split(';');
+        if params.next() != Some("RTP/AVP/TCP") {
+            continue;
+        }
+        let mut channel = 0;
+        for p in params {
+            if p.starts_with("interleaved=") {
+                channel = p["interleaved=".len()..].split('-').next()?.parse().ok()?;
+            }
+        }
+        return Some(channel);
+    }
+    None
+}
+
+/// Returns the session id from a `Session` header, without any parameters.
+fn session_id(session: &str) -> &str {
+    session.split(';').next().unwrap().trim()
+}
+
+fn random_u64() -> u64 {
+    let mut b = [0u8; 8];
+    openssl::rand::rand_bytes(&mut b).expect("random number generator should work");
+    u64::from_ne_bytes(b)
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|h| h.0.eq_ignore_ascii_case(name)).map(|h| h.1.as_str())
+    }
+}
+
+/// Reads a line, failing if it's unreasonably long. Returns an empty string on EOF.
+async fn read_line<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<String, Error> {
+    let mut line = String::new();
+    (&mut *r).take(MAX_LINE_LEN).read_line(&mut line).await?;
+    if line.len() as u64 == MAX_LINE_LEN && !line.ends_with('\n') {
+        bail!("line too long");
+    }
+    Ok(line)
+}
+
+/// Reads the next request, skipping any interleaved data (such as RTCP receiver reports) the
+/// client sends. Returns `None` on a clean EOF.
+async fn read_request<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<Option<Request>, Error> {
+    let request_line = loop {
+        let buf = r.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if buf[0] == b'$' {
+            let mut hdr = [0u8; 4];
+            r.read_exact(&mut hdr).await?;
+            let len = u16::from_be_bytes([hdr[2], hdr[3]]) as usize;
+            let mut data = vec![0u8; len];
+            r.read_exact(&mut data).await?;
+            continue;
+        }
+        let line = read_line(r).await?;
+        if !line.trim().is_empty() {
+            break line;
+        }
+    };
+    let mut parts = request_line.split_whitespace();
+    let (method, url, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(u), Some(v), None) => (m, u, v),
+        _ => bail!("bad request line {:?}", request_line),
+    };
+    if version != "RTSP/1.0" {
+        bail!("unsupported version {:?}", version);
+    }
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(r).await?;
+        if line.is_empty() {
+            bail!("EOF within request headers");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut kv = line.splitn(2, ':');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) => headers.push((k.trim().to_owned(), v.trim().to_owned())),
+            _ => bail!("bad header line {:?}", line),
+        }
+    }
+    let mut req = Request {
+        method: method.to_owned(),
+        url: url.to_owned(),
+        headers,
+        body: Vec::new(),
+    };
+    if let Some(l) = req.header("Content-Length") {
+        let len: usize = l.parse().map_err(|_| format_err!("bad Content-Length {:?}", l))?;
+        if len > MAX_BODY_LEN {
+            bail!("body of {} bytes is too long", len);
+        }
+        req.body.resize(len, 0);
+        r.read_exact(&mut req.body).await?;
+    }
+    Ok(Some(req))
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, reason: &'static str) -> Self {
+        Response {
+            status,
+            reason,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn ok() -> Self { Response::new(200, "OK") }
+
+    fn header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Sets a plain text body explaining an error.
+    fn text(self, text: String) -> Self {
+        self.body("text/plain", text.into_bytes())
+    }
+
+    fn body(mut self, content_type: &'static str, body: Vec<u8>) -> Self {
+        self.headers.push(("Content-Type", content_type.to_owned()));
+        self.body = body;
+        self
+    }
+
+    fn serialize(&self, cseq: Option<&str>) -> Vec<u8> {
+        let mut out = format!("RTSP/1.0 {} {}\r\n", self.status, self.reason);
+        if let Some(c) = cseq {
+            out.push_str(&format!("CSeq: {}\r\n", c));
+        }
+        out.push_str("Server: moonfire-nvr\r\n");
+        for (k, v) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        if !self.body.is_empty() {
+            out.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        out.push_str("\r\n");
+        let mut out = out.into_bytes();
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+fn bad_request(e: Error) -> Response { Response::new(400, "Bad Request").text(e.to_string()) }
+
+fn not_found<S: Into<String>>(msg: S) -> Response {
+    Response::new(404, "Not Found").text(msg.into())
+}
+
+fn internal_error(e: Error) -> Response {
+    warn!("rtsp: internal error: {}", e);
+    Response::new(500, "Internal Server Error").text(e.to_string())
+}
+
+fn unauthorized() -> Response {
+    Response::new(401, "Unauthorized")
+        .header("WWW-Authenticate", "Basic realm=\"Moonfire NVR\"".to_owned())
+}
+
+fn forbidden<S: Into<String>>(msg: S) -> Response {
+    Response::new(403, "Forbidden").text(msg.into())
+}
+
+/// A previously accepted `Authorization` header and the user it authenticated.
+struct Authorization {
+    header: String,
+    user_id: i32,
+
+    /// The user's `password_id` when the header was accepted. It changes with the password.
+    password_id: i32,
+}
+
+/// The state created by `SETUP`. There's at most one session per connection.
+struct Session {
+    id: String,
+    target: Target,
+    channel: u8,
+    ssrc: u32,
+    initial_seq: u16,
+    initial_rtptime: u32,
+
+    /// While playing, the sender whose drop stops the play task.
+    playing: Option<oneshot::Sender<()>>,
+}
+
+struct Conn {
+    inner: Arc<ServerInner>,
+    peer: SocketAddr,
+    out: mpsc::Sender<Vec<u8>>,
+
+    /// The last `Authorization` header which was accepted, to avoid rehashing the password on
+    /// every request. The user is still looked up on every request, so that changing the password
+    /// or disabling the user takes effect on existing connections.
+    authorization: Option<Authorization>,
+
+    session: Option<Session>,
+}
+
+impl Conn {
+    async fn run(mut self, sock: TcpStream, mut out_rx: mpsc::Receiver<Vec<u8>>)
+                 -> Result<(), Error> {
+        let (read, mut write) = tokio::io::split(sock);
+        let mut read = BufReader::new(read);
+        let writer = async move {
+            while let Some(b) = out_rx.next().await {
+                write.write_all(&b).await?;
+            }
+            Ok::<_, Error>(())
+        };
+        let reader = async move {
+            while let Some(req) = read_request(&mut read).await? {
+                debug!("rtsp: {}: {} {}", self.peer, req.method, req.url);
+                let (resp, play) = match self.handle(&req) {
+                    Ok(r) => r,
+                    Err(resp) => (resp, None),
+                };
+                self.out.send(resp.serialize(req.header("CSeq"))).await?;
+                if let Some(p) = play {
+                    tokio::spawn(p);
+                }
+            }
+            Ok::<_, Error>(())
+        };
+        futures::pin_mut!(reader, writer);
+        match future::select(reader, writer).await {
+            Either::Left((r, _)) | Either::Right((r, _)) => r,
+        }
+    }
+
+    fn handle(&mut self, req: &Request) -> Result<(Response, Option<BoxFuture<'static, ()>>),
+                                                  Response> {
+        match req.method.as_str() {
+            "OPTIONS" => Ok((Response::ok().header(
+                "Public",
+                "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_owned()), None)),
+            "DESCRIBE" => Ok((self.describe(req)?, None)),
+            "SETUP" => Ok((self.setup(req)?, None)),
+            "PLAY" => self.play(req),
+            "TEARDOWN" => {
+                self.session(req)?;
+                self.session = None;
+                Ok((Response::ok(), None))
+            },
+            "GET_PARAMETER" => {
+                // Clients send this as a keepalive; there are no parameters to get.
+                let resp = match self.session {
+                    Some(ref s) => Response::ok().header("Session", s.id.clone()),
+                    None => Response::ok(),
+                };
+                Ok((resp, None))
+            },
+            _ => Err(Response::new(501, "Not Implemented")),
+        }
+    }
+
+    /// Checks that the client may view video from the given camera, either because
+    /// unauthenticated users may or because the request has a username and password of a user who
+    /// may. Returns the id of that user, if any.
+    fn authorize(&mut self, req: &Request, camera_uuid: Uuid) -> Result<Option<i32>, Response> {
+        if let Some(ref p) = self.inner.allow_unauthenticated_permissions {
+            if p.view_video && p.allows_camera(camera_uuid) {
+                return Ok(None);
+            }
+        }
+        let permissions = self.authenticate(req)?;
+        if !permissions.view_video {
+            return Err(forbidden("view_video required"));
+        }
+        if !permissions.allows_camera(camera_uuid) {
+            return Err(forbidden(format!("camera {} is not allowed", camera_uuid)));
+        }
+        Ok(self.authorization.as_ref().map(|a| a.user_id))
+    }
+
+    /// Returns the current permissions of the user named by the request's `Authorization`
+    /// header.
+    fn authenticate(&mut self, req: &Request) -> Result<db::Permissions, Response> {
+        let hdr = req.header("Authorization").ok_or_else(unauthorized)?;
+        if let Some(a) = self.authorization.take() {
+            if a.header == hdr {
+                let permissions = match self.inner.db.lock().users_by_id().get(&a.user_id) {
+                    Some(u) if u.password_id == a.password_id && !u.disabled() &&
+                               !u.password_locked() => Some(u.permissions.clone()),
+                    _ => None,
+                };
+                if let Some(p) = permissions {
+                    self.authorization = Some(a);
+                    return Ok(p);
+                }
+                info!("rtsp: {}: user {} changed; reauthenticating", self.peer, a.user_id);
+            }
+        }
+        let creds = if hdr.len() > 6 && hdr[..6].eq_ignore_ascii_case("Basic ") {
+            base64::decode(hdr[6..].trim()).ok().and_then(|c| String::from_utf8(c).ok())
+        } else {
+            None
+        };
+        let creds = creds.ok_or_else(unauthorized)?;
+        let mut parts = creds.splitn(2, ':');
+        let (username, password) = match (parts.next(), parts.next()) {
+            (Some(u), Some(p)) => (u, p),
+            _ => return Err(unauthorized()),
+        };
+        let inner = &self.inner;
+        let authreq = db::auth::Request {
+            when_sec: Some(inner.db.clocks().realtime().sec),
+            user_agent: req.header("User-Agent").map(|ua| ua.as_bytes().to_vec()),
+            addr: Some(self.peer.ip()),
+        };
+        let user = tokio::task::block_in_place(|| {
+            inner.db.lock().authenticate_password(authreq, username, password.to_owned())
+                 .map(|u| (u.id, u.password_id, u.permissions.clone()))
+        });
+        match user {
+            Err(e) => {
+                info!("rtsp: {}: authentication failed: {}", self.peer, e);
+                Err(unauthorized())
+            },
+            Ok((user_id, password_id, permissions)) => {
+                self.authorization = Some(Authorization {
+                    header: hdr.to_owned(),
+                    user_id,
+                    password_id,
+                });
+                Ok(permissions)
+            },
+        }
+    }
+
+    /// Returns the session named by the request's `Session` header.
+    fn session(&mut self, req: &Request) -> Result<&mut Session, Response> {
+        let id = req.header("Session").map(session_id);
+        match self.session {
+            Some(ref mut s) if id == Some(s.id.as_str()) => Ok(s),
+            _ => Err(Response::new(454, "Session Not Found")),
+        }
+    }
+
+    fn describe(&mut self, req: &Request) -> Result<Response, Response> {
+        let target = Target::parse(&req.url).map_err(bad_request)?;
+        self.authorize(req, target.camera_uuid)?;
+        let stream_id = self.inner.stream_id(&target)?;
+        let (vse, duration) = self.inner.describe(stream_id, &target)?;
+        let control = track_url(&req.url).map_err(bad_request)?;
+        let sdp = rtp::session_description(&vse, &control, duration).map_err(internal_error)?;
+        Ok(Response::ok()
+           .header("Content-Base", req.url.clone())
+           .body("application/sdp", sdp.into_bytes()))
+    }
+
+    fn setup(&mut self, req: &Request) -> Result<Response, Response> {
+        let target = Target::parse(&req.url).map_err(bad_request)?;
+        self.authorize(req, target.camera_uuid)?;
+        if self.session.is_some() {
+            return Err(Response::new(455, "Method Not Valid in This State")
+                       .text("only one session per connection is supported".to_owned()));
+        }
+        self.inner.stream_id(&target)?;
+        let channel = req.header("Transport").and_then(interleaved_channel)
+            .ok_or_else(|| Response::new(461, "Unsupported Transport")
+                           .text("only RTP/AVP/TCP (interleaved) is supported".to_owned()))?;
+        let r = random_u64();
+        let s = Session {
+            id: format!("{:016x}", random_u64()),
+            target,
+            channel,
+            ssrc: r as u32,
+            initial_seq: (r >> 32) as u16,
+            initial_rtptime: random_u64() as u32,
+            playing: None,
+        };
+        let resp = Response::ok()
+            .header("Transport", format!("RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={:08X}",
+                                         channel, channel.wrapping_add(1), s.ssrc))
+            .header("Session", format!("{};timeout={}", s.id, SESSION_TIMEOUT_SEC));
+        self.session = Some(s);
+        Ok(resp)
+    }
+
+    fn play(&mut self, req: &Request) -> Result<(Response, Option<BoxFuture<'static, ()>>),
+                                                Response> {
+        let camera_uuid = self.session(req)?.target.camera_uuid;
+        let user_id = self.authorize(req, camera_uuid)?;
+        let inner = self.inner.clone();
+        let out = self.out.clone();
+        let peer = self.peer;
+        let s = self.session(req)?;
+        let resp = Response::ok().header("Session", s.id.clone());
+        if s.playing.is_some() {
+            // PAUSE isn't supported, so a repeated PLAY has nothing to do.
+            return Ok((resp, None));
+        }
+        let stream_id = inner.stream_id(&s.target)?;
+        let packetizer = |vse: &db::VideoSampleEntry| -> Result<rtp::Packetizer, Response> {
+            let config = h264::AvcDecoderConfig::from_sample_entry(&vse.data)
+                .map_err(internal_error)?;
+            Ok(rtp::Packetizer::new(s.ssrc, s.initial_seq, config.length_size, s.channel))
+        };
+        let now = inner.db.clocks().realtime();
+        let sent = Arc::new(AtomicU64::new(0));
+        let (range, task) = match s.target.replay {
+            None => {
+                let (vse_id, frames) = inner.live_frames.subscribe(stream_id)
+                    .ok_or_else(|| not_found("stream is not connected to its camera"))?;
+                let p = packetizer(&*inner.video_sample_entry(vse_id)?)?;
+                ("npt=now-", play_live(out, frames, p, s.initial_rtptime, sent.clone()).boxed())
+            },
+            Some(ref time) => {
+                let rows = inner.list_recordings(stream_id, time.clone())?;
+                let p = packetizer(&*inner.video_sample_entry(rows[0].video_sample_entry_id)?)?;
+                ("npt=0-", play_recorded(inner.clone(), out, stream_id, time.clone(), rows, p,
+                                         s.initial_rtptime, sent.clone()).boxed())
+            },
+        };
+
+        // Like the web server's video endpoints, note the video sent in the audit log once the
+        // session ends. Live video's time range is the time the client was connected.
+        let live = s.target.replay.is_none();
+        let audit_entry = db::audit::Entry {
+            request: db::auth::Request {
+                when_sec: Some(now.sec),
+                user_agent: req.header("User-Agent").map(|ua| ua.as_bytes().to_vec()),
+                addr: Some(peer.ip()),
+            },
+            user_id,
+            action: "rtsp".to_owned(),
+            camera_uuid: Some(s.target.camera_uuid),
+            stream_type: Some(s.target.stream_type),
+            time_90k: Some(match s.target.replay {
+                None => recording::Time::new(now) .. recording::Time::new(now),
+                Some(ref time) => time.clone(),
+            }),
+            ..Default::default()
+        };
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        s.playing = Some(cancel_tx);
+        let rtp_info = format!("url={};seq={};rtptime={}",
+                               track_url(&req.url).map_err(bad_request)?, s.initial_seq,
+                               s.initial_rtptime);
+        let play = async move {
+            if let Either::Left((Err(e), _)) = future::select(task, cancel_rx).await {
+                info!("rtsp: {}: play ended: {}", peer, e);
+            }
+            web::add_video_audit_entry(&inner.db, audit_entry, sent.load(Ordering::Relaxed), live);
+        };
+        Ok((resp.header("Range", range.to_owned()).header("RTP-Info", rtp_info),
+            Some(play.boxed())))
+    }
+}
+
+/// Sends live frames, starting with the next key frame.
+async fn play_live(mut out: mpsc::Sender<Vec<u8>>, mut frames: mpsc::Receiver<Frame>,
+                   mut p: rtp::Packetizer, initial_rtptime: u32, sent: Arc<AtomicU64>)
+                   -> Result<(), Error> {
+    let mut first_pts = None;
+    while let Some(f) = frames.next().await {
+        let first = match first_pts {
+            Some(p) => p,
+            None if f.is_key => *first_pts.get_or_insert(f.pts),
+            None => continue,
+        };
+        let mut buf = Vec::with_capacity(f.data.len() + 64);
+        p.append_sample(initial_rtptime.wrapping_add((f.pts - first) as u32), &f.data, &mut buf)?;
+        let len = buf.len() as u64;
+        out.send(buf).await?;
+        sent.fetch_add(len, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Reads the given range of a sample file's plaintext.
+fn read_frame(dir: &dir::SampleFileDir, f: &fs::File, id: db::CompositeId, r: Range<u64>)
+              -> Result<Vec<u8>, Error> {
+    if dir.is_encrypted() {
+        return dir.read_range(f, id, r);
+    }
+    let mut buf = vec![0u8; (r.end - r.start) as usize];
+    f.read_exact_at(&mut buf, r.start)?;
+    Ok(buf)
+}
+
+/// Sends recorded frames in real time, starting from the last key frame at or before
+/// `time.start`.
+async fn play_recorded(inner: Arc<ServerInner>, mut out: mpsc::Sender<Vec<u8>>, stream_id: i32,
+                       time: Range<recording::Time>, rows: Vec<db::ListRecordingsRow>,
+                       mut p: rtp::Packetizer, initial_rtptime: u32, sent: Arc<AtomicU64>)
+                       -> Result<(), Error> {
+    // Look up the directory now rather than at startup; the stream may have been migrated.
+    let dir = inner.db.lock().dirs_by_stream_id().remove(&stream_id)
+        .ok_or_else(|| format_err!("stream {} has no sample file dir", stream_id))?;
+    let video_sample_entry_id = rows[0].video_sample_entry_id;
+    let start = tokio::time::Instant::now();
+    let mut first_frame_time = None;
+    for r in rows {
+        if r.video_sample_entry_id != video_sample_entry_id {
+            // The session description told the client the parameters, and they can't change.
+            info!("rtsp: stopping replay of stream {} at {} because its parameters changed",
+                  stream_id, r.start);
+            break;
+        }
+        let index = inner.db.lock().with_recording_playback(
+            r.id, &mut |playback| Ok(playback.video_index.to_vec()))?;
+        let f = tokio::task::block_in_place(|| dir.open_file(r.id))?;
+        let mut skip_90k = 0;
+        if first_frame_time.is_none() && r.start < time.start {
+            let rel_start_90k = (time.start - r.start).0;
+            let mut it = recording::SampleIndexIterator::new();
+            while it.next(&index)? {
+                if it.start_90k as i64 > rel_start_90k {
+                    break;
+                }
+                if it.is_key() {
+                    skip_90k = it.start_90k;
+                }
+            }
+        }
+        let mut it = recording::SampleIndexIterator::new();
+        while it.next(&index)? {
+            if it.start_90k < skip_90k {
+                continue;
+            }
+            let frame_time = r.start + recording::Duration(it.start_90k as i64);
+            if frame_time >= time.end {
+                return Ok(());
+            }
+            let elapsed_90k = (frame_time - *first_frame_time.get_or_insert(frame_time)).0;
+            tokio::time::delay_until(
+                start + recording::Duration(elapsed_90k).to_tm_duration().to_std()?).await;
+            let data = tokio::task::block_in_place(|| read_frame(
+                &dir, &f, r.id, it.pos as u64 .. (it.pos + it.bytes) as u64))?;
+            let mut buf = Vec::with_capacity(data.len() + 64);
+            p.append_sample(initial_rtptime.wrapping_add(elapsed_90k as u32), &data, &mut buf)?;
+            let len = buf.len() as u64;
+            out.send(buf).await?;
+            sent.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use db::recording;
+    use db::testutil::{self, TestDb};
+    use futures::StreamExt;
+    use futures::channel::mpsc;
+    use std::sync::Arc;
+    use super::{Conn, LiveFrames, Request, ServerInner, Target};
+    use uuid::Uuid;
+
+    #[test]
+    fn parse_target() {
+        let uuid = Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
+        let live = Target {
+            camera_uuid: uuid,
+            stream_type: db::StreamType::MAIN,
+            replay: None,
+        };
+        assert_eq!(Target::parse("rtsp://h:8554/35144640-ff1e-4619-b0d5-4c74c185741c/main")
+                   .unwrap(), live);
+        assert_eq!(Target::parse("rtsp://h/35144640-ff1e-4619-b0d5-4c74c185741c/main/")
+                   .unwrap(), live);
+        assert_eq!(Target::parse("rtsp://h/35144640-ff1e-4619-b0d5-4c74c185741c/main/trackID=0")
+                   .unwrap(), live);
+        assert_eq!(Target::parse("rtsp://h/35144640-ff1e-4619-b0d5-4c74c185741c/sub?start=1")
+                   .unwrap(),
+                   Target {
+                       camera_uuid: uuid,
+                       stream_type: db::StreamType::SUB,
+                       replay: Some(recording::Time(1) .. recording::Time::max_value()),
+                   });
+        assert!(Target::parse("rtsp://h/35144640-ff1e-4619-b0d5-4c74c185741c").is_err());
+        assert!(Target::parse("rtsp://h/35144640-ff1e-4619-b0d5-4c74c185741c/foo").is_err());
+        assert!(Target::parse("rtsp://h/35144640-ff1e-4619-b0d5-4c74c185741c/main?x=1").is_err());
+    }
+
+    #[test]
+    fn track_url() {
+        assert_eq!(super::track_url("rtsp://h/u/main?start=1").unwrap(),
+                   "rtsp://h/u/main/trackID=0?start=1");
+        assert_eq!(super::track_url("rtsp://h/u/main/").unwrap(), "rtsp://h/u/main/trackID=0");
+        assert_eq!(super::track_url("rtsp://h/u/main/trackID=0").unwrap(),
+                   "rtsp://h/u/main/trackID=0");
+    }
+
+    #[test]
+    fn interleaved_channel() {
+        assert_eq!(super::interleaved_channel("RTP/AVP;unicast;client_port=5000-5001"), None);
+        assert_eq!(super::interleaved_channel("RTP/AVP/TCP;unicast;interleaved=2-3"), Some(2));
+        assert_eq!(super::interleaved_channel(
+            "RTP/AVP;unicast;client_port=5000-5001, RTP/AVP/TCP;interleaved=4-5"), Some(4));
+        assert_eq!(super::interleaved_channel("RTP/AVP/TCP;unicast"), Some(0));
+    }
+
+    #[tokio::test]
+    async fn read_request() {
+        let mut input: &[u8] = b"$\x01\x00\x02ab\
+                                 SETUP rtsp://h/u/main/trackID=0 RTSP/1.0\r\n\
+                                 CSeq: 3\r\n\
+                                 Transport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\
+                                 Content-Length: 2\r\n\
+                                 \r\n\
+                                 hi\
+                                 \r\n\
+                                 OPTIONS * RTSP/1.0\r\n\
+                                 cseq: 4\r\n\
+                                 \r\n";
+        let req = super::read_request(&mut input).await.unwrap().unwrap();
+        assert_eq!(req.method, "SETUP");
+        assert_eq!(req.url, "rtsp://h/u/main/trackID=0");
+        assert_eq!(req.header("cseq"), Some("3"));
+        assert_eq!(req.header("Transport"), Some("RTP/AVP/TCP;unicast;interleaved=0-1"));
+        assert_eq!(&req.body[..], b"hi");
+        let req = super::read_request(&mut input).await.unwrap().unwrap();
+        assert_eq!(req.method, "OPTIONS");
+        assert_eq!(req.header("CSeq"), Some("4"));
+        assert!(super::read_request(&mut input).await.unwrap().is_none());
+    }
+
+    /// Returns a connection which hasn't yet authenticated.
+    fn conn(db: &TestDb<base::clock::RealClocks>) -> Conn {
+        Conn {
+            inner: Arc::new(ServerInner {
+                db: db.db.clone(),
+                live_frames: Arc::new(LiveFrames::default()),
+                allow_unauthenticated_permissions: None,
+            }),
+            peer: "127.0.0.1:5000".parse().unwrap(),
+            out: mpsc::channel(1).0,
+            authorization: None,
+            session: None,
+        }
+    }
+
+    fn describe(camera_uuid: Uuid, username: &str, password: &str) -> Request {
+        Request {
+            method: "DESCRIBE".to_owned(),
+            url: format!("rtsp://h/{}/main", camera_uuid),
+            headers: vec![
+                ("CSeq".to_owned(), "1".to_owned()),
+                ("Authorization".to_owned(),
+                 format!("Basic {}", base64::encode(&format!("{}:{}", username, password)))),
+            ],
+            body: Vec::new(),
+        }
+    }
+
+    /// Returns the status of the response to `req`.
+    fn status(conn: &mut Conn, req: &Request) -> u16 {
+        match conn.handle(req) {
+            Ok((resp, _)) => resp.status,
+            Err(resp) => resp.status,
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn authorize_camera() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let mut c = db::UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        c.permissions.view_video = true;
+        c.permissions.camera_uuids.push(db.test_camera_uuid.to_string());
+        db.db.lock().apply_user_change(c).unwrap();
+        let mut conn = conn(&db);
+
+        // The test camera isn't connected, so a DESCRIBE which passes authorization fails later.
+        assert_eq!(status(&mut conn, &describe(db.test_camera_uuid, "slamb", "hunter2")), 404);
+
+        let other = Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
+        assert_eq!(status(&mut conn, &describe(other, "slamb", "hunter2")), 403);
+        assert_eq!(status(&mut conn, &describe(other, "slamb", "wrong")), 401);
+    }
+
+    /// Changes to the user take effect on an open connection.
+    #[tokio::test(threaded_scheduler)]
+    async fn reauthorize() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let mut c = db::UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        c.permissions.view_video = true;
+        let id = db.db.lock().apply_user_change(c).unwrap().id;
+        let mut conn = conn(&db);
+        let req = describe(db.test_camera_uuid, "slamb", "hunter2");
+        assert_eq!(status(&mut conn, &req), 404);
+
+        let mut c = db.db.lock().users_by_id().get(&id).unwrap().change();
+        c.permissions.view_video = false;
+        db.db.lock().apply_user_change(c).unwrap();
+        assert_eq!(status(&mut conn, &req), 403);
+
+        let mut c = db.db.lock().users_by_id().get(&id).unwrap().change();
+        c.permissions.view_video = true;
+        c.set_password("hunter3".to_owned());
+        db.db.lock().apply_user_change(c).unwrap();
+        assert_eq!(status(&mut conn, &req), 401);
+        assert_eq!(status(&mut conn, &describe(db.test_camera_uuid, "slamb", "hunter3")), 404);
+
+        let mut c = db.db.lock().users_by_id().get(&id).unwrap().change();
+        c.disable();
+        db.db.lock().apply_user_change(c).unwrap();
+        assert_eq!(status(&mut conn, &describe(db.test_camera_uuid, "slamb", "hunter3")), 401);
+    }
+
+    /// Playing video adds an entry to the audit log once the session ends.
+    #[tokio::test(threaded_scheduler)]
+    async fn audit_play() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let mut c = db::UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        c.permissions.view_video = true;
+        let user_id = db.db.lock().apply_user_change(c).unwrap().id;
+        let vse_id = db.db.lock().insert_video_sample_entry(
+            1920, 1080, testutil::TEST_VIDEO_SAMPLE_ENTRY_DATA.to_vec(),
+            "avc1.4d002a".to_owned()).unwrap();
+        let mut conn = conn(&db);
+        let (out, mut out_rx) = mpsc::channel(16);
+        conn.out = out;
+        let live_frames = conn.inner.live_frames.clone();
+        live_frames.start(testutil::TEST_STREAM_ID, vse_id);
+
+        let mut req = describe(db.test_camera_uuid, "slamb", "hunter2");
+        req.method = "SETUP".to_owned();
+        req.headers.push(("Transport".to_owned(), "RTP/AVP/TCP;interleaved=0-1".to_owned()));
+        let (resp, _) = conn.handle(&req).ok().unwrap();
+        let session = resp.headers.iter().find(|&&(k, _)| k == "Session").unwrap().1.clone();
+        req.method = "PLAY".to_owned();
+        req.headers.push(("Session".to_owned(), session));
+        let play = match conn.handle(&req) {
+            Ok((_, Some(play))) => tokio::spawn(play),
+            _ => panic!("PLAY failed"),
+        };
+
+        live_frames.send(testutil::TEST_STREAM_ID, 0, true, b"\x00\x00\x00\x02\x65\x88");
+        let sent = out_rx.next().await.unwrap();
+        live_frames.end(testutil::TEST_STREAM_ID);
+        play.await.unwrap();
+
+        let entries = db.db.lock().list_audit_entries(&db::audit::Query {
+            before_id: None,
+            time_sec: None,
+            username: None,
+            camera_uuid: None,
+            limit: 10,
+        }).unwrap();
+        assert_eq!(entries.len(), 1);
+        let e = &entries[0];
+        assert_eq!(e.action, "rtsp");
+        assert_eq!(e.user_id, Some(user_id));
+        assert_eq!(e.request.addr, Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(e.camera_uuid, Some(db.test_camera_uuid));
+        assert_eq!(e.stream_type, Some(db::StreamType::MAIN));
+        assert_eq!(e.bytes, Some(sent.len() as i64));
+    }
+
+    #[test]
+    fn live_frames() {
+        let l = LiveFrames::default();
+        assert!(l.subscribe(1).is_none());
+        l.start(1, 42);
+        let (vse_id, mut rx) = l.subscribe(1).unwrap();
+        assert_eq!(vse_id, 42);
+        l.send(1, 0, true, b"frame");
+        let f = rx.try_next().unwrap().unwrap();
+        assert_eq!((f.pts, f.is_key, &f.data[..]), (0, true, &b"frame"[..]));
+        drop(rx);
+        l.send(1, 1, false, b"frame");
+        assert!(l.0.lock().get(&1).unwrap().subscribers.is_empty());
+        l.end(1);
+        assert!(l.video_sample_entry_id(1).is_none());
+    }
+}
@@ -0,0 +1,213 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! RTP packetization of H.264 video (RFC 6184) and the SDP description (RFC 4566) of it.
+
+use crate::h264;
+use db::recording;
+use failure::{Error, bail, format_err};
+
+/// The RTP payload type used for video: the first of the dynamic payload types.
+const PAYLOAD_TYPE: u8 = 96;
+
+/// The maximum length of an RTP payload. Interleaved packets aren't limited by the network's
+/// MTU, but many clients assume they are and allocate their buffers accordingly.
+const MAX_PAYLOAD_LEN: usize = 1400;
+
+/// The NAL unit type of a fragmentation unit (RFC 6184 section 5.8).
+const FU_A: u8 = 28;
+
+/// Splits AVC-format samples (as stored in sample files) into RTP packets.
+pub struct Packetizer {
+    ssrc: u32,
+    next_seq: u16,
+    length_size: usize,
+    channel: u8,
+}
+
+impl Packetizer {
+    /// Creates a packetizer for samples with NAL unit length prefixes of `length_size` bytes
+    /// which will be sent on the given interleaved `channel`.
+    pub fn new(ssrc: u32, initial_seq: u16, length_size: usize, channel: u8) -> Self {
+        Packetizer {
+            ssrc,
+            next_seq: initial_seq,
+            length_size,
+            channel,
+        }
+    }
+
+    /// Appends the packets for a sample to `out`, each with RTSP interleaved framing
+    /// (RFC 2326 section 10.12). The marker bit is set on the sample's last packet.
+    pub fn append_sample(&mut self, timestamp: u32, sample: &[u8], out: &mut Vec<u8>)
+                         -> Result<(), Error> {
+        let mut nals = Vec::new();
+        let mut pos = 0;
+        while pos < sample.len() {
+            let len = sample.get(pos .. pos + self.length_size)
+                            .ok_or_else(|| format_err!("NAL unit length is truncated"))?;
+            let len = len.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            pos += self.length_size;
+            let nal = sample.get(pos .. pos + len)
+                            .ok_or_else(|| format_err!("NAL unit is truncated"))?;
+            if nal.is_empty() {
+                bail!("empty NAL unit");
+            }
+            nals.push(nal);
+            pos += len;
+        }
+        let num_nals = nals.len();
+        for (i, nal) in nals.into_iter().enumerate() {
+            let last_nal = i + 1 == num_nals;
+            if nal.len() <= MAX_PAYLOAD_LEN {
+                self.append_packet(timestamp, last_nal, &[], nal, out);
+                continue;
+            }
+
+            // Fragment the NAL unit. The FU indicator takes the original header's F and NRI
+            // bits; the FU header takes its type.
+            let indicator = (nal[0] & 0xe0) | FU_A;
+            let mut rest = &nal[1..];
+            let mut start = 0x80;
+            while !rest.is_empty() {
+                let (chunk, r) = rest.split_at(::std::cmp::min(rest.len(), MAX_PAYLOAD_LEN - 2));
+                rest = r;
+                let end = if rest.is_empty() { 0x40 } else { 0 };
+                let header = start | end | (nal[0] & 0x1f);
+                self.append_packet(timestamp, last_nal && rest.is_empty(), &[indicator, header],
+                                   chunk, out);
+                start = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn append_packet(&mut self, timestamp: u32, marker: bool, prefix: &[u8], payload: &[u8],
+                     out: &mut Vec<u8>) {
+        let len = 12 + prefix.len() + payload.len();
+        out.reserve(4 + len);
+        out.push(b'$');
+        out.push(self.channel);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+        out.push(0x80);  // version 2, no padding, no extension, no CSRCs.
+        out.push(if marker { 0x80 } else { 0 } | PAYLOAD_TYPE);
+        out.extend_from_slice(&self.next_seq.to_be_bytes());
+        out.extend_from_slice(&timestamp.to_be_bytes());
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+        out.extend_from_slice(prefix);
+        out.extend_from_slice(payload);
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+}
+
+/// Returns an SDP session description of a single H.264 stream with the given sample entry.
+/// `control` is the absolute URL of its track. `duration` is the length of recorded video, or
+/// `None` for live video.
+pub fn session_description(vse: &db::VideoSampleEntry, control: &str,
+                           duration: Option<recording::Duration>) -> Result<String, Error> {
+    let config = h264::AvcDecoderConfig::from_sample_entry(&vse.data)?;
+
+    // rfc6381_codec is of the form "avc1.4d001f"; the last six digits are the profile_idc,
+    // constraint flags, and level_idc which make up the profile-level-id.
+    let profile_level_id = match vse.rfc6381_codec.get(5..) {
+        Some(p) if p.len() == 6 => p,
+        _ => bail!("unexpected codec {:?}", vse.rfc6381_codec),
+    };
+    let sprop_parameter_sets: Vec<String> =
+        config.sps.iter().chain(config.pps.iter()).map(|s| base64::encode(s)).collect();
+    let range = match duration {
+        None => "npt=now-".to_owned(),
+        Some(d) => format!("npt=0-{}.{:03}", d.0 / recording::TIME_UNITS_PER_SEC,
+                           d.0 % recording::TIME_UNITS_PER_SEC / 90),
+    };
+    Ok(format!("v=0\r\n\
+                o=- 0 0 IN IP4 0.0.0.0\r\n\
+                s=Moonfire NVR\r\n\
+                t=0 0\r\n\
+                a=control:*\r\n\
+                a=range:{range}\r\n\
+                m=video 0 RTP/AVP {pt}\r\n\
+                a=rtpmap:{pt} H264/90000\r\n\
+                a=fmtp:{pt} packetization-mode=1;profile-level-id={plid};\
+                sprop-parameter-sets={sprop}\r\n\
+                a=control:{control}\r\n",
+               range = range, pt = PAYLOAD_TYPE, plid = profile_level_id,
+               sprop = sprop_parameter_sets.join(","), control = control))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Packetizer;
+
+    #[test]
+    fn packetize_single_nal() {
+        let mut p = Packetizer::new(0x01020304, 0xffff, 4, 2);
+        let mut out = Vec::new();
+        p.append_sample(90000, b"\x00\x00\x00\x02\x65\xaa\x00\x00\x00\x01\x06", &mut out)
+         .unwrap();
+        assert_eq!(&out[..], &b"$\x02\x00\x0e\x80\x60\xff\xff\x00\x01\x5f\x90\x01\x02\x03\x04\
+                                \x65\xaa\
+                                $\x02\x00\x0d\x80\xe0\x00\x00\x00\x01\x5f\x90\x01\x02\x03\x04\
+                                \x06"[..]);
+    }
+
+    #[test]
+    fn packetize_fragmented_nal() {
+        let mut sample = vec![0x00, 0x00, 0x0b, 0xb9, 0x65];
+        sample.resize(5 + 3000, 0xaa);
+        let mut p = Packetizer::new(0x01020304, 0, 4, 0);
+        let mut out = Vec::new();
+        p.append_sample(0, &sample, &mut out).unwrap();
+
+        // The 3000 bytes after the NAL header should be split 1398 + 1398 + 204.
+        let mut pos = 0;
+        let mut packets = Vec::new();
+        while pos < out.len() {
+            assert_eq!(out[pos], b'$');
+            let len = ((out[pos + 2] as usize) << 8) | out[pos + 3] as usize;
+            packets.push(&out[pos + 4 .. pos + 4 + len]);
+            pos += 4 + len;
+        }
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets.iter().map(|p| p.len()).collect::<Vec<_>>(),
+                   vec![12 + 2 + 1398, 12 + 2 + 1398, 12 + 2 + 204]);
+        assert_eq!(packets.iter().map(|p| p[1]).collect::<Vec<_>>(), vec![0x60, 0x60, 0xe0]);
+        assert_eq!(packets.iter().map(|p| (p[12], p[13])).collect::<Vec<_>>(),
+                   vec![(0x7c, 0x85), (0x7c, 0x05), (0x7c, 0x45)]);
+        assert_eq!(packets.iter().map(|p| p[3]).collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn packetize_truncated() {
+        let mut p = Packetizer::new(0, 0, 4, 0);
+        let mut out = Vec::new();
+        assert!(p.append_sample(0, b"\x00\x00\x00\x05\x65", &mut out).is_err());
+    }
+}
@@ -30,8 +30,9 @@
 
 use base::clock::{Clocks, TimerGuard};
 use crate::h264;
+use crate::rtsp;
 use crate::stream;
-use db::{Camera, Database, Stream, dir, recording, writer};
+use db::{Camera, Database, Stream, crypto, dir, recording, writer};
 use failure::{Error, bail, format_err};
 use log::{debug, info, trace, warn};
 use std::result::Result;
@@ -47,6 +48,12 @@ pub struct Environment<'a, 'b, C, S> where C: Clocks + Clone, S: 'a + stream::St
     pub opener: &'a dyn stream::Opener<S>,
     pub db: &'b Arc<Database<C>>,
     pub shutdown: &'b Arc<AtomicBool>,
+
+    /// The key with which camera credentials are sealed, if supplied.
+    pub credentials_key: Option<&'b crypto::Key>,
+
+    /// Where to send frames for RTSP clients watching live.
+    pub live_frames: &'b Arc<rtsp::LiveFrames>,
 }
 
 pub struct Streamer<'a, C, S> where C: Clocks + Clone, S: 'a + stream::Stream {
@@ -57,7 +64,8 @@ pub struct Streamer<'a, C, S> where C: Clocks + Clone, S: 'a + stream::Stream {
     rotate_interval_sec: i64,
     db: Arc<Database<C>>,
     dir: Arc<dir::SampleFileDir>,
-    syncer_channel: writer::SyncerChannel<::std::fs::File>,
+    syncer_channel: writer::SyncerChannel<dir::SampleFileWriter>,
+    live_frames: Arc<rtsp::LiveFrames>,
     opener: &'a dyn stream::Opener<S>,
     stream_id: i32,
     short_name: String,
@@ -67,15 +75,19 @@ pub struct Streamer<'a, C, S> where C: Clocks + Clone, S: 'a + stream::Stream {
 
 impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::Stream {
     pub fn new<'b>(env: &Environment<'a, 'b, C, S>, dir: Arc<dir::SampleFileDir>,
-                   syncer_channel: writer::SyncerChannel<::std::fs::File>,
+                   syncer_channel: writer::SyncerChannel<dir::SampleFileWriter>,
                    stream_id: i32, c: &Camera, s: &Stream, rotate_offset_sec: i64,
                    rotate_interval_sec: i64) -> Result<Self, Error> {
         let mut url = Url::parse(&s.rtsp_url)?;
         let mut redacted_url = url.clone();
-        if !c.username.is_empty() {
-            url.set_username(&c.username).map_err(|_| format_err!("can't set username"))?;
-            redacted_url.set_username(&c.username).unwrap();
-            url.set_password(Some(&c.password)).unwrap();
+        if let Some(ref creds) = c.credentials {
+            let key = env.credentials_key.ok_or_else(
+                || format_err!("{}: no credentials key to decrypt camera credentials",
+                               c.short_name))?;
+            let (username, password) = creds.open(key, c.uuid)?;
+            url.set_username(&username).map_err(|_| format_err!("can't set username"))?;
+            redacted_url.set_username(&username).unwrap();
+            url.set_password(Some(&password)).unwrap();
             redacted_url.set_password(Some("redacted")).unwrap();
         }
         Ok(Streamer {
@@ -85,6 +97,7 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
             db: env.db.clone(),
             dir,
             syncer_channel: syncer_channel,
+            live_frames: env.live_frames.clone(),
             opener: env.opener,
             stream_id: stream_id,
             short_name: format!("{}-{}", c.short_name, s.type_.as_str()),
@@ -97,7 +110,9 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
 
     pub fn run(&mut self) {
         while !self.shutdown.load(Ordering::SeqCst) {
-            if let Err(e) = self.run_once() {
+            let r = self.run_once();
+            self.live_frames.end(self.stream_id);
+            if let Err(e) = r {
                 let sleep_time = time::Duration::seconds(1);
                 warn!("{}: sleeping for {:?} after error: {:?}", self.short_name, sleep_time, e);
                 self.db.clocks().sleep(sleep_time);
@@ -107,6 +122,18 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
     }
 
     fn run_once(&mut self) -> Result<(), Error> {
+        // Don't bother connecting to the camera if there's nowhere to write.
+        {
+            let l = self.db.lock();
+            let dir = l.streams_by_id()
+                       .get(&self.stream_id)
+                       .and_then(|s| s.sample_file_dir_id)
+                       .and_then(|id| l.sample_file_dirs_by_id().get(&id));
+            if let Some(reason) = dir.and_then(|d| d.failure()) {
+                bail!("sample file dir has failed: {}", reason);
+            }
+        }
+
         info!("{}: Opening input: {}", self.short_name, self.redacted_url);
         let clocks = self.db.clocks();
 
@@ -127,6 +154,7 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
                                                      extra_data.rfc6381_codec)?
         };
         debug!("{}: video_sample_entry_id={}", self.short_name, video_sample_entry_id);
+        self.live_frames.start(self.stream_id, video_sample_entry_id);
         let mut seen_key_frame = false;
 
         // Seconds since epoch at which to next rotate.
@@ -187,6 +215,7 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
             let _t = TimerGuard::new(&clocks,
                                       || format!("writing {} bytes", transformed_data.len()));
             w.write(transformed_data, local_time, pts, pkt.is_key())?;
+            self.live_frames.send(self.stream_id, pts, pkt.is_key(), transformed_data);
             rotate = Some(r);
         }
         if rotate.is_some() {
@@ -345,10 +374,13 @@ mod tests {
             shutdown: Arc::new(AtomicBool::new(false)),
         };
         let db = testutil::TestDb::new(clocks.clone());
+        let credentials_key = testutil::credentials_key();
         let env = super::Environment {
             opener: &opener,
             db: &db.db,
             shutdown: &opener.shutdown,
+            credentials_key: Some(&credentials_key),
+            live_frames: &Default::default(),
         };
         let mut stream;
         {
@@ -0,0 +1,149 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Native HTTPS support: a TLS acceptor built from PEM files, which can be reloaded (as on
+//! `SIGHUP`) when the certificate is renewed.
+
+use failure::{Error, ResultExt, bail};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+
+pub struct Acceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: Mutex<tokio_tls::TlsAcceptor>,
+}
+
+impl Acceptor {
+    /// Creates an acceptor from a PEM-encoded certificate chain (leaf first) and private key.
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, Error> {
+        let current = Mutex::new(load(&cert_path, &key_path)?);
+        Ok(Acceptor {
+            cert_path,
+            key_path,
+            current,
+        })
+    }
+
+    /// Returns the current acceptor, for use with a new connection.
+    pub fn get(&self) -> tokio_tls::TlsAcceptor {
+        self.current.lock().clone()
+    }
+
+    /// Reloads the certificate chain and private key. On failure, the previous ones stay in use.
+    pub fn reload(&self) -> Result<(), Error> {
+        let a = load(&self.cert_path, &self.key_path)?;
+        *self.current.lock() = a;
+        Ok(())
+    }
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<tokio_tls::TlsAcceptor, Error> {
+    let certs = std::fs::read(cert_path)
+        .with_context(|_| format!("Unable to read {}", cert_path.display()))?;
+    let mut certs = X509::stack_from_pem(&certs)
+        .with_context(|_| format!("Unable to parse certificates in {}", cert_path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates in {}", cert_path.display());
+    }
+    let leaf = certs.remove(0);
+    let mut chain = Stack::new()?;
+    for c in certs {
+        chain.push(c)?;
+    }
+    let key = std::fs::read(key_path)
+        .with_context(|_| format!("Unable to read {}", key_path.display()))?;
+    let key = PKey::private_key_from_pem(&key)
+        .with_context(|_| format!("Unable to parse private key in {}", key_path.display()))?;
+
+    // native-tls only accepts an identity as a PKCS #12 archive, so build one in memory.
+    let mut builder = Pkcs12::builder();
+    builder.ca(chain);
+    let pkcs12 = builder.build("", "moonfire-nvr", &key, &leaf)
+        .context("Unable to combine certificate and private key; do they match?")?
+        .to_der()?;
+    let identity = native_tls::Identity::from_pkcs12(&pkcs12, "")?;
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509NameBuilder};
+    use std::path::Path;
+    use super::*;
+
+    fn self_signed() -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut b = X509::builder().unwrap();
+        b.set_version(2).unwrap();
+        b.set_subject_name(&name).unwrap();
+        b.set_issuer_name(&name).unwrap();
+        b.set_pubkey(&key).unwrap();
+        b.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        b.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        b.sign(&key, MessageDigest::sha256()).unwrap();
+        (b.build(), key)
+    }
+
+    fn write(dir: &Path, cert: &X509, key: &PKey<Private>) {
+        std::fs::write(dir.join("cert.pem"), cert.to_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn load_and_reload() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let (cert, key) = self_signed();
+        write(tmpdir.path(), &cert, &key);
+        let a = Acceptor::new(tmpdir.path().join("cert.pem"), tmpdir.path().join("key.pem"))
+            .unwrap();
+        a.reload().unwrap();
+
+        // A certificate which doesn't match the key should be rejected.
+        let (other_cert, _) = self_signed();
+        write(tmpdir.path(), &other_cert, &key);
+        a.reload().unwrap_err();
+
+        // So should garbage.
+        std::fs::write(tmpdir.path().join("cert.pem"), b"garbage").unwrap();
+        a.reload().unwrap_err();
+    }
+}
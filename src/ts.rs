@@ -0,0 +1,586 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `.ts` virtual file serving.
+//!
+//! The `ts` module builds virtual files representing MPEG-2 transport streams (ISO/IEC 13818-1)
+//! from one or more recordings, for tools which expect broadcast-style streams. Like `mp4::File`,
+//! these support HTTP range serving and have stable etags. Each frame becomes one PES packet split
+//! across fixed-size transport stream packets; each key frame is preceded by the program tables
+//! and its parameter sets, so playback can start at any key frame. The packets are generated on
+//! request from a table of frames built along with the file.
+
+use base::{strutil, Error, ErrorKind, ResultExt, bail_t, format_err_t};
+use byteorder::{BigEndian, ByteOrder};
+use crate::body::{self, Chunk, BoxedError, wrap_error};
+use crate::h264;
+use crate::slices::{self, Slices};
+use db::dir;
+use db::recording;
+use futures::{Stream, stream};
+use http::header::HeaderValue;
+use log::trace;
+use openssl::hash;
+use reffers::ARefss;
+use std::cmp;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// This value should be incremented any time a change is made to this file that causes different
+/// bytes to be output for a particular set of `FileBuilder` options. Incrementing this value will
+/// cause the etag to change as well.
+const FORMAT_VERSION: [u8; 1] = [0x00];
+
+const PACKET_LEN: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+
+/// The program association table, without its trailing CRC. It lists a single program whose map
+/// is on `PMT_PID`.
+const PAT: &[u8] = b"\x00\xb0\x0d\x00\x01\xc1\x00\x00\x00\x01\xf0\x00";
+
+/// The program map table, without its trailing CRC. It lists a single H.264 stream on
+/// `VIDEO_PID`, which also carries the program clock reference.
+const PMT: &[u8] = b"\x02\xb0\x12\x00\x01\xc1\x00\x00\xe1\x00\xf0\x00\x1b\xe1\x00\xf0\x00";
+
+/// The fixed-length PES header before the presentation timestamp: stream id `0xe0` (the first
+/// video stream), unbounded length, and a 5-byte optional header holding only the PTS.
+const PES_HEADER: &[u8] = b"\x00\x00\x01\xe0\x00\x00\x80\x80\x05";
+const PES_HEADER_LEN: usize = 14;
+
+/// An access unit delimiter NAL, in Annex B form. H.264 in MPEG-TS requires one per frame.
+const ACCESS_UNIT_DELIMITER: &[u8] = b"\x00\x00\x00\x01\x09\xf0";
+
+const START_CODE: &[u8] = b"\x00\x00\x00\x01";
+
+/// The amount each presentation timestamp is ahead of the clock reference, matching ffmpeg's
+/// default `muxdelay` of 0.7 seconds.
+const PTS_DELAY_90K: i64 = 63_000;
+
+/// Timestamps and clock references are 33-bit values which wrap around.
+const TIMESTAMP_MASK: i64 = 0x1_ffff_ffff;
+
+/// The payload capacity of the first packet of a PES packet, which carries an 8-byte adaptation
+/// field with the clock reference.
+const FIRST_PAYLOAD_LEN: usize = PACKET_LEN - 4 - 8;
+const PAYLOAD_LEN: usize = PACKET_LEN - 4;
+
+/// Computes the MPEG-2 CRC-32 of a PSI section.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &b in data {
+        crc ^= (b as u32) << 24;
+        for _ in 0 .. 8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Returns the number of transport stream packets needed to carry a PES packet of `len` bytes.
+fn num_packets(len: usize) -> usize {
+    if len <= FIRST_PAYLOAD_LEN {
+        return 1;
+    }
+    1 + (len - FIRST_PAYLOAD_LEN + PAYLOAD_LEN - 1) / PAYLOAD_LEN
+}
+
+/// Appends a packet holding the given PSI section (without its CRC), which must fit in one packet.
+fn append_psi_packet(out: &mut Vec<u8>, pid: u16, cc: u8, section: &[u8]) {
+    let start = out.len();
+    out.extend_from_slice(&[SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | (cc & 0x0f),
+                            0x00 /* pointer field */]);
+    out.extend_from_slice(section);
+    let mut crc = [0u8; 4];
+    BigEndian::write_u32(&mut crc, crc32(section));
+    out.extend_from_slice(&crc);
+    out.resize(start + PACKET_LEN, 0xff);
+}
+
+/// Appends `pes` split into packets on `VIDEO_PID`, starting with continuity counter `cc`. The
+/// first packet's adaptation field carries a clock reference of `pcr_90k`; the last packet is
+/// padded via its adaptation field.
+fn append_pes_packets(out: &mut Vec<u8>, pes: &[u8], mut cc: u8, pcr_90k: i64, is_key: bool) {
+    let mut pos = 0;
+    while pos < pes.len() {
+        let first = pos == 0;
+        let payload_len = cmp::min(if first { FIRST_PAYLOAD_LEN } else { PAYLOAD_LEN },
+                                   pes.len() - pos);
+        let af_len = PAYLOAD_LEN - payload_len;  // including the adaptation_field_length byte.
+        out.extend_from_slice(&[
+            SYNC_BYTE,
+            if first { 0x40 } else { 0x00 } | (VIDEO_PID >> 8) as u8,
+            VIDEO_PID as u8,
+            if af_len > 0 { 0x30 } else { 0x10 } | (cc & 0x0f),
+        ]);
+        if af_len > 0 {
+            out.push((af_len - 1) as u8);
+        }
+        if af_len > 1 {
+            let start = out.len() - 1;
+            if first {
+                let pcr = (pcr_90k & TIMESTAMP_MASK) as u64;
+                out.extend_from_slice(&[
+                    0x10 | if is_key { 0x40 } else { 0x00 },  // PCR flag, random access flag.
+                    (pcr >> 25) as u8,
+                    (pcr >> 17) as u8,
+                    (pcr >> 9) as u8,
+                    (pcr >> 1) as u8,
+                    ((pcr & 1) << 7) as u8 | 0x7e,
+                    0x00,
+                ]);
+            } else {
+                out.push(0x00);
+            }
+            out.resize(start + af_len, 0xff);
+        }
+        out.extend_from_slice(&pes[pos .. pos + payload_len]);
+        pos += payload_len;
+        cc = cc.wrapping_add(1);
+    }
+}
+
+/// Appends a presentation timestamp in the PES header encoding.
+fn append_pts(out: &mut Vec<u8>, pts_90k: i64) {
+    let pts = (pts_90k & TIMESTAMP_MASK) as u64;
+    out.extend_from_slice(&[
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        ((pts >> 14) & 0xfe) as u8 | 1,
+        (pts >> 7) as u8,
+        ((pts << 1) & 0xfe) as u8 | 1,
+    ]);
+}
+
+/// A video sample entry's parameter sets, in Annex B form.
+struct SampleEntry {
+    id: i32,
+    parameter_sets: Vec<u8>,
+}
+
+impl SampleEntry {
+    fn new(e: &db::VideoSampleEntry) -> Result<Self, Error> {
+        let c = h264::AvcDecoderConfig::from_sample_entry(&e.data).err_kind(ErrorKind::Internal)?;
+        if c.length_size != 4 {
+            bail_t!(Internal, "video sample entry {} has unsupported NAL length size {}",
+                    e.id, c.length_size);
+        }
+        let mut parameter_sets = Vec::new();
+        for s in c.sps.iter().chain(c.pps.iter()) {
+            parameter_sets.extend_from_slice(START_CODE);
+            parameter_sets.extend_from_slice(s);
+        }
+        Ok(SampleEntry {
+            id: e.id,
+            parameter_sets,
+        })
+    }
+}
+
+/// A frame within a `Segment`, as laid out in the generated file.
+#[derive(Debug)]
+struct Frame {
+    /// The position of this frame's first packet within the segment's slice.
+    pos: u64,
+
+    /// The position of this frame's data within the sample file.
+    sample_pos: u64,
+    bytes: u32,
+    is_key: bool,
+
+    /// This frame's time since the start of the file, used for its clock reference.
+    time_90k: i64,
+
+    /// The continuity counter of this frame's first video packet.
+    cc: u8,
+
+    /// If this is a key frame, the continuity counter of its program table packets.
+    psi_cc: u8,
+}
+
+impl Frame {
+    fn pes_len(&self, e: &SampleEntry) -> usize {
+        PES_HEADER_LEN + ACCESS_UNIT_DELIMITER.len() +
+        if self.is_key { e.parameter_sets.len() } else { 0 } + self.bytes as usize
+    }
+
+    fn len(&self, e: &SampleEntry) -> u64 {
+        let psi_packets = if self.is_key { 2 } else { 0 };
+        ((psi_packets + num_packets(self.pes_len(e))) * PACKET_LEN) as u64
+    }
+
+    /// Appends this frame's packets, given its sample data in AVC format.
+    fn append_packets(&self, out: &mut Vec<u8>, e: &SampleEntry, data: &[u8])
+                      -> Result<(), Error> {
+        if self.is_key {
+            append_psi_packet(out, PAT_PID, self.psi_cc, PAT);
+            append_psi_packet(out, PMT_PID, self.psi_cc, PMT);
+        }
+        let mut pes = Vec::with_capacity(self.pes_len(e));
+        pes.extend_from_slice(PES_HEADER);
+        append_pts(&mut pes, self.time_90k + PTS_DELAY_90K);
+        pes.extend_from_slice(ACCESS_UNIT_DELIMITER);
+        if self.is_key {
+            pes.extend_from_slice(&e.parameter_sets);
+        }
+
+        // Convert to Annex B by replacing each 4-byte length prefix with a start code.
+        let data_start = pes.len();
+        pes.extend_from_slice(data);
+        let mut pos = data_start;
+        while pos < pes.len() {
+            if pos + 4 > pes.len() {
+                bail_t!(DataLoss, "frame has truncated NAL length");
+            }
+            let len = BigEndian::read_u32(&pes[pos .. pos + 4]) as usize;
+            pes[pos .. pos + 4].copy_from_slice(START_CODE);
+            pos += 4 + len;
+        }
+        if pos != pes.len() {
+            bail_t!(DataLoss, "frame has truncated NAL");
+        }
+        append_pes_packets(out, &pes, self.cc, self.time_90k, self.is_key);
+        Ok(())
+    }
+}
+
+/// A wrapper around `recording::Segment` with the layout of its packets.
+struct Segment {
+    s: recording::Segment,
+
+    /// The index of this segment's entry within `FileInner::sample_entries`.
+    sample_entry: usize,
+    frames: Vec<Frame>,
+
+    /// The length of this segment's slice.
+    len: u64,
+}
+
+impl fmt::Debug for Segment {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ts::Segment")
+           .field("s", &self.s)
+           .field("sample_entry", &self.sample_entry)
+           .field("frames", &self.frames.len())
+           .field("len", &self.len)
+           .finish()
+    }
+}
+
+pub struct FileBuilder {
+    segments: Vec<Segment>,
+    sample_entries: Vec<SampleEntry>,
+
+    /// The total duration of segments appended so far.
+    duration_90k: i64,
+
+    /// The number of video packets and key frames appended so far, for continuity counters.
+    video_packets: usize,
+    key_frames: usize,
+}
+
+impl FileBuilder {
+    pub fn new() -> Self {
+        FileBuilder {
+            segments: Vec::new(),
+            sample_entries: Vec::new(),
+            duration_90k: 0,
+            video_packets: 0,
+            key_frames: 0,
+        }
+    }
+
+    /// Reserves space for the given number of additional segments.
+    pub fn reserve(&mut self, additional: usize) {
+        self.segments.reserve(additional);
+    }
+
+    /// Appends a segment for (a subset of) the given recording.
+    pub fn append(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
+                  rel_range_90k: Range<i32>) -> Result<(), Error> {
+        let sample_entry = match self.sample_entries.iter()
+                                     .position(|e| e.id == row.video_sample_entry_id) {
+            Some(i) => i,
+            None => {
+                let vse = db.video_sample_entries_by_id().get(&row.video_sample_entry_id)
+                            .unwrap();
+                self.sample_entries.push(SampleEntry::new(vse)?);
+                self.sample_entries.len() - 1
+            },
+        };
+        let e = &self.sample_entries[sample_entry];
+        let s = recording::Segment::new(db, &row, rel_range_90k).err_kind(ErrorKind::Unknown)?;
+        let mut frames = Vec::with_capacity(s.frames as usize);
+        let mut pos = 0;
+        let mut end_90k = 0;
+        let actual_start_90k = s.actual_start_90k();
+        let duration_90k = self.duration_90k;
+        let video_packets = &mut self.video_packets;
+        let key_frames = &mut self.key_frames;
+        db.with_recording_playback(s.id, &mut |playback| s.foreach(playback, |it| {
+            let f = Frame {
+                pos,
+                sample_pos: it.pos as u64,
+                bytes: it.bytes as u32,
+                is_key: it.is_key(),
+                time_90k: duration_90k + (it.start_90k - actual_start_90k) as i64,
+                cc: (*video_packets % 16) as u8,
+                psi_cc: (*key_frames % 16) as u8,
+            };
+            pos += f.len(e);
+            *video_packets += num_packets(f.pes_len(e));
+            if f.is_key {
+                *key_frames += 1;
+            }
+            end_90k = cmp::min(s.desired_range_90k.end, it.start_90k + it.duration_90k);
+            frames.push(f);
+            Ok(())
+        })).err_kind(ErrorKind::Unknown)?;
+        self.duration_90k += (end_90k - actual_start_90k) as i64;
+        self.segments.push(Segment {
+            s,
+            sample_entry,
+            frames,
+            len: pos,
+        });
+        Ok(())
+    }
+
+    /// Builds the `File`, consuming the builder.
+    pub fn build(self, db: Arc<db::Database>,
+                 dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>)
+                 -> Result<File, Error> {
+        if self.segments.is_empty() {
+            bail_t!(InvalidArgument, "no recordings specified");
+        }
+        let mut etag = hash::Hasher::new(hash::MessageDigest::sha1())
+            .err_kind(ErrorKind::Internal)?;
+        etag.update(&FORMAT_VERSION[..]).err_kind(ErrorKind::Internal)?;
+        etag.update(b":ts:").err_kind(ErrorKind::Internal)?;
+        let mut max_end = None;
+        let mut slices = Slices::new();
+        slices.reserve(self.segments.len());
+        for (i, s) in self.segments.iter().enumerate() {
+            let d = &s.s.desired_range_90k;
+            let end = s.s.start + recording::Duration(d.end as i64);
+            max_end = Some(cmp::max(max_end.unwrap_or(end), end));
+            let mut data = Vec::with_capacity(28);
+            data.extend_from_slice(&s.s.id.0.to_be_bytes());
+            data.extend_from_slice(&s.s.start.0.to_be_bytes());
+            data.extend_from_slice(&s.s.open_id.to_be_bytes());
+            data.extend_from_slice(&d.start.to_be_bytes());
+            data.extend_from_slice(&d.end.to_be_bytes());
+            etag.update(&data).err_kind(ErrorKind::Internal)?;
+            slices.append(Slice { end: slices.len() + s.len, segment: i })
+                  .err_kind(ErrorKind::Internal)?;
+        }
+        let max_end = max_end.map(|e| e.unix_seconds()).unwrap_or(0);
+        let last_modified = ::std::time::UNIX_EPOCH +
+                            ::std::time::Duration::from_secs(max_end as u64);
+        let etag = etag.finish().err_kind(ErrorKind::Internal)?;
+        Ok(File(Arc::new(FileInner {
+            db,
+            dirs_by_stream_id,
+            segments: self.segments,
+            sample_entries: self.sample_entries,
+            slices,
+            last_modified,
+            etag: HeaderValue::from_str(&format!("\"{}\"", &strutil::hex(&etag)))
+                  .expect("hex string should be valid UTF-8"),
+        })))
+    }
+}
+
+/// A single slice of a `File`, for use with a `Slices` object. There's one per segment.
+struct Slice {
+    end: u64,
+    segment: usize,
+}
+
+impl fmt::Debug for Slice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        // Omit end(); Slices writes that part.
+        write!(f, "Packets({})", self.segment)
+    }
+}
+
+impl slices::Slice for Slice {
+    type Ctx = File;
+    type Chunk = Chunk;
+
+    fn end(&self) -> u64 { self.end }
+    fn get_range(&self, f: &File, range: Range<u64>, _len: u64)
+                 -> Box<dyn Stream<Item = Result<Self::Chunk, BoxedError>> + Send + Sync> {
+        trace!("getting ts slice {:?}'s range {:?}", self, range);
+        match f.0.get_packets(self.segment, range) {
+            Ok(c) => Box::new(stream::iter(c.into_iter().map(Ok::<_, BoxedError>))),
+            Err(e) => Box::new(stream::once(futures::future::err(wrap_error(e)))),
+        }
+    }
+
+    fn get_slices(ctx: &File) -> &Slices<Self> { &ctx.0.slices }
+}
+
+struct FileInner {
+    db: Arc<db::Database>,
+    dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>,
+    segments: Vec<Segment>,
+    sample_entries: Vec<SampleEntry>,
+    slices: Slices<Slice>,
+    last_modified: SystemTime,
+    etag: HeaderValue,
+}
+
+impl FileInner {
+    /// Gets the given range of segment `i`'s packets, generating those of each overlapping frame.
+    fn get_packets(&self, i: usize, r: Range<u64>) -> Result<Vec<Chunk>, Error> {
+        let s = &self.segments[i];
+        let e = &self.sample_entries[s.sample_entry];
+        let first = match s.frames.binary_search_by_key(&r.start, |f| f.pos) {
+            Ok(i) => i,
+            Err(i) => i - 1,  // frames[0].pos == 0, so i > 0.
+        };
+        let mut last = first;
+        while last + 1 < s.frames.len() && s.frames[last + 1].pos < r.end {
+            last += 1;
+        }
+        let d = self.dirs_by_stream_id
+                    .get(&s.s.id.stream())
+                    .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found", s.s.id))?;
+        let data_start = s.frames[first].sample_pos;
+        let data_end = s.frames[last].sample_pos + s.frames[last].bytes as u64;
+        let data = body::sample_file_bytes(d, s.s.id, data_start .. data_end)?;
+        let mut chunks = Vec::with_capacity(last + 1 - first);
+        let mut len = 0;
+        for f in &s.frames[first ..= last] {
+            let off = (f.sample_pos - data_start) as usize;
+            let mut packets = Vec::with_capacity(f.len(e) as usize);
+            f.append_packets(&mut packets, e, &data[off .. off + f.bytes as usize])?;
+            let p = (cmp::max(r.start, f.pos) - f.pos) as usize ..
+                    (cmp::min(r.end, f.pos + packets.len() as u64) - f.pos) as usize;
+            len += p.end - p.start;
+            chunks.push(ARefss::new(packets).map(|v| &v[p]).into());
+        }
+        if len as u64 != r.end - r.start {
+            bail_t!(Internal, "{}: range {:?} produced incorrect len {}", s.s.id, r, len);
+        }
+        Ok(chunks)
+    }
+}
+
+#[derive(Clone)]
+pub struct File(Arc<FileInner>);
+
+impl http_serve::Entity for File {
+    type Data = Chunk;
+    type Error = BoxedError;
+
+    fn add_headers(&self, hdrs: &mut http::header::HeaderMap) {
+        hdrs.insert(http::header::CONTENT_TYPE, HeaderValue::from_static("video/mp2t"));
+    }
+    fn last_modified(&self) -> Option<SystemTime> { Some(self.0.last_modified) }
+    fn etag(&self) -> Option<HeaderValue> { Some(self.0.etag.clone()) }
+    fn len(&self) -> u64 { self.0.slices.len() }
+    fn get_range(&self, range: Range<u64>)
+                 -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        self.0.slices.get_range(self, range)
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ts::File")
+            .field("last_modified", &self.0.last_modified)
+            .field("etag", &self.0.etag)
+            .field("slices", &self.0.slices)
+            .field("segments", &self.0.segments)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pat_crc() {
+        let mut out = Vec::new();
+        append_psi_packet(&mut out, PAT_PID, 0, PAT);
+        assert_eq!(&out[..21], &b"\x47\x40\x00\x10\x00\x00\xb0\x0d\x00\x01\xc1\x00\x00\x00\x01\
+                                   \xf0\x00\x2a\xb1\x04\xb2"[..]);
+        assert_eq!(out.len(), PACKET_LEN);
+        assert!(out[21..].iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn small_pes() {
+        let pes = [0xab; 100];
+        let mut out = Vec::new();
+        append_pes_packets(&mut out, &pes, 15, 90_000, true);
+        assert_eq!(out.len(), PACKET_LEN);
+        assert_eq!(num_packets(pes.len()), 1);
+        assert_eq!(&out[..4], b"\x47\x41\x00\x3f");
+        assert_eq!(out[4] as usize, PACKET_LEN - 5 - pes.len());  // adaptation field length.
+        assert_eq!(&out[5..12], b"\x50\x00\x00\xaf\xc8\x7e\x00");  // flags, PCR of 1 second.
+        assert!(out[12 .. PACKET_LEN - pes.len()].iter().all(|&b| b == 0xff));
+        assert_eq!(&out[PACKET_LEN - pes.len() ..], &pes[..]);
+    }
+
+    #[test]
+    fn large_pes() {
+        // One full first packet, one full packet, and one needing a 1-byte adaptation field.
+        let pes: Vec<u8> = (0 .. FIRST_PAYLOAD_LEN + PAYLOAD_LEN + PAYLOAD_LEN - 1)
+            .map(|i| i as u8).collect();
+        let mut out = Vec::new();
+        append_pes_packets(&mut out, &pes, 14, 0, false);
+        assert_eq!(num_packets(pes.len()), 3);
+        assert_eq!(out.len(), 3 * PACKET_LEN);
+        assert_eq!(&out[..6], b"\x47\x41\x00\x3e\x07\x10");
+        assert_eq!(&out[PACKET_LEN .. PACKET_LEN + 4], b"\x47\x01\x00\x1f");
+        assert_eq!(&out[2 * PACKET_LEN .. 2 * PACKET_LEN + 5], b"\x47\x01\x00\x30\x00");
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&out[12 .. PACKET_LEN]);
+        payload.extend_from_slice(&out[PACKET_LEN + 4 .. 2 * PACKET_LEN]);
+        payload.extend_from_slice(&out[2 * PACKET_LEN + 5 ..]);
+        assert_eq!(payload, pes);
+    }
+
+    #[test]
+    fn pts() {
+        let mut out = Vec::new();
+        append_pts(&mut out, 90_000 + PTS_DELAY_90K);
+        assert_eq!(&out[..], b"\x21\x00\x09\xab\x51");
+    }
+}
@@ -0,0 +1,231 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! WebVTT sidecar files for recorded ranges.
+//!
+//! Browsers ignore the `.mp4`'s subtitle tracks, so the web UI instead attaches a `view.vtt`,
+//! requested with the same `s` parameters as the `view.mp4`, to its `<video>` element. Cues follow
+//! the `.mp4`'s presentation timeline: each segment's desired range is presented immediately after
+//! the previous segment's, and any portion of a recording before its desired start (which the
+//! `.mp4` includes only to begin at a key frame, then skips via its edit list) isn't presented.
+//! As with the `.mp4`'s timestamp subtitle track, there's one timestamp cue per wall-clock second.
+//! Signal cues, if requested, are placed at the top of the video.
+
+use base::{Error, ErrorKind, ResultExt, format_err_t};
+use crate::mp4;
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use std::cmp;
+use std::fmt::Write;
+use std::ops::Range;
+
+/// A portion of a recording, as appended to a `FileBuilder`.
+struct Segment {
+    stream_id: i32,
+
+    /// The wall-clock time range to present.
+    range: Range<recording::Time>,
+}
+
+struct Cue {
+    /// The range of the `.mp4`'s presentation timeline covered by the cue, in 90 kHz units.
+    range: Range<i64>,
+    settings: &'static str,
+    text: String,
+}
+
+pub struct FileBuilder {
+    segments: Vec<Segment>,
+    include_signals: bool,
+}
+
+impl FileBuilder {
+    pub fn new() -> Self {
+        FileBuilder {
+            segments: Vec::new(),
+            include_signals: false,
+        }
+    }
+
+    /// Sets if the file should include cues for the states of the camera's signals.
+    pub fn include_signals(&mut self, b: bool) {
+        self.include_signals = b;
+    }
+
+    /// Reserves space for the given number of additional segments.
+    pub fn reserve(&mut self, additional: usize) {
+        self.segments.reserve(additional);
+    }
+
+    /// Appends a segment for (a subset of) the given recording.
+    pub fn append(&mut self, _db: &db::LockedDatabase, row: db::ListRecordingsRow,
+                  rel_range_90k: Range<i32>) -> Result<(), Error> {
+        self.segments.push(Segment {
+            stream_id: row.id.stream(),
+            range: row.start + recording::Duration(rel_range_90k.start as i64) ..
+                   row.start + recording::Duration(rel_range_90k.end as i64),
+        });
+        Ok(())
+    }
+
+    /// Builds the file's text.
+    pub fn build(self, db: &db::LockedDatabase) -> Result<String, Error> {
+        let mut cues = Vec::new();
+        let mut signal_cues: Vec<Cue> = Vec::new();
+        let mut pos = 0;
+        for s in &self.segments {
+            let mut t = s.range.start;
+            while t < s.range.end {
+                let next_sec = recording::Time(t.0 - t.0 % TIME_UNITS_PER_SEC + TIME_UNITS_PER_SEC);
+                let end = cmp::min(next_sec, s.range.end);
+                let tm = time::at(time::Timespec{sec: t.unix_seconds(), nsec: 0});
+                cues.push(Cue {
+                    range: pos + (t - s.range.start).0 .. pos + (end - s.range.start).0,
+                    settings: "",
+                    text: tm.strftime(mp4::SUBTITLE_TEMPLATE).err_kind(ErrorKind::Internal)?
+                            .to_string(),
+                });
+                t = end;
+            }
+            if self.include_signals {
+                let camera_id = db.streams_by_id().get(&s.stream_id)
+                                  .ok_or_else(|| format_err_t!(NotFound, "stream {} not found",
+                                                               s.stream_id))?
+                                  .camera_id;
+                let mut start = pos;
+                mp4::signal_intervals(db, camera_id, s.range.clone(), &mut |duration, text| {
+                    let end = start + duration.0;
+                    let merged = match signal_cues.last_mut() {
+                        Some(c) if c.range.end == start && c.text == text => {
+                            c.range.end = end;
+                            true
+                        },
+                        _ => false,
+                    };
+                    if !merged && !text.is_empty() {
+                        signal_cues.push(Cue {
+                            range: start .. end,
+                            settings: " line:0",
+                            text: text.to_owned(),
+                        });
+                    }
+                    start = end;
+                });
+            }
+            pos += (s.range.end - s.range.start).0;
+        }
+
+        // Cues must be in order of start time.
+        cues.extend(signal_cues);
+        cues.sort_by_key(|c| c.range.start);
+        let mut out = String::from("WEBVTT\n");
+        for c in &cues {
+            write!(&mut out, "\n{} --> {}{}\n", Timestamp(c.range.start), Timestamp(c.range.end),
+                   c.settings).expect("String write shouldn't fail");
+            for ch in c.text.chars() {
+                match ch {
+                    '&' => out.push_str("&amp;"),
+                    '<' => out.push_str("&lt;"),
+                    '>' => out.push_str("&gt;"),
+                    c => out.push(c),
+                }
+            }
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+/// A WebVTT cue timestamp, from a position in 90 kHz units, rounded to the nearest millisecond.
+struct Timestamp(i64);
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ms = (self.0 + 45) / 90;
+        write!(f, "{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, (ms / 60_000) % 60,
+               (ms / 1_000) % 60, ms % 1_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::clock::RealClocks;
+    use db::testutil::{self, TestDb, TEST_STREAM_ID};
+    use super::*;
+
+    #[test]
+    fn timestamp() {
+        assert_eq!(Timestamp(0).to_string(), "00:00:00.000");
+        assert_eq!(Timestamp(44).to_string(), "00:00:00.000");
+        assert_eq!(Timestamp(45).to_string(), "00:00:00.001");
+        assert_eq!(Timestamp((3_723 * TIME_UNITS_PER_SEC) + 4 * 90).to_string(),
+                   "01:02:03.004");
+    }
+
+    /// Tests that cues follow the `.mp4`'s timeline across a trimmed start and a wall-clock gap.
+    #[test]
+    fn timestamps() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let row = |id, start, duration_90k| db::ListRecordingsRow {
+            start,
+            video_sample_entry_id: 1,
+            id: db::CompositeId::new(TEST_STREAM_ID, id),
+            duration_90k,
+            video_samples: 1,
+            video_sync_samples: 1,
+            sample_file_bytes: 1,
+            run_offset: 0,
+            open_id: 1,
+            flags: 0,
+        };
+        let mut builder = FileBuilder::new();
+        let l = db.db.lock();
+        builder.append(&l, row(1, start, 270_000), 45_000 .. 270_000).unwrap();
+        builder.append(&l, row(2, start + recording::Duration(900_000), 180_000), 0 .. 135_000)
+               .unwrap();
+        assert_eq!(builder.build(&l).unwrap(), "WEBVTT\n\
+                                                \n\
+                                                00:00:00.000 --> 00:00:00.500\n\
+                                                2015-04-25 17:00:00 -0700\n\
+                                                \n\
+                                                00:00:00.500 --> 00:00:01.500\n\
+                                                2015-04-25 17:00:01 -0700\n\
+                                                \n\
+                                                00:00:01.500 --> 00:00:02.500\n\
+                                                2015-04-25 17:00:02 -0700\n\
+                                                \n\
+                                                00:00:02.500 --> 00:00:03.500\n\
+                                                2015-04-25 17:00:10 -0700\n\
+                                                \n\
+                                                00:00:03.500 --> 00:00:04.000\n\
+                                                2015-04-25 17:00:11 -0700\n");
+    }
+}
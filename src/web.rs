@@ -31,17 +31,22 @@
 use base::clock::Clocks;
 use base::{ErrorKind, ResultExt, bail_t, strutil};
 use bytes::Bytes;
-use crate::body::{Body, BoxedError};
+use crate::body::{Body, BoxedError, Chunk};
+use crate::export;
 use crate::json;
+use crate::mkv;
 use crate::mp4;
+use crate::oidc;
+use crate::ts;
+use crate::vtt;
 use base64;
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use core::borrow::Borrow;
 use core::str::FromStr;
 use db::{auth, recording};
 use db::dir::SampleFileDir;
 use failure::{Error, bail, format_err};
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::future::{self, Future, TryFutureExt};
 use futures::stream::{Stream, StreamExt, TryStreamExt};
 use http::{Request, Response, status::StatusCode};
@@ -49,6 +54,7 @@ use http_serve;
 use http::header::{self, HeaderValue};
 use lazy_static::lazy_static;
 use log::{debug, info, warn};
+use openssl::hash;
 use regex::Regex;
 use serde_json;
 use std::collections::HashMap;
@@ -70,6 +76,12 @@ lazy_static! {
         Regex::new(r"^(\d+)(-\d+)?(@\d+)?(?:\.(\d+)?-(\d+)?)?$").unwrap();
 }
 
+/// The maximum number of recordings to list in a `live.m3u8` playlist.
+const LIVE_HLS_RECORDINGS: usize = 3;
+
+/// The most entries returned by one `/api/audit` request.
+const MAX_AUDIT_ENTRIES: i64 = 1000;
+
 type BoxedFuture = Box<dyn Future<Output = Result<Response<Body>, BoxedError>> +
                        Sync + Send + 'static>;
 
@@ -78,14 +90,28 @@ enum Path {
     TopLevel,                                         // "/api/"
     Request,                                          // "/api/request"
     InitSegment([u8; 20], bool),                      // "/api/init/<sha1>.mp4{.txt}"
+    ViewMp4(bool),                                    // "/api/view.mp4{.txt}"
     Camera(Uuid),                                     // "/api/cameras/<uuid>/"
+    CameraViewMpd(Uuid),                              // "/api/cameras/<uuid>/view.mpd"
     Signals,                                          // "/api/signals"
+    Sessions,                                         // "/api/sessions"
+    Tokens,                                           // "/api/tokens"
+    Audit,                                            // "/api/audit"
     StreamRecordings(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/recordings"
     StreamViewMp4(Uuid, db::StreamType, bool),        // "/api/cameras/<uuid>/<type>/view.mp4{.txt}"
     StreamViewMp4Segment(Uuid, db::StreamType, bool), // "/api/cameras/<uuid>/<type>/view.m4s{.txt}"
+    StreamViewMkv(Uuid, db::StreamType),              // "/api/cameras/<uuid>/<type>/view.mkv"
+    StreamViewTs(Uuid, db::StreamType),               // "/api/cameras/<uuid>/<type>/view.ts"
+    StreamViewVtt(Uuid, db::StreamType),              // "/api/cameras/<uuid>/<type>/view.vtt"
+    StreamExport(Uuid, db::StreamType),               // "/api/cameras/<uuid>/<type>/export.tar"
     StreamLiveMp4Segments(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/live.m4s"
+    StreamHlsLive(Uuid, db::StreamType),              // "/api/cameras/<uuid>/<type>/live.m3u8"
+    StreamHlsRecorded(Uuid, db::StreamType),          // "/api/cameras/<uuid>/<type>/recorded.m3u8"
+    StreamMigrate(Uuid, db::StreamType),              // "/api/cameras/<uuid>/<type>/migrate"
     Login,                                            // "/api/login"
     Logout,                                           // "/api/logout"
+    OidcLogin,                                        // "/api/oidc/login"
+    OidcCallback,                                     // "/api/oidc/callback"
     Static,                                           // (anything that doesn't start with "/api/")
     NotFound,
 }
@@ -102,8 +128,15 @@ impl Path {
         match path {
             "/login" => return Path::Login,
             "/logout" => return Path::Logout,
+            "/oidc/login" => return Path::OidcLogin,
+            "/oidc/callback" => return Path::OidcCallback,
             "/request" => return Path::Request,
             "/signals" => return Path::Signals,
+            "/sessions" => return Path::Sessions,
+            "/tokens" => return Path::Tokens,
+            "/audit" => return Path::Audit,
+            "/view.mp4" => return Path::ViewMp4(false),
+            "/view.mp4.txt" => return Path::ViewMp4(true),
             _ => {},
         };
         if path.starts_with("/init/") {
@@ -140,6 +173,9 @@ impl Path {
         if path.is_empty() {
             return Path::Camera(uuid);
         }
+        if path == "view.mpd" {
+            return Path::CameraViewMpd(uuid);
+        }
 
         let slash = match path.find('/') {
             None => { return Path::NotFound; },
@@ -157,12 +193,33 @@ impl Path {
             "/view.mp4.txt" => Path::StreamViewMp4(uuid, type_, true),
             "/view.m4s" => Path::StreamViewMp4Segment(uuid, type_, false),
             "/view.m4s.txt" => Path::StreamViewMp4Segment(uuid, type_, true),
+            "/view.mkv" => Path::StreamViewMkv(uuid, type_),
+            "/export.tar" => Path::StreamExport(uuid, type_),
+            "/view.ts" => Path::StreamViewTs(uuid, type_),
+            "/view.vtt" => Path::StreamViewVtt(uuid, type_),
             "/live.m4s" => Path::StreamLiveMp4Segments(uuid, type_),
+            "/live.m3u8" => Path::StreamHlsLive(uuid, type_),
+            "/recorded.m3u8" => Path::StreamHlsRecorded(uuid, type_),
+            "/migrate" => Path::StreamMigrate(uuid, type_),
             _ => Path::NotFound,
         }
     }
 }
 
+/// The peer which sent a request, as stored in the request's extensions by `Service::serve`.
+#[derive(Copy, Clone, Debug)]
+pub enum Peer {
+    /// A TCP peer, by address.
+    Addr(IpAddr),
+
+    /// A Unix domain socket peer, by uid (as returned by `SO_PEERCRED`).
+    Uid(u32),
+}
+
+/// A marker in the request's extensions that it arrived over TLS, as stored by `Service::serve`.
+#[derive(Copy, Clone)]
+struct Secure;
+
 fn plain_response<B: Into<Body>>(status: http::StatusCode, body: B) -> Response<Body> {
     Response::builder()
         .status(status)
@@ -182,6 +239,14 @@ fn internal_server_err<E: Into<Error>>(err: E) -> Response<Body> {
     plain_response(StatusCode::INTERNAL_SERVER_ERROR, err.into().to_string())
 }
 
+/// Parses the speed factor of a `timelapse` parameter, a positive integer.
+fn parse_timelapse(value: &str) -> Result<u32, Response<Body>> {
+    match u32::from_str(value) {
+        Ok(speed) if speed > 0 => Ok(speed),
+        _ => Err(bad_req("timelapse must be a positive integer")),
+    }
+}
+
 fn from_base_error(err: base::Error) -> Response<Body> {
     let status_code = match err.kind() {
         ErrorKind::PermissionDenied | ErrorKind::Unauthenticated => StatusCode::UNAUTHORIZED,
@@ -238,6 +303,304 @@ impl Segments {
     }
 }
 
+/// A builder of virtual files from segments, as requested through `s` parameters.
+trait SegmentsBuilder {
+    fn reserve(&mut self, additional: usize);
+    fn append(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
+              rel_range_90k: Range<i32>) -> Result<(), base::Error>;
+}
+
+macro_rules! impl_segments_builder {
+    ($t:ty) => {
+        impl SegmentsBuilder for $t {
+            fn reserve(&mut self, additional: usize) { <$t>::reserve(self, additional) }
+            fn append(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
+                      rel_range_90k: Range<i32>) -> Result<(), base::Error> {
+                <$t>::append(self, db, row, rel_range_90k)
+            }
+        }
+    }
+}
+
+impl_segments_builder!(mp4::FileBuilder);
+impl_segments_builder!(mkv::FileBuilder);
+impl_segments_builder!(ts::FileBuilder);
+impl_segments_builder!(vtt::FileBuilder);
+
+/// Builds the `.mp4` within an `export.tar`, noting each recording for the manifest.
+struct ExportBuilder {
+    mp4: mp4::FileBuilder,
+    recordings: Vec<export::ManifestRecording>,
+}
+
+impl SegmentsBuilder for ExportBuilder {
+    fn reserve(&mut self, additional: usize) {
+        self.mp4.reserve(additional);
+        self.recordings.reserve(additional);
+    }
+
+    fn append(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
+              rel_range_90k: Range<i32>) -> Result<(), base::Error> {
+        let sha1 = db.get_sample_file_sha1(row.id).err_kind(ErrorKind::Internal)?;
+        self.recordings.push(export::ManifestRecording {
+            id: row.id.recording(),
+            open_id: row.open_id,
+            start_time_90k: (row.start + recording::Duration(rel_range_90k.start as i64)).0,
+            end_time_90k: (row.start + recording::Duration(rel_range_90k.end as i64)).0,
+            sample_file_sha1: sha1.map(|s| strutil::hex(&s[..])),
+        });
+        self.mp4.append(db, row, rel_range_90k)
+    }
+}
+
+/// A recording to be listed as a single media segment within an HLS playlist or DASH manifest.
+#[derive(Debug)]
+struct PlaylistRecording {
+    id: i32,
+    open_id: u32,
+    start: recording::Time,
+    duration_90k: i32,
+    run_offset: i32,
+    sample_file_bytes: i32,
+    video_sample_entry: Arc<db::VideoSampleEntry>,
+}
+
+/// Parses the `start` and `end` query parameters of playlist and manifest requests.
+fn playlist_time_range(req: &Request<::hyper::Body>)
+                       -> Result<Range<recording::Time>, Response<Body>> {
+    let mut time = recording::Time::min_value() .. recording::Time::max_value();
+    if let Some(q) = req.uri().query() {
+        for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+            let (key, value) = (key.borrow(), value.borrow());
+            match key {
+                "start" => {
+                    time.start = recording::Time::parse(value)
+                        .map_err(|_| bad_req("unparseable start"))?
+                },
+                "end" => {
+                    time.end = recording::Time::parse(value)
+                        .map_err(|_| bad_req("unparseable end"))?
+                },
+                _ => return Err(bad_req(format!("parameter {} not understood", key))),
+            }
+        }
+    }
+    Ok(time)
+}
+
+/// Lists the complete recordings of `stream_id` which overlap `time`, in order of id.
+/// The growing recording is omitted because its contents will change.
+fn list_playlist_recordings(db: &db::LockedDatabase, stream_id: i32,
+                            time: Range<recording::Time>)
+                            -> Result<Vec<PlaylistRecording>, Error> {
+    let mut out = Vec::new();
+    db.list_recordings_by_time(stream_id, time, &mut |r| {
+        if (r.flags & db::RecordingFlags::Growing as i32) != 0 {
+            return Ok(());
+        }
+        out.push(PlaylistRecording {
+            id: r.id.recording(),
+            open_id: r.open_id,
+            start: r.start,
+            duration_90k: r.duration_90k,
+            run_offset: r.run_offset,
+            sample_file_bytes: r.sample_file_bytes,
+            video_sample_entry:
+                db.video_sample_entries_by_id().get(&r.video_sample_entry_id).unwrap().clone(),
+        });
+        Ok(())
+    })?;
+    out.sort_by_key(|r| r.id);
+    Ok(out)
+}
+
+/// A single media segment within an HLS playlist; currently always a full recording.
+#[derive(Debug)]
+struct HlsSegment {
+    id: i32,
+    open_id: u32,
+    start: recording::Time,
+    duration_90k: i32,
+    run_offset: i32,
+    video_sample_entry_sha1: [u8; 20],
+}
+
+/// Formats `t` as required by `EXT-X-PROGRAM-DATE-TIME`: ISO 8601 in UTC with milliseconds.
+fn hls_date_time(t: recording::Time) -> String {
+    let tm = time::at_utc(time::Timespec{sec: t.unix_seconds(), nsec: 0});
+    format!("{}.{:03}Z", tm.strftime("%Y-%m-%dT%H:%M:%S").expect("format should be valid"),
+            (t.0 % recording::TIME_UNITS_PER_SEC) / (recording::TIME_UNITS_PER_SEC / 1000))
+}
+
+/// Writes an HLS media playlist (RFC 8216) referencing `view.m4s` URLs relative to the playlist.
+/// A discontinuity is marked at the start of each run and wherever recordings are missing or the
+/// video sample entry changes. `live` playlists have no end and are expected to be reloaded.
+///
+/// A live playlist's discontinuity sequence must stay consistent as segments leave the window.
+/// Live playlists should list only one run, which has no discontinuities; it's numbered by the
+/// run's first recording id, which changes only when a new run replaces the whole window.
+fn hls_playlist(segments: &[HlsSegment], live: bool) -> String {
+    use std::fmt::Write;
+    let max_duration_90k = segments.iter().map(|s| s.duration_90k as i64).max().unwrap_or(0);
+    let target_duration = (max_duration_90k + recording::TIME_UNITS_PER_SEC - 1) /
+                          recording::TIME_UNITS_PER_SEC;
+    let mut out = String::new();
+    write!(&mut out, "#EXTM3U\n\
+                      #EXT-X-VERSION:7\n\
+                      #EXT-X-TARGETDURATION:{}\n\
+                      #EXT-X-MEDIA-SEQUENCE:{}\n",
+           target_duration, segments.first().map(|s| s.id).unwrap_or(0)).unwrap();
+    if live {
+        write!(&mut out, "#EXT-X-DISCONTINUITY-SEQUENCE:{}\n",
+               segments.first().map(|s| s.id - s.run_offset).unwrap_or(0)).unwrap();
+    } else {
+        out.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+    }
+    out.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+
+    let mut prev: Option<&HlsSegment> = None;
+    for s in segments {
+        let new_sha1 = prev.map(|p| p.video_sample_entry_sha1 != s.video_sample_entry_sha1)
+                           .unwrap_or(true);
+        if let Some(p) = prev {
+            if s.run_offset == 0 || s.id != p.id + 1 || new_sha1 {
+                out.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+        }
+        if new_sha1 {
+            write!(&mut out, "#EXT-X-MAP:URI=\"/api/init/{}.mp4\"\n",
+                   strutil::hex(&s.video_sample_entry_sha1)).unwrap();
+        }
+        write!(&mut out, "#EXT-X-PROGRAM-DATE-TIME:{}\n\
+                          #EXTINF:{}.{:03},\n\
+                          view.m4s?s={}@{}\n",
+               hls_date_time(s.start),
+               s.duration_90k as i64 / recording::TIME_UNITS_PER_SEC,
+               (s.duration_90k as i64 % recording::TIME_UNITS_PER_SEC) /
+               (recording::TIME_UNITS_PER_SEC / 1000),
+               s.id, s.open_id).unwrap();
+        prev = Some(s);
+    }
+    if !live {
+        out.push_str("#EXT-X-ENDLIST\n");
+    }
+    out
+}
+
+/// Formats `d` as an `xs:duration` for use in a DASH manifest.
+fn dash_duration(d: recording::Duration) -> String {
+    format!("PT{}.{:03}S", d.0 / recording::TIME_UNITS_PER_SEC,
+            (d.0 % recording::TIME_UNITS_PER_SEC) / (recording::TIME_UNITS_PER_SEC / 1000))
+}
+
+/// Writes a static MPEG-DASH manifest (ISO/IEC 23009-1) with one representation per stream.
+///
+/// Each recording is a media segment, addressed by a `SegmentTemplate` whose `$Number$` is the
+/// recording id. Within a period, every representation must have a single initialization segment
+/// and consecutive recording ids, so a new period starts wherever any stream's video sample entry
+/// changes or its recordings are missing. The media segments' `tfdt` is the wall-clock time, so
+/// each period's `presentationTimeOffset` is simply its start time.
+fn dash_mpd(streams: &[(db::StreamType, Vec<PlaylistRecording>)]) -> String {
+    use std::fmt::Write;
+    let all = || streams.iter().flat_map(|&(_, ref recs)| recs.iter());
+    let start = all().map(|r| r.start).min().unwrap_or(recording::Time(0));
+    let end = all().map(|r| r.start + recording::Duration(r.duration_90k as i64)).max()
+                   .unwrap_or(start);
+    let mut boundaries = vec![start];
+    for &(_, ref recs) in streams {
+        for w in recs.windows(2) {
+            if w[1].id != w[0].id + 1 || w[1].video_sample_entry.id != w[0].video_sample_entry.id {
+                boundaries.push(w[1].start);
+            }
+        }
+    }
+    boundaries.sort();
+    boundaries.dedup();
+
+    let mut out = String::new();
+    write!(&mut out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                      <MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" \
+                      profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"static\" \
+                      mediaPresentationDuration=\"{}\" minBufferTime=\"PT2S\">\n",
+           dash_duration(end - start)).unwrap();
+    for (i, &p_start) in boundaries.iter().enumerate() {
+        let p_end = boundaries.get(i + 1).cloned();
+        let in_period = |r: &&PlaylistRecording| {
+            r.start >= p_start && p_end.map(|e| r.start < e).unwrap_or(true)
+        };
+        write!(&mut out, "  <Period id=\"{}\" start=\"{}\">\n    \
+                          <AdaptationSet contentType=\"video\" mimeType=\"video/mp4\" \
+                          segmentAlignment=\"false\">\n",
+               i, dash_duration(p_start - start)).unwrap();
+        for &(type_, ref recs) in streams {
+            let recs: Vec<&PlaylistRecording> = recs.iter().filter(in_period).collect();
+            let first = match recs.first() {
+                None => continue,
+                Some(r) => r,
+            };
+            let vse = &first.video_sample_entry;
+            let bytes: i64 = recs.iter().map(|r| r.sample_file_bytes as i64).sum();
+            let duration_90k: i64 = recs.iter().map(|r| r.duration_90k as i64).sum();
+            let bandwidth = if duration_90k == 0 {
+                0
+            } else {
+                bytes * 8 * recording::TIME_UNITS_PER_SEC / duration_90k
+            };
+            write!(&mut out, "      <Representation id=\"{}\" codecs=\"{}\" width=\"{}\" \
+                              height=\"{}\" bandwidth=\"{}\">\n        \
+                              <SegmentTemplate timescale=\"{}\" presentationTimeOffset=\"{}\" \
+                              startNumber=\"{}\" initialization=\"/api/init/{}.mp4\" \
+                              media=\"{}/view.m4s?s=$Number$\">\n          \
+                              <SegmentTimeline>\n",
+                   type_, vse.rfc6381_codec, vse.width, vse.height, bandwidth,
+                   recording::TIME_UNITS_PER_SEC, p_start.0, first.id, strutil::hex(&vse.sha1),
+                   type_).unwrap();
+            for r in &recs {
+                write!(&mut out, "            <S t=\"{}\" d=\"{}\"/>\n",
+                       r.start.0, r.duration_90k).unwrap();
+            }
+            out.push_str("          </SegmentTimeline>\n        \
+                          </SegmentTemplate>\n      \
+                          </Representation>\n");
+        }
+        out.push_str("    </AdaptationSet>\n  </Period>\n");
+    }
+    out.push_str("</MPD>\n");
+    out
+}
+
+/// Returns true iff `req` asks to upgrade the connection to a WebSocket.
+fn is_websocket_upgrade(req: &Request<::hyper::Body>) -> bool {
+    req.headers().get(header::UPGRADE)
+       .and_then(|v| v.to_str().ok())
+       .map(|v| v.eq_ignore_ascii_case("websocket"))
+       .unwrap_or(false)
+}
+
+/// Validates a WebSocket opening handshake (RFC 6455 section 4.2.1), returning the
+/// `Sec-WebSocket-Accept` value for the response.
+fn websocket_accept_key(req: &Request<::hyper::Body>) -> Result<String, Response<Body>> {
+    let hdrs = req.headers();
+    if hdrs.get(header::SEC_WEBSOCKET_VERSION).map(|v| v.as_bytes()) != Some(&b"13"[..]) {
+        return Err(bad_req("Sec-WebSocket-Version 13 required"));
+    }
+
+    // Browsers send the session cookie with WebSocket requests from any origin and don't apply
+    // CORS restrictions to the response, so reject requests from other origins.
+    if let Some(origin) = hdrs.get(header::ORIGIN) {
+        let origin_host = origin.to_str().ok().and_then(|o| o.splitn(2, "://").nth(1));
+        let host = hdrs.get(header::HOST).and_then(|h| h.to_str().ok());
+        if origin_host.is_none() || origin_host != host {
+            return Err(plain_response(StatusCode::FORBIDDEN, "cross-origin WebSocket request"));
+        }
+    }
+    let key = hdrs.get(header::SEC_WEBSOCKET_KEY)
+                  .ok_or_else(|| bad_req("Sec-WebSocket-Key required"))?;
+    let mut input = key.as_bytes().to_vec();
+    input.extend_from_slice(b"258EAFA5-E914-47DA-95CA-C5AB0DC11D63");
+    Ok(base64::encode(&openssl::sha::sha1(&input)))
+}
+
 /// A user interface file (.html, .js, etc).
 /// The list of files is loaded into the server at startup; this makes path canonicalization easy.
 /// The files themselves are opened on every request so they can be changed during development.
@@ -250,18 +613,52 @@ struct UiFile {
 struct Caller {
     permissions: db::Permissions,
     session: Option<json::Session>,
+
+    /// The user id and session hash, if authenticated via a session cookie.
+    user_session: Option<(i32, auth::SessionHash)>,
+
+    /// The user and session or API token behind the request, however authenticated, as recorded
+    /// in the audit log.
+    user_id: Option<i32>,
+    session_hash: Option<auth::SessionHash>,
 }
 
 impl Caller {
+    /// Returns the camera with the given uuid, treating one outside the caller's permissions as
+    /// nonexistent.
+    fn camera<'db>(&self, db: &'db db::LockedDatabase, uuid: Uuid)
+                   -> Result<&'db db::Camera, Response<Body>> {
+        db.get_camera(uuid)
+          .filter(|_| self.permissions.allows_camera(uuid))
+          .ok_or_else(|| not_found(format!("no such camera {}", uuid)))
+    }
+
+    /// Returns true iff the caller's permissions extend to the signal with the given id.
+    fn allows_signal(&self, db: &db::LockedDatabase, id: u32) -> bool {
+        self.permissions.camera_uuids.is_empty() ||
+        db.signals_by_id().get(&id)
+          .map(|s| self.permissions.allows_signal(s, db.cameras_by_id()))
+          .unwrap_or(false)
+    }
 }
 
 struct ServiceInner {
     db: Arc<db::Database>,
-    dirs_by_stream_id: Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
     ui_files: HashMap<String, UiFile>,
     time_zone_name: String,
     allow_unauthenticated_permissions: Option<db::Permissions>,
     trust_forward_hdrs: bool,
+    export_key: Option<Arc<export::SigningKey>>,
+    https_redirect_port: Option<u16>,
+    oidc: Option<Arc<oidc::Client>>,
+    trusted_user_hdr: Option<header::HeaderName>,
+    trusted_proxies: Vec<IpAddr>,
+
+    /// The streams with a `POST .../migrate` in progress.
+    migrating_streams: parking_lot::Mutex<FnvHashSet<i32>>,
+
+    /// The effective uid of this process, which may act as anyone via a Unix domain socket.
+    own_uid: u32,
 }
 
 type ResponseResult = Result<Response<Body>, Response<Body>>;
@@ -301,22 +698,21 @@ impl ServiceInner {
         let db = self.db.lock();
         serve_json(req, &json::TopLevel {
             time_zone_name: &self.time_zone_name,
-            cameras: (&db, days, camera_configs),
+            cameras: (&db, &caller.permissions, days, camera_configs),
             session: caller.session,
-            signals: (&db, days),
+            signals: (&db, &caller.permissions, days),
             signal_types: &db,
         })
     }
 
-    fn camera(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> ResponseResult {
+    fn camera(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid) -> ResponseResult {
         let db = self.db.lock();
-        let camera = db.get_camera(uuid)
-                       .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+        let camera = caller.camera(&db, uuid)?;
         serve_json(req, &json::Camera::wrap(camera, &db, true, false).map_err(internal_server_err)?)
     }
 
-    fn stream_recordings(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
-                         -> ResponseResult {
+    fn stream_recordings(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
+                         type_: db::StreamType) -> ResponseResult {
         let (r, split) = {
             let mut time = recording::Time::min_value() .. recording::Time::max_value();
             let mut split = recording::Duration(i64::max_value());
@@ -345,9 +741,7 @@ impl ServiceInner {
         let mut out = json::ListRecordings{recordings: Vec::new()};
         {
             let db = self.db.lock();
-            let camera = db.get_camera(uuid)
-                           .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
-                                                         format!("no such camera {}", uuid)))?;
+            let camera = caller.camera(&db, uuid)?;
             let stream_id = camera.streams[type_.index()]
                 .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
                                               format!("no such stream {}/{}", uuid, type_)))?;
@@ -381,7 +775,7 @@ impl ServiceInner {
         for ent in db.video_sample_entries_by_id().values() {
             if ent.sha1 == sha1 {
                 builder.append_video_sample_entry(ent.clone());
-                let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())
+                let mp4 = builder.build(self.db.clone(), Arc::new(db.dirs_by_stream_id()))
                     .map_err(from_base_error)?;
                 if debug {
                     return Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)));
@@ -393,118 +787,603 @@ impl ServiceInner {
         Err(not_found("no such init segment"))
     }
 
+    /// Looks up the id of the given stream.
+    fn stream_id(&self, caller: &Caller, uuid: Uuid, stream_type: db::StreamType)
+                 -> Result<i32, Response<Body>> {
+        let db = self.db.lock();
+        let camera = caller.camera(&db, uuid)?;
+        camera.streams[stream_type.index()]
+            .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
+                                          format!("no such stream {}/{}", uuid, stream_type)))
+    }
+
+    /// Returns each stream's sample file directory. This is looked up as needed rather than once
+    /// at startup, as `POST .../migrate` can change it.
+    fn dirs_by_stream_id(&self) -> Arc<FnvHashMap<i32, Arc<SampleFileDir>>> {
+        Arc::new(self.db.lock().dirs_by_stream_id())
+    }
+
+    /// Appends the recordings described by an `s` parameter (as in `design/api.md`) to `builder`,
+    /// returning the time range of the appended video, if any.
+    fn append_segments(&self, stream_id: i32, value: &str, builder: &mut dyn SegmentsBuilder)
+                       -> Result<Option<Range<recording::Time>>, Response<Body>> {
+        let s = Segments::parse(value).map_err(
+            |()| plain_response(StatusCode::BAD_REQUEST,
+                                format!("invalid s parameter: {}", value)))?;
+        debug!("append_segments: appending s={:?}", s);
+        let mut est_segments = (s.ids.end - s.ids.start) as usize;
+        if let Some(end) = s.end_time {
+            // There should be roughly ceil((end - start) /
+            // desired_recording_duration) recordings in the desired timespan if
+            // there are no gaps or overlap, possibly another for misalignment of
+            // the requested timespan with the rotate offset and another because
+            // rotation only happens at key frames.
+            let ceil_durations = (end - s.start_time +
+                                  recording::DESIRED_RECORDING_DURATION - 1) /
+                                 recording::DESIRED_RECORDING_DURATION;
+            est_segments = cmp::min(est_segments, (ceil_durations + 2) as usize);
+        }
+        builder.reserve(est_segments);
+        let db = self.db.lock();
+        let mut prev = None;
+        let mut cur_off = 0;
+        let mut span = None;
+        db.list_recordings_by_id(stream_id, s.ids.clone(), &mut |r| {
+            let recording_id = r.id.recording();
+
+            if let Some(o) = s.open_id {
+                if r.open_id != o {
+                    bail!("recording {} has open id {}, requested {}",
+                          r.id, r.open_id, o);
+                }
+            }
+
+            // Check for missing recordings.
+            match prev {
+                None if recording_id == s.ids.start => {},
+                None => bail!("no such recording {}/{}", stream_id, s.ids.start),
+                Some(id) if r.id.recording() != id + 1 => {
+                    bail!("no such recording {}/{}", stream_id, id + 1);
+                },
+                _ => {},
+            };
+            prev = Some(recording_id);
+
+            // Add a segment for the relevant part of the recording, if any.
+            let end_time = s.end_time.unwrap_or(i64::max_value());
+            let d = r.duration_90k as i64;
+            if s.start_time <= cur_off + d && cur_off < end_time {
+                let start = cmp::max(0, s.start_time - cur_off);
+                let end = cmp::min(d, end_time - cur_off);
+                let times = start as i32 .. end as i32;
+                debug!("...appending recording {} with times {:?} \
+                       (out of dur {})", r.id, times, d);
+                extend_span(&mut span, r.start + recording::Duration(start) ..
+                                       r.start + recording::Duration(end));
+                builder.append(&db, r, start as i32 .. end as i32)?;
+            } else {
+                debug!("...skipping recording {} dur {}", r.id, d);
+            }
+            cur_off += d;
+            Ok(())
+        }).map_err(internal_server_err)?;
+
+        // Check for missing recordings.
+        match prev {
+            Some(id) if s.ids.end != id + 1 => {
+                return Err(not_found(format!("no such recording {}/{}",
+                                             stream_id, s.ids.end - 1)));
+            },
+            None => {
+                return Err(not_found(format!("no such recording {}/{}",
+                                             stream_id, s.ids.start)));
+            },
+            _ => {},
+        };
+        if let Some(end) = s.end_time {
+            if end > cur_off {
+                return Err(plain_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("end time {} is beyond specified recordings",
+                                end)));
+            }
+        }
+        Ok(span)
+    }
+
     fn stream_view_mp4(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
                        stream_type: db::StreamType, mp4_type: mp4::Type, debug: bool)
                        -> ResponseResult {
         if !caller.permissions.view_video {
             return Err(plain_response(StatusCode::UNAUTHORIZED, "view_video required"));
         }
-        let stream_id = {
-            let db = self.db.lock();
-            let camera = db.get_camera(uuid)
-                           .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
-                                                         format!("no such camera {}", uuid)))?;
-            camera.streams[stream_type.index()]
-                .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
-                                              format!("no such stream {}/{}", uuid,
-                                                      stream_type)))?
-        };
+        let stream_id = self.stream_id(&caller, uuid, stream_type)?;
+        let action = if mp4_type == mp4::Type::MediaSegment { "view.m4s" } else { "view.mp4" };
         let mut builder = mp4::FileBuilder::new(mp4_type);
+        let mut span = None;
         if let Some(q) = req.uri().query() {
             for (key, value) in form_urlencoded::parse(q.as_bytes()) {
                 let (key, value) = (key.borrow(), value.borrow());
                 match key {
-                    "s" => {
-                        let s = Segments::parse(value).map_err(
-                            |()| plain_response(StatusCode::BAD_REQUEST,
-                                                format!("invalid s parameter: {}", value)))?;
-                        debug!("stream_view_mp4: appending s={:?}", s);
-                        let mut est_segments = (s.ids.end - s.ids.start) as usize;
-                        if let Some(end) = s.end_time {
-                            // There should be roughly ceil((end - start) /
-                            // desired_recording_duration) recordings in the desired timespan if
-                            // there are no gaps or overlap, possibly another for misalignment of
-                            // the requested timespan with the rotate offset and another because
-                            // rotation only happens at key frames.
-                            let ceil_durations = (end - s.start_time +
-                                                  recording::DESIRED_RECORDING_DURATION - 1) /
-                                                 recording::DESIRED_RECORDING_DURATION;
-                            est_segments = cmp::min(est_segments, (ceil_durations + 2) as usize);
-                        }
-                        builder.reserve(est_segments);
-                        let db = self.db.lock();
-                        let mut prev = None;
-                        let mut cur_off = 0;
-                        db.list_recordings_by_id(stream_id, s.ids.clone(), &mut |r| {
-                            let recording_id = r.id.recording();
-
-                            if let Some(o) = s.open_id {
-                                if r.open_id != o {
-                                    bail!("recording {} has open id {}, requested {}",
-                                          r.id, r.open_id, o);
-                                }
-                            }
-
-                            // Check for missing recordings.
-                            match prev {
-                                None if recording_id == s.ids.start => {},
-                                None => bail!("no such recording {}/{}", stream_id, s.ids.start),
-                                Some(id) if r.id.recording() != id + 1 => {
-                                    bail!("no such recording {}/{}", stream_id, id + 1);
-                                },
-                                _ => {},
-                            };
-                            prev = Some(recording_id);
-
-                            // Add a segment for the relevant part of the recording, if any.
-                            let end_time = s.end_time.unwrap_or(i64::max_value());
-                            let d = r.duration_90k as i64;
-                            if s.start_time <= cur_off + d && cur_off < end_time {
-                                let start = cmp::max(0, s.start_time - cur_off);
-                                let end = cmp::min(d, end_time - cur_off);
-                                let times = start as i32 .. end as i32;
-                                debug!("...appending recording {} with times {:?} \
-                                       (out of dur {})", r.id, times, d);
-                                builder.append(&db, r, start as i32 .. end as i32)?;
-                            } else {
-                                debug!("...skipping recording {} dur {}", r.id, d);
-                            }
-                            cur_off += d;
-                            Ok(())
-                        }).map_err(internal_server_err)?;
-
-                        // Check for missing recordings.
-                        match prev {
-                            Some(id) if s.ids.end != id + 1 => {
-                                return Err(not_found(format!("no such recording {}/{}",
-                                                             stream_id, s.ids.end - 1)));
-                            },
-                            None => {
-                                return Err(not_found(format!("no such recording {}/{}",
-                                                             stream_id, s.ids.start)));
-                            },
-                            _ => {},
-                        };
-                        if let Some(end) = s.end_time {
-                            if end > cur_off {
-                                return Err(plain_response(
-                                        StatusCode::BAD_REQUEST,
-                                        format!("end time {} is beyond specified recordings",
-                                                end)));
-                            }
-                        }
+                    "s" => if let Some(t) = self.append_segments(stream_id, value, &mut builder)? {
+                        extend_span(&mut span, t);
                     },
                     "ts" => builder.include_timestamp_subtitle_track(value == "true"),
+                    "signals" => builder.include_signal_subtitle_track(value == "true"),
+                    "timelapse" => builder.key_frames_only(Some(parse_timelapse(value)?)),
                     _ => return Err(bad_req(format!("parameter {} not understood", key))),
                 }
             };
         }
-        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())
+        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id())
+                         .map_err(from_base_error)?;
+        if debug {
+            return Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)));
+        }
+        let entry = self.video_audit_entry(req, &caller, action, uuid, stream_type, span);
+        Ok(self.audit_video(http_serve::serve(mp4, req), vec![entry], false))
+    }
+
+    /// Appends the segments requested for a `view.mkv`, `view.ts`, or `export.tar`, which take
+    /// only `s` parameters, returning the time range of the appended video.
+    fn append_export_segments(&self, req: &Request<::hyper::Body>, caller: &Caller, uuid: Uuid,
+                              stream_type: db::StreamType, builder: &mut dyn SegmentsBuilder)
+                              -> Result<Option<Range<recording::Time>>, Response<Body>> {
+        if !caller.permissions.view_video {
+            return Err(plain_response(StatusCode::UNAUTHORIZED, "view_video required"));
+        }
+        let stream_id = self.stream_id(caller, uuid, stream_type)?;
+        let mut span = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "s" => if let Some(t) = self.append_segments(stream_id, value, builder)? {
+                        extend_span(&mut span, t);
+                    },
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            };
+        }
+        Ok(span)
+    }
+
+    fn stream_view_mkv(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
+                       stream_type: db::StreamType) -> ResponseResult {
+        let mut builder = mkv::FileBuilder::new();
+        let span = self.append_export_segments(req, &caller, uuid, stream_type, &mut builder)?;
+        let mkv = builder.build(self.db.clone(), self.dirs_by_stream_id())
+                         .map_err(from_base_error)?;
+        let entry = self.video_audit_entry(req, &caller, "view.mkv", uuid, stream_type, span);
+        Ok(self.audit_video(http_serve::serve(mkv, req), vec![entry], false))
+    }
+
+    fn stream_view_ts(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
+                      stream_type: db::StreamType) -> ResponseResult {
+        let mut builder = ts::FileBuilder::new();
+        let span = self.append_export_segments(req, &caller, uuid, stream_type, &mut builder)?;
+        let ts = builder.build(self.db.clone(), self.dirs_by_stream_id())
+                        .map_err(from_base_error)?;
+        let entry = self.video_audit_entry(req, &caller, "view.ts", uuid, stream_type, span);
+        Ok(self.audit_video(http_serve::serve(ts, req), vec![entry], false))
+    }
+
+    /// Serves a `view.vtt`: WebVTT cues aligned with the `view.mp4` of the same segments.
+    fn stream_view_vtt(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
+                       stream_type: db::StreamType) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(StatusCode::UNAUTHORIZED, "view_video required"));
+        }
+        let stream_id = self.stream_id(&caller, uuid, stream_type)?;
+        let mut builder = vtt::FileBuilder::new();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "s" => { self.append_segments(stream_id, value, &mut builder)?; },
+                    "signals" => builder.include_signals(value == "true"),
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            };
+        }
+        let vtt = builder.build(&self.db.lock()).map_err(from_base_error)?;
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, HeaderValue::from_static("text/vtt; charset=utf-8"))
+            .body(vtt.into())
+            .expect("hardcoded head should be valid"))
+    }
+
+    /// Serves an `export.tar`, a signed evidence bundle as described in `export`. The archive is
+    /// streamed: the `.mp4` is hashed as it's sent, and the manifest and signature follow it.
+    fn stream_export(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
+                     stream_type: db::StreamType) -> ResponseResult {
+        use http_serve::Entity;
+        let key = match self.export_key {
+            Some(ref k) => k.clone(),
+            None => return Err(not_found("exports require the server's --export-key option")),
+        };
+        // The caller may be authenticated by API token, unix peer, or trusted header rather than
+        // by session, so look the name up from the user id.
+        let exported_by = caller.user_id.and_then(
+            |id| self.db.lock().users_by_id().get(&id).map(|u| u.username.clone()));
+        let mut builder = ExportBuilder {
+            mp4: mp4::FileBuilder::new(mp4::Type::Normal),
+            recordings: Vec::new(),
+        };
+        let span = self.append_export_segments(req, &caller, uuid, stream_type, &mut builder)?;
+        if builder.recordings.is_empty() {
+            return Err(bad_req("at least one s parameter is required"));
+        }
+        let camera_short_name = self.db.lock().get_camera(uuid).map(|c| c.short_name.clone())
+                                    .unwrap_or_default();
+        let now = self.db.clocks().realtime();
+        let mut manifest = export::Manifest {
+            camera_uuid: uuid,
+            camera_short_name,
+            stream_type: stream_type.as_str().to_owned(),
+            start_time_90k: builder.recordings.iter().map(|r| r.start_time_90k).min().unwrap(),
+            end_time_90k: builder.recordings.iter().map(|r| r.end_time_90k).max().unwrap(),
+            recordings: builder.recordings,
+            exported_by,
+            export_time_90k: recording::Time::new(now).0,
+            mp4_len: 0,
+            mp4_sha256: "0".repeat(64),  // placeholder of the correct length; see below.
+        };
+        let mp4 = builder.mp4.build(self.db.clone(), self.dirs_by_stream_id())
+                             .map_err(from_base_error)?;
+        let mp4_len = mp4.len();
+        manifest.mp4_len = mp4_len;
+
+        // The archive's length is known in advance, as the real manifest will have the same
+        // length as this one with a placeholder hash.
+        let mtime = now.sec as u64;
+        let public_key = key.public_key_pem().map_err(internal_server_err)?;
+        let tail_len = |manifest_len: usize| -> u64 {
+            let entry_len = |l: usize| (512 + l + export::tar_padding(l as u64)) as u64;
+            export::tar_padding(mp4_len) as u64 + entry_len(manifest_len) + entry_len(64) +
+                entry_len(public_key.len()) + export::tar_trailer().len() as u64
+        };
+        let placeholder = serde_json::to_vec_pretty(&manifest).map_err(internal_server_err)?;
+        let len = 512 + mp4_len + tail_len(placeholder.len());
+
+        let hasher = Arc::new(parking_lot::Mutex::new(
+            hash::Hasher::new(hash::MessageDigest::sha256()).map_err(internal_server_err)?));
+        let head = export::tar_header(export::MP4_NAME, mp4_len, mtime).to_vec();
+        let body = Pin::from(mp4.get_range(0 .. mp4_len)).and_then({
+            let hasher = hasher.clone();
+            move |c| future::ready(hasher.lock().update(c.bytes()).map(|()| c)
+                                         .map_err(|e| Box::new(e) as BoxedError))
+        });
+        let tail = future::lazy(move |_| {
+            let r: Result<Vec<u8>, Error> = (|| {
+                manifest.mp4_sha256 = strutil::hex(&hasher.lock().finish()?);
+                let m = serde_json::to_vec_pretty(&manifest)?;
+                let signature = key.sign(&m)?;
+                let mut v = vec![0; export::tar_padding(mp4_len)];
+                v.extend_from_slice(&export::tar_entry(export::MANIFEST_NAME, &m, mtime));
+                v.extend_from_slice(&export::tar_entry(export::SIGNATURE_NAME, &signature, mtime));
+                v.extend_from_slice(&export::tar_entry(export::PUBLIC_KEY_NAME, &public_key,
+                                                       mtime));
+                v.extend_from_slice(&export::tar_trailer());
+                Ok(v)
+            })();
+            r.map(Chunk::from).map_err(|e| Box::new(e.compat()) as BoxedError)
+        });
+        let body = futures::stream::once(future::ok(head.into()))
+            .chain(body)
+            .chain(futures::stream::once(tail));
+        let body: crate::body::BodyStream = Box::new(body);
+        let filename = format!("{}-{}-{}.tar", uuid, stream_type, now.sec);
+        let resp = http::Response::builder()
+            .header(header::CONTENT_TYPE, "application/x-tar")
+            .header(header::CONTENT_LENGTH, len.to_string())
+            .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))
+            .body(body.into())
+            .unwrap();
+        let entry = self.video_audit_entry(req, &caller, "export.tar", uuid, stream_type, span);
+        Ok(self.audit_video(resp, vec![entry], false))
+    }
+
+    /// Serves `/api/view.mp4`: a single `.mp4` with one video track per requested stream, each
+    /// covering the same wall-clock range.
+    fn view_mp4(&self, req: &Request<::hyper::Body>, caller: Caller, debug: bool)
+                -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(StatusCode::UNAUTHORIZED, "view_video required"));
+        }
+        let mut streams = Vec::new();
+        let mut start = None;
+        let mut end = None;
+        let mut timelapse = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "stream" => {
+                        let invalid = || bad_req(format!("invalid stream {:?}", value));
+                        let slash = value.find('/').ok_or_else(invalid)?;
+                        let uuid = Uuid::parse_str(&value[.. slash]).map_err(|_| invalid())?;
+                        let type_ = db::StreamType::parse(&value[slash+1 ..])
+                            .ok_or_else(invalid)?;
+                        streams.push((uuid, type_));
+                    },
+                    "start" => {
+                        start = Some(recording::Time::parse(value)
+                                     .map_err(|_| bad_req("unparseable start"))?)
+                    },
+                    "end" => {
+                        end = Some(recording::Time::parse(value)
+                                   .map_err(|_| bad_req("unparseable end"))?)
+                    },
+                    "timelapse" => timelapse = Some(parse_timelapse(value)?),
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            }
+        }
+        let time = match (start, end) {
+            (Some(s), Some(e)) if s < e => s .. e,
+            (Some(_), Some(_)) => return Err(bad_req("end must be after start")),
+            _ => return Err(bad_req("start and end are required")),
+        };
+        if streams.is_empty() {
+            return Err(bad_req("at least one stream is required"));
+        }
+        let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+        builder.key_frames_only(timelapse);
+        let mut tracks = Vec::new();
+        {
+            let db = self.db.lock();
+            for (uuid, type_) in streams {
+                let camera = caller.camera(&db, uuid)?;
+                let stream_id = camera.streams[type_.index()]
+                    .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_)))?;
+                let mut rows = Vec::new();
+                db.list_recordings_by_time(stream_id, time.clone(), &mut |r| {
+                    // Omit the growing recording, as in playlists, so the etag is stable.
+                    if (r.flags & db::RecordingFlags::Growing as i32) == 0 {
+                        rows.push(r);
+                    }
+                    Ok(())
+                }).map_err(internal_server_err)?;
+                if rows.is_empty() {
+                    continue;  // omit the track entirely.
+                }
+                rows.sort_by_key(|r| r.id);
+                builder.append_track(time.start);
+                tracks.push((uuid, type_));
+                for r in rows {
+                    // A run's final frame may have zero duration, which can't be followed by
+                    // another recording within the track. Omit it by ending just before it.
+                    let mut d = r.duration_90k as i64;
+                    if (r.flags & db::RecordingFlags::TrailingZero as i32) != 0 {
+                        d -= 1;
+                    }
+                    let rel_start = cmp::max(0, (time.start - r.start).0);
+                    let rel_end = cmp::min(d, (time.end - r.start).0);
+                    if rel_start >= rel_end {
+                        continue;
+                    }
+                    builder.append(&db, r, rel_start as i32 .. rel_end as i32)
+                           .map_err(from_base_error)?;
+                }
+            }
+        }
+        if tracks.is_empty() {
+            return Err(not_found("no recordings in the requested range"));
+        }
+        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id())
                          .map_err(from_base_error)?;
         if debug {
             return Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)));
         }
-        Ok(http_serve::serve(mp4, req))
+
+        // Each stream gets its own entry, so that it can be found by camera.
+        let n = tracks.len();
+        let entries = tracks.into_iter().map(|(uuid, type_)| {
+            let mut e = self.video_audit_entry(req, &caller, "view.mp4", uuid, type_,
+                                               Some(time.clone()));
+            if n > 1 {
+                e.detail = Some(format!("one of {} tracks", n));
+            }
+            e
+        }).collect();
+        Ok(self.audit_video(http_serve::serve(mp4, req), entries, false))
+    }
+
+    fn stream_hls(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
+                  stream_type: db::StreamType, live: bool) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(StatusCode::UNAUTHORIZED, "view_video required"));
+        }
+        let mut time = if live {
+            if req.uri().query().is_some() {
+                return Err(bad_req("live.m3u8 takes no parameters"));
+            }
+            recording::Time::min_value() .. recording::Time::max_value()
+        } else {
+            playlist_time_range(req)?
+        };
+        let mut recs = {
+            let db = self.db.lock();
+            let camera = caller.camera(&db, uuid)?;
+            let stream_id = camera.streams[stream_type.index()]
+                .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
+                                              format!("no such stream {}/{}", uuid,
+                                                      stream_type)))?;
+            if live {
+                // Look back far enough from the end of the committed recordings to be sure of
+                // finding the last few; uncommitted recordings are always listed.
+                let s = db.streams_by_id().get(&stream_id).expect("stream_id refed by camera");
+                if let Some(ref r) = s.range {
+                    time.start = r.end - recording::Duration(
+                        LIVE_HLS_RECORDINGS as i64 * 2 * recording::DESIRED_RECORDING_DURATION);
+                }
+            }
+            list_playlist_recordings(&db, stream_id, time).map_err(internal_server_err)?
+        };
+        if live {
+            // List only the current run, as `hls_playlist` requires.
+            if let Some(i) = recs.iter().rposition(|r| r.run_offset == 0) {
+                recs.drain(.. i);
+            }
+            let excess = recs.len().saturating_sub(LIVE_HLS_RECORDINGS);
+            recs.drain(.. excess);
+        } else if recs.is_empty() {
+            return Err(not_found("no recordings in the requested range"));
+        }
+        let segments: Vec<HlsSegment> = recs.iter().map(|r| HlsSegment {
+            id: r.id,
+            open_id: r.open_id,
+            start: r.start,
+            duration_90k: r.duration_90k,
+            run_offset: r.run_offset,
+            video_sample_entry_sha1: r.video_sample_entry.sha1,
+        }).collect();
+        let mut resp = plain_response(StatusCode::OK, hls_playlist(&segments, live));
+        resp.headers_mut().insert(header::CONTENT_TYPE,
+                                  HeaderValue::from_static("application/vnd.apple.mpegurl"));
+        Ok(resp)
+    }
+
+    fn camera_view_mpd(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid)
+                       -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(StatusCode::UNAUTHORIZED, "view_video required"));
+        }
+        let time = playlist_time_range(req)?;
+        let mut streams = Vec::new();
+        {
+            let db = self.db.lock();
+            let camera = caller.camera(&db, uuid)?;
+            for &type_ in &db::ALL_STREAM_TYPES {
+                if let Some(stream_id) = camera.streams[type_.index()] {
+                    let recs = list_playlist_recordings(&db, stream_id, time.clone())
+                        .map_err(internal_server_err)?;
+                    if !recs.is_empty() {
+                        streams.push((type_, recs));
+                    }
+                }
+            }
+        }
+        if streams.is_empty() {
+            return Err(not_found("no recordings in the requested range"));
+        }
+        let mut resp = plain_response(StatusCode::OK, dash_mpd(&streams));
+        resp.headers_mut().insert(header::CONTENT_TYPE,
+                                  HeaderValue::from_static("application/dash+xml"));
+        Ok(resp)
+    }
+
+    /// Subscribes to the live segments of a stream, returning the stream id, the open id which
+    /// will be assigned to its recordings, and the receiving end of the subscription.
+    fn watch_live(&self, caller: &Caller, uuid: Uuid, stream_type: db::StreamType)
+                  -> Result<(i32, u32, futures::channel::mpsc::UnboundedReceiver<db::LiveSegment>),
+                            Response<Body>> {
+        if !caller.permissions.view_video {
+            return Err(plain_response(StatusCode::UNAUTHORIZED, "view_video required"));
+        }
+        let (sub_tx, sub_rx) = futures::channel::mpsc::unbounded();
+        let mut db = self.db.lock();
+        let open_id = match db.open {
+            None => return Err(plain_response(
+                    StatusCode::PRECONDITION_FAILED,
+                    "database is read-only; there are no live streams")),
+            Some(o) => o.id,
+        };
+        let camera = caller.camera(&db, uuid)?;
+        let stream_id = camera.streams[stream_type.index()]
+            .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
+                                          format!("no such stream {}/{}", uuid,
+                                                  stream_type)))?;
+        db.watch_live(stream_id, Box::new(move |l| sub_tx.unbounded_send(l).is_ok()))
+            .expect("stream_id refed by camera");
+        Ok((stream_id, open_id, sub_rx))
+    }
+
+    /// Builds the media segment for `live`, returning it along with the hex-encoded SHA-1 of its
+    /// video sample entry.
+    fn live_segment_mp4(&self, stream_id: i32, live: &db::LiveSegment)
+                        -> Result<(mp4::File, String), base::Error> {
+        let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
+        let mut vse_id = None;
+        {
+            let db = self.db.lock();
+            let mut rows = 0;
+            db.list_recordings_by_id(stream_id, live.recording .. live.recording+1, &mut |r| {
+                rows += 1;
+                let vse = db.video_sample_entries_by_id().get(&r.video_sample_entry_id).unwrap();
+                vse_id = Some(strutil::hex(&vse.sha1));
+                builder.append(&db, r, live.off_90k.clone())?;
+                Ok(())
+            }).err_kind(base::ErrorKind::Unknown)?;
+            if rows != 1 {
+                bail_t!(Internal, "unable to find {:?}", live);
+            }
+        }
+        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id())?;
+        Ok((mp4, vse_id.unwrap()))
+    }
+
+    /// Sends live segments over a WebSocket until either side goes away, adding the length of
+    /// each message sent to `bytes`.
+    async fn send_live_ws(&self, ws: tokio_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+                          stream_id: i32, open_id: u32,
+                          mut sub_rx: futures::channel::mpsc::UnboundedReceiver<db::LiveSegment>,
+                          bytes: &mut u64) -> Result<(), Error> {
+        use futures::sink::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+        let (mut tx, mut rx) = ws.split();
+        let send = async {
+            while let Some(live) = sub_rx.next().await {
+                let msg = self.live_segment_ws_message(stream_id, open_id, &live).await?;
+                let len = msg.len() as u64;
+                tx.send(Message::Binary(msg)).await?;
+                *bytes += len;
+            }
+            Ok::<_, Error>(())
+        };
+
+        // Incoming messages are discarded, but they must be read for pings to be answered and for
+        // a close to be noticed.
+        let recv = async {
+            while let Some(m) = rx.next().await {
+                if let Message::Close(_) = m? {
+                    break;
+                }
+            }
+            Ok::<_, Error>(())
+        };
+        futures::pin_mut!(send, recv);
+        match future::select(send, recv).await {
+            future::Either::Left((r, _)) | future::Either::Right((r, _)) => r,
+        }
+    }
+
+    /// Returns a WebSocket message for `live`: a `json::LiveSegmentHeader`, a newline, and the
+    /// media segment.
+    async fn live_segment_ws_message(&self, stream_id: i32, open_id: u32,
+                                     live: &db::LiveSegment) -> Result<Vec<u8>, Error> {
+        use http_serve::Entity;
+        let (mp4, vse_id) = self.live_segment_mp4(stream_id, live)?;
+        let mut hdrs = http::header::HeaderMap::new();
+        mp4.add_headers(&mut hdrs);
+        let mut msg = serde_json::to_vec(&json::LiveSegmentHeader {
+            content_type: hdrs.get(header::CONTENT_TYPE).unwrap().to_str().unwrap(),
+            open_id,
+            recording_id: live.recording,
+            rel_start_time_90k: live.off_90k.start,
+            rel_end_time_90k: live.off_90k.end,
+            video_sample_entry_sha1: &vse_id,
+        })?;
+        msg.push(b'\n');
+        let len = mp4.len();
+        msg.reserve(len as usize);
+        let mut body = Pin::from(mp4.get_range(0 .. len));
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| format_err!("unable to read live segment: {}", e))?;
+            msg.extend_from_slice(bytes::Buf::bytes(&chunk));
+        }
+        Ok(msg)
     }
 
     fn static_file(&self, req: &Request<::hyper::Body>, path: &str) -> ResponseResult {
@@ -525,11 +1404,57 @@ impl ServiceInner {
                 req.headers().get("X-Real-IP")
                    .and_then(|v| v.to_str().ok())
                    .and_then(|v| IpAddr::from_str(v).ok())
-            } else { None },
+            } else {
+                match req.extensions().get::<Peer>() {
+                    Some(&Peer::Addr(a)) => Some(a),
+                    _ => None,
+                }
+            },
             user_agent: req.headers().get(header::USER_AGENT).map(|ua| ua.as_bytes().to_vec()),
         }
     }
 
+    /// Returns an audit log entry for the given action by `caller`, for the caller to fill in.
+    fn audit_entry(&self, req: &Request<::hyper::Body>, caller: &Caller, action: &str)
+                   -> db::audit::Entry {
+        db::audit::Entry {
+            request: self.authreq(req),
+            user_id: caller.user_id,
+            session_id_hash: caller.session_hash,
+            action: action.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn video_audit_entry(&self, req: &Request<::hyper::Body>, caller: &Caller, action: &str,
+                         uuid: Uuid, stream_type: db::StreamType,
+                         time_90k: Option<Range<recording::Time>>) -> db::audit::Entry {
+        let mut e = self.audit_entry(req, caller, action);
+        e.camera_uuid = Some(uuid);
+        e.stream_type = Some(stream_type);
+        e.time_90k = time_90k;
+        e
+    }
+
+    /// Returns an audit log entry for a `live.m4s` connection, whose time range starts now.
+    fn live_audit_entry(&self, req: &Request<::hyper::Body>, caller: &Caller, uuid: Uuid,
+                        stream_type: db::StreamType) -> db::audit::Entry {
+        let now = recording::Time::new(self.db.clocks().realtime());
+        self.video_audit_entry(req, caller, "live.m4s", uuid, stream_type, Some(now .. now))
+    }
+
+    /// Returns `resp` with a body which adds `entries` to the audit log once it's done, noting
+    /// the number of bytes actually sent. For `live`, each entry's time range ends then.
+    fn audit_video(&self, resp: Response<Body>, entries: Vec<db::audit::Entry>, live: bool)
+                   -> Response<Body> {
+        let db = self.db.clone();
+        resp.map(move |b| b.on_end(move |bytes| {
+            for e in entries {
+                add_video_audit_entry(&db, e, bytes, live);
+            }
+        }))
+    }
+
     fn request(&self, req: &Request<::hyper::Body>) -> ResponseResult {
         let authreq = self.authreq(req);
         let host = req.headers().get(header::HOST).map(|h| String::from_utf8_lossy(h.as_bytes()));
@@ -551,49 +1476,129 @@ impl ServiceInner {
     }
 
     fn is_secure(&self, req: &Request<::hyper::Body>) -> bool {
-        self.trust_forward_hdrs &&
+        req.extensions().get::<Secure>().is_some() ||
+        (self.trust_forward_hdrs &&
             req.headers().get("X-Forwarded-Proto")
                .map(|v| v.as_bytes() == b"https")
-               .unwrap_or(false)
+               .unwrap_or(false))
     }
 
     fn login(&self, req: &Request<::hyper::Body>, body: Bytes) -> ResponseResult {
         let r: json::LoginRequest = serde_json::from_slice(&body)
             .map_err(|e| bad_req(e.to_string()))?;
         let authreq = self.authreq(req);
-        let host = req.headers().get(header::HOST).ok_or_else(|| bad_req("missing Host header!"))?;
-        let host = host.as_bytes();
-        let domain = match ::memchr::memchr(b':', host) {
-            Some(colon) => &host[0..colon],
-            None => host,
-        }.to_owned();
+        let domain = cookie_domain(req)?;
         let mut l = self.db.lock();
         let is_secure = self.is_secure(req);
-        let flags = (auth::SessionFlags::HttpOnly as i32) |
-                    (auth::SessionFlags::SameSite as i32) |
-                    (auth::SessionFlags::SameSiteStrict as i32) |
-                    if is_secure { (auth::SessionFlags::Secure as i32) } else { 0 };
-        let (sid, _) = l.login_by_password(authreq, &r.username, r.password, Some(domain),
-            flags)
-            .map_err(|e| plain_response(StatusCode::UNAUTHORIZED, e.to_string()))?;
-        let s_suffix = if is_secure {
-            &b"; HttpOnly; Secure; SameSite=Strict; Max-Age=2147483648; Path=/"[..]
-        } else {
-            &b"; HttpOnly; SameSite=Strict; Max-Age=2147483648; Path=/"[..]
-        };
-        let mut encoded = [0u8; 64];
-        base64::encode_config_slice(&sid, base64::STANDARD_NO_PAD, &mut encoded);
-        let mut cookie = BytesMut::with_capacity("s=".len() + encoded.len() + s_suffix.len());
-        cookie.put(&b"s="[..]);
-        cookie.put(&encoded[..]);
-        cookie.put(s_suffix);
+        let flags = session_flags(is_secure);
+        let (sid, _) = l.login_by_password(authreq, &r.username, r.password,
+                                           r.code.as_ref().map(String::as_str), Some(domain),
+                                           flags)
+            .map_err(|e| match e.downcast_ref::<auth::LoginThrottled>() {
+                Some(t) => Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"))
+                    .header(header::RETRY_AFTER, t.retry_after_sec.to_string())
+                    .body(e.to_string().into())
+                    .expect("hardcoded head should be valid"),
+                None => plain_response(StatusCode::UNAUTHORIZED, e.to_string()),
+            })?;
         Ok(Response::builder()
-            .header(header::SET_COOKIE, HeaderValue::from_maybe_shared(cookie.freeze())
-                                        .expect("cookie can't have invalid bytes"))
+            .header(header::SET_COOKIE, session_cookie(&sid, is_secure))
             .status(StatusCode::NO_CONTENT)
             .body(b""[..].into()).unwrap())
     }
 
+    /// Starts an OpenID Connect login by redirecting to the provider.
+    async fn oidc_login(self: Arc<Self>, req: Request<hyper::Body>) -> ResponseResult {
+        let oidc = self.oidc.as_ref()
+                       .ok_or_else(|| not_found("OpenID Connect login isn't configured"))?;
+        if *req.method() != http::method::Method::GET {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "GET expected"));
+        }
+        let (state, url) = oidc.start_login(self.db.clocks().realtime().sec).await
+            .map_err(|e| {
+                warn!("unable to start OpenID Connect login: {}", e);
+                plain_response(StatusCode::BAD_GATEWAY, "identity provider is unavailable")
+            })?;
+
+        // SameSite=Strict would keep the cookie from accompanying the provider's redirect back.
+        let cookie = format!("oidc={}; HttpOnly;{} SameSite=Lax; Max-Age={}; Path=/api/oidc",
+                             state, if self.is_secure(&req) { " Secure;" } else { "" },
+                             oidc::PENDING_TIMEOUT_SEC);
+        Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, url)
+            .header(header::SET_COOKIE, cookie)
+            .body(b""[..].into())
+            .map_err(internal_server_err)
+    }
+
+    /// Finishes an OpenID Connect login, creating a session for the matching user.
+    async fn oidc_callback(self: Arc<Self>, req: Request<hyper::Body>) -> ResponseResult {
+        let oidc = self.oidc.as_ref()
+                       .ok_or_else(|| not_found("OpenID Connect login isn't configured"))?;
+        let (mut state, mut code) = (None, None);
+        for (key, value) in form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes()) {
+            match &*key {
+                "state" => state = Some(value.into_owned()),
+                "code" => code = Some(value.into_owned()),
+                "error" => return Err(plain_response(StatusCode::UNAUTHORIZED,
+                                                     format!("identity provider error: {}",
+                                                             value))),
+                _ => {},
+            };
+        }
+        let state = state.ok_or_else(|| bad_req("missing state"))?;
+        let code = code.ok_or_else(|| bad_req("missing code"))?;
+        if extract_cookie(&req, b"oidc") != Some(state.as_bytes()) {
+            return Err(bad_req("login wasn't started by this browser"));
+        }
+        let username = oidc.finish_login(&state, &code, self.db.clocks().realtime().sec).await
+            .map_err(|e| {
+                info!("OpenID Connect login failed: {}", e);
+                plain_response(StatusCode::UNAUTHORIZED, e.to_string())
+            })?;
+
+        let authreq = self.authreq(&req);
+        let domain = cookie_domain(&req)?;
+        let is_secure = self.is_secure(&req);
+        let mut l = self.db.lock();
+        let existing_id = l.get_user(&username).map(|u| u.id);
+        let id = match (existing_id, oidc.auto_provision()) {
+            (Some(id), _) => id,
+            (None, Some(permissions)) => {
+                let mut change = db::UserChange::add_user(username.clone());
+                change.permissions = permissions.clone();
+                let id = l.apply_user_change(change).map_err(internal_server_err)?.id;
+                info!("added user {:?} on first OpenID Connect login", username);
+                add_audit_entry(&mut l, &db::audit::Entry {
+                    request: authreq.clone(),
+                    user_id: Some(id),
+                    action: "add user".to_owned(),
+                    detail: Some(format!("{} on first OpenID Connect login", username)),
+                    ..Default::default()
+                });
+                id
+            },
+            (None, None) => {
+                info!("OpenID Connect login for unknown user {:?}", username);
+                return Err(plain_response(StatusCode::UNAUTHORIZED,
+                                          format!("no such user {:?}", username)));
+            },
+        };
+        let permissions = l.users_by_id()[&id].permissions.clone();
+        let (sid, _) = l.make_session(authreq, id, Some(domain), session_flags(is_secure),
+                                      permissions)
+                        .map_err(|e| plain_response(StatusCode::UNAUTHORIZED, e.to_string()))?;
+        Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, "/")
+            .header(header::SET_COOKIE, session_cookie(&sid, is_secure))
+            .header(header::SET_COOKIE, "oidc=; Max-Age=0; Path=/api/oidc")
+            .body(b""[..].into()).unwrap())
+    }
+
     fn logout(&self, req: &Request<hyper::Body>, body: Bytes) -> ResponseResult {
         let r: json::LogoutRequest = serde_json::from_slice(&body)
             .map_err(|e| bad_req(e.to_string()))?;
@@ -643,6 +1648,10 @@ impl ServiceInner {
         let r: json::PostSignalsRequest = serde_json::from_slice(&body)
             .map_err(|e| bad_req(e.to_string()))?;
         let mut l = self.db.lock();
+        if let Some(id) = r.signal_ids.iter().find(|&&id| !caller.allows_signal(&l, id)) {
+            return Err(plain_response(StatusCode::UNAUTHORIZED,
+                                      format!("update_signals required for signal {}", id)));
+        }
         let now = recording::Time::new(self.db.clocks().realtime());
         let start = r.start_time_90k.map(recording::Time).unwrap_or(now);
         let end = match r.end_base {
@@ -658,7 +1667,201 @@ impl ServiceInner {
         })
     }
 
-    fn get_signals(&self, req: &Request<hyper::Body>) -> ResponseResult {
+    fn get_sessions(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        let mut username = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "username" => username = Some(value.to_owned()),
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            }
+        }
+        let now_sec = self.db.clocks().realtime().sec;
+        let mut l = self.db.lock();
+        let (user_id, current) = match username {
+            // Listing another user's sessions is an administrative action.
+            Some(username) => {
+                if !caller.permissions.admin {
+                    return Err(plain_response(StatusCode::UNAUTHORIZED, "admin required"));
+                }
+                let id = l.get_user(&username).map(|u| u.id).ok_or_else(
+                    || not_found(format!("no such user {:?}", username)))?;
+                (id, caller.user_session.map(|(_, h)| h))
+            },
+            None => {
+                let (id, h) = caller.user_session.ok_or_else(
+                    || plain_response(StatusCode::UNAUTHORIZED, "session required"))?;
+                (id, Some(h))
+            },
+        };
+        let sessions = l.list_sessions(user_id, Some(now_sec)).map_err(internal_server_err)?;
+        serve_json(req, &json::Sessions {
+            sessions: sessions.iter()
+                              .map(|&(ref h, s)| json::SessionInfo::new(h, s, Some(*h) == current))
+                              .collect(),
+        })
+    }
+
+    fn post_sessions(&self, req: &Request<hyper::Body>, caller: Caller, body: Bytes)
+                     -> ResponseResult {
+        let r: json::PostSessionsRequest = serde_json::from_slice(&body)
+            .map_err(|e| bad_req(e.to_string()))?;
+
+        // Callers authenticated by cookie must prove the request isn't forged. Others (API tokens,
+        // unix peers, trusted headers) can only act on another user's sessions, as admins.
+        if let Some(ref s) = caller.session {
+            if !r.csrf.map(|c| csrf_matches(c, s.csrf)).unwrap_or(false) {
+                warn!("sessions request with missing/incorrect csrf");
+                return Err(bad_req("incorrect csrf token"));
+            }
+        }
+        let authreq = self.authreq(req);
+        let mut l = self.db.lock();
+        let user_id = match r.username {
+            // Revoking another user's sessions is an administrative action.
+            Some(username) => {
+                if !caller.permissions.admin {
+                    return Err(plain_response(StatusCode::UNAUTHORIZED, "admin required"));
+                }
+                l.get_user(username).map(|u| u.id).ok_or_else(
+                    || not_found(format!("no such user {:?}", username)))?
+            },
+            None => caller.user_session.ok_or_else(
+                || plain_response(StatusCode::UNAUTHORIZED, "session required"))?.0,
+        };
+        let valid: Vec<auth::SessionHash> = l.list_sessions(user_id, authreq.when_sec)
+            .map_err(internal_server_err)?
+            .iter()
+            .map(|&(h, _)| h)
+            .collect();
+        revoke_by_id(&mut l, &authreq, &valid, &r.revoke)?;
+        for id in &r.revoke {
+            let mut e = self.audit_entry(req, &caller, "revoke session");
+            e.detail = Some(id.clone());
+            add_audit_entry(&mut l, &e);
+        }
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into()).unwrap())
+    }
+
+    fn get_tokens(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        let (user_id, _) = caller.user_session.ok_or_else(
+            || plain_response(StatusCode::UNAUTHORIZED, "session required"))?;
+        let now_sec = self.db.clocks().realtime().sec;
+        let mut l = self.db.lock();
+        let tokens = l.list_tokens(user_id, Some(now_sec)).map_err(internal_server_err)?;
+        serve_json(req, &json::Tokens {
+            tokens: tokens.iter().map(|&(ref h, s)| json::TokenInfo::new(h, s)).collect(),
+        })
+    }
+
+    fn post_tokens(&self, req: &Request<hyper::Body>, caller: Caller, body: Bytes)
+                   -> ResponseResult {
+        // API tokens can't be managed with API tokens, only with a session.
+        let (user_id, _) = caller.user_session.ok_or_else(
+            || plain_response(StatusCode::UNAUTHORIZED, "session required"))?;
+        let r: json::PostTokensRequest = serde_json::from_slice(&body)
+            .map_err(|e| bad_req(e.to_string()))?;
+        let csrf = caller.session.as_ref().expect("user_session implies session").csrf;
+        if !csrf_matches(r.csrf, csrf) {
+            warn!("tokens request with missing/incorrect csrf");
+            return Err(bad_req("incorrect csrf token"));
+        }
+        let authreq = self.authreq(req);
+        let mut l = self.db.lock();
+        let valid: Vec<auth::SessionHash> = l.list_tokens(user_id, authreq.when_sec)
+            .map_err(internal_server_err)?
+            .iter()
+            .map(|&(h, _)| h)
+            .collect();
+        revoke_by_id(&mut l, &authreq, &valid, &r.revoke)?;
+        for id in &r.revoke {
+            let mut e = self.audit_entry(req, &caller, "revoke API token");
+            e.detail = Some(id.clone());
+            add_audit_entry(&mut l, &e);
+        }
+        let t = match r.create {
+            None => return Ok(Response::builder()
+                              .status(StatusCode::NO_CONTENT)
+                              .body(b""[..].into()).unwrap()),
+            Some(t) => t,
+        };
+        let permissions = match t.permissions {
+            None => caller.permissions.clone(),
+            Some(p) => {
+                let mut p = p.to_db();
+                if p.camera_uuids.is_empty() {
+                    // Inherit the session's cameras rather than asking for all of them.
+                    p.camera_uuids = caller.permissions.camera_uuids.clone();
+                }
+                if !permissions_within(&p, &caller.permissions) {
+                    return Err(plain_response(StatusCode::UNAUTHORIZED,
+                                              "token permissions exceed the session's"));
+                }
+                p
+            },
+        };
+        if let (Some(e), Some(now)) = (t.expiration_time_sec, authreq.when_sec) {
+            if e <= now {
+                return Err(bad_req("expirationTimeSec must be in the future"));
+            }
+        }
+        let (token, s) = l.make_token(authreq, user_id, t.name, permissions, t.expiration_time_sec)
+                          .map_err(|e| bad_req(e.to_string()))?;
+        info!("created API token {:?} for user {}", s.description(), user_id);
+        let mut e = self.audit_entry(req, &caller, "create API token");
+        e.detail = Some(format!("{:?} ({})", s.description(), json::session_id(&token.hash())));
+        add_audit_entry(&mut l, &e);
+        serve_json(req, &json::PostTokensResponse {
+            id: json::session_id(&token.hash()),
+            token: base64::encode_config(&token, base64::STANDARD_NO_PAD),
+        })
+    }
+
+    fn get_audit(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.view_audit_log {
+            return Err(plain_response(StatusCode::UNAUTHORIZED, "view_audit_log required"));
+        }
+        let mut q = db::audit::Query {
+            before_id: None,
+            time_sec: None,
+            username: None,
+            camera_uuid: None,
+            limit: 100,
+        };
+        let (mut start_sec, mut end_sec) = (None, None);
+        if let Some(query) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                let int = || i64::from_str(value).map_err(|_| bad_req(format!("bad {}", key)));
+                match key {
+                    "startTimeSec" => start_sec = Some(int()?),
+                    "endTimeSec" => end_sec = Some(int()?),
+                    "before" => q.before_id = Some(int()?),
+                    "limit" => q.limit = cmp::min(cmp::max(int()?, 0), MAX_AUDIT_ENTRIES),
+                    "username" => q.username = Some(value.to_owned()),
+                    "cameraUuid" => {
+                        q.camera_uuid = Some(Uuid::parse_str(value)
+                                             .map_err(|_| bad_req("bad cameraUuid"))?)
+                    },
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            }
+        }
+        if start_sec.is_some() || end_sec.is_some() {
+            q.time_sec = Some(start_sec.unwrap_or(i64::min_value()) ..
+                              end_sec.unwrap_or(i64::max_value()));
+        }
+        let entries = self.db.lock().list_audit_entries(&q).map_err(internal_server_err)?;
+        serve_json(req, &json::AuditEntries {
+            entries: entries.into_iter().map(json::AuditEntry::new).collect(),
+        })
+    }
+
+    fn get_signals(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
         let mut time = recording::Time::min_value() .. recording::Time::max_value();
         if let Some(q) = req.uri().query() {
             for (key, value) in form_urlencoded::parse(q.as_bytes()) {
@@ -678,7 +1881,11 @@ impl ServiceInner {
         }
 
         let mut signals = json::Signals::default();
-        self.db.lock().list_changes_by_time(time, &mut |c: &db::signal::ListStateChangesRow| {
+        let db = self.db.lock();
+        db.list_changes_by_time(time, &mut |c: &db::signal::ListStateChangesRow| {
+            if !caller.allows_signal(&db, c.signal) {
+                return;
+            }
             signals.times_90k.push(c.when.0);
             signals.signal_ids.push(c.signal);
             signals.states.push(c.state);
@@ -686,8 +1893,83 @@ impl ServiceInner {
         serve_json(req, &signals)
     }
 
+    /// Moves a stream's recordings to another sample file directory, as `moonfire-nvr
+    /// migrate-stream` does. This completes even if the client goes away.
+    async fn post_migrate(self: Arc<Self>, req: Request<hyper::Body>, caller: Caller, uuid: Uuid,
+                          stream_type: db::StreamType) -> ResponseResult {
+        if !caller.permissions.admin {
+            return Err(plain_response(StatusCode::UNAUTHORIZED, "admin required"));
+        }
+        let (req, body) = with_json_body(req).await?;
+        let r: json::PostMigrateRequest = serde_json::from_slice(&body)
+            .map_err(|e| bad_req(e.to_string()))?;
+        let (stream_id, to_dir_id, syncer_dir_ids) = {
+            let l = self.db.lock();
+            if l.open.is_none() {
+                return Err(plain_response(StatusCode::PRECONDITION_FAILED,
+                                          "database is read-only"));
+            }
+            let camera = caller.camera(&l, uuid)?;
+            let stream_id = camera.streams[stream_type.index()]
+                .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, stream_type)))?;
+            let to_dir_id = l.sample_file_dirs_by_id()
+                             .values()
+                             .find(|d| d.path == r.to_dir)
+                             .map(|d| d.id)
+                             .ok_or_else(|| bad_req(format!("no sample file dir with path {:?}",
+                                                            r.to_dir)))?;
+            if l.streams_by_id()[&stream_id].record {
+                return Err(plain_response(
+                        StatusCode::PRECONDITION_FAILED,
+                        "stream is being recorded; use moonfire-nvr migrate-stream instead"));
+            }
+
+            // The server runs a syncer for each directory with a recording stream.
+            let syncer_dir_ids: Vec<i32> =
+                l.streams_by_id().values()
+                 .filter(|s| s.record)
+                 .filter_map(|s| s.sample_file_dir_id)
+                 .collect();
+            (stream_id, to_dir_id, syncer_dir_ids)
+        };
+        if !self.migrating_streams.lock().insert(stream_id) {
+            return Err(plain_response(StatusCode::CONFLICT, "stream is already being migrated"));
+        }
+        let db = self.db.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            db::writer::migrate_stream(db, stream_id, to_dir_id, &syncer_dir_ids)
+        }).await;
+        self.migrating_streams.lock().remove(&stream_id);
+        result.map_err(internal_server_err)?.map_err(internal_server_err)?;
+        let mut e = self.audit_entry(&req, &caller, "migrate stream");
+        e.camera_uuid = Some(uuid);
+        e.stream_type = Some(stream_type);
+        e.detail = Some(r.to_dir);
+        add_audit_entry(&mut self.db.lock(), &e);
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into()).unwrap())
+    }
+
     fn authenticate(&self, req: &Request<hyper::Body>, unauth_path: bool)
                     -> Result<Caller, base::Error> {
+        if let Some(token) = extract_token(req)? {
+            let authreq = self.authreq(req);
+            return match self.db.lock().authenticate_token(authreq, &token.hash()) {
+                Ok((s, _)) => Ok(Caller {
+                    permissions: s.permissions.clone(),
+                    session: None,
+                    user_session: None,
+                    user_id: Some(s.user_id()),
+                    session_hash: Some(token.hash()),
+                }),
+                Err(e) => {
+                    info!("authenticate_token failed: {}", e);
+                    bail_t!(Unauthenticated, "invalid API token");
+                },
+            };
+        }
+
         if let Some(sid) = extract_sid(req) {
             let authreq = self.authreq(req);
 
@@ -700,15 +1982,31 @@ impl ServiceInner {
                         username: u.username.clone(),
                         csrf: s.csrf(),
                     }),
+                    user_session: Some((u.id, sid.hash())),
+                    user_id: Some(u.id),
+                    session_hash: Some(sid.hash()),
                 });
             }
             info!("authenticate_session failed");
         }
 
+        if let Some(c) = self.authenticate_trusted_user(req) {
+            return Ok(c);
+        }
+
+        if let Some(&Peer::Uid(uid)) = req.extensions().get::<Peer>() {
+            if let Some(c) = self.authenticate_uid(uid) {
+                return Ok(c);
+            }
+        }
+
         if let Some(s) = self.allow_unauthenticated_permissions.as_ref() {
             return Ok(Caller {
                 permissions: s.clone(),
                 session: None,
+                user_session: None,
+                user_id: None,
+                session_hash: None,
             });
         }
 
@@ -716,11 +2014,175 @@ impl ServiceInner {
             return Ok(Caller {
                 permissions: db::Permissions::default(),
                 session: None,
+                user_session: None,
+                user_id: None,
+                session_hash: None,
             })
         }
 
         bail_t!(Unauthenticated, "unauthenticated");
     }
+
+    /// Authenticates a request by the username in the trusted user header, if it's present and the
+    /// request came directly from a trusted proxy. Elsewhere, the header is ignored.
+    fn authenticate_trusted_user(&self, req: &Request<hyper::Body>) -> Option<Caller> {
+        let hdr = self.trusted_user_hdr.as_ref()?;
+        match req.extensions().get::<Peer>() {
+            Some(Peer::Addr(a)) if self.trusted_proxies.contains(a) => {},
+            _ => return None,
+        };
+        let username = req.headers().get(hdr)?.to_str().ok()?;
+        match self.db.lock().authenticate_trusted_username(username) {
+            Ok(u) => Some(Caller {
+                permissions: u.permissions.clone(),
+                session: None,
+                user_session: None,
+                user_id: Some(u.id),
+                session_hash: None,
+            }),
+            Err(e) => {
+                info!("authenticate_trusted_username failed: {}", e);
+                None
+            },
+        }
+    }
+
+    /// Authenticates a Unix domain socket peer by uid. A user with a matching `unix_uid` gets
+    /// that user's permissions. Failing that, this process's own uid gets all permissions; it
+    /// could do anything by accessing the database directly anyway.
+    fn authenticate_uid(&self, uid: u32) -> Option<Caller> {
+        let l = self.db.lock();
+        let e = match l.authenticate_unix_uid(uid as i32) {
+            Ok(u) => return Some(Caller {
+                permissions: u.permissions.clone(),
+                session: None,
+                user_session: None,
+                user_id: Some(u.id),
+                session_hash: None,
+            }),
+            Err(e) => e,
+        };
+        if uid == self.own_uid {
+            let mut permissions = db::Permissions::new();
+            permissions.view_video = true;
+            permissions.read_camera_configs = true;
+            permissions.update_signals = true;
+            permissions.view_audit_log = true;
+            permissions.admin = true;
+            return Some(Caller {
+                permissions,
+                session: None,
+                user_session: None,
+                user_id: None,
+                session_hash: None,
+            });
+        }
+        info!("authenticate_unix_uid failed: {}", e);
+        None
+    }
+}
+
+/// Returns a redirect from a plain HTTP request to the same URL on the HTTPS server with the given
+/// port.
+fn https_redirect(req: &Request<::hyper::Body>, https_port: u16) -> Response<Body> {
+    let host = match req.headers().get(header::HOST).and_then(|h| h.to_str().ok()) {
+        None => return bad_req("missing Host header"),
+        Some(h) => h,
+    };
+
+    // Strip the port, if any, taking care not to mistake part of an IPv6 literal for it.
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, format!("https://{}{}{}", host, port, path))
+        .body(b""[..].into())
+        .unwrap_or_else(|_| bad_req("bad Host header"))
+}
+
+/// Extracts an API token from an `Authorization: Bearer` header, if present.
+fn extract_token(req: &Request<hyper::Body>) -> Result<Option<auth::RawSessionId>, base::Error> {
+    let hdr = match req.headers().get(header::AUTHORIZATION) {
+        None => return Ok(None),
+        Some(h) => h.as_bytes(),
+    };
+    if !hdr.starts_with(b"Bearer ") {
+        bail_t!(Unauthenticated, "unsupported Authorization scheme");
+    }
+    match auth::RawSessionId::decode_base64(&hdr[b"Bearer ".len()..]) {
+        Ok(t) => Ok(Some(t)),
+        Err(_) => bail_t!(Unauthenticated, "malformed API token"),
+    }
+}
+
+/// Returns true iff `p` grants nothing beyond `limit`.
+fn permissions_within(p: &db::Permissions, limit: &db::Permissions) -> bool {
+    let cameras_within = limit.camera_uuids.is_empty() || (
+        !p.camera_uuids.is_empty() &&
+        p.camera_uuids.iter().all(|u| Uuid::parse_str(u).map(|u| limit.allows_camera(u))
+                                                        .unwrap_or(true)));
+    cameras_within &&
+    (!p.view_video || limit.view_video) &&
+    (!p.read_camera_configs || limit.read_camera_configs) &&
+    (!p.update_signals || limit.update_signals) &&
+    (!p.view_audit_log || limit.view_audit_log) &&
+    (!p.admin || limit.admin)
+}
+
+/// Revokes the sessions or API tokens with the given ids (as in `json::session_id`), all of
+/// which must be in `valid`. If any is not, revokes nothing.
+fn revoke_by_id(l: &mut db::LockedDatabase, authreq: &auth::Request, valid: &[auth::SessionHash],
+                ids: &[String]) -> Result<(), Response<Body>> {
+    let mut to_revoke = Vec::with_capacity(ids.len());
+    for id in ids {
+        let hash = auth::SessionHash::decode_base64(id.as_bytes())
+            .map_err(|_| bad_req(format!("bad id {:?}", id)))?;
+
+        // Don't reveal whether a session or token which belongs to some other user exists.
+        if !valid.contains(&hash) {
+            return Err(not_found(format!("no such id {:?}", id)));
+        }
+        to_revoke.push(hash);
+    }
+    for hash in &to_revoke {
+        info!("revoking {:?} by user request", hash);
+        l.revoke_session(auth::RevocationReason::UserRevoked, None, authreq.clone(), hash)
+         .map_err(internal_server_err)?;
+    }
+    Ok(())
+}
+
+/// Adds `e` to the audit log. A failure is logged rather than returned: it's too late to refuse
+/// the request.
+fn add_audit_entry(l: &mut db::LockedDatabase, e: &db::audit::Entry) {
+    if let Err(err) = l.add_audit_entry(e) {
+        warn!("unable to add audit entry {:?}: {}", e, err);
+    }
+}
+
+/// Adds `e`, the sending of `bytes` of video, to the audit log. For live video, its time range
+/// is taken to end now.
+pub(crate) fn add_video_audit_entry(db: &db::Database, mut e: db::audit::Entry, bytes: u64,
+                                    live: bool) {
+    e.bytes = Some(bytes as i64);
+    if live {
+        if let Some(ref mut t) = e.time_90k {
+            t.end = recording::Time::new(db.clocks().realtime());
+        }
+    }
+    add_audit_entry(&mut db.lock(), &e);
+}
+
+/// Extends `span` to cover `t`.
+fn extend_span(span: &mut Option<Range<recording::Time>>, t: Range<recording::Time>) {
+    *span = Some(match span.take() {
+        None => t,
+        Some(s) => cmp::min(s.start, t.start) .. cmp::max(s.end, t.end),
+    });
 }
 
 fn csrf_matches(csrf: &str, session: auth::SessionHash) -> bool {
@@ -731,24 +2193,69 @@ fn csrf_matches(csrf: &str, session: auth::SessionHash) -> bool {
 
 /// Extracts `s` cookie from the HTTP request. Does not authenticate.
 fn extract_sid(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
-    let hdr = match req.headers().get(header::COOKIE) {
-        None => return None,
-        Some(c) => c,
-    };
+    extract_cookie(req, b"s").and_then(|s| auth::RawSessionId::decode_base64(s).ok())
+}
+
+/// Extracts the value of the named cookie, if present.
+fn extract_cookie<'a>(req: &'a Request<hyper::Body>, name: &[u8]) -> Option<&'a [u8]> {
+    let hdr = req.headers().get(header::COOKIE)?;
     for mut cookie in hdr.as_bytes().split(|&b| b == b';') {
         if cookie.starts_with(b" ") {
             cookie = &cookie[1..];
         }
-        if cookie.starts_with(b"s=") {
-            let s = &cookie[2..];
-            if let Ok(s) = auth::RawSessionId::decode_base64(s) {
-                return Some(s);
-            }
+        if cookie.len() > name.len() && cookie.starts_with(name) && cookie[name.len()] == b'=' {
+            return Some(&cookie[name.len() + 1..]);
         }
     }
     None
 }
 
+/// Returns the domain to record with a new session: the `Host` header without its port.
+fn cookie_domain(req: &Request<hyper::Body>) -> Result<Vec<u8>, Response<Body>> {
+    let host = req.headers().get(header::HOST).ok_or_else(|| bad_req("missing Host header!"))?;
+    let host = host.as_bytes();
+    Ok(match ::memchr::memchr(b':', host) {
+        Some(colon) => &host[0..colon],
+        None => host,
+    }.to_owned())
+}
+
+/// Returns the flags of a session created via the login page.
+fn session_flags(is_secure: bool) -> i32 {
+    (auth::SessionFlags::HttpOnly as i32) |
+    (auth::SessionFlags::SameSite as i32) |
+    (auth::SessionFlags::SameSiteStrict as i32) |
+    if is_secure { (auth::SessionFlags::Secure as i32) } else { 0 }
+}
+
+/// Returns a `Set-Cookie` header value for the given session, matching `session_flags`.
+fn session_cookie(sid: &auth::RawSessionId, is_secure: bool) -> HeaderValue {
+    let s_suffix = if is_secure {
+        &b"; HttpOnly; Secure; SameSite=Strict; Max-Age=2147483648; Path=/"[..]
+    } else {
+        &b"; HttpOnly; SameSite=Strict; Max-Age=2147483648; Path=/"[..]
+    };
+    let mut encoded = [0u8; 64];
+    base64::encode_config_slice(sid, base64::STANDARD_NO_PAD, &mut encoded);
+    let mut cookie = BytesMut::with_capacity("s=".len() + encoded.len() + s_suffix.len());
+    cookie.put(&b"s="[..]);
+    cookie.put(&encoded[..]);
+    cookie.put(s_suffix);
+    HeaderValue::from_maybe_shared(cookie.freeze()).expect("cookie can't have invalid bytes")
+}
+
+/// Runs a handler on its own task, for handlers whose futures are `Send` but not `Sync`.
+fn spawn_handler<F>(f: F) -> impl Future<Output = ResponseResult> + Send + Sync + 'static
+where F: Future<Output = ResponseResult> + Send + 'static {
+    let (tx, rx) = futures::channel::oneshot::channel();
+    tokio::spawn(async move {
+        let _ = tx.send(f.await);
+    });
+    async move {
+        rx.await.unwrap_or_else(|_| Err(internal_server_err(format_err!("handler panicked"))))
+    }
+}
+
 /// Returns a future separating the request from its JSON body.
 ///
 /// If this is not a `POST` or the body's `Content-Type` is not
@@ -782,6 +2289,20 @@ pub struct Config<'a> {
     pub trust_forward_hdrs: bool,
     pub time_zone_name: String,
     pub allow_unauthenticated_permissions: Option<db::Permissions>,
+
+    /// The key with which to sign `export.tar` bundles. If absent, exports are unavailable.
+    pub export_key: Option<Arc<export::SigningKey>>,
+
+    /// If set, plain HTTP requests from TCP peers are redirected to HTTPS on this port.
+    pub https_redirect_port: Option<u16>,
+
+    /// If set, users may log in via this OpenID Connect provider.
+    pub oidc: Option<Arc<oidc::Client>>,
+
+    /// If set, a header naming the user, as set by a reverse proxy which has already
+    /// authenticated the request. It's trusted only from the `trusted_proxies` peer addresses.
+    pub trusted_user_hdr: Option<header::HeaderName>,
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 #[derive(Clone)]
@@ -794,30 +2315,19 @@ impl Service {
             Service::fill_ui_files(d, &mut ui_files);
         }
         debug!("UI files: {:#?}", ui_files);
-        let dirs_by_stream_id = {
-            let l = config.db.lock();
-            let mut d =
-                FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
-            for (&id, s) in l.streams_by_id().iter() {
-                let dir_id = match s.sample_file_dir_id {
-                    Some(d) => d,
-                    None => continue,
-                };
-                d.insert(id, l.sample_file_dirs_by_id()
-                              .get(&dir_id)
-                              .unwrap()
-                              .get()?);
-            }
-            Arc::new(d)
-        };
-
         Ok(Service(Arc::new(ServiceInner {
             db: config.db,
-            dirs_by_stream_id,
             ui_files,
             allow_unauthenticated_permissions: config.allow_unauthenticated_permissions,
             trust_forward_hdrs: config.trust_forward_hdrs,
             time_zone_name: config.time_zone_name,
+            export_key: config.export_key,
+            https_redirect_port: config.https_redirect_port,
+            oidc: config.oidc,
+            trusted_user_hdr: config.trusted_user_hdr,
+            trusted_proxies: config.trusted_proxies,
+            migrating_streams: parking_lot::Mutex::new(FnvHashSet::default()),
+            own_uid: nix::unistd::Uid::effective().as_raw(),
         })))
     }
 
@@ -862,56 +2372,15 @@ impl Service {
         }
     }
 
-    fn stream_live_m4s(&self, _req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
+    fn stream_live_m4s(&self, req: &Request<::hyper::Body>, caller: Caller, uuid: Uuid,
                        stream_type: db::StreamType) -> ResponseResult {
-        if !caller.permissions.view_video {
-            return Err(plain_response(StatusCode::UNAUTHORIZED, "view_video required"));
-        }
-        let stream_id;
-        let open_id;
-        let (sub_tx, sub_rx) = futures::channel::mpsc::unbounded();
-        {
-            let mut db = self.0.db.lock();
-            open_id = match db.open {
-                None => return Err(plain_response(
-                        StatusCode::PRECONDITION_FAILED,
-                        "database is read-only; there are no live streams")),
-                Some(o) => o.id,
-            };
-            let camera = db.get_camera(uuid)
-                           .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
-                                                         format!("no such camera {}", uuid)))?;
-            stream_id = camera.streams[stream_type.index()]
-                .ok_or_else(|| plain_response(StatusCode::NOT_FOUND,
-                                              format!("no such stream {}/{}", uuid,
-                                                      stream_type)))?;
-            db.watch_live(stream_id, Box::new(move |l| sub_tx.unbounded_send(l).is_ok()))
-                .expect("stream_id refed by camera");
-        }
+        let (stream_id, open_id, sub_rx) = self.0.watch_live(&caller, uuid, stream_type)?;
+        let entry = self.0.live_audit_entry(req, &caller, uuid, stream_type);
         let inner = self.0.clone();
         let body = sub_rx
             .map(move |live| -> Result<_, base::Error> {
-                let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
-                let mut vse_id = None;
-                {
-                    let db = inner.db.lock();
-                    let mut rows = 0;
-                    db.list_recordings_by_id(stream_id, live.recording .. live.recording+1,
-                                             &mut |r| {
-                        rows += 1;
-                        let vse = db.video_sample_entries_by_id().get(&r.video_sample_entry_id)
-                                    .unwrap();
-                        vse_id = Some(strutil::hex(&vse.sha1));
-                        builder.append(&db, r, live.off_90k.clone())?;
-                        Ok(())
-                    }).err_kind(base::ErrorKind::Unknown)?;
-                    if rows != 1 {
-                        bail_t!(Internal, "unable to find {:?}", live);
-                    }
-                }
-                let vse_id = vse_id.unwrap();
+                let (mp4, vse_id) = inner.live_segment_mp4(stream_id, &live)?;
                 use http_serve::Entity;
-                let mp4 = builder.build(inner.db.clone(), inner.dirs_by_stream_id.clone())?;
                 let mut hdrs = http::header::HeaderMap::new();
                 mp4.add_headers(&mut hdrs);
                 let mime_type = hdrs.get(http::header::CONTENT_TYPE).unwrap();
@@ -942,10 +2411,45 @@ impl Service {
         let body = body.try_flatten();
         let body: crate::body::BodyStream = Box::new(body);
         let body: Body = body.into();
-        Ok(http::Response::builder()
+        let resp = http::Response::builder()
             .header("X-Open-Id", open_id.to_string())
             .header("Content-Type", "multipart/mixed; boundary=B")
             .body(body)
+            .unwrap();
+        Ok(self.0.audit_video(resp, vec![entry], true))
+    }
+
+    /// Serves `live.m4s` over a WebSocket: upgrades the connection, then sends each live segment
+    /// as a binary message consisting of a JSON header, a newline, and the `.mp4` media segment.
+    fn stream_live_m4s_ws(&self, req: Request<::hyper::Body>, caller: Caller, uuid: Uuid,
+                          stream_type: db::StreamType) -> ResponseResult {
+        let key = websocket_accept_key(&req)?;
+        let (stream_id, open_id, sub_rx) = self.0.watch_live(&caller, uuid, stream_type)?;
+        let entry = self.0.live_audit_entry(&req, &caller, uuid, stream_type);
+        let inner = self.0.clone();
+        let on_upgrade = req.into_body().on_upgrade();
+        tokio::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(u) => u,
+                Err(e) => {
+                    warn!("live.m4s WebSocket upgrade failed: {}", e);
+                    return;
+                },
+            };
+            let ws = tokio_tungstenite::WebSocketStream::from_raw_socket(
+                upgraded, tokio_tungstenite::tungstenite::protocol::Role::Server, None).await;
+            let mut bytes = 0;
+            if let Err(e) = inner.send_live_ws(ws, stream_id, open_id, sub_rx, &mut bytes).await {
+                info!("live.m4s WebSocket for stream {} ended: {}", stream_id, e);
+            }
+            add_video_audit_entry(&inner.db, entry, bytes, true);
+        });
+        Ok(Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, HeaderValue::from_static("Upgrade"))
+            .header(header::UPGRADE, HeaderValue::from_static("websocket"))
+            .header(header::SEC_WEBSOCKET_ACCEPT, key)
+            .body(Body::from(""))
             .unwrap())
     }
 
@@ -958,13 +2462,59 @@ impl Service {
                                          let s = self.0.clone();
                                          move |(req, b)| future::ready(s.post_signals(&req, caller, b))
                                      })),
-            Method::GET | Method::HEAD => Box::new(future::ready(self.0.get_signals(&req))),
+            Method::GET | Method::HEAD => {
+                Box::new(future::ready(self.0.get_signals(&req, caller)))
+            },
+            _ => Box::new(future::err(plain_response(StatusCode::METHOD_NOT_ALLOWED,
+                                                     "POST, GET, or HEAD expected"))),
+        }
+    }
+
+    fn sessions(&self, req: Request<hyper::Body>, caller: Caller)
+                -> Box<dyn Future<Output = Result<Response<Body>, Response<Body>>> + Send + Sync + 'static> {
+        use http::method::Method;
+        match *req.method() {
+            Method::POST => Box::new(with_json_body(req)
+                                     .and_then({
+                                         let s = self.0.clone();
+                                         move |(req, b)| future::ready(s.post_sessions(&req, caller, b))
+                                     })),
+            Method::GET | Method::HEAD => Box::new(future::ready(self.0.get_sessions(&req, caller))),
+            _ => Box::new(future::err(plain_response(StatusCode::METHOD_NOT_ALLOWED,
+                                                     "POST, GET, or HEAD expected"))),
+        }
+    }
+
+    fn tokens(&self, req: Request<hyper::Body>, caller: Caller)
+              -> Box<dyn Future<Output = Result<Response<Body>, Response<Body>>> + Send + Sync + 'static> {
+        use http::method::Method;
+        match *req.method() {
+            Method::POST => Box::new(with_json_body(req)
+                                     .and_then({
+                                         let s = self.0.clone();
+                                         move |(req, b)| future::ready(s.post_tokens(&req, caller, b))
+                                     })),
+            Method::GET | Method::HEAD => Box::new(future::ready(self.0.get_tokens(&req, caller))),
             _ => Box::new(future::err(plain_response(StatusCode::METHOD_NOT_ALLOWED,
                                                      "POST, GET, or HEAD expected"))),
         }
     }
 
-    pub fn serve(&mut self, req: Request<::hyper::Body>) -> BoxedFuture {
+    /// Serves a request from the given peer, if known. `secure` should be true iff the request
+    /// arrived over TLS.
+    pub fn serve(&mut self, mut req: Request<::hyper::Body>, peer: Option<Peer>, secure: bool)
+                 -> BoxedFuture {
+        if let Some(p) = peer {
+            req.extensions_mut().insert(p);
+        }
+        if secure {
+            req.extensions_mut().insert(Secure);
+        }
+        if let (Some(port), Some(Peer::Addr(_))) = (self.0.https_redirect_port, peer) {
+            if !self.0.is_secure(&req) {
+                return Box::new(future::ok::<_, BoxedError>(https_redirect(&req, port)));
+            }
+        }
         fn wrap<R>(is_private: bool, r: R) -> BoxedFuture
         where R: Future<Output = Result<Response<Body>, Response<Body>>> + Send + Sync + 'static {
             return Box::new(r.or_else(|e| futures::future::ok(e)).map_ok(move |mut r| {
@@ -982,7 +2532,8 @@ impl Service {
 
         let p = Path::decode(req.uri().path());
         let always_allow_unauthenticated = match p {
-            Path::NotFound | Path::Request | Path::Login | Path::Logout | Path::Static => true,
+            Path::NotFound | Path::Request | Path::Login | Path::Logout | Path::OidcLogin |
+            Path::OidcCallback | Path::Static => true,
             _ => false,
         };
         debug!("request on: {}: {:?}", req.uri(), p);
@@ -992,11 +2543,13 @@ impl Service {
         };
         match p {
             Path::InitSegment(sha1, debug) => wrap_r(true, self.0.init_segment(sha1, debug, &req)),
+            Path::ViewMp4(debug) => wrap_r(true, self.0.view_mp4(&req, caller, debug)),
             Path::TopLevel => wrap_r(true, self.0.top_level(&req, caller)),
             Path::Request => wrap_r(true, self.0.request(&req)),
-            Path::Camera(uuid) => wrap_r(true, self.0.camera(&req, uuid)),
+            Path::Camera(uuid) => wrap_r(true, self.0.camera(&req, caller, uuid)),
+            Path::CameraViewMpd(uuid) => wrap_r(true, self.0.camera_view_mpd(&req, caller, uuid)),
             Path::StreamRecordings(uuid, type_) => {
-                wrap_r(true, self.0.stream_recordings(&req, uuid, type_))
+                wrap_r(true, self.0.stream_recordings(&req, caller, uuid, type_))
             },
             Path::StreamViewMp4(uuid, type_, debug) => {
                 wrap_r(true, self.0.stream_view_mp4(&req, caller, uuid, type_, mp4::Type::Normal,
@@ -1006,8 +2559,33 @@ impl Service {
                 wrap_r(true, self.0.stream_view_mp4(&req, caller, uuid, type_,
                                                     mp4::Type::MediaSegment, debug))
             },
+            Path::StreamViewMkv(uuid, type_) => {
+                wrap_r(true, self.0.stream_view_mkv(&req, caller, uuid, type_))
+            },
+            Path::StreamViewTs(uuid, type_) => {
+                wrap_r(true, self.0.stream_view_ts(&req, caller, uuid, type_))
+            },
+            Path::StreamViewVtt(uuid, type_) => {
+                wrap_r(true, self.0.stream_view_vtt(&req, caller, uuid, type_))
+            },
+            Path::StreamExport(uuid, type_) => {
+                wrap_r(true, self.0.stream_export(&req, caller, uuid, type_))
+            },
             Path::StreamLiveMp4Segments(uuid, type_) => {
-                wrap_r(true, self.stream_live_m4s(&req, caller, uuid, type_))
+                if is_websocket_upgrade(&req) {
+                    wrap_r(true, self.stream_live_m4s_ws(req, caller, uuid, type_))
+                } else {
+                    wrap_r(true, self.stream_live_m4s(&req, caller, uuid, type_))
+                }
+            },
+            Path::StreamHlsLive(uuid, type_) => {
+                wrap_r(true, self.0.stream_hls(&req, caller, uuid, type_, true))
+            },
+            Path::StreamHlsRecorded(uuid, type_) => {
+                wrap_r(true, self.0.stream_hls(&req, caller, uuid, type_, false))
+            },
+            Path::StreamMigrate(uuid, type_) => {
+                wrap(true, spawn_handler(self.0.clone().post_migrate(req, caller, uuid, type_)))
             },
             Path::NotFound => wrap(true, future::err(not_found("path not understood"))),
             Path::Login => wrap(true, with_json_body(req).and_then({
@@ -1018,7 +2596,12 @@ impl Service {
                 let s = self.clone();
                 move |(req, b)| future::ready(s.0.logout(&req, b))
             })),
+            Path::OidcLogin => wrap(true, spawn_handler(self.0.clone().oidc_login(req))),
+            Path::OidcCallback => wrap(true, spawn_handler(self.0.clone().oidc_callback(req))),
             Path::Signals => wrap(true, Pin::from(self.signals(req, caller))),
+            Path::Sessions => wrap(true, Pin::from(self.sessions(req, caller))),
+            Path::Tokens => wrap(true, Pin::from(self.tokens(req, caller))),
+            Path::Audit => wrap_r(true, self.0.get_audit(&req, caller)),
             Path::Static => wrap_r(false, self.0.static_file(&req, req.uri().path())),
         }
     }
@@ -1030,6 +2613,7 @@ mod tests {
     use futures::future::FutureExt;
     use log::info;
     use std::collections::HashMap;
+    use std::sync::Arc;
     use super::Segments;
 
     struct Server {
@@ -1042,21 +2626,48 @@ mod tests {
 
     impl Server {
         fn new(allow_unauthenticated_permissions: Option<db::Permissions>) -> Server {
+            Server::with_config(allow_unauthenticated_permissions, |_| {})
+        }
+
+        /// Creates a server which trusts `trusted_user_hdr` from `trusted_proxies`.
+        fn with_trusted_user_hdr(allow_unauthenticated_permissions: Option<db::Permissions>,
+                                 trusted_user_hdr: &'static str,
+                                 trusted_proxies: Vec<std::net::IpAddr>) -> Server {
+            Server::with_config(allow_unauthenticated_permissions, |c| {
+                c.trusted_user_hdr = Some(http::header::HeaderName::from_static(trusted_user_hdr));
+                c.trusted_proxies = trusted_proxies;
+            })
+        }
+
+        /// Creates a server with the default test config as modified by `configure`.
+        fn with_config(allow_unauthenticated_permissions: Option<db::Permissions>,
+                       configure: impl FnOnce(&mut super::Config)) -> Server {
             let db = TestDb::new(base::clock::RealClocks {});
             let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel::<()>();
-            let service = super::Service::new(super::Config {
+            let mut config = super::Config {
                 db: db.db.clone(),
                 ui_dir: None,
                 allow_unauthenticated_permissions,
                 trust_forward_hdrs: true,
                 time_zone_name: "".to_owned(),
-            }).unwrap();
-            let make_svc = hyper::service::make_service_fn(move |_conn| {
-                futures::future::ok::<_, std::convert::Infallible>(hyper::service::service_fn({
-                    let mut s = service.clone();
-                    move |req| std::pin::Pin::from(s.serve(req))
-                }))
-            });
+                export_key: None,
+                https_redirect_port: None,
+                oidc: None,
+                trusted_user_hdr: None,
+                trusted_proxies: Vec::new(),
+            };
+            configure(&mut config);
+            let service = super::Service::new(config).unwrap();
+            let make_svc = hyper::service::make_service_fn(
+                move |conn: &hyper::server::conn::AddrStream| {
+                    let peer = conn.remote_addr().ip();
+                    futures::future::ok::<_, std::convert::Infallible>(
+                        hyper::service::service_fn({
+                            let mut s = service.clone();
+                            let peer = Some(super::Peer::Addr(peer));
+                            move |req| std::pin::Pin::from(s.serve(req, peer, false))
+                        }))
+                });
             let (tx, rx) = std::sync::mpsc::channel();
             let handle = ::std::thread::spawn(move || {
                 let addr = ([127, 0, 0, 1], 0).into();
@@ -1147,6 +2758,8 @@ mod tests {
                    Path::NotFound);  // too short
         assert_eq!(Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/"),
                    Path::Camera(cam_uuid));
+        assert_eq!(Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/view.mpd"),
+                   Path::CameraViewMpd(cam_uuid));
         assert_eq!(Path::decode("/api/cameras/asdf/"), Path::NotFound);
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/recordings"),
@@ -1169,18 +2782,107 @@ mod tests {
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/view.m4s.txt"),
             Path::StreamViewMp4Segment(cam_uuid, db::StreamType::MAIN, true));
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/view.mkv"),
+            Path::StreamViewMkv(cam_uuid, db::StreamType::MAIN));
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/view.ts"),
+            Path::StreamViewTs(cam_uuid, db::StreamType::SUB));
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/view.vtt"),
+            Path::StreamViewVtt(cam_uuid, db::StreamType::MAIN));
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/export.tar"),
+            Path::StreamExport(cam_uuid, db::StreamType::MAIN));
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live.m4s"),
             Path::StreamLiveMp4Segments(cam_uuid, db::StreamType::MAIN));
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live.m3u8"),
+            Path::StreamHlsLive(cam_uuid, db::StreamType::MAIN));
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/recorded.m3u8"),
+            Path::StreamHlsRecorded(cam_uuid, db::StreamType::SUB));
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/junk"),
             Path::NotFound);
         assert_eq!(Path::decode("/api/login"), Path::Login);
+        assert_eq!(Path::decode("/api/oidc/login"), Path::OidcLogin);
+        assert_eq!(Path::decode("/api/oidc/callback"), Path::OidcCallback);
         assert_eq!(Path::decode("/api/logout"), Path::Logout);
         assert_eq!(Path::decode("/api/signals"), Path::Signals);
+        assert_eq!(Path::decode("/api/sessions"), Path::Sessions);
+        assert_eq!(Path::decode("/api/tokens"), Path::Tokens);
+        assert_eq!(Path::decode("/api/audit"), Path::Audit);
+        assert_eq!(Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/migrate"),
+                   Path::StreamMigrate(cam_uuid, db::StreamType::MAIN));
+        assert_eq!(Path::decode("/api/view.mp4"), Path::ViewMp4(false));
+        assert_eq!(Path::decode("/api/view.mp4.txt"), Path::ViewMp4(true));
         assert_eq!(Path::decode("/api/junk"), Path::NotFound);
     }
 
+    #[test]
+    fn websocket_accept_key() {
+        // Example from RFC 6455 section 1.3.
+        let req = http::Request::get(
+                "/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live.m4s")
+            .header("Host", "nvr.example.com")
+            .header("Origin", "https://nvr.example.com")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(hyper::Body::empty())
+            .unwrap();
+        assert!(super::is_websocket_upgrade(&req));
+        assert_eq!(super::websocket_accept_key(&req).ok().unwrap(),
+                   "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let (mut parts, body) = req.into_parts();
+        parts.headers.insert("Origin", http::HeaderValue::from_static("https://evil.example.com"));
+        let req = http::Request::from_parts(parts, body);
+        assert_eq!(super::websocket_accept_key(&req).err().unwrap().status(),
+                   http::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn https_redirect() {
+        for &(host, port, want) in &[
+            ("nvr.example.com", 443, "https://nvr.example.com/api/?days=true"),
+            ("nvr.example.com:8080", 8443, "https://nvr.example.com:8443/api/?days=true"),
+            ("[::1]:8080", 443, "https://[::1]/api/?days=true"),
+            ("[::1]", 443, "https://[::1]/api/?days=true"),
+        ] {
+            let req = http::Request::get("/api/?days=true")
+                .header("Host", host)
+                .body(hyper::Body::empty())
+                .unwrap();
+            let resp = super::https_redirect(&req, port);
+            assert_eq!(resp.status(), http::StatusCode::MOVED_PERMANENTLY);
+            assert_eq!(resp.headers().get("Location").unwrap(), want);
+        }
+    }
+
+    #[test]
+    fn permissions_within() {
+        const A: &str = "f8cd4c9a-0de1-4d3c-a6b3-9e1c2b5a4a0e";
+        const B: &str = "2a4d3b7e-54d5-4bb0-8a3c-0b1d9e6f7c21";
+        let perms = |cameras: &[&str]| {
+            let mut p = db::Permissions::new();
+            p.view_video = true;
+            for &c in cameras {
+                p.camera_uuids.push(c.to_owned());
+            }
+            p
+        };
+        assert!(super::permissions_within(&perms(&[A]), &perms(&[])));
+        assert!(super::permissions_within(&perms(&[A]), &perms(&[A, B])));
+        assert!(!super::permissions_within(&perms(&[]), &perms(&[A])));
+        assert!(!super::permissions_within(&perms(&[A, B]), &perms(&[A])));
+        let mut p = perms(&[A]);
+        p.update_signals = true;
+        assert!(!super::permissions_within(&p, &perms(&[A])));
+    }
+
     #[test]
     fn test_segments() {
         testutil::init();
@@ -1206,6 +2908,164 @@ mod tests {
                    Segments::parse("1-5.26-42").unwrap());
     }
 
+    #[test]
+    fn hls_playlist() {
+        use db::recording::{self, Time, TIME_UNITS_PER_SEC};
+        use super::HlsSegment;
+        testutil::init();
+        let start = Time(1430006400 * TIME_UNITS_PER_SEC);
+        let segments = [
+            HlsSegment {
+                id: 1, open_id: 42, start, duration_90k: 60 * 90_000, run_offset: 0,
+                video_sample_entry_sha1: [0x11; 20],
+            },
+            HlsSegment {
+                id: 2, open_id: 42, start: start + recording::Duration(60 * 90_000),
+                duration_90k: 61 * 90_000 + 450, run_offset: 1,
+                video_sample_entry_sha1: [0x11; 20],
+            },
+            HlsSegment {
+                id: 3, open_id: 43, start: start + recording::Duration(300 * 90_000),
+                duration_90k: 30 * 90_000, run_offset: 0,
+                video_sample_entry_sha1: [0x22; 20],
+            },
+        ];
+        assert_eq!(super::hls_playlist(&segments, false), "\
+            #EXTM3U\n\
+            #EXT-X-VERSION:7\n\
+            #EXT-X-TARGETDURATION:62\n\
+            #EXT-X-MEDIA-SEQUENCE:1\n\
+            #EXT-X-PLAYLIST-TYPE:VOD\n\
+            #EXT-X-INDEPENDENT-SEGMENTS\n\
+            #EXT-X-MAP:URI=\"/api/init/1111111111111111111111111111111111111111.mp4\"\n\
+            #EXT-X-PROGRAM-DATE-TIME:2015-04-26T00:00:00.000Z\n\
+            #EXTINF:60.000,\n\
+            view.m4s?s=1@42\n\
+            #EXT-X-PROGRAM-DATE-TIME:2015-04-26T00:01:00.000Z\n\
+            #EXTINF:61.005,\n\
+            view.m4s?s=2@42\n\
+            #EXT-X-DISCONTINUITY\n\
+            #EXT-X-MAP:URI=\"/api/init/2222222222222222222222222222222222222222.mp4\"\n\
+            #EXT-X-PROGRAM-DATE-TIME:2015-04-26T00:05:00.000Z\n\
+            #EXTINF:30.000,\n\
+            view.m4s?s=3@43\n\
+            #EXT-X-ENDLIST\n");
+        let live = super::hls_playlist(&segments[2..], true);
+        assert!(live.contains("#EXT-X-MEDIA-SEQUENCE:3\n"));
+        assert!(live.contains("#EXT-X-DISCONTINUITY-SEQUENCE:3\n"));
+        assert!(!live.contains("#EXT-X-DISCONTINUITY\n"));
+        assert!(!live.contains("#EXT-X-ENDLIST"));
+
+        // The discontinuity sequence stays the same as the window moves within a run.
+        let live = super::hls_playlist(&segments[1..2], true);
+        assert!(live.contains("#EXT-X-MEDIA-SEQUENCE:2\n"));
+        assert!(live.contains("#EXT-X-DISCONTINUITY-SEQUENCE:1\n"));
+    }
+
+    /// Returns a `PlaylistRecording` for use in manifest tests.
+    fn playlist_recording(id: i32, start_sec: i64, duration_90k: i32, run_offset: i32,
+                          vse: &Arc<db::VideoSampleEntry>) -> super::PlaylistRecording {
+        use db::recording::{Time, TIME_UNITS_PER_SEC};
+        super::PlaylistRecording {
+            id,
+            open_id: 42,
+            start: Time((1430006400 + start_sec) * TIME_UNITS_PER_SEC),
+            duration_90k,
+            run_offset,
+            sample_file_bytes: duration_90k / 90,  // 8 kbps.
+            video_sample_entry: vse.clone(),
+        }
+    }
+
+    fn video_sample_entry(id: i32, sha1: u8) -> Arc<db::VideoSampleEntry> {
+        Arc::new(db::VideoSampleEntry {
+            data: Vec::new(),
+            rfc6381_codec: "avc1.4d0029".to_owned(),
+            id,
+            width: 1920,
+            height: 1080,
+            sha1: [sha1; 20],
+        })
+    }
+
+    #[test]
+    fn dash_mpd() {
+        testutil::init();
+        let vse1 = video_sample_entry(1, 0x11);
+        let vse2 = video_sample_entry(2, 0x22);
+        let vse3 = video_sample_entry(3, 0x33);
+        let streams = [
+            (db::StreamType::MAIN, vec![
+                playlist_recording(1, 0, 60 * 90_000, 0, &vse1),
+                playlist_recording(2, 60, 60 * 90_000, 1, &vse2),
+            ]),
+            (db::StreamType::SUB, vec![
+                playlist_recording(7, 1, 60 * 90_000, 0, &vse3),
+                playlist_recording(8, 61, 60 * 90_000, 1, &vse3),
+            ]),
+        ];
+        assert_eq!(super::dash_mpd(&streams), "\
+            <?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" \
+            profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"static\" \
+            mediaPresentationDuration=\"PT121.000S\" minBufferTime=\"PT2S\">\n  \
+            <Period id=\"0\" start=\"PT0.000S\">\n    \
+            <AdaptationSet contentType=\"video\" mimeType=\"video/mp4\" \
+            segmentAlignment=\"false\">\n      \
+            <Representation id=\"main\" codecs=\"avc1.4d0029\" width=\"1920\" \
+            height=\"1080\" bandwidth=\"8000\">\n        \
+            <SegmentTemplate timescale=\"90000\" presentationTimeOffset=\"128700576000000\" \
+            startNumber=\"1\" \
+            initialization=\"/api/init/1111111111111111111111111111111111111111.mp4\" \
+            media=\"main/view.m4s?s=$Number$\">\n          \
+            <SegmentTimeline>\n            \
+            <S t=\"128700576000000\" d=\"5400000\"/>\n          \
+            </SegmentTimeline>\n        \
+            </SegmentTemplate>\n      \
+            </Representation>\n      \
+            <Representation id=\"sub\" codecs=\"avc1.4d0029\" width=\"1920\" \
+            height=\"1080\" bandwidth=\"8000\">\n        \
+            <SegmentTemplate timescale=\"90000\" presentationTimeOffset=\"128700576000000\" \
+            startNumber=\"7\" \
+            initialization=\"/api/init/3333333333333333333333333333333333333333.mp4\" \
+            media=\"sub/view.m4s?s=$Number$\">\n          \
+            <SegmentTimeline>\n            \
+            <S t=\"128700576090000\" d=\"5400000\"/>\n          \
+            </SegmentTimeline>\n        \
+            </SegmentTemplate>\n      \
+            </Representation>\n    \
+            </AdaptationSet>\n  \
+            </Period>\n  \
+            <Period id=\"1\" start=\"PT60.000S\">\n    \
+            <AdaptationSet contentType=\"video\" mimeType=\"video/mp4\" \
+            segmentAlignment=\"false\">\n      \
+            <Representation id=\"main\" codecs=\"avc1.4d0029\" width=\"1920\" \
+            height=\"1080\" bandwidth=\"8000\">\n        \
+            <SegmentTemplate timescale=\"90000\" presentationTimeOffset=\"128700581400000\" \
+            startNumber=\"2\" \
+            initialization=\"/api/init/2222222222222222222222222222222222222222.mp4\" \
+            media=\"main/view.m4s?s=$Number$\">\n          \
+            <SegmentTimeline>\n            \
+            <S t=\"128700581400000\" d=\"5400000\"/>\n          \
+            </SegmentTimeline>\n        \
+            </SegmentTemplate>\n      \
+            </Representation>\n      \
+            <Representation id=\"sub\" codecs=\"avc1.4d0029\" width=\"1920\" \
+            height=\"1080\" bandwidth=\"8000\">\n        \
+            <SegmentTemplate timescale=\"90000\" presentationTimeOffset=\"128700581400000\" \
+            startNumber=\"8\" \
+            initialization=\"/api/init/3333333333333333333333333333333333333333.mp4\" \
+            media=\"sub/view.m4s?s=$Number$\">\n          \
+            <SegmentTimeline>\n            \
+            <S t=\"128700581490000\" d=\"5400000\"/>\n          \
+            </SegmentTimeline>\n        \
+            </SegmentTemplate>\n      \
+            </Representation>\n    \
+            </AdaptationSet>\n  \
+            </Period>\n\
+            </MPD>\n");
+    }
+
     #[tokio::test]
     async fn unauthorized_without_cookie() {
         testutil::init();
@@ -1249,6 +3109,196 @@ mod tests {
         assert_eq!(resp.status(), reqwest::StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn trusted_user_hdr() {
+        testutil::init();
+        let cli = reqwest::Client::new();
+
+        // The header is ignored unless configured.
+        let s = Server::new(None);
+        let url = format!("{}/api/", &s.base_url);
+        let resp = cli.get(&url).header("X-Forwarded-User", "slamb").send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // ...or when it comes from an untrusted peer.
+        let s = Server::with_trusted_user_hdr(None, "x-forwarded-user",
+                                              vec![std::net::Ipv4Addr::new(192, 0, 2, 1).into()]);
+        let url = format!("{}/api/", &s.base_url);
+        let resp = cli.get(&url).header("X-Forwarded-User", "slamb").send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let s = Server::with_trusted_user_hdr(None, "x-forwarded-user",
+                                              vec![std::net::Ipv4Addr::LOCALHOST.into()]);
+        let url = format!("{}/api/", &s.base_url);
+        let resp = cli.get(&url).header("X-Forwarded-User", "slamb").send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp = cli.get(&url).header("X-Forwarded-User", "root").send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn audit() {
+        testutil::init();
+        let mut p = db::Permissions::new();
+        p.view_audit_log = true;
+        let s = Server::new(Some(p));
+        s.db.db.lock().add_audit_entry(&db::audit::Entry {
+            request: db::auth::Request { when_sec: Some(42), ..Default::default() },
+            action: "add user".to_owned(),
+            detail: Some("bob".to_owned()),
+            ..Default::default()
+        }).unwrap();
+        let cli = reqwest::Client::new();
+        let url = format!("{}/api/audit", &s.base_url);
+        let audit: serde_json::Value = cli.get(&url).send().await.unwrap().json().await.unwrap();
+        let entries = audit.get("entries").unwrap().as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["timeSec"], 42);
+        assert_eq!(entries[0]["detail"], "bob");
+
+        // slamb lacks the permission.
+        let mut p = HashMap::new();
+        p.insert("username", "slamb");
+        p.insert("password", "hunter2");
+        let resp = cli.post(&format!("{}/api/login", &s.base_url)).json(&p).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let cookie = SessionCookie::new(resp.headers());
+        let resp = cli.get(&url)
+                      .header(reqwest::header::COOKIE, cookie.header())
+                      .send()
+                      .await
+                      .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn sessions() {
+        testutil::init();
+        let mut p = db::Permissions::new();
+        p.admin = true;
+        let s = Server::new(Some(p));
+        let cli = reqwest::Client::new();
+        let mut p = HashMap::new();
+        p.insert("username", "slamb");
+        p.insert("password", "hunter2");
+        let resp = cli.post(&format!("{}/api/login", &s.base_url)).json(&p).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let cookie = SessionCookie::new(resp.headers());
+        let url = format!("{}/api/sessions", &s.base_url);
+        let other_url = format!("{}/api/sessions?username=slamb", &s.base_url);
+
+        // slamb can list its own sessions but isn't an admin.
+        let resp = cli.get(&url).header(reqwest::header::COOKIE, cookie.header())
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let sessions: serde_json::Value = resp.json().await.unwrap();
+        let sessions = sessions.get("sessions").unwrap().as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["current"], true);
+        let resp = cli.get(&other_url).header(reqwest::header::COOKIE, cookie.header())
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // The unauthenticated caller has no sessions of its own but is an admin.
+        let resp = cli.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let resp = cli.get(&other_url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let sessions: serde_json::Value = resp.json().await.unwrap();
+        let sessions = sessions.get("sessions").unwrap().as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["current"], false);
+        let resp = cli.get(&format!("{}/api/sessions?username=nobody", &s.base_url))
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        let id = sessions[0]["id"].as_str().unwrap();
+
+        // Likewise for revocation: slamb can't revoke by username, and the admin can't revoke a
+        // session belonging to some other user by omitting the username.
+        let toplevel: serde_json::Value = cli.get(&format!("{}/api/", &s.base_url))
+                                             .header(reqwest::header::COOKIE, cookie.header())
+                                             .send().await.unwrap()
+                                             .json().await.unwrap();
+        let csrf = toplevel["session"]["csrf"].as_str().unwrap();
+        let resp = cli.post(&url).header(reqwest::header::COOKIE, cookie.header())
+                      .json(&serde_json::json!({"username": "slamb", "revoke": [id]}))
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);  // missing csrf.
+        let resp = cli.post(&url).header(reqwest::header::COOKIE, cookie.header())
+                      .json(&serde_json::json!({"csrf": csrf, "username": "slamb", "revoke": [id]}))
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let resp = cli.post(&url).json(&serde_json::json!({"revoke": [id]})).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let resp = cli.post(&url).json(&serde_json::json!({"username": "nobody", "revoke": [id]}))
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        let resp = cli.post(&url).json(&serde_json::json!({"username": "slamb", "revoke": [id]}))
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // The revoked cookie no longer works.
+        let resp = cli.get(&url).header(reqwest::header::COOKIE, cookie.header())
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn migrate() {
+        testutil::init();
+
+        // Viewing video isn't enough.
+        let mut p = db::Permissions::new();
+        p.view_video = true;
+        let s = Server::new(Some(p));
+        let url = format!("{}/api/cameras/{}/main/migrate", &s.base_url, s.db.test_camera_uuid);
+        let resp = reqwest::Client::new().post(&url).json(&serde_json::json!({"toDir": "/"}))
+                                         .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let mut p = db::Permissions::new();
+        p.admin = true;
+        let s = Server::new(Some(p));
+        let cli = reqwest::Client::new();
+        let url = format!("{}/api/cameras/{}/main/migrate", &s.base_url, s.db.test_camera_uuid);
+        let dir = s.db.db.lock().sample_file_dirs_by_id().values().next().unwrap().path.clone();
+
+        let resp = cli.post(&url).json(&serde_json::json!({"toDir": "/nonexistent"}))
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // The test stream is recording.
+        let resp = cli.post(&url).json(&serde_json::json!({"toDir": dir})).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::PRECONDITION_FAILED);
+
+        // Once it's stopped, its recordings can be moved to another directory.
+        crate::mp4::tests::copy_mp4_to_db(&s.db);
+        let id = db::CompositeId::new(testutil::TEST_STREAM_ID, 1);
+        let to_tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let to_path = to_tmpdir.path().to_str().unwrap().to_owned();
+        let to_dir_id = {
+            let mut l = s.db.db.lock();
+            l.update_retention(&[db::RetentionChange {
+                stream_id: testutil::TEST_STREAM_ID,
+                new_record: false,
+                new_limit: 1048576,
+                new_weight: 1,
+            }]).unwrap();
+            l.add_sample_file_dir(to_path.clone(), None).unwrap()
+        };
+        let from = s.db.dirs_by_stream_id[&testutil::TEST_STREAM_ID].clone();
+        from.open_file(id).unwrap();
+        let resp = cli.post(&url).json(&serde_json::json!({"toDir": to_path}))
+                      .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let l = s.db.db.lock();
+        assert_eq!(l.streams_by_id()[&testutil::TEST_STREAM_ID].sample_file_dir_id,
+                   Some(to_dir_id));
+        let to = l.sample_file_dirs_by_id()[&to_dir_id].get().unwrap();
+        to.open_file(id).unwrap();
+        from.open_file(id).unwrap_err();  // unlinked.
+    }
+
     #[tokio::test]
     async fn logout() {
         testutil::init();
@@ -1320,6 +3370,43 @@ mod tests {
             .send().await.unwrap();
         assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
     }
+
+    #[tokio::test]
+    async fn export() {
+        use crate::export;
+        testutil::init();
+        let keydir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let key = keydir.path().join("export-key.pem");
+        let key = Arc::new(export::SigningKey::open_or_create(key.to_str().unwrap()).unwrap());
+        let s = Server::with_config(None, |c| c.export_key = Some(key.clone()));
+        crate::mp4::tests::copy_mp4_to_db(&s.db);
+
+        // Authenticate with an API token, which has no session.
+        let token = {
+            let mut l = s.db.db.lock();
+            let user_id = l.get_user("slamb").unwrap().id;
+            let mut p = db::Permissions::new();
+            p.view_video = true;
+            let (token, _) = l.make_token(db::auth::Request::default(), user_id,
+                                          "export".to_owned(), p, None).unwrap();
+            base64::encode_config(&token, base64::STANDARD_NO_PAD)
+        };
+        let resp = reqwest::Client::new()
+            .get(&format!("{}/api/cameras/{}/main/export.tar?s=1",
+                          &s.base_url, s.db.test_camera_uuid))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+            .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let archive = resp.bytes().await.unwrap();
+        let c = export::read(&mut &archive[..]).unwrap();
+        assert_eq!(c.public_key, key.public_key_pem().unwrap());
+        assert!(export::verify(&c.public_key, &c.manifest, &c.signature).unwrap());
+        let manifest: export::Manifest = serde_json::from_slice(&c.manifest).unwrap();
+        assert_eq!(manifest.exported_by.as_ref().map(|u| u.as_str()), Some("slamb"));
+        assert_eq!(manifest.recordings.len(), 1);
+        assert_eq!(manifest.mp4_len, c.mp4_len);
+        assert_eq!(manifest.mp4_sha256, c.mp4_sha256);
+    }
 }
 
 #[cfg(all(test, feature="nightly"))]
@@ -1347,13 +3434,22 @@ mod bench {
                 allow_unauthenticated_permissions: Some(db::Permissions::default()),
                 trust_forward_hdrs: false,
                 time_zone_name: "".to_owned(),
+                export_key: None,
+                https_redirect_port: None,
+                oidc: None,
+                trusted_user_hdr: None,
+                trusted_proxies: Vec::new(),
             }).unwrap();
-            let make_svc = hyper::service::make_service_fn(move |_conn| {
-                futures::future::ok::<_, std::convert::Infallible>(hyper::service::service_fn({
-                    let mut s = service.clone();
-                    move |req| std::pin::Pin::from(s.serve(req))
-                }))
-            });
+            let make_svc = hyper::service::make_service_fn(
+                move |conn: &hyper::server::conn::AddrStream| {
+                    let peer = conn.remote_addr().ip();
+                    futures::future::ok::<_, std::convert::Infallible>(
+                        hyper::service::service_fn({
+                            let mut s = service.clone();
+                            let peer = Some(super::Peer::Addr(peer));
+                            move |req| std::pin::Pin::from(s.serve(req, peer, false))
+                        }))
+                });
             let mut rt = tokio::runtime::Runtime::new().unwrap();
             let srv = rt.enter(|| {
                 let addr = ([127, 0, 0, 1], 0).into();
@@ -141,11 +141,14 @@ function onSelectVideo(nvrSettingsView, camera, streamType, range, recording) {
     trimmedRange.startTime90k,
     trimmedRange.endTime90k
   );
+  const subtitlesUrl = nvrSettingsView.timeStampTrack ?
+    api.subtitlesUrl(camera.uuid, streamType, recording, trimmedRange) :
+    null;
   const videoTitle =
     camera.shortName + ', ' + formattedStart + ' to ' + formattedEnd;
   new VideoDialogView()
     .attach($('body'))
-    .play(videoTitle, recording.videoSampleEntryWidth / 4, url);
+    .play(videoTitle, recording.videoSampleEntryWidth / 4, url, subtitlesUrl);
 }
 
 /**
@@ -99,17 +99,14 @@ export default class MoonfireAPI {
   }
 
   /**
-   * URL that will playback a video segment.
+   * The "s" parameter selecting a (trimmed) recording, as used by view.mp4
+   * and view.vtt.
    *
-   * @param  {String} cameraUUID UUID for the camera from whence comes the video
-   * @param  {String} streamType "main" or "sub"
    * @param  {Recording}  recording     Recording model object
    * @param  {Range90k}   trimmedRange   Range restricting segments
-   * @param  {Boolean}    timestampTrack   True if track should be timestamped
-   * @return {String}                 Constructed url
+   * @return {String}                 The parameter's value
    */
-  videoPlayUrl(cameraUUID, streamType, recording, trimmedRange,
-               timestampTrack = true) {
+  _segmentsParam(recording, trimmedRange) {
     let sParam = recording.startId;
     if (recording.endId !== undefined) {
       sParam += '-' + recording.endId;
@@ -131,6 +128,22 @@ export default class MoonfireAPI {
     if (rel !== '-') {
       sParam += '.' + rel;
     }
+    return sParam;
+  }
+
+  /**
+   * URL that will playback a video segment.
+   *
+   * @param  {String} cameraUUID UUID for the camera from whence comes the video
+   * @param  {String} streamType "main" or "sub"
+   * @param  {Recording}  recording     Recording model object
+   * @param  {Range90k}   trimmedRange   Range restricting segments
+   * @param  {Boolean}    timestampTrack   True if track should be timestamped
+   * @return {String}                 Constructed url
+   */
+  videoPlayUrl(cameraUUID, streamType, recording, trimmedRange,
+               timestampTrack = true) {
+    const sParam = this._segmentsParam(recording, trimmedRange);
     console.log('Video query:', {
       s: sParam,
       ts: timestampTrack,
@@ -142,6 +155,23 @@ export default class MoonfireAPI {
     });
   }
 
+  /**
+   * URL of WebVTT timestamp subtitles to accompany videoPlayUrl's video.
+   * Browsers don't display the .mp4's own subtitle track.
+   *
+   * @param  {String} cameraUUID UUID for the camera from whence comes the video
+   * @param  {String} streamType "main" or "sub"
+   * @param  {Recording}  recording     Recording model object
+   * @param  {Range90k}   trimmedRange   Range restricting segments
+   * @return {String}                 Constructed url
+   */
+  subtitlesUrl(cameraUUID, streamType, recording, trimmedRange) {
+    return this._builder.makeUrl('cameras/' + cameraUUID + '/' + streamType +
+                                 '/view.vtt', {
+      s: this._segmentsParam(recording, trimmedRange),
+    });
+  }
+
   /**
    * Start a new AJAX request with the specified URL.
    *
@@ -77,9 +77,10 @@ export default class VideoDialogView {
    * @param  {String} title Title of the video player
    * @param  {Number} width Width of the player
    * @param  {String} url   URL for source media
+   * @param  {String} subtitlesUrl URL for WebVTT subtitles, or null
    * @return {VideoDialogView}       Returns "this" for chaining.
    */
-  play(title, width, url) {
+  play(title, width, url, subtitlesUrl = null) {
     this._dialogElement.dialog({
       title: title,
       width: width,
@@ -94,6 +95,11 @@ export default class VideoDialogView {
     });
     // Now that dialog is up, set the src so video starts
     console.log('Video url: ' + url);
+    if (subtitlesUrl !== null) {
+      this._videoElement.append(
+        $('<track kind="subtitles" default />').attr('src', subtitlesUrl)
+      );
+    }
     this._videoElement.attr('src', url);
     return this;
   }